@@ -0,0 +1,2 @@
+#!/bin/sh
+cbindgen --config cbindgen.toml --crate larz --output include/larz.h
\ No newline at end of file
@@ -0,0 +1,342 @@
+/* This file is part of larz, and is licensed under the GNU Affero General Public License, version 3 or later. */
+
+#ifndef LARZ_H
+#define LARZ_H
+
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#include <stdbool.h>
+#include <stdint.h>
+
+// The outcome of a call through the C interface
+typedef enum LarzStatus {
+  // The call succeeded
+  LARZ_STATUS_OK = 0,
+  // A required pointer was null, or a string was not valid
+  LARZ_STATUS_INVALID_ARGUMENT = 1,
+  // A file or directory could not be found
+  LARZ_STATUS_NOT_FOUND = 2,
+  // A file or directory could not be accessed
+  LARZ_STATUS_PERMISSION_DENIED = 3,
+  // Reading or writing failed
+  LARZ_STATUS_IO = 4,
+  // The archive is malformed, corrupted or truncated
+  LARZ_STATUS_INVALID_DATA = 5,
+  // Extracted files do not match the hashes recorded in the archive
+  LARZ_STATUS_INTEGRITY = 6,
+  // The archive could not be decrypted; the passphrase is wrong, or the archive is corrupted
+  LARZ_STATUS_DECRYPTION = 7,
+  // The archive's signature is missing, untrusted or invalid
+  LARZ_STATUS_SIGNATURE = 8,
+  // The dictionary needed to decompress the archive is missing or does not match
+  LARZ_STATUS_DICTIONARY = 9,
+  // The archives of a listed-incremental backup are not given in order
+  LARZ_STATUS_INCREMENTAL = 10,
+  // A volume of a multi-volume archive is missing or truncated
+  LARZ_STATUS_VOLUME = 11,
+  // The operation needs a feature this build of the library does not include
+  LARZ_STATUS_UNSUPPORTED = 12,
+  // The library panicked
+  LARZ_STATUS_PANIC = 13,
+} LarzStatus;
+
+// How FIFOs, sockets & device nodes are archived
+typedef enum LarzSpecialFiles {
+  // Store FIFOs & device nodes; sockets cannot be stored in an archive, and are skipped
+  LARZ_SPECIAL_FILES_STORE,
+  // Leave special files out of the archive
+  LARZ_SPECIAL_FILES_SKIP,
+  // Fail when a special file is found
+  LARZ_SPECIAL_FILES_ERROR,
+} LarzSpecialFiles;
+
+// How symbolic links are archived
+typedef enum LarzSymlinks {
+  // Store the files & directories symbolic links point to, as if they were at the link's path
+  LARZ_SYMLINKS_FOLLOW,
+  // Store symbolic links as links
+  LARZ_SYMLINKS_STORE,
+} LarzSymlinks;
+
+// The TAR formats archives can be written in
+//
+// Unless the format is ustar, the hashes of files are recorded in PAX extended headers, so that they can be checked when extracted.
+// Files with holes are written as sparse entries, in the 1.0 format GNU tar writes in PAX archives, and are stored in full in ustar archives.
+typedef enum LarzTarFormat {
+  // POSIX.1-1988 ustar headers, refusing paths, link targets and files too long or too large for them
+  LARZ_TAR_FORMAT_USTAR,
+  // GNU headers, storing long paths & link targets in GNU extension entries, and large sizes in binary
+  LARZ_TAR_FORMAT_GNU,
+  // POSIX.1-2001 PAX headers, storing long paths & link targets, large sizes and sub-second modification times in extended headers
+  LARZ_TAR_FORMAT_PAX,
+} LarzTarFormat;
+
+// A job writing paths into an archive
+typedef struct LarzCompressJob LarzCompressJob;
+
+// A job extracting archives into a directory
+typedef struct LarzExtractJob LarzExtractJob;
+
+// Called as each member of an archive is written or extracted, with the member's path within the archive, its size in bytes, and the `user_data` given with the callback
+//
+// The callback is called on the thread running the job, and the path is only valid until it returns.
+// A null callback reports no progress.
+typedef void (*LarzProgressCallback)(const char *member, uint64_t size, void *user_data);
+
+// The sizes of an archive at each stage of writing it
+typedef struct LarzArchiveStats {
+  // The size of the uncompressed TAR data
+  uint64_t uncompressed_size;
+  // The size of the TAR data after deduplication, or the size of the TAR data if it was not deduplicated
+  uint64_t deduplicated_size;
+  // The size of the archive file, or the total size of its volumes
+  uint64_t archive_size;
+  // The number of files the archive was written to
+  uint32_t volumes;
+} LarzArchiveStats;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Get the description of the last error raised on the calling thread
+//
+// Returns a null pointer if no error has been raised.
+// The string is owned by the library, and is valid until the next call on the same thread that fails.
+const char *larz_last_error_message(void);
+
+// Get the version of the library, as a string such as `"0.3.2"`
+const char *larz_version(void);
+
+// Create a job writing an archive
+//
+// Returns a null pointer if `output_path` is null or is not a valid path.
+//
+// # Safety
+//
+// `output_path` must be null or point to a NUL-terminated string.
+struct LarzCompressJob *larz_compress_job_new(const char *output_path);
+
+// Add a file or directory to be archived
+//
+// The contents of directories are stored at the root of the archive, and files are stored under their file name.
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`], and `path` must be null or point to a NUL-terminated string.
+enum LarzStatus larz_compress_job_add_path(struct LarzCompressJob *job,
+                                           const char *path);
+
+// Perform the job solely in memory, rather than streaming the archive
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_in_memory(struct LarzCompressJob *job, bool in_memory);
+
+// Store repeated regions of the archived files only once
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_deduplicate(struct LarzCompressJob *job, bool deduplicate);
+
+// Store files with identical contents only once, as hard links to the first of them
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_link_duplicates(struct LarzCompressJob *job,
+                                                      bool link_duplicates);
+
+// Choose whether symbolic links are followed or stored as links
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_symlinks(struct LarzCompressJob *job,
+                                               enum LarzSymlinks symlinks);
+
+// Do not descend into directories on a different filesystem from the directories being archived
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_one_file_system(struct LarzCompressJob *job,
+                                                      bool one_file_system);
+
+// Do not descend more than `max_depth` levels beneath the directories being archived, or descend without limit if `max_depth` is negative
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_max_depth(struct LarzCompressJob *job, int64_t max_depth);
+
+// Choose whether FIFOs, sockets & device nodes are stored, skipped or refused
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_special_files(struct LarzCompressJob *job,
+                                                    enum LarzSpecialFiles special_files);
+
+// Choose the TAR format the archive is written in
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_format(struct LarzCompressJob *job, enum LarzTarFormat format);
+
+// Split the archive into volumes of at most `volume_size` bytes, or write a single file if `volume_size` is zero
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_volume_size(struct LarzCompressJob *job,
+                                                  uint64_t volume_size);
+
+// Choose whether the job fails rather than replacing an archive that already exists
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_no_clobber(struct LarzCompressJob *job, bool no_clobber);
+
+// Choose whether the job keeps a checkpoint journal, continuing from the last checkpoint if an earlier run was interrupted
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`].
+enum LarzStatus larz_compress_job_set_resume(struct LarzCompressJob *job, bool resume);
+
+// Encrypt the archive with a key derived from a passphrase, or leave it unencrypted if `passphrase` is null
+//
+// Returns [`Status::Unsupported`] if the library was built without encryption.
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`], and `passphrase` must be null or point to a NUL-terminated string.
+enum LarzStatus larz_compress_job_set_passphrase(struct LarzCompressJob *job,
+                                                 const char *passphrase);
+
+// Call `callback` as each member is written to the archive, or stop reporting progress if `callback` is null
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`], and `user_data` must remain valid until the job is freed.
+enum LarzStatus larz_compress_job_set_progress(struct LarzCompressJob *job,
+                                               LarzProgressCallback callback,
+                                               void *user_data);
+
+// Write the archive
+//
+// If `stats` is not null, the sizes of the archive are written to it.
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`], and `stats` must be null or point to writable memory for an [`ArchiveStats`].
+enum LarzStatus larz_compress_job_run(struct LarzCompressJob *job,
+                                      struct LarzArchiveStats *stats);
+
+// Free a job writing an archive
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_compress_job_new`] that has not already been freed.
+void larz_compress_job_free(struct LarzCompressJob *job);
+
+// Create a job extracting archives
+//
+// Returns a null pointer if `output_path` is null or is not a valid path.
+//
+// # Safety
+//
+// `output_path` must be null or point to a NUL-terminated string.
+struct LarzExtractJob *larz_extract_job_new(const char *output_path);
+
+// Add an archive to be extracted
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`], and `path` must be null or point to a NUL-terminated string.
+enum LarzStatus larz_extract_job_add_path(struct LarzExtractJob *job,
+                                          const char *path);
+
+// Perform the job solely in memory; archives written by larz 0.3 and earlier are treated as in-memory archives
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`].
+enum LarzStatus larz_extract_job_set_in_memory(struct LarzExtractJob *job,
+                                               bool in_memory);
+
+// Do not check extracted files against the hashes recorded in the archive
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`].
+enum LarzStatus larz_extract_job_set_skip_verification(struct LarzExtractJob *job,
+                                                       bool skip_verification);
+
+// Extract into a staging directory beside the output directory, which replaces it only once every archive has been extracted & verified
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`].
+enum LarzStatus larz_extract_job_set_atomic(struct LarzExtractJob *job, bool atomic);
+
+// Choose whether the job keeps a checkpoint journal, skipping whatever an earlier run that was interrupted already extracted
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`].
+enum LarzStatus larz_extract_job_set_resume(struct LarzExtractJob *job, bool resume);
+
+// Decrypt encrypted archives with a passphrase, or forget the passphrase if `passphrase` is null
+//
+// Returns [`Status::Unsupported`] if the library was built without encryption.
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`], and `passphrase` must be null or point to a NUL-terminated string.
+enum LarzStatus larz_extract_job_set_passphrase(struct LarzExtractJob *job,
+                                                const char *passphrase);
+
+// Refuse to extract archives unless they are signed with the public key in the file at `key_path`, or accept unsigned archives if `key_path` is null
+//
+// Returns [`Status::Unsupported`] if the library was built without signing.
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`], and `key_path` must be null or point to a NUL-terminated string.
+enum LarzStatus larz_extract_job_set_verifying_key(struct LarzExtractJob *job,
+                                                   const char *key_path);
+
+// Call `callback` as each member is extracted, or stop reporting progress if `callback` is null
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`], and `user_data` must remain valid until the job is freed.
+enum LarzStatus larz_extract_job_set_progress(struct LarzExtractJob *job,
+                                              LarzProgressCallback callback,
+                                              void *user_data);
+
+// Extract the archives
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`].
+enum LarzStatus larz_extract_job_run(struct LarzExtractJob *job);
+
+// Free a job extracting archives
+//
+// # Safety
+//
+// `job` must be null or a job returned by [`larz_extract_job_new`] that has not already been freed.
+void larz_extract_job_free(struct LarzExtractJob *job);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* LARZ_H */
@@ -0,0 +1,912 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::format;
+use crate::incremental::Incremental;
+use crate::journal::{Checkpoint, CompressJournal};
+use crate::{CompressOptions, SpecialFiles, Symlinks, TarFormat, WalkOptions};
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The files already appended to an archive, so that later links to them are stored as hard links rather than as copies
+#[derive(Debug, Default)]
+pub(crate) struct HardLinks {
+	/// The path within the archive and content hash of each file with more than one link, by its device and inode numbers
+	inodes: HashMap<(u64, u64), (PathBuf, blake3::Hash)>,
+	/// The path within the archive of the first file with each content hash, if files with identical contents are linked together
+	contents: Option<HashMap<blake3::Hash, PathBuf>>,
+}
+
+impl HardLinks {
+	/// Begin tracking the files appended to an archive
+	///
+	/// # Arguments
+	///
+	/// * `link_duplicates` - Whether to store files with identical contents as hard links to the first of them, even if they are not linked on disk
+	pub(crate) fn new(link_duplicates: bool) -> HardLinks {
+		HardLinks {
+			inodes: HashMap::new(),
+			contents: link_duplicates.then(HashMap::new),
+		}
+	}
+
+	/// Find the member of the archive a file should be stored as a hard link to, if any
+	///
+	/// # Arguments
+	///
+	/// * `metadata` - The metadata of the file
+	///
+	/// * `hash` - The hash of the file's contents
+	fn target(&self, metadata: &Metadata, hash: &blake3::Hash) -> Option<&Path> {
+		if let Some((target, _)) = file_id(metadata).and_then(|id| self.inodes.get(&id)) {
+			return Some(target);
+		}
+		// Empty files are all identical, but are not worth linking together
+		match metadata.len() {
+			0 => None,
+			_ => self.contents.as_ref()?.get(hash).map(PathBuf::as_path),
+		}
+	}
+
+	/// Record that a file has been stored in full
+	///
+	/// # Arguments
+	///
+	/// * `name` - The path of the file within the archive
+	///
+	/// * `metadata` - The metadata of the file
+	///
+	/// * `hash` - The hash of the file's contents
+	fn record(&mut self, name: &Path, metadata: &Metadata, hash: blake3::Hash) {
+		if let Some(id) = file_id(metadata) {
+			self.inodes.insert(id, (name.to_path_buf(), hash));
+		}
+		if let Some(contents) = self.contents.as_mut() {
+			contents.entry(hash).or_insert_with(|| name.to_path_buf());
+		}
+	}
+}
+
+/// The directories being walked into, so that a symbolic link leading back to one of them is not followed around the loop
+#[derive(Debug, Default)]
+pub(crate) struct Ancestors {
+	/// The index of the parent of each directory descended into
+	parents: Vec<Option<usize>>,
+	/// The device and inode numbers of each directory descended into
+	ids: Vec<Option<(u64, u64)>>,
+}
+
+impl Ancestors {
+	/// Record that a directory is being descended into, returning the index to give its children
+	///
+	/// Returns `None` if the directory is already being walked, beneath itself.
+	///
+	/// # Arguments
+	///
+	/// * `parent` - The index of the directory's parent, or `None` if it is the directory being archived
+	///
+	/// * `metadata` - The metadata of the directory
+	pub(crate) fn enter(&mut self, parent: Option<usize>, metadata: &Metadata) -> Option<usize> {
+		let id = directory_id(metadata);
+		let mut ancestor = parent;
+		while let Some(index) = ancestor {
+			if id.is_some() && self.ids[index] == id {
+				return None;
+			}
+			ancestor = self.parents[index];
+		}
+		self.parents.push(parent);
+		self.ids.push(id);
+		Some(self.ids.len() - 1)
+	}
+}
+
+/// The device and inode numbers of a directory
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the directory
+#[cfg(unix)]
+fn directory_id(metadata: &Metadata) -> Option<(u64, u64)> {
+	use std::os::unix::fs::MetadataExt;
+	Some((metadata.dev(), metadata.ino()))
+}
+
+/// The device and inode numbers of a directory
+///
+/// Loops are only detected on Unix-like platforms.
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the directory
+#[cfg(not(unix))]
+fn directory_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+	None
+}
+
+/// The device and inode numbers of a file with more than one hard link
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+	use std::os::unix::fs::MetadataExt;
+	(metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+/// The device and inode numbers of a file with more than one hard link
+///
+/// Hard links are only detected on Unix-like platforms.
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+	None
+}
+
+/// The device number of a file, if files on different filesystems can be told apart
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(unix)]
+pub(crate) fn device_id(metadata: &Metadata) -> Option<u64> {
+	use std::os::unix::fs::MetadataExt;
+	Some(metadata.dev())
+}
+
+/// The device number of a file, if files on different filesystems can be told apart
+///
+/// Filesystems are only told apart on Unix-like platforms.
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(not(unix))]
+pub(crate) fn device_id(_metadata: &Metadata) -> Option<u64> {
+	None
+}
+
+/// Whether a directory's contents should be archived
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the directory
+///
+/// * `depth` - How many levels beneath the directory being archived the directory is
+///
+/// * `root_device` - The device number of the directory being archived
+///
+/// * `walk` - How directories are walked
+pub(crate) fn should_descend(
+	metadata: &Metadata,
+	depth: usize,
+	root_device: Option<u64>,
+	walk: &WalkOptions,
+) -> bool {
+	walk.max_depth.is_none_or(|max_depth| depth < max_depth)
+		&& (!walk.one_file_system || device_id(metadata) == root_device)
+}
+
+/// Whether a file should be left out of an archive because it is a special file, failing if special files are not allowed
+///
+/// # Arguments
+///
+/// * `fs_path` - Path to the file
+///
+/// * `metadata` - The metadata of the file
+///
+/// * `walk` - How directories are walked
+pub(crate) fn skip_special(
+	fs_path: &Path,
+	metadata: &Metadata,
+	walk: &WalkOptions,
+) -> Result<bool> {
+	let file_type = metadata.file_type();
+	if file_type.is_file() || file_type.is_dir() || file_type.is_symlink() {
+		return Ok(false);
+	}
+	match walk.special_files {
+		SpecialFiles::Store => Ok(is_socket(metadata)),
+		SpecialFiles::Skip => Ok(true),
+		SpecialFiles::Error => Err(Error::new(
+			ErrorKind::Unsupported,
+			format!("'{}' is a special file", crate::escape_path(fs_path)),
+		)),
+	}
+}
+
+/// Whether a file is a socket, which cannot be stored in an archive
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(unix)]
+fn is_socket(metadata: &Metadata) -> bool {
+	use std::os::unix::fs::FileTypeExt;
+	metadata.file_type().is_socket()
+}
+
+/// Whether a file is a socket, which cannot be stored in an archive
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(not(unix))]
+fn is_socket(_metadata: &Metadata) -> bool {
+	false
+}
+
+/// The header of a FIFO or device node, without its path
+///
+/// # Arguments
+///
+/// * `fs_path` - Path to the file
+///
+/// * `metadata` - The metadata of the file
+///
+/// * `format` - The format the archive is written in
+#[cfg(unix)]
+pub(crate) fn special_header(
+	fs_path: &Path,
+	metadata: &Metadata,
+	format: TarFormat,
+) -> Result<tar::Header> {
+	use std::os::unix::fs::{FileTypeExt, MetadataExt};
+	let file_type = metadata.file_type();
+	let entry_type = if file_type.is_fifo() {
+		tar::EntryType::Fifo
+	} else if file_type.is_char_device() {
+		tar::EntryType::Char
+	} else if file_type.is_block_device() {
+		tar::EntryType::Block
+	} else {
+		return Err(Error::new(
+			ErrorKind::Unsupported,
+			format!("'{}' has an unknown file type", crate::escape_path(fs_path)),
+		));
+	};
+	let mut header = format::new_header(format);
+	header.set_metadata(metadata);
+	header.set_entry_type(entry_type);
+	header.set_size(0);
+	// Split the device number as glibc's `major` and `minor` do
+	let device = metadata.rdev();
+	header.set_device_major((((device >> 32) & 0xffff_f000) | ((device >> 8) & 0x0fff)) as u32)?;
+	header.set_device_minor((((device >> 12) & 0xffff_ff00) | (device & 0xff)) as u32)?;
+	Ok(header)
+}
+
+/// The header of a FIFO or device node, without its path
+///
+/// Special files only exist on Unix-like platforms.
+///
+/// # Arguments
+///
+/// * `fs_path` - Path to the file
+///
+/// * `metadata` - The metadata of the file
+///
+/// * `format` - The format the archive is written in
+#[cfg(not(unix))]
+pub(crate) fn special_header(
+	fs_path: &Path,
+	_metadata: &Metadata,
+	_format: TarFormat,
+) -> Result<tar::Header> {
+	Err(Error::new(
+		ErrorKind::Unsupported,
+		format!("'{}' has an unknown file type", crate::escape_path(fs_path)),
+	))
+}
+
+/// Read the metadata of a file, following it if it is a symbolic link that is to be followed
+///
+/// # Arguments
+///
+/// * `fs_path` - Path to the file
+///
+/// * `walk` - How directories are walked
+fn read_metadata(fs_path: &Path, walk: &WalkOptions) -> Result<Metadata> {
+	match walk.symlinks {
+		Symlinks::Follow => std::fs::metadata(fs_path),
+		Symlinks::Store => std::fs::symlink_metadata(fs_path),
+	}
+}
+
+/// Files no larger than this are held in memory between being read and being written to an archive, rather than in a scratch file
+pub(crate) const MEMORY_SPOOL_LIMIT: u64 = 1024 * 1024;
+
+/// The contents of a regular file, read once so that what is written to an archive is exactly what was hashed
+struct FileContents {
+	/// The hash of the file's contents
+	hash: blake3::Hash,
+	/// The offset and length of each region of the file holding data, if it has holes
+	regions: Option<Vec<(u64, u64)>>,
+	/// The data read from the file, or from its regions holding data if it has holes
+	data: Box<dyn Read>,
+}
+
+impl FileContents {
+	/// Read and hash a regular file, holding its contents until they are written
+	///
+	/// Only as much of the file as its metadata records is read, and it is an error if the file is shorter than that.
+	///
+	/// # Arguments
+	///
+	/// * `fs_path` - Path to the file
+	///
+	/// * `metadata` - The metadata of the file
+	///
+	/// * `sparse` - Whether to read only the regions of the file holding data, if it has holes
+	fn read(fs_path: &Path, metadata: &Metadata, sparse: bool) -> Result<FileContents> {
+		let mut file = File::open(fs_path)?;
+		let regions = match sparse {
+			true => data_regions(&file, metadata)?,
+			false => None,
+		};
+		let length: u64 = regions.as_ref().map_or(metadata.len(), |regions| {
+			regions.iter().map(|(_, length)| length).sum()
+		});
+		let mut scratch = match length > MEMORY_SPOOL_LIMIT {
+			true => Some(crate::scratch()?),
+			false => None,
+		};
+		let mut memory = Vec::new();
+
+		let mut hasher = blake3::Hasher::new();
+		let mut buffer = vec![0u8; 64 * 1024];
+		let mut position = 0;
+		for (offset, length) in regions.clone().unwrap_or_else(|| vec![(0, metadata.len())]) {
+			// Holes read as zeros
+			hash_zeros(&mut hasher, offset - position);
+			file.seek(SeekFrom::Start(offset))?;
+			let mut remaining = length;
+			while remaining > 0 {
+				let wanted = buffer.len().min(remaining.try_into().unwrap_or(usize::MAX));
+				let read = match file.read(&mut buffer[..wanted]) {
+					Ok(0) => {
+						return Err(Error::new(
+							ErrorKind::UnexpectedEof,
+							format!(
+								"'{}' was truncated while it was being archived",
+								crate::escape_path(fs_path)
+							),
+						))
+					}
+					Ok(read) => read,
+					Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+					Err(e) => return Err(e),
+				};
+				hasher.update(&buffer[..read]);
+				match scratch.as_mut() {
+					Some(scratch) => scratch.write_all(&buffer[..read])?,
+					None => memory.extend_from_slice(&buffer[..read]),
+				}
+				remaining -= read as u64;
+			}
+			position = offset + length;
+		}
+		hash_zeros(&mut hasher, metadata.len().saturating_sub(position));
+
+		let data: Box<dyn Read> = match scratch {
+			Some(mut scratch) => {
+				scratch.seek(SeekFrom::Start(0))?;
+				Box::new(BufReader::new(scratch))
+			}
+			None => Box::new(std::io::Cursor::new(memory)),
+		};
+		Ok(FileContents {
+			hash: hasher.finalize(),
+			regions,
+			data,
+		})
+	}
+}
+
+/// Hash a run of zeros, as the holes of a file read
+///
+/// # Arguments
+///
+/// * `hasher` - The hasher to update
+///
+/// * `length` - How many zeros to hash
+pub(crate) fn hash_zeros(hasher: &mut blake3::Hasher, mut length: u64) {
+	static ZEROS: [u8; 64 * 1024] = [0u8; 64 * 1024];
+	while length > 0 {
+		let run = length.min(ZEROS.len() as u64);
+		hasher.update(&ZEROS[..run as usize]);
+		length -= run;
+	}
+}
+
+/// Append files & directories to an archive
+///
+/// The contents of directories are stored at the root of the archive, and files are stored under their file name.
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `paths` - A list of paths pointing to files or directories intended to be archived
+///
+/// * `options` - Options controlling how the archive is written
+///
+/// * `incremental` - The state of the listed-incremental backup being written, if any; unchanged files are left out
+///
+/// * `links` - The files already appended to the archive
+///
+/// * `journal` - The checkpoint journal of the archive, if it can be resumed; entries it records as written are left out
+///
+/// * `optional_logger` - An optional `BufWriter` to log information to
+pub(crate) fn append_paths<T: Checkpoint, W: Write>(
+	tar: &mut tar::Builder<T>,
+	paths: Vec<PathBuf>,
+	options: &CompressOptions,
+	mut incremental: Option<&mut Incremental>,
+	links: &mut HardLinks,
+	mut journal: Option<&mut CompressJournal>,
+	mut optional_logger: Option<&mut BufWriter<W>>,
+) -> Result<()> {
+	tar.follow_symlinks(options.walk.symlinks == Symlinks::Follow);
+	for fs_path in paths {
+		if let Some(ref mut logger) = optional_logger {
+			writeln!(logger, "Compressing '{}' … ", crate::escape_path(&fs_path))?;
+		}
+		match read_metadata(&fs_path, &options.walk)?.is_dir() {
+			true => {
+				append_tree(
+					tar,
+					&fs_path,
+					options,
+					incremental.as_deref_mut(),
+					links,
+					journal.as_deref_mut(),
+				)?;
+			}
+			false => {
+				let name = fs_path.file_name().ok_or_else(|| {
+					Error::new(
+						ErrorKind::InvalidInput,
+						format!("'{}' has no file name", crate::escape_path(&fs_path)),
+					)
+				})?;
+				append_entry(
+					tar,
+					&fs_path,
+					Path::new(name),
+					options,
+					incremental.as_deref_mut(),
+					links,
+					journal.as_deref_mut(),
+				)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Append a directory and everything beneath it to the root of an archive
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `root` - Path to the directory
+///
+/// * `options` - Options controlling how the archive is written
+///
+/// * `incremental` - The state of the listed-incremental backup being written, if any
+///
+/// * `links` - The files already appended to the archive
+///
+/// * `journal` - The checkpoint journal of the archive, if it can be resumed
+fn append_tree<T: Checkpoint>(
+	tar: &mut tar::Builder<T>,
+	root: &Path,
+	options: &CompressOptions,
+	mut incremental: Option<&mut Incremental>,
+	links: &mut HardLinks,
+	mut journal: Option<&mut CompressJournal>,
+) -> Result<()> {
+	let walk = &options.walk;
+	let root_device = device_id(&read_metadata(root, walk)?);
+	let mut ancestors = Ancestors::default();
+	let mut stack = vec![(root.to_path_buf(), Path::new(".").to_path_buf(), 0, None)];
+	while let Some((fs_path, name, depth, parent)) = stack.pop() {
+		let metadata = read_metadata(&fs_path, walk)?;
+		if metadata.is_dir() {
+			// Directories written before the checkpoint being resumed from are still walked, as their contents may not have been
+			let completed = journal
+				.as_deref()
+				.is_some_and(|journal| journal.completed(&name).is_some());
+			if !completed {
+				append_other(tar, &fs_path, &name, &metadata, options.format)?;
+				if let Some(incremental) = incremental.as_deref_mut() {
+					incremental.record(&name, &metadata, None);
+				}
+				if let Some(journal) = journal.as_deref_mut() {
+					journal.record(tar, &name, None)?;
+				}
+				if let Some(progress) = &options.progress {
+					progress.report(&name, 0);
+				}
+			}
+			if !should_descend(&metadata, depth, root_device, walk) {
+				continue;
+			}
+			// A directory reached again through a symbolic link beneath it is stored without its contents
+			let Some(index) = ancestors.enter(parent, &metadata) else {
+				continue;
+			};
+			let mut children = std::fs::read_dir(&fs_path)?
+				.map(|child| child.map(|child| child.file_name()))
+				.collect::<Result<Vec<_>>>()?;
+			// Visit children in order of their names, so archives of the same tree are identical
+			children.sort_unstable_by(|a, b| b.cmp(a));
+			stack.extend(children.into_iter().map(|child| {
+				(
+					fs_path.join(&child),
+					name.join(&child),
+					depth + 1,
+					Some(index),
+				)
+			}));
+		} else {
+			append_entry(
+				tar,
+				&fs_path,
+				&name,
+				options,
+				incremental.as_deref_mut(),
+				links,
+				journal.as_deref_mut(),
+			)?;
+		}
+	}
+	Ok(())
+}
+
+/// Append a single file to an archive, recording the hash of its contents if it is a regular file
+///
+/// A regular file is stored as a hard link if it is another link to a file already in the archive, or, if files with identical contents are linked together, if its contents are already in the archive.
+/// Special files are left out of the archive, or refused, as the walk options direct.
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `fs_path` - Path to the file
+///
+/// * `name` - The path of the file within the archive
+///
+/// * `options` - Options controlling how the archive is written
+///
+/// * `incremental` - The state of the listed-incremental backup being written, if any; the file is left out if it is unchanged
+///
+/// * `links` - The files already appended to the archive
+///
+/// * `journal` - The checkpoint journal of the archive, if it can be resumed; the file is left out if it was written before the checkpoint being resumed from
+fn append_entry<T: Checkpoint>(
+	tar: &mut tar::Builder<T>,
+	fs_path: &Path,
+	name: &Path,
+	options: &CompressOptions,
+	mut incremental: Option<&mut Incremental>,
+	links: &mut HardLinks,
+	journal: Option<&mut CompressJournal>,
+) -> Result<()> {
+	let format = options.format;
+	let metadata = read_metadata(fs_path, &options.walk)?;
+	if skip_special(fs_path, &metadata, &options.walk)? {
+		return Ok(());
+	}
+	if let Some(completed) = journal
+		.as_deref()
+		.and_then(|journal| journal.completed(name))
+	{
+		// Files stored in full are still remembered, so that later links to them are stored as hard links
+		if let (true, Some(hash)) = (metadata.is_file(), completed) {
+			links.record(name, &metadata, hash);
+		}
+		return Ok(());
+	}
+	let mut hash = None;
+	let mut stored_hash = None;
+	let mut size = 0;
+	if metadata.is_file() {
+		if let Some(incremental) = incremental.as_deref_mut() {
+			if incremental.carry_over(name, &metadata) {
+				return Ok(());
+			}
+		}
+		// Other links to a file already in the archive are stored as hard links without reading them again
+		let (file_hash, contents) = match file_id(&metadata).and_then(|id| links.inodes.get(&id)) {
+			Some((_, known_hash)) => (*known_hash, None),
+			None => {
+				let contents = FileContents::read(fs_path, &metadata, format != TarFormat::Ustar)?;
+				(contents.hash, Some(contents))
+			}
+		};
+		match links.target(&metadata, &file_hash).map(Path::to_path_buf) {
+			Some(target) => {
+				let mut header = format::new_header(format);
+				header.set_metadata(&metadata);
+				header.set_entry_type(tar::EntryType::Link);
+				header.set_size(0);
+				format::append(
+					tar,
+					format,
+					&mut header,
+					name,
+					Some(&target),
+					metadata.modified().ok(),
+					None,
+					std::io::empty(),
+				)?;
+			}
+			None => {
+				let contents = match contents {
+					Some(contents) => contents,
+					None => FileContents::read(fs_path, &metadata, format != TarFormat::Ustar)?,
+				};
+				append_file(tar, name, &metadata, format, contents)?;
+				links.record(name, &metadata, file_hash);
+				stored_hash = Some(file_hash);
+				size = metadata.len();
+			}
+		}
+		hash = Some(file_hash);
+	} else {
+		append_other(tar, fs_path, name, &metadata, format)?;
+	}
+	if let Some(incremental) = incremental {
+		incremental.record(name, &metadata, hash);
+	}
+	if let Some(journal) = journal {
+		journal.record(tar, name, stored_hash)?;
+	}
+	if let Some(progress) = &options.progress {
+		progress.report(name, size);
+	}
+	Ok(())
+}
+
+/// Append the contents of a regular file to an archive
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `name` - The path of the file within the archive
+///
+/// * `metadata` - The metadata of the file
+///
+/// * `format` - The format the archive is written in
+///
+/// * `contents` - The contents of the file, as they were read and hashed
+fn append_file<T: Write>(
+	tar: &mut tar::Builder<T>,
+	name: &Path,
+	metadata: &Metadata,
+	format: TarFormat,
+	contents: FileContents,
+) -> Result<()> {
+	let mut header = format::new_header(format);
+	header.set_metadata(metadata);
+	match (contents.regions, format) {
+		// GNU archives have sparse entries of their own, and PAX archives the 1.0 format GNU tar writes in them
+		(Some(regions), TarFormat::Gnu) => {
+			let records = format::records(format, metadata.modified().ok(), Some(contents.hash));
+			tar.append_pax_extensions(records.iter().map(|(key, value)| (*key, value.as_slice())))?;
+			append_sparse(tar, name, metadata, &regions, contents.data)
+		}
+		(Some(regions), _) => format::append_pax_sparse(
+			tar,
+			&mut header,
+			name,
+			metadata.len(),
+			metadata.modified().ok(),
+			contents.hash,
+			&regions,
+			contents.data,
+		),
+		(None, _) => format::append(
+			tar,
+			format,
+			&mut header,
+			name,
+			None,
+			metadata.modified().ok(),
+			Some(contents.hash),
+			contents.data,
+		),
+	}
+}
+
+/// Append a directory, symbolic link or special file to an archive
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `fs_path` - Path to the file
+///
+/// * `name` - The path of the file within the archive
+///
+/// * `metadata` - The metadata of the file
+///
+/// * `format` - The format the archive is written in
+fn append_other<T: Write>(
+	tar: &mut tar::Builder<T>,
+	fs_path: &Path,
+	name: &Path,
+	metadata: &Metadata,
+	format: TarFormat,
+) -> Result<()> {
+	let mut header = match metadata.is_dir() || metadata.is_symlink() {
+		true => {
+			let mut header = format::new_header(format);
+			header.set_metadata(metadata);
+			header
+		}
+		false => special_header(fs_path, metadata, format)?,
+	};
+	let link = match metadata.is_symlink() {
+		true => Some(std::fs::read_link(fs_path)?),
+		false => None,
+	};
+	format::append(
+		tar,
+		format,
+		&mut header,
+		name,
+		link.as_deref(),
+		metadata.modified().ok(),
+		None,
+		std::io::empty(),
+	)
+}
+
+/// Append a file with holes to an archive as a GNU sparse entry, storing only the regions holding data
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `name` - The path of the file within the archive
+///
+/// * `metadata` - The metadata of the file
+///
+/// * `regions` - The offset and length of each region of the file holding data, as found by [`data_regions`]
+///
+/// * `data` - The contents of those regions, one after another
+fn append_sparse<T: Write>(
+	tar: &mut tar::Builder<T>,
+	name: &Path,
+	metadata: &Metadata,
+	regions: &[(u64, u64)],
+	data: impl Read,
+) -> Result<()> {
+	let mut header = tar::Header::new_gnu();
+	header.set_metadata(metadata);
+	header.set_entry_type(tar::EntryType::GNUSparse);
+	header.set_size(regions.iter().map(|(_, length)| length).sum());
+
+	// The first four regions are listed in the header, and the rest in extension headers of 21 regions each, written before the data
+	let (listed, extended) = regions.split_at(regions.len().min(4));
+	let gnu = header
+		.as_gnu_mut()
+		.expect("GNU headers have GNU extensions");
+	gnu.set_real_size(metadata.len());
+	for ((offset, length), field) in listed.iter().zip(gnu.sparse.iter_mut()) {
+		field.set_offset(*offset);
+		field.set_length(*length);
+	}
+	gnu.set_is_extended(!extended.is_empty());
+	let mut extensions = Vec::new();
+	let mut chunks = extended.chunks(21).peekable();
+	while let Some(chunk) = chunks.next() {
+		let mut extension = tar::GnuExtSparseHeader::new();
+		for ((offset, length), field) in chunk.iter().zip(extension.sparse.iter_mut()) {
+			field.set_offset(*offset);
+			field.set_length(*length);
+		}
+		extension.set_is_extended(chunks.peek().is_some());
+		extensions.extend_from_slice(extension.as_bytes());
+	}
+	tar.append_data(&mut header, name, extensions.as_slice().chain(data))
+}
+
+/// Find the regions of a file holding data, using `SEEK_DATA` and `SEEK_HOLE`
+///
+/// Returns the offset and length of each region, ending with an empty region at the end of the file; or `None` if the file has no holes, or the filesystem cannot find them.
+///
+/// # Arguments
+///
+/// * `file` - The file, opened for reading
+///
+/// * `metadata` - The metadata of the file
+#[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux"))]
+pub(crate) fn data_regions<F: std::os::unix::io::AsRawFd>(
+	file: &F,
+	metadata: &Metadata,
+) -> Result<Option<Vec<(u64, u64)>>> {
+	use std::os::unix::fs::MetadataExt;
+
+	#[cfg(target_os = "freebsd")]
+	use libc::lseek;
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	use libc::lseek64 as lseek;
+
+	// Files taking up as many blocks as their size needs have no holes
+	let size = metadata.len();
+	if size == 0 || metadata.blocks() * 512 >= size {
+		return Ok(None);
+	}
+	let seek = |offset: u64, whence: libc::c_int| match unsafe {
+		lseek(file.as_raw_fd(), offset as _, whence)
+	} {
+		-1 => Err(Error::last_os_error()),
+		position => Ok(position as u64),
+	};
+
+	let mut regions = Vec::new();
+	let mut offset = 0;
+	while offset < size {
+		let start = match seek(offset, libc::SEEK_DATA) {
+			Ok(start) => start,
+			// There is no more data before the end of the file
+			Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+			Err(_) => return Ok(None),
+		};
+		let end = match seek(start, libc::SEEK_HOLE) {
+			Ok(end) => end.min(size),
+			Err(_) => return Ok(None),
+		};
+		if end > start {
+			regions.push((start, end - start));
+		}
+		offset = end;
+	}
+	if regions == [(0, size)] {
+		return Ok(None);
+	}
+	if regions
+		.last()
+		.is_none_or(|(start, length)| start + length < size)
+	{
+		regions.push((size, 0));
+	}
+	Ok(Some(regions))
+}
+
+/// Find the regions of a file holding data
+///
+/// Holes cannot be found on this platform, so files are always stored in full.
+///
+/// # Arguments
+///
+/// * `file` - The file, opened for reading
+///
+/// * `metadata` - The metadata of the file
+#[cfg(not(any(target_os = "android", target_os = "freebsd", target_os = "linux")))]
+pub(crate) fn data_regions<F>(_file: &F, _metadata: &Metadata) -> Result<Option<Vec<(u64, u64)>>> {
+	Ok(None)
+}
@@ -0,0 +1,1099 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Asynchronous compression and extraction, using [Tokio](https://tokio.rs).
+//!
+//! [`compress_archive`] writes an archive to any [`AsyncWrite`], and [`extract_archive`] reads one from any [`AsyncRead`], so archives can be streamed to and from sockets and other asynchronous I/O without blocking a thread.
+//! Both respect backpressure: no more data is produced than the destination accepts.
+//!
+//! The futures can be cancelled by dropping them; work stops at the next point the future would wait for I/O.
+//! Whatever was written before then is left in place, so a cancelled archive or extraction is incomplete.
+//!
+//! These functions write and read streaming archives (LZ4 frames), including those written by larz 0.3 and earlier.
+//! Encryption, dictionaries, deduplication, incremental backups and multi-volume archives are not supported here; use the blocking functions for those.
+//! As with the blocking functions, files with holes are stored as sparse entries unless the format is ustar, where the filesystem can report them, and extracted with their holes intact.
+
+use crate::header::{ArchiveHeader, Codec, Flags, FIXED_HEADER_SIZE, MAGIC};
+use crate::integrity::{IntegrityError, HASH_PAX_KEY};
+use crate::{
+	append, format, incremental, CompressOptions, ExtractOptions, Symlinks, TarFormat, WalkOptions,
+};
+use lz4_flex::frame::{FrameDecoder, FrameEncoder, FrameInfo};
+use std::collections::VecDeque;
+use std::io::{Cursor, Error, ErrorKind, Read, Result, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_stream::StreamExt;
+
+/// The most uncompressed input accepted by the encoder in a single write
+const MAX_WRITE_SIZE: usize = 64 * 1024;
+
+/// How many compressed bytes the decoder reads at a time
+const READ_SIZE: usize = 64 * 1024;
+
+/// The length of the smallest LZ4 frame header
+const MIN_FRAME_HEADER_SIZE: usize = 7;
+
+/// Compresses data into an LZ4 frame as it is written to an asynchronous writer
+pub struct Lz4FrameEncoder<W: AsyncWrite + Unpin> {
+	/// Where the frame is written to
+	inner: W,
+	/// Compresses data into an in-memory buffer, until the frame is finished
+	encoder: Option<FrameEncoder<Vec<u8>>>,
+	/// Compressed data yet to be written
+	pending: Vec<u8>,
+	/// How much of the pending data has been written
+	position: usize,
+}
+
+impl<W: AsyncWrite + Unpin> Lz4FrameEncoder<W> {
+	/// Begin an LZ4 frame
+	///
+	/// # Arguments
+	///
+	/// * `inner` - Where the frame is written to
+	///
+	/// * `frame_info` - The parameters of the frame
+	pub fn new(inner: W, frame_info: FrameInfo) -> Lz4FrameEncoder<W> {
+		Lz4FrameEncoder {
+			inner,
+			encoder: Some(FrameEncoder::with_frame_info(frame_info, Vec::new())),
+			pending: Vec::new(),
+			position: 0,
+		}
+	}
+
+	/// Finish the frame and flush it, returning the underlying writer
+	pub async fn finish(mut self) -> Result<W> {
+		std::future::poll_fn(|cx| self.poll_finish(cx)).await?;
+		self.inner.flush().await?;
+		Ok(self.inner)
+	}
+
+	/// Move any compressed data out of the encoder, to be written
+	fn take_compressed(&mut self) {
+		if let Some(encoder) = self.encoder.as_mut() {
+			let compressed = encoder.get_mut();
+			if !compressed.is_empty() {
+				self.pending.append(compressed);
+			}
+		}
+	}
+
+	/// Write all pending compressed data to the underlying writer
+	///
+	/// # Arguments
+	///
+	/// * `cx` - The context of the task being polled
+	fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+		while self.position < self.pending.len() {
+			let written =
+				ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.position..]))?;
+			if written == 0 {
+				return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+			}
+			self.position += written;
+		}
+		self.pending.clear();
+		self.position = 0;
+		Poll::Ready(Ok(()))
+	}
+
+	/// Write the end of the frame, and any compressed data before it
+	///
+	/// # Arguments
+	///
+	/// * `cx` - The context of the task being polled
+	fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+		if let Some(encoder) = self.encoder.take() {
+			let compressed = encoder.finish().map_err(Error::other)?;
+			self.pending.extend_from_slice(&compressed);
+		}
+		self.poll_drain(cx)
+	}
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Lz4FrameEncoder<W> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<Result<usize>> {
+		ready!(self.poll_drain(cx))?;
+		let Some(encoder) = self.encoder.as_mut() else {
+			return Poll::Ready(Err(Error::other("The LZ4 frame has already been finished")));
+		};
+		let length = buf.len().min(MAX_WRITE_SIZE);
+		encoder.write_all(&buf[..length])?;
+		self.take_compressed();
+		Poll::Ready(Ok(length))
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+		ready!(self.poll_drain(cx))?;
+		if let Some(encoder) = self.encoder.as_mut() {
+			encoder.flush()?;
+			self.take_compressed();
+			ready!(self.poll_drain(cx))?;
+		}
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+		ready!(self.poll_finish(cx))?;
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+/// Decompresses LZ4 frames as they are read from an asynchronous reader
+///
+/// Concatenated frames are read one after another, as by [`lz4_flex::frame::FrameDecoder`].
+pub struct Lz4FrameDecoder<R: AsyncRead + Unpin> {
+	/// Where the frames are read from
+	inner: R,
+	/// Decodes the compressed data buffered so far
+	///
+	/// It is only asked to decode once a whole block is buffered, so it never runs out of input part-way through.
+	decoder: FrameDecoder<VecDeque<u8>>,
+	/// Whether the block checksum and content checksum flags are set, if a frame is being read
+	frame: Option<(bool, bool)>,
+	/// The largest block the frame being read may contain
+	max_block_size: usize,
+	/// The most recently decompressed block
+	output: Vec<u8>,
+	/// How much of the decompressed block has been read
+	position: usize,
+	/// Whether the underlying reader has no more data
+	eof: bool,
+}
+
+/// The next piece of a frame for the decoder to read
+struct Unit {
+	/// How many buffered bytes the piece takes up, including the frame header if it starts a frame
+	length: usize,
+	/// The flags of the frame the piece belongs to
+	flags: (bool, bool),
+	/// The largest block the frame may contain
+	max_block_size: usize,
+	/// Whether the piece ends the frame
+	end: bool,
+}
+
+impl<R: AsyncRead + Unpin> Lz4FrameDecoder<R> {
+	/// Decompress LZ4 frames read from an asynchronous reader
+	///
+	/// # Arguments
+	///
+	/// * `inner` - Where the frames are read from
+	pub fn new(inner: R) -> Lz4FrameDecoder<R> {
+		Lz4FrameDecoder {
+			inner,
+			decoder: FrameDecoder::new(VecDeque::new()),
+			frame: None,
+			max_block_size: 0,
+			output: Vec::new(),
+			position: 0,
+			eof: false,
+		}
+	}
+
+	/// Work out the next piece of the frame from the buffered data, if enough of it is buffered to tell
+	fn next_unit(&self) -> Option<Unit> {
+		let buffered = self.decoder.get_ref();
+		let byte = |index: usize| buffered.get(index).copied();
+		let (offset, flags, max_block_size) = match self.frame {
+			Some(flags) => (0, flags, self.max_block_size),
+			None => {
+				let descriptor = byte(4)?;
+				let block_descriptor = byte(5)?;
+				let offset = MIN_FRAME_HEADER_SIZE
+					+ if descriptor & 0x08 != 0 { 8 } else { 0 }
+					+ if descriptor & 0x01 != 0 { 4 } else { 0 };
+				let max_block_size = match (block_descriptor >> 4) & 0x07 {
+					4 => 64 * 1024,
+					5 => 256 * 1024,
+					6 => 1024 * 1024,
+					_ => 4 * 1024 * 1024,
+				};
+				(
+					offset,
+					(descriptor & 0x10 != 0, descriptor & 0x04 != 0),
+					max_block_size,
+				)
+			}
+		};
+		let block_info = u32::from_le_bytes([
+			byte(offset)?,
+			byte(offset + 1)?,
+			byte(offset + 2)?,
+			byte(offset + 3)?,
+		]);
+		let (block_checksums, content_checksum) = flags;
+		let (length, end) = match block_info {
+			0 => (4 + if content_checksum { 4 } else { 0 }, true),
+			_ => (
+				4 + (block_info & 0x7fff_ffff) as usize + if block_checksums { 4 } else { 0 },
+				false,
+			),
+		};
+		Some(Unit {
+			length: offset + length,
+			flags,
+			max_block_size,
+			end,
+		})
+	}
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Lz4FrameDecoder<R> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<Result<()>> {
+		let this = &mut *self;
+		loop {
+			if this.position < this.output.len() {
+				let length = buf.remaining().min(this.output.len() - this.position);
+				buf.put_slice(&this.output[this.position..this.position + length]);
+				this.position += length;
+				return Poll::Ready(Ok(()));
+			}
+
+			match this.next_unit() {
+				Some(unit) if this.decoder.get_ref().len() >= unit.length => {
+					// The output buffer holds a whole block, so the decoder never keeps decompressed data back
+					this.output.resize(unit.max_block_size, 0);
+					let length = this.decoder.read(&mut this.output)?;
+					this.output.truncate(length);
+					this.position = 0;
+					this.frame = (!unit.end).then_some(unit.flags);
+					this.max_block_size = unit.max_block_size;
+				}
+				_ if this.eof => {
+					return match this.frame.is_none() && this.decoder.get_ref().is_empty() {
+						true => Poll::Ready(Ok(())),
+						false => Poll::Ready(Err(Error::new(
+							ErrorKind::UnexpectedEof,
+							"The LZ4 frame ends part-way through",
+						))),
+					};
+				}
+				_ => {
+					let mut chunk = [0u8; READ_SIZE];
+					let mut chunk_buf = ReadBuf::new(&mut chunk);
+					ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+					match chunk_buf.filled() {
+						[] => this.eof = true,
+						filled => this.decoder.get_mut().extend(filled),
+					}
+				}
+			}
+		}
+	}
+}
+
+/// Archive & compress a file or set of files, writing the archive to an asynchronous writer
+///
+/// Returns the writer once the archive has been written in full and flushed.
+///
+/// # Arguments
+///
+/// * `paths` - A list of paths pointing to files or directories intended to be archived
+///
+/// * `output` - Where the archive is written to
+///
+/// * `options` - Options controlling how the archive is written
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::asynchronous::compress_archive;
+/// use larz::CompressOptions;
+/// use std::path::PathBuf;
+///
+/// async fn write_archive() -> std::io::Result<()> {
+///     let paths = vec![PathBuf::from("path/to/file"), PathBuf::from("path/to/directory")];
+///     let output = tokio::fs::File::create("path/to/output.larz").await?;
+///     compress_archive(paths, output, &CompressOptions::default()).await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn compress_archive<W: AsyncWrite + Unpin + Send + 'static>(
+	paths: Vec<PathBuf>,
+	mut output: W,
+	options: &CompressOptions,
+) -> Result<W> {
+	check_supported(options)?;
+
+	let mut header = ArchiveHeader::new(Codec::Lz4Frame);
+	header.flags.insert(Flags::CHECKSUMMED);
+	let mut header_bytes = Vec::new();
+	header.write_to(&mut header_bytes)?;
+	output.write_all(&header_bytes).await?;
+
+	let mut frame_info = FrameInfo::new();
+	frame_info.content_checksum = true;
+	let mut tar = tokio_tar::Builder::new(Lz4FrameEncoder::new(output, frame_info));
+	tar.follow_symlinks(options.walk.symlinks == Symlinks::Follow);
+	for fs_path in paths {
+		match read_metadata(&fs_path, &options.walk).await?.is_dir() {
+			true => append_tree(&mut tar, &fs_path, options).await?,
+			false => {
+				let name = fs_path.file_name().ok_or_else(|| {
+					Error::new(
+						ErrorKind::InvalidInput,
+						format!("'{}' has no file name", crate::escape_path(&fs_path)),
+					)
+				})?;
+				append_entry(&mut tar, &fs_path, Path::new(name), options).await?;
+			}
+		}
+	}
+	tar.into_inner().await?.finish().await
+}
+
+/// Extract & decompress an archive read from an asynchronous reader
+///
+/// Signatures are checked against the archive file, so archives read from a reader cannot be verified, and a verifying key is refused.
+///
+/// # Arguments
+///
+/// * `input` - Where the archive is read from
+///
+/// * `output_path` - Path to write the extracted files to
+///
+/// * `options` - Options controlling how the archive is read
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::asynchronous::extract_archive;
+/// use larz::ExtractOptions;
+/// use std::path::Path;
+///
+/// async fn read_archive() -> std::io::Result<()> {
+///     let input = tokio::fs::File::open("path/to/archive.larz").await?;
+///     extract_archive(input, Path::new("path/to/output"), &ExtractOptions::default()).await
+/// }
+/// ```
+pub async fn extract_archive<R: AsyncRead + Unpin>(
+	mut input: R,
+	output_path: &Path,
+	options: &ExtractOptions,
+) -> Result<()> {
+	#[cfg(feature = "signing")]
+	if options.verifying_key.is_some() {
+		return Err(Error::new(
+			ErrorKind::Unsupported,
+			"Signatures cannot be verified when extracting asynchronously",
+		));
+	}
+	let (header, prefix) = read_header(&mut input).await?;
+	if let Some(header) = &header {
+		if header.codec != Codec::Lz4Frame
+			|| header.flags.contains(Flags::ENCRYPTED)
+			|| header.flags.contains(Flags::DEDUPLICATED)
+		{
+			return Err(Error::new(
+				ErrorKind::Unsupported,
+				"Only unencrypted streaming archives can be extracted asynchronously",
+			));
+		}
+	}
+	if options.resume {
+		return Err(Error::new(
+			ErrorKind::Unsupported,
+			"Asynchronous extraction cannot be resumed",
+		));
+	}
+	let body = AsyncReadExt::chain(Cursor::new(prefix), input);
+
+	if !options.atomic {
+		tokio::fs::create_dir_all(output_path).await?;
+		return unpack(body, output_path, options).await;
+	}
+	let staging = crate::atomic::StagingDir::create(output_path)?;
+	unpack(body, staging.path(), options).await?;
+	staging.commit()
+}
+
+/// Unpack the TAR data of an archive into a directory, verifying the contents of each file once it is written
+///
+/// # Arguments
+///
+/// * `body` - The compressed TAR data
+///
+/// * `output_path` - Path to the directory to write the extracted files to
+///
+/// * `options` - Options controlling how the archive is read
+async fn unpack<R: AsyncRead + Unpin>(
+	body: R,
+	output_path: &Path,
+	options: &ExtractOptions,
+) -> Result<()> {
+	let output_path = tokio::fs::canonicalize(output_path).await?;
+	let mut archive = tokio_tar::Archive::new(Lz4FrameDecoder::new(body));
+	let mut entries = archive.entries()?;
+	let mut directories = Vec::new();
+	let mut mismatched = Vec::new();
+	while let Some(entry) = entries.next().await {
+		let mut entry = entry?;
+		let mut member = entry.path()?.into_owned();
+		if member == Path::new(incremental::DELETIONS_MEMBER) && is_deletions(&mut entry).await? {
+			continue;
+		}
+		// Files with holes in PAX archives are named by their PAX records rather than their headers
+		let sparse = pax_sparse(&mut entry).await?;
+		if let Some(sparse) = &sparse {
+			member = sparse.name.clone();
+		}
+		// Directories are created last, so their permissions do not prevent their contents from being written
+		if entry.header().entry_type() == tokio_tar::EntryType::Directory {
+			directories.push((member, entry));
+			continue;
+		}
+
+		let expected = match options.skip_verification {
+			true => None,
+			false => recorded_hash(&mut entry).await?,
+		};
+		let destination = match &sparse {
+			Some(sparse) => unpack_pax_sparse(&mut entry, &output_path, &member, sparse).await?,
+			None => entry.unpack_in(&output_path).await?,
+		};
+		if let Some(destination) = &destination {
+			let header = tar::Header::from_byte_slice(entry.header().as_bytes());
+			if matches!(
+				header.entry_type(),
+				tar::EntryType::Fifo | tar::EntryType::Char | tar::EntryType::Block
+			) {
+				crate::extract::make_special(destination, header)?;
+			}
+			let entry_type = header.entry_type();
+			let mtime = recorded_mtime(&mut entry).await?;
+			crate::extract::restore_mtime(destination, entry_type, mtime)?;
+		}
+		if let (Some(expected), Some(destination)) = (expected, destination) {
+			if hash_file(&destination).await? != expected {
+				mismatched.push(member.clone());
+			}
+		}
+		if let Some(progress) = &options.progress {
+			progress.report(&member, entry.header().size()?);
+		}
+	}
+
+	directories.sort_by(|(a, _), (b, _)| b.cmp(a));
+	for (member, mut directory) in directories {
+		if let Some(destination) = directory.unpack_in(&output_path).await? {
+			let mtime = recorded_mtime(&mut directory).await?;
+			crate::extract::restore_mtime(&destination, tar::EntryType::Directory, mtime)?;
+		}
+		if let Some(progress) = &options.progress {
+			progress.report(&member, 0);
+		}
+	}
+
+	match mismatched.is_empty() {
+		true => Ok(()),
+		false => Err(Error::new(
+			ErrorKind::InvalidData,
+			IntegrityError {
+				members: mismatched,
+			},
+		)),
+	}
+}
+
+/// Write out a file stored as a PAX sparse entry, leaving holes where it holds no data
+///
+/// Returns where the file was written, or `None` if it was not because its path leaves the output directory.
+///
+/// # Arguments
+///
+/// * `entry` - The entry of the file, with its map not yet read
+///
+/// * `output_path` - Path the archive is being extracted to
+///
+/// * `member` - The path of the file within the archive
+///
+/// * `sparse` - The path and size of the file, as its PAX records give them
+async fn unpack_pax_sparse<R: AsyncRead + Unpin>(
+	entry: &mut tokio_tar::Entry<R>,
+	output_path: &Path,
+	member: &Path,
+	sparse: &format::PaxSparse,
+) -> Result<Option<PathBuf>> {
+	let Some((destination, file)) = crate::extract::create_sparse(output_path, member)? else {
+		return Ok(None);
+	};
+	let mut file = tokio::fs::File::from_std(file);
+	let mut map = format::SparseMap::new(sparse.real_size);
+	let regions = loop {
+		let mut block = [0u8; 512];
+		entry.read_exact(&mut block).await?;
+		if let Some(regions) = map.feed(&block)? {
+			break regions;
+		}
+	};
+	for (offset, length) in regions {
+		file.seek(SeekFrom::Start(offset)).await?;
+		if tokio::io::copy(&mut AsyncReadExt::take(&mut *entry, length), &mut file).await? != length
+		{
+			return Err(crate::extract::sparse_truncated(member));
+		}
+	}
+	file.flush().await?;
+	let header = tar::Header::from_byte_slice(entry.header().as_bytes());
+	crate::extract::finish_sparse(&file.into_std().await, header, sparse.real_size)?;
+	Ok(Some(destination))
+}
+
+/// Refuse options the asynchronous functions do not support
+///
+/// # Arguments
+///
+/// * `options` - Options controlling how the archive is written
+fn check_supported(options: &CompressOptions) -> Result<()> {
+	#[cfg(feature = "encryption")]
+	let encrypted = options.passphrase.is_some();
+	#[cfg(not(feature = "encryption"))]
+	let encrypted = false;
+	match encrypted
+		|| options.dictionary.is_some()
+		|| options.deduplicate
+		|| options.snapshot.is_some()
+		|| options.volume_size.is_some()
+		|| options.resume
+	{
+		true => Err(Error::new(
+			ErrorKind::Unsupported,
+			"Encryption, dictionaries, deduplication, incremental backups, volumes and resuming are not supported asynchronously",
+		)),
+		false => Ok(()),
+	}
+}
+
+/// Read the header of an archive, if it has one
+///
+/// Returns the header, and any bytes read that belong to the body of an archive without a header.
+///
+/// # Arguments
+///
+/// * `input` - Where the archive is read from
+async fn read_header<R: AsyncRead + Unpin>(
+	input: &mut R,
+) -> Result<(Option<ArchiveHeader>, Vec<u8>)> {
+	let mut bytes = vec![0u8; MAGIC.len()];
+	input.read_exact(&mut bytes).await?;
+	if bytes != MAGIC {
+		return Ok((None, bytes));
+	}
+	bytes.resize(FIXED_HEADER_SIZE, 0);
+	input.read_exact(&mut bytes[MAGIC.len()..]).await?;
+	let metadata_length = u32::from_le_bytes(
+		bytes[FIXED_HEADER_SIZE - 4..FIXED_HEADER_SIZE]
+			.try_into()
+			.unwrap(),
+	);
+	bytes.resize(FIXED_HEADER_SIZE + metadata_length as usize, 0);
+	input.read_exact(&mut bytes[FIXED_HEADER_SIZE..]).await?;
+	let (header, _) = ArchiveHeader::read_from(Cursor::new(bytes))?;
+	Ok((header, Vec::new()))
+}
+
+/// Append a directory and everything beneath it to the root of an archive
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `root` - Path to the directory
+///
+/// * `options` - Options controlling how the archive is written
+async fn append_tree<W: AsyncWrite + Unpin + Send>(
+	tar: &mut tokio_tar::Builder<W>,
+	root: &Path,
+	options: &CompressOptions,
+) -> Result<()> {
+	let walk = &options.walk;
+	let root_device = append::device_id(&read_metadata(root, walk).await?);
+	let mut ancestors = append::Ancestors::default();
+	let mut stack = vec![(root.to_path_buf(), Path::new(".").to_path_buf(), 0, None)];
+	while let Some((fs_path, name, depth, parent)) = stack.pop() {
+		let metadata = read_metadata(&fs_path, walk).await?;
+		append_entry(tar, &fs_path, &name, options).await?;
+		if metadata.is_dir() {
+			if !append::should_descend(&metadata, depth, root_device, walk) {
+				continue;
+			}
+			// A directory reached again through a symbolic link beneath it is stored without its contents
+			let Some(index) = ancestors.enter(parent, &metadata) else {
+				continue;
+			};
+			let mut children = Vec::new();
+			let mut read_dir = tokio::fs::read_dir(&fs_path).await?;
+			while let Some(child) = read_dir.next_entry().await? {
+				children.push(child.file_name());
+			}
+			// Visit children in order of their names, so archives of the same tree are identical
+			children.sort_unstable_by(|a, b| b.cmp(a));
+			stack.extend(children.into_iter().map(|child| {
+				(
+					fs_path.join(&child),
+					name.join(&child),
+					depth + 1,
+					Some(index),
+				)
+			}));
+		}
+	}
+	Ok(())
+}
+
+/// Append a single file or directory to an archive, recording the hash of its contents if it is a regular file
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `fs_path` - Path to the file
+///
+/// * `name` - The path of the file within the archive
+///
+/// * `options` - Options controlling how the archive is written
+async fn append_entry<W: AsyncWrite + Unpin + Send>(
+	tar: &mut tokio_tar::Builder<W>,
+	fs_path: &Path,
+	name: &Path,
+	options: &CompressOptions,
+) -> Result<()> {
+	let format = options.format;
+	let metadata = read_metadata(fs_path, &options.walk).await?;
+	if append::skip_special(fs_path, &metadata, &options.walk)? {
+		return Ok(());
+	}
+	let special = !metadata.is_file() && !metadata.is_dir() && !metadata.is_symlink();
+	let modified = metadata.modified().ok();
+	let contents = match metadata.is_file() {
+		// Ustar archives cannot hold sparse entries, so files with holes are stored in full in them
+		true => Some(FileContents::read(fs_path, &metadata, format != TarFormat::Ustar).await?),
+		false => None,
+	};
+	let hash = contents.as_ref().map(|contents| contents.hash);
+	match contents {
+		Some(FileContents {
+			hash,
+			regions: Some(regions),
+			data,
+		}) if format == TarFormat::Gnu => {
+			append_pax_records(tar, &format::records(format, modified, Some(hash))).await?;
+			append_sparse(tar, data, name, &metadata, regions).await?
+		}
+		Some(FileContents {
+			hash,
+			regions: Some(regions),
+			data,
+		}) => {
+			let mut header = format::new_header(format);
+			header.set_metadata(&metadata);
+			let (records, map) = format::pax_sparse_extensions(
+				&mut header,
+				name,
+				metadata.len(),
+				modified,
+				hash,
+				&regions,
+			)?;
+			append_pax_records(tar, &records).await?;
+			let header = tokio_tar::Header::from_byte_slice(header.as_bytes());
+			tar.append(header, AsyncReadExt::chain(Cursor::new(map), data))
+				.await?
+		}
+		// Directories & links in GNU archives are left to the builder, which stores long link targets in GNU extension entries
+		None if format == TarFormat::Gnu && !special => {
+			append_pax_records(tar, &format::records(format, modified, None)).await?;
+			tar.append_path_with_name(fs_path, name).await?
+		}
+		contents => {
+			let mut header = match special {
+				true => append::special_header(fs_path, &metadata, format)?,
+				false => {
+					let mut header = format::new_header(format);
+					header.set_metadata(&metadata);
+					header
+				}
+			};
+			let link = match metadata.is_symlink() {
+				true => Some(tokio::fs::read_link(fs_path).await?),
+				false => None,
+			};
+			let records =
+				format::extensions(format, &mut header, name, link.as_deref(), modified, hash)?;
+			append_pax_records(tar, &records).await?;
+			header.set_cksum();
+			let mut header = tokio_tar::Header::from_byte_slice(header.as_bytes()).clone();
+			// The contents were read in full before the header was written, so exactly as much data as the header records is written
+			let data: Box<dyn AsyncRead + Unpin + Send> = match contents {
+				Some(contents) => contents.data,
+				None => Box::new(tokio::io::empty()),
+			};
+			match format {
+				TarFormat::Gnu => tar.append_data(&mut header, name, data).await?,
+				TarFormat::Ustar | TarFormat::Pax => tar.append(&header, data).await?,
+			}
+		}
+	}
+	if let Some(progress) = &options.progress {
+		progress.report(
+			name,
+			if metadata.is_file() {
+				metadata.len()
+			} else {
+				0
+			},
+		);
+	}
+	Ok(())
+}
+
+/// The contents of a regular file, read once so that what is written to an archive is exactly what was hashed
+struct FileContents {
+	/// The hash of the file's contents
+	hash: blake3::Hash,
+	/// The offset and length of each region of the file holding data, if it has holes
+	regions: Option<Vec<(u64, u64)>>,
+	/// The data read from the file, or from its regions holding data if it has holes
+	data: Box<dyn AsyncRead + Unpin + Send>,
+}
+
+impl FileContents {
+	/// Read and hash a regular file without blocking, holding its contents until they are written
+	///
+	/// Only as much of the file as its metadata records is read, and it is an error if the file is shorter than that.
+	///
+	/// # Arguments
+	///
+	/// * `fs_path` - Path to the file
+	///
+	/// * `metadata` - The metadata of the file
+	///
+	/// * `sparse` - Whether to read only the regions of the file holding data, if it has holes
+	async fn read(
+		fs_path: &Path,
+		metadata: &std::fs::Metadata,
+		sparse: bool,
+	) -> Result<FileContents> {
+		let mut file = tokio::fs::File::open(fs_path).await?;
+		let regions = match sparse {
+			true => append::data_regions(&file, metadata)?,
+			false => None,
+		};
+		let length: u64 = regions.as_ref().map_or(metadata.len(), |regions| {
+			regions.iter().map(|(_, length)| length).sum()
+		});
+		let mut scratch = match length > append::MEMORY_SPOOL_LIMIT {
+			true => Some(tokio::fs::File::from_std(crate::scratch()?)),
+			false => None,
+		};
+		let mut memory = Vec::new();
+
+		let mut hasher = blake3::Hasher::new();
+		let mut buffer = vec![0u8; READ_SIZE];
+		let mut position = 0;
+		for (offset, length) in regions.clone().unwrap_or_else(|| vec![(0, metadata.len())]) {
+			// Holes read as zeros
+			append::hash_zeros(&mut hasher, offset - position);
+			file.seek(SeekFrom::Start(offset)).await?;
+			let mut remaining = length;
+			while remaining > 0 {
+				let wanted = buffer.len().min(remaining.try_into().unwrap_or(usize::MAX));
+				let read = file.read(&mut buffer[..wanted]).await?;
+				if read == 0 {
+					return Err(Error::new(
+						ErrorKind::UnexpectedEof,
+						format!(
+							"'{}' was truncated while it was being archived",
+							crate::escape_path(fs_path)
+						),
+					));
+				}
+				hasher.update(&buffer[..read]);
+				match scratch.as_mut() {
+					Some(scratch) => scratch.write_all(&buffer[..read]).await?,
+					None => memory.extend_from_slice(&buffer[..read]),
+				}
+				remaining -= read as u64;
+			}
+			position = offset + length;
+		}
+		append::hash_zeros(&mut hasher, metadata.len().saturating_sub(position));
+
+		let data: Box<dyn AsyncRead + Unpin + Send> = match scratch {
+			Some(mut scratch) => {
+				scratch.seek(SeekFrom::Start(0)).await?;
+				Box::new(tokio::io::BufReader::new(scratch))
+			}
+			None => Box::new(Cursor::new(memory)),
+		};
+		Ok(FileContents {
+			hash: hasher.finalize(),
+			regions,
+			data,
+		})
+	}
+}
+
+/// Append a PAX extended header holding the given records, if there are any
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `records` - The keyword and value of each record
+async fn append_pax_records<W: AsyncWrite + Unpin + Send>(
+	tar: &mut tokio_tar::Builder<W>,
+	records: &[(&str, Vec<u8>)],
+) -> Result<()> {
+	if records.is_empty() {
+		return Ok(());
+	}
+	let data: Vec<u8> = records
+		.iter()
+		.flat_map(|(key, value)| pax_record(key, value))
+		.collect();
+	let mut header = tokio_tar::Header::new_ustar();
+	header.set_entry_type(tokio_tar::EntryType::XHeader);
+	header.set_size(data.len() as u64);
+	header.set_mode(0o644);
+	tar.append_data(&mut header, "././@PaxHeader", data.as_slice())
+		.await
+}
+
+/// Read the metadata of a file, following it if it is a symbolic link that is to be followed
+///
+/// # Arguments
+///
+/// * `fs_path` - Path to the file
+///
+/// * `walk` - How directories are walked
+async fn read_metadata(fs_path: &Path, walk: &WalkOptions) -> Result<std::fs::Metadata> {
+	match walk.symlinks {
+		Symlinks::Follow => tokio::fs::metadata(fs_path).await,
+		Symlinks::Store => tokio::fs::symlink_metadata(fs_path).await,
+	}
+}
+
+/// Append a file with holes to an archive as a GNU sparse entry, storing only the regions holding data
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `data` - The contents of the regions of the file holding data, one after another
+///
+/// * `name` - The path of the file within the archive
+///
+/// * `metadata` - The metadata of the file
+///
+/// * `regions` - The offset and length of each region of the file holding data, as found by [`append::data_regions`]
+async fn append_sparse<W: AsyncWrite + Unpin + Send>(
+	tar: &mut tokio_tar::Builder<W>,
+	data: impl AsyncRead + Unpin + Send,
+	name: &Path,
+	metadata: &std::fs::Metadata,
+	regions: Vec<(u64, u64)>,
+) -> Result<()> {
+	let mut header = tokio_tar::Header::new_gnu();
+	header.set_metadata(metadata);
+	header.set_entry_type(tokio_tar::EntryType::GNUSparse);
+	header.set_size(regions.iter().map(|(_, length)| length).sum());
+
+	// The first four regions are listed in the header, and the rest in extension headers of 21 regions each, written before the data
+	let (listed, extended) = regions.split_at(regions.len().min(4));
+	let gnu = header
+		.as_gnu_mut()
+		.expect("GNU headers have GNU extensions");
+	set_numeric_field(&mut gnu.realsize, metadata.len());
+	for ((offset, length), field) in listed.iter().zip(gnu.sparse.iter_mut()) {
+		set_numeric_field(&mut field.offset, *offset);
+		set_numeric_field(&mut field.numbytes, *length);
+	}
+	gnu.isextended[0] = u8::from(!extended.is_empty());
+	let mut extensions = Vec::new();
+	let mut chunks = extended.chunks(21).peekable();
+	while let Some(chunk) = chunks.next() {
+		let mut extension = tokio_tar::GnuExtSparseHeader::new();
+		for ((offset, length), field) in chunk.iter().zip(extension.sparse.iter_mut()) {
+			set_numeric_field(&mut field.offset, *offset);
+			set_numeric_field(&mut field.numbytes, *length);
+		}
+		extension.isextended[0] = u8::from(chunks.peek().is_some());
+		extensions.extend_from_slice(extension.as_bytes());
+	}
+
+	tar.append_data(
+		&mut header,
+		name,
+		AsyncReadExt::chain(Cursor::new(extensions), data),
+	)
+	.await
+}
+
+/// Encode a number into a numeric field of a TAR header, in octal if it fits, or in base-256 otherwise
+///
+/// # Arguments
+///
+/// * `field` - The field to encode the number into
+///
+/// * `value` - The number
+fn set_numeric_field(field: &mut [u8; 12], value: u64) {
+	match value < 8u64.pow(11) {
+		true => field.copy_from_slice(format!("{value:011o}\0").as_bytes()),
+		false => {
+			field.fill(0);
+			field[0] = 0x80;
+			field[4..].copy_from_slice(&value.to_be_bytes());
+		}
+	}
+}
+
+/// Encode a PAX extended header record
+///
+/// # Arguments
+///
+/// * `key` - The keyword of the record
+///
+/// * `value` - The value of the record
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+	// The length prefix counts itself, so find the length at which it stops growing
+	let rest = 1 + key.len() + 1 + value.len() + 1;
+	let mut length = rest;
+	while length != rest + length.to_string().len() {
+		length = rest + length.to_string().len();
+	}
+	let mut record = format!("{length} {key}=").into_bytes();
+	record.extend_from_slice(value);
+	record.push(b'\n');
+	record
+}
+
+/// Get the hash recorded for an archive entry, if there is one
+///
+/// # Arguments
+///
+/// * `entry` - An entry being read from an archive
+async fn recorded_hash<R: AsyncRead + Unpin>(
+	entry: &mut tokio_tar::Entry<R>,
+) -> Result<Option<blake3::Hash>> {
+	let Some(extensions) = entry.pax_extensions().await? else {
+		return Ok(None);
+	};
+	for extension in extensions {
+		let extension = extension?;
+		if extension.key_bytes() == HASH_PAX_KEY.as_bytes() {
+			return Ok(extension
+				.value()
+				.ok()
+				.and_then(|value| blake3::Hash::from_hex(value).ok()));
+		}
+	}
+	Ok(None)
+}
+
+/// Get the modification time recorded for an archive entry, if it is recorded more precisely than its header can
+///
+/// # Arguments
+///
+/// * `entry` - An entry being read from an archive
+async fn recorded_mtime<R: AsyncRead + Unpin>(
+	entry: &mut tokio_tar::Entry<R>,
+) -> Result<Option<(i64, u32)>> {
+	let Some(extensions) = entry.pax_extensions().await? else {
+		return Ok(None);
+	};
+	for extension in extensions {
+		let extension = extension?;
+		if extension.key_bytes() == format::MTIME_PAX_KEY.as_bytes() {
+			return Ok(extension.value().ok().and_then(format::parse_mtime));
+		}
+	}
+	Ok(None)
+}
+
+/// Get the path and size of the file an entry holds, if it is a PAX sparse entry
+///
+/// # Arguments
+///
+/// * `entry` - An entry being read from an archive
+async fn pax_sparse<R: AsyncRead + Unpin>(
+	entry: &mut tokio_tar::Entry<R>,
+) -> Result<Option<format::PaxSparse>> {
+	let Some(extensions) = entry.pax_extensions().await? else {
+		return Ok(None);
+	};
+	let records = extensions
+		.map(|extension| {
+			extension.map(|extension| {
+				(
+					extension.key_bytes().to_vec(),
+					extension.value_bytes().to_vec(),
+				)
+			})
+		})
+		.collect::<Result<Vec<_>>>()?;
+	format::parse_pax_sparse(
+		records
+			.iter()
+			.map(|(key, value)| (key.as_slice(), value.as_slice())),
+	)
+}
+
+/// Check whether an archive member is the list of paths deleted since the previous backup
+///
+/// # Arguments
+///
+/// * `entry` - An entry being read from an archive
+async fn is_deletions<R: AsyncRead + Unpin>(entry: &mut tokio_tar::Entry<R>) -> Result<bool> {
+	let Some(extensions) = entry.pax_extensions().await? else {
+		return Ok(false);
+	};
+	for extension in extensions {
+		if extension?.key_bytes() == incremental::DELETIONS_PAX_KEY.as_bytes() {
+			return Ok(true);
+		}
+	}
+	Ok(false)
+}
+
+/// Compute the BLAKE3 hash of a file's contents, without blocking
+///
+/// # Arguments
+///
+/// * `path` - Path to the file
+async fn hash_file(path: &Path) -> Result<blake3::Hash> {
+	let mut f = tokio::fs::File::open(path).await?;
+	let mut hasher = blake3::Hasher::new();
+	let mut buffer = vec![0u8; READ_SIZE];
+	loop {
+		let length = f.read(&mut buffer).await?;
+		if length == 0 {
+			return Ok(hasher.finalize());
+		}
+		hasher.update(&buffer[..length]);
+	}
+}
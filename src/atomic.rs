@@ -0,0 +1,416 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Writing files & directories atomically.
+//!
+//! Archives are written to a temporary file beside their destination, which is synced to disk and renamed over the destination only once it is complete.
+//! A failed or interrupted write therefore never leaves a half-written archive where a good one used to be.
+//! Likewise, atomic extraction unpacks into a staging directory beside the destination, which is swapped into its place once everything has been extracted.
+//! Archives written with a checkpoint journal are instead written to a partial file that is kept if writing fails, so that it can be resumed; see [`crate::journal`].
+
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The temporary files & staging directories still being written, so that they can be removed if the process is interrupted
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Counts the temporary files & staging directories created, so that each is given a different name
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// A file that replaces its destination only once it is complete
+///
+/// The temporary file is removed if this is dropped before it is committed, unless writing it can be resumed.
+pub(crate) struct AtomicFile {
+	/// The temporary file being written
+	file: File,
+	/// Path to the temporary file
+	temporary_path: PathBuf,
+	/// Path the file is written to once it is complete
+	path: PathBuf,
+	/// Refuse to replace an existing file at the destination
+	no_clobber: bool,
+	/// Whether the file has replaced its destination
+	committed: bool,
+	/// Keep the file if it is not completed, so that writing it can be resumed
+	resumable: bool,
+}
+
+impl AtomicFile {
+	/// Begin writing a file, in a temporary file beside its destination
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path the file is written to once it is complete
+	///
+	/// * `no_clobber` - Refuse to replace an existing file at `path`
+	pub(crate) fn create(path: &Path, no_clobber: bool) -> Result<AtomicFile> {
+		if no_clobber && path.symlink_metadata().is_ok() {
+			return Err(already_exists(path));
+		}
+		loop {
+			let temporary_path = temporary_path(path, "tmp")?;
+			// Files left behind by a process that was killed outright are skipped over
+			match OpenOptions::new()
+				.write(true)
+				.create_new(true)
+				.open(&temporary_path)
+			{
+				Ok(file) => {
+					pending().push(temporary_path.clone());
+					return Ok(AtomicFile {
+						file,
+						temporary_path,
+						path: path.to_path_buf(),
+						no_clobber,
+						committed: false,
+						resumable: false,
+					});
+				}
+				Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	/// Begin or resume writing a file, in a partial file beside its destination that is kept if it is not completed
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path the file is written to once it is complete
+	///
+	/// * `no_clobber` - Refuse to replace an existing file at `path`
+	///
+	/// * `offset` - How much of the partial file to keep, or `None` to start it again
+	#[cfg(feature = "streaming")]
+	pub(crate) fn resume(path: &Path, no_clobber: bool, offset: Option<u64>) -> Result<AtomicFile> {
+		use std::io::{Seek, SeekFrom};
+		if no_clobber && path.symlink_metadata().is_ok() {
+			return Err(already_exists(path));
+		}
+		let temporary_path = hidden_path(path, "partial")?;
+		let mut file = match offset {
+			Some(offset) => {
+				let file = OpenOptions::new().write(true).open(&temporary_path)?;
+				if file.metadata()?.len() < offset {
+					return Err(Error::new(
+						ErrorKind::UnexpectedEof,
+						format!(
+							"'{}' is shorter than its journal records",
+							crate::escape_path(&temporary_path)
+						),
+					));
+				}
+				file.set_len(offset)?;
+				file
+			}
+			None => File::create(&temporary_path)?,
+		};
+		file.seek(SeekFrom::End(0))?;
+		Ok(AtomicFile {
+			file,
+			temporary_path,
+			path: path.to_path_buf(),
+			no_clobber,
+			committed: false,
+			resumable: true,
+		})
+	}
+
+	/// Sync what has been written so far to disk, returning its length
+	#[cfg(feature = "streaming")]
+	pub(crate) fn sync(&mut self) -> Result<u64> {
+		use std::io::Seek;
+		self.file.flush()?;
+		self.file.sync_data()?;
+		self.file.stream_position()
+	}
+
+	/// Sync the file to disk, then replace its destination with it
+	pub(crate) fn commit(mut self) -> Result<File> {
+		self.file.flush()?;
+		self.file.sync_all()?;
+		match self.no_clobber {
+			true => rename_no_clobber(&self.temporary_path, &self.path)?,
+			false => std::fs::rename(&self.temporary_path, &self.path)?,
+		}
+		self.committed = true;
+		sync_parent(&self.path)?;
+		self.file.try_clone()
+	}
+}
+
+impl Write for AtomicFile {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		self.file.write(buf)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.file.flush()
+	}
+}
+
+impl Drop for AtomicFile {
+	fn drop(&mut self) {
+		pending().retain(|path| *path != self.temporary_path);
+		if !self.committed && !self.resumable {
+			let _ = std::fs::remove_file(&self.temporary_path);
+		}
+	}
+}
+
+/// A directory that is extracted into, then swapped into the place of its destination once everything has been extracted
+///
+/// The staging directory is removed if this is dropped before it is committed.
+pub(crate) struct StagingDir {
+	/// Path to the staging directory
+	staging_path: PathBuf,
+	/// Path the directory is moved to once it is complete
+	destination: PathBuf,
+	/// Whether the directory has replaced its destination
+	committed: bool,
+}
+
+impl StagingDir {
+	/// Create an empty staging directory beside its destination
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path the directory is moved to once it is complete
+	pub(crate) fn create(path: &Path) -> Result<StagingDir> {
+		// Paths such as `.` have no name to put the staging directory beside, but a symbolic link to a directory is replaced rather than followed
+		let path = match path.file_name() {
+			Some(_) => path.to_path_buf(),
+			None => path.canonicalize()?,
+		};
+		if let Some(parent) = path
+			.parent()
+			.filter(|parent| !parent.as_os_str().is_empty())
+		{
+			std::fs::create_dir_all(parent)?;
+		}
+		loop {
+			let staging_path = temporary_path(&path, "staging")?;
+			match std::fs::create_dir(&staging_path) {
+				Ok(()) => {
+					pending().push(staging_path.clone());
+					return Ok(StagingDir {
+						staging_path,
+						destination: path,
+						committed: false,
+					});
+				}
+				Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	/// Path to the staging directory, to extract into
+	pub(crate) fn path(&self) -> &Path {
+		&self.staging_path
+	}
+
+	/// Move the directory into the place of its destination, removing whatever was there before
+	///
+	/// An existing destination is exchanged with the staging directory in one step where the platform allows it, so that there is no moment at which neither exists.
+	pub(crate) fn commit(mut self) -> Result<()> {
+		match self.destination.symlink_metadata() {
+			Ok(_) => exchange(&self.staging_path, &self.destination)?,
+			Err(e) if e.kind() == ErrorKind::NotFound => {
+				std::fs::rename(&self.staging_path, &self.destination)?
+			}
+			Err(e) => return Err(e),
+		}
+		self.committed = true;
+		sync_parent(&self.destination)?;
+		// The staging directory now holds whatever was at the destination
+		remove(&self.staging_path)
+	}
+}
+
+impl Drop for StagingDir {
+	fn drop(&mut self) {
+		pending().retain(|path| *path != self.staging_path);
+		if !self.committed {
+			let _ = remove(&self.staging_path);
+		}
+	}
+}
+
+/// Remove every temporary file & staging directory still being written
+pub(crate) fn remove_pending() {
+	for path in pending().drain(..) {
+		let _ = remove(&path);
+	}
+}
+
+/// Remove a file, or a directory and everything in it, if it exists
+///
+/// # Arguments
+///
+/// * `path` - Path to the file or directory
+fn remove(path: &Path) -> Result<()> {
+	let removed = match path.symlink_metadata() {
+		Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+		Ok(_) => std::fs::remove_file(path),
+		Err(e) => Err(e),
+	};
+	match removed {
+		Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+		removed => removed,
+	}
+}
+
+/// Find an unused, hidden path beside a file or directory, to write it at until it is complete
+///
+/// # Arguments
+///
+/// * `path` - Path to the file or directory
+///
+/// * `suffix` - The extension given to the hidden path
+fn temporary_path(path: &Path, suffix: &str) -> Result<PathBuf> {
+	hidden_path(
+		path,
+		&format!("{}.{suffix}", CREATED.fetch_add(1, Ordering::Relaxed)),
+	)
+}
+
+/// The hidden path beside a file or directory with the given extension, such as `.archive.larz.partial`
+///
+/// # Arguments
+///
+/// * `path` - Path to the file or directory
+///
+/// * `suffix` - The extension given to the hidden path
+pub(crate) fn hidden_path(path: &Path, suffix: &str) -> Result<PathBuf> {
+	let file_name = path.file_name().ok_or_else(|| {
+		Error::new(
+			ErrorKind::InvalidInput,
+			format!("'{}' has no file name", crate::escape_path(path)),
+		)
+	})?;
+	let mut hidden_name = OsString::from(".");
+	hidden_name.push(file_name);
+	hidden_name.push(format!(".{suffix}"));
+	Ok(path.with_file_name(hidden_name))
+}
+
+/// Lock the list of temporary files still being written, even if a thread panicked while holding it
+fn pending() -> std::sync::MutexGuard<'static, Vec<PathBuf>> {
+	PENDING.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Move a file to a path where nothing exists yet
+///
+/// The file is hard-linked to its new path, so that nothing that appears there in the meantime is replaced; filesystems without hard links fall back to checking first.
+///
+/// # Arguments
+///
+/// * `from` - Path to the file
+///
+/// * `to` - Path to move the file to
+fn rename_no_clobber(from: &Path, to: &Path) -> Result<()> {
+	match std::fs::hard_link(from, to) {
+		Ok(()) => std::fs::remove_file(from),
+		Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(already_exists(to)),
+		Err(_) if to.symlink_metadata().is_ok() => Err(already_exists(to)),
+		Err(_) => std::fs::rename(from, to),
+	}
+}
+
+/// Exchange two paths, each of which exists
+///
+/// Linux with glibc exchanges them in one step; elsewhere, and on filesystems that cannot, `to` is moved aside first, and moved back if `from` cannot take its place.
+///
+/// # Arguments
+///
+/// * `from` - The path to move to `to`
+///
+/// * `to` - The path to move to `from`
+fn exchange(from: &Path, to: &Path) -> Result<()> {
+	#[cfg(all(target_os = "linux", target_env = "gnu"))]
+	{
+		use std::os::unix::ffi::OsStrExt;
+		let from_c = std::ffi::CString::new(from.as_os_str().as_bytes())?;
+		let to_c = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+		let exchanged = unsafe {
+			libc::renameat2(
+				libc::AT_FDCWD,
+				from_c.as_ptr(),
+				libc::AT_FDCWD,
+				to_c.as_ptr(),
+				libc::RENAME_EXCHANGE,
+			)
+		};
+		match exchanged {
+			0 => return Ok(()),
+			_ => {
+				let e = Error::last_os_error();
+				if !matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) {
+					return Err(e);
+				}
+			}
+		}
+	}
+	let aside = temporary_path(to, "old")?;
+	std::fs::rename(to, &aside)?;
+	if let Err(e) = std::fs::rename(from, to) {
+		std::fs::rename(&aside, to)?;
+		return Err(e);
+	}
+	std::fs::rename(&aside, from)
+}
+
+/// Sync the directory holding a file, so that the file's new name is on disk
+///
+/// Directories cannot be opened as files on Windows, and are synced along with the files in them.
+///
+/// # Arguments
+///
+/// * `path` - Path to the file
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<()> {
+	match path.parent() {
+		Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+		_ => File::open(".")?.sync_all(),
+	}
+}
+
+/// Sync the directory holding a file, so that the file's new name is on disk
+///
+/// Directories cannot be opened as files on Windows, and are synced along with the files in them.
+///
+/// # Arguments
+///
+/// * `_path` - Path to the file
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<()> {
+	Ok(())
+}
+
+/// The error returned when a file would be replaced, but replacing it was refused
+///
+/// # Arguments
+///
+/// * `path` - Path to the file
+fn already_exists(path: &Path) -> Error {
+	Error::new(
+		ErrorKind::AlreadyExists,
+		format!("'{}' already exists", crate::escape_path(path)),
+	)
+}
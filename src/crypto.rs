@@ -0,0 +1,453 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Password-based authenticated encryption of archives.
+//!
+//! The compressed body of an archive is split into chunks of [`CHUNK_SIZE`] bytes, and each chunk is sealed with XChaCha20-Poly1305 using the [STREAM](https://eprint.iacr.org/2015/189.pdf) construction: every nonce is made of a random prefix, the index of the chunk, and a flag marking the final chunk.
+//! This lets archives be encrypted and decrypted as they are streamed, while still detecting reordered, dropped, or truncated chunks.
+//!
+//! The key is derived from a passphrase using Argon2id. The salt, the Argon2id parameters, and the nonce prefix are stored in the archive header, which is also authenticated as associated data of every chunk.
+
+use crate::header::{ArchiveHeader, Flags};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use zeroize::Zeroizing;
+
+/// The number of plaintext bytes sealed in each chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of bytes each chunk grows by when sealed
+pub const TAG_SIZE: usize = 16;
+
+/// The length of the random part of each nonce
+const NONCE_PREFIX_SIZE: usize = 19;
+
+/// The length of the Argon2id salt
+const SALT_SIZE: usize = 16;
+
+/// The length of the derived key
+const KEY_SIZE: usize = 32;
+
+/// The largest Argon2id memory cost accepted from an archive header, in KiB
+const MAX_M_COST: u32 = 1 << 21;
+
+/// The largest Argon2id number of iterations accepted from an archive header
+const MAX_T_COST: u32 = 16;
+
+/// The largest Argon2id degree of parallelism accepted from an archive header
+const MAX_P_COST: u32 = 16;
+
+/// Header metadata key naming the cipher
+const CIPHER_KEY: &str = "larz.cipher";
+
+/// Header metadata key holding the Argon2id parameters
+const KDF_KEY: &str = "larz.kdf";
+
+/// Header metadata key holding the Argon2id salt
+const SALT_KEY: &str = "larz.salt";
+
+/// Header metadata key holding the nonce prefix
+const NONCE_KEY: &str = "larz.nonce";
+
+/// The name of the cipher recorded in the header
+const CIPHER_NAME: &[u8] = b"xchacha20poly1305-stream";
+
+/// A passphrase used to encrypt or decrypt an archive
+///
+/// The passphrase is wiped from memory when dropped, and is never printed.
+#[derive(Clone)]
+pub struct Passphrase(Zeroizing<Vec<u8>>);
+
+impl Passphrase {
+	/// Create a passphrase from raw bytes
+	///
+	/// # Arguments
+	///
+	/// * `bytes` - The passphrase
+	pub fn new(bytes: impl Into<Vec<u8>>) -> Passphrase {
+		Passphrase(Zeroizing::new(bytes.into()))
+	}
+
+	/// The raw bytes of this passphrase
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl fmt::Debug for Passphrase {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("Passphrase(..)")
+	}
+}
+
+/// An error raised when a chunk of an encrypted archive cannot be authenticated
+///
+/// This is the inner error of the `std::io::Error` returned while reading an encrypted archive; retrieve it with `std::io::Error::get_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptionError {
+	/// The index of the chunk that could not be decrypted, starting from zero
+	pub chunk: u64,
+	/// The offset of the chunk within the encrypted body of the archive
+	pub offset: u64,
+}
+
+impl fmt::Display for DecryptionError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"Could not decrypt chunk {} (at byte {} of the encrypted data); the passphrase is wrong, or the archive is corrupted or truncated",
+			self.chunk, self.offset
+		)
+	}
+}
+
+impl std::error::Error for DecryptionError {}
+
+/// The per-archive parameters needed to derive the key and nonces
+struct Parameters {
+	/// Argon2id memory cost, in KiB
+	m_cost: u32,
+	/// Argon2id number of iterations
+	t_cost: u32,
+	/// Argon2id degree of parallelism
+	p_cost: u32,
+	/// Argon2id salt
+	salt: [u8; SALT_SIZE],
+	/// The random part of each nonce
+	nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+}
+
+impl Parameters {
+	/// Generate fresh parameters for a new archive
+	fn generate() -> Result<Parameters> {
+		let mut salt = [0u8; SALT_SIZE];
+		let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+		getrandom::getrandom(&mut salt).map_err(|e| Error::other(e.to_string()))?;
+		getrandom::getrandom(&mut nonce_prefix).map_err(|e| Error::other(e.to_string()))?;
+		Ok(Parameters {
+			m_cost: Params::DEFAULT_M_COST,
+			t_cost: Params::DEFAULT_T_COST,
+			p_cost: Params::DEFAULT_P_COST,
+			salt,
+			nonce_prefix,
+		})
+	}
+
+	/// Record these parameters in an archive header
+	fn store(&self, header: &mut ArchiveHeader) {
+		let mut kdf = Vec::with_capacity(12);
+		kdf.extend_from_slice(&self.m_cost.to_le_bytes());
+		kdf.extend_from_slice(&self.t_cost.to_le_bytes());
+		kdf.extend_from_slice(&self.p_cost.to_le_bytes());
+		header.flags.insert(Flags::ENCRYPTED);
+		header
+			.metadata
+			.insert(CIPHER_KEY.to_owned(), CIPHER_NAME.to_vec());
+		header.metadata.insert(KDF_KEY.to_owned(), kdf);
+		header
+			.metadata
+			.insert(SALT_KEY.to_owned(), self.salt.to_vec());
+		header
+			.metadata
+			.insert(NONCE_KEY.to_owned(), self.nonce_prefix.to_vec());
+	}
+
+	/// Read these parameters from an archive header
+	///
+	/// The header is not authenticated until a chunk is decrypted, so key derivation parameters costlier than [`MAX_M_COST`], [`MAX_T_COST`] or [`MAX_P_COST`] are refused.
+	fn load(header: &ArchiveHeader) -> Result<Parameters> {
+		let malformed = |what: &str| {
+			Error::new(
+				ErrorKind::InvalidData,
+				format!("Encrypted archive has a missing or malformed {what}"),
+			)
+		};
+		if header.metadata.get(CIPHER_KEY).map(Vec::as_slice) != Some(CIPHER_NAME) {
+			return Err(Error::new(
+				ErrorKind::Unsupported,
+				"Archive is encrypted with an unsupported cipher",
+			));
+		}
+		let kdf = header
+			.metadata
+			.get(KDF_KEY)
+			.filter(|kdf| kdf.len() == 12)
+			.ok_or_else(|| malformed("key derivation parameters"))?;
+		let salt = header
+			.metadata
+			.get(SALT_KEY)
+			.and_then(|salt| salt.as_slice().try_into().ok())
+			.ok_or_else(|| malformed("salt"))?;
+		let nonce_prefix = header
+			.metadata
+			.get(NONCE_KEY)
+			.and_then(|nonce| nonce.as_slice().try_into().ok())
+			.ok_or_else(|| malformed("nonce"))?;
+		let m_cost = u32::from_le_bytes(kdf[0..4].try_into().unwrap());
+		let t_cost = u32::from_le_bytes(kdf[4..8].try_into().unwrap());
+		let p_cost = u32::from_le_bytes(kdf[8..12].try_into().unwrap());
+		if m_cost > MAX_M_COST || t_cost > MAX_T_COST || p_cost > MAX_P_COST {
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				format!("Encrypted archive asks for key derivation parameters beyond the limits of {MAX_M_COST} KiB of memory, {MAX_T_COST} iterations and {MAX_P_COST} lanes"),
+			));
+		}
+		Ok(Parameters {
+			m_cost,
+			t_cost,
+			p_cost,
+			salt,
+			nonce_prefix,
+		})
+	}
+
+	/// Derive the archive key from a passphrase using Argon2id
+	fn derive_cipher(&self, passphrase: &Passphrase) -> Result<XChaCha20Poly1305> {
+		let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_SIZE))
+			.map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+		let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+		Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+			.hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+			.map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+		XChaCha20Poly1305::new_from_slice(key.as_ref())
+			.map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+	}
+
+	/// The nonce of a given chunk
+	fn nonce(&self, chunk: u64, last: bool) -> Result<XNonce> {
+		let counter = u32::try_from(chunk)
+			.map_err(|_| Error::other("Archive is too large to be encrypted"))?;
+		let mut nonce = XNonce::default();
+		nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+		nonce[NONCE_PREFIX_SIZE..NONCE_PREFIX_SIZE + 4].copy_from_slice(&counter.to_be_bytes());
+		nonce[NONCE_PREFIX_SIZE + 4] = last as u8;
+		Ok(nonce)
+	}
+}
+
+/// Encrypts the body of an archive as it is written
+pub struct EncryptWriter<W: Write> {
+	/// Where sealed chunks are written to
+	inner: W,
+	/// The cipher keyed from the passphrase
+	cipher: XChaCha20Poly1305,
+	/// The key derivation parameters and nonce prefix
+	parameters: Parameters,
+	/// The serialised archive header, authenticated with every chunk
+	associated_data: Vec<u8>,
+	/// Plaintext waiting to be sealed
+	buffer: Vec<u8>,
+	/// The index of the next chunk
+	chunk: u64,
+}
+
+impl<W: Write> EncryptWriter<W> {
+	/// Prepare to encrypt a new archive
+	///
+	/// This generates a fresh salt and nonce prefix, derives the key from the passphrase, and records the parameters in `header`.
+	/// The header must not be changed after this; write it out, followed by the encrypted body.
+	///
+	/// # Arguments
+	///
+	/// * `inner` - Where the encrypted body is written to
+	///
+	/// * `passphrase` - The passphrase to derive the key from
+	///
+	/// * `header` - The header of the archive being written
+	pub fn new(
+		inner: W,
+		passphrase: &Passphrase,
+		header: &mut ArchiveHeader,
+	) -> Result<EncryptWriter<W>> {
+		let parameters = Parameters::generate()?;
+		parameters.store(header);
+		let cipher = parameters.derive_cipher(passphrase)?;
+		let mut associated_data = Vec::new();
+		header.write_to(&mut associated_data)?;
+		Ok(EncryptWriter {
+			inner,
+			cipher,
+			parameters,
+			associated_data,
+			buffer: Vec::with_capacity(CHUNK_SIZE),
+			chunk: 0,
+		})
+	}
+
+	/// Seal and write out the buffered plaintext as one chunk
+	fn seal(&mut self, last: bool) -> Result<()> {
+		let nonce = self.parameters.nonce(self.chunk, last)?;
+		let sealed = self
+			.cipher
+			.encrypt(
+				&nonce,
+				Payload {
+					msg: &self.buffer,
+					aad: &self.associated_data,
+				},
+			)
+			.map_err(|_| Error::other(format!("Could not encrypt chunk {}", self.chunk)))?;
+		self.inner.write_all(&sealed)?;
+		self.buffer.clear();
+		self.chunk += 1;
+		Ok(())
+	}
+
+	/// Get a mutable reference to the underlying writer
+	///
+	/// Anything written directly to it bypasses encryption.
+	pub fn get_mut(&mut self) -> &mut W {
+		&mut self.inner
+	}
+
+	/// Seal the final chunk, returning the underlying writer
+	pub fn finish(mut self) -> Result<W> {
+		self.seal(true)?;
+		self.inner.flush()?;
+		Ok(self.inner)
+	}
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		// A full chunk is only sealed once more data arrives, so the final chunk is never empty unless the whole body is
+		if self.buffer.len() == CHUNK_SIZE && !buf.is_empty() {
+			self.seal(false)?;
+		}
+		let length = buf.len().min(CHUNK_SIZE - self.buffer.len());
+		self.buffer.extend_from_slice(&buf[..length]);
+		Ok(length)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Decrypts the body of an archive as it is read
+pub struct DecryptReader<R: Read> {
+	/// Where sealed chunks are read from
+	inner: R,
+	/// The cipher keyed from the passphrase
+	cipher: XChaCha20Poly1305,
+	/// The key derivation parameters and nonce prefix
+	parameters: Parameters,
+	/// The serialised archive header, authenticated with every chunk
+	associated_data: Vec<u8>,
+	/// Decrypted plaintext not yet returned
+	plaintext: Vec<u8>,
+	/// How much of `plaintext` has been returned
+	position: usize,
+	/// A byte read ahead of the current chunk to find out whether it was the last one
+	lookahead: Option<u8>,
+	/// The index of the next chunk
+	chunk: u64,
+	/// Whether the final chunk has been read
+	finished: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+	/// Prepare to decrypt an archive
+	///
+	/// # Arguments
+	///
+	/// * `inner` - The encrypted body of the archive
+	///
+	/// * `passphrase` - The passphrase the archive was encrypted with
+	///
+	/// * `header` - The header of the archive
+	pub fn new(
+		inner: R,
+		passphrase: &Passphrase,
+		header: &ArchiveHeader,
+	) -> Result<DecryptReader<R>> {
+		let parameters = Parameters::load(header)?;
+		let cipher = parameters.derive_cipher(passphrase)?;
+		let mut associated_data = Vec::new();
+		header.write_to(&mut associated_data)?;
+		Ok(DecryptReader {
+			inner,
+			cipher,
+			parameters,
+			associated_data,
+			plaintext: Vec::new(),
+			position: 0,
+			lookahead: None,
+			chunk: 0,
+			finished: false,
+		})
+	}
+
+	/// Read and open the next chunk
+	fn open_next(&mut self) -> Result<()> {
+		let mut sealed = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+		sealed.extend(self.lookahead.take());
+		(&mut self.inner)
+			.take((CHUNK_SIZE + TAG_SIZE - sealed.len()) as u64)
+			.read_to_end(&mut sealed)?;
+
+		// Only a full chunk followed by more data can be anything but the last chunk
+		let mut next = [0u8; 1];
+		let last = sealed.len() < CHUNK_SIZE + TAG_SIZE
+			|| loop {
+				match self.inner.read(&mut next) {
+					Ok(0) => break true,
+					Ok(_) => {
+						self.lookahead = Some(next[0]);
+						break false;
+					}
+					Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+					Err(e) => return Err(e),
+				}
+			};
+
+		let error = DecryptionError {
+			chunk: self.chunk,
+			offset: self.chunk * (CHUNK_SIZE + TAG_SIZE) as u64,
+		};
+		let nonce = self.parameters.nonce(self.chunk, last)?;
+		self.plaintext = self
+			.cipher
+			.decrypt(
+				&nonce,
+				Payload {
+					msg: &sealed,
+					aad: &self.associated_data,
+				},
+			)
+			.map_err(|_| Error::new(ErrorKind::InvalidData, error))?;
+		self.position = 0;
+		self.chunk += 1;
+		self.finished = last;
+		Ok(())
+	}
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		while self.position == self.plaintext.len() {
+			if self.finished {
+				return Ok(0);
+			}
+			self.open_next()?;
+		}
+		let length = buf.len().min(self.plaintext.len() - self.position);
+		buf[..length].copy_from_slice(&self.plaintext[self.position..self.position + length]);
+		self.position += length;
+		Ok(length)
+	}
+}
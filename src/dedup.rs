@@ -0,0 +1,319 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Content-defined chunking deduplication.
+//!
+//! LZ4 can only refer to data up to 64 KiB back, so it cannot take advantage of large regions repeated far apart, as are common in disk images and build trees.
+//! Deduplication runs before compression: the TAR data is cut into chunks wherever a rolling hash of the last few bytes matches a pattern, so that boundaries depend on content rather than offsets and repeated regions are cut into the same chunks wherever they occur.
+//! Each distinct chunk is stored once, and repeats are replaced with a reference to the earlier copy.
+//!
+//! Deduplicated archives have the [`Flags::DEDUPLICATED`](crate::header::Flags::DEDUPLICATED) flag set, and their uncompressed body is a sequence of records:
+//!
+//! | Tag    | Followed by                                              | Meaning                                 |
+//! | ------ | -------------------------------------------------------- | --------------------------------------- |
+//! | `0x00` | Nothing                                                  | The end of the TAR data                 |
+//! | `0x01` | A four-byte little-endian length, then the chunk's bytes | A chunk seen for the first time         |
+//! | `0x02` | A four-byte little-endian chunk number                   | A repeat of an earlier chunk            |
+//!
+//! Chunks are numbered from zero in the order they are first stored.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+
+/// The smallest chunk, other than the last
+pub const MIN_CHUNK_SIZE: usize = 2 * 1024;
+
+/// The average chunk size targeted by the chunker
+pub const AVERAGE_CHUNK_SIZE: usize = 8 * 1024;
+
+/// The largest chunk
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A chunk boundary is placed where none of these bits of the rolling hash are set
+const BOUNDARY_MASK: u64 =
+	(AVERAGE_CHUNK_SIZE as u64 - 1) << (64 - AVERAGE_CHUNK_SIZE.trailing_zeros());
+
+/// Marks the end of the TAR data
+const END_TAG: u8 = 0x00;
+
+/// Marks a chunk seen for the first time
+const CHUNK_TAG: u8 = 0x01;
+
+/// Marks a repeat of an earlier chunk
+const REFERENCE_TAG: u8 = 0x02;
+
+/// Random values mixed into the rolling hash for each byte value
+const GEAR: [u64; 256] = gear_table();
+
+/// Generate the table of values mixed into the rolling hash, using SplitMix64 so the table is the same in every build
+const fn gear_table() -> [u64; 256] {
+	let mut table = [0u64; 256];
+	let mut state: u64 = 0x6c61_727a_6465_6475; // "larzdedu"
+	let mut i = 0;
+	while i < table.len() {
+		state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		table[i] = z ^ (z >> 31);
+		i += 1;
+	}
+	table
+}
+
+/// Finds content-defined chunk boundaries using a gear rolling hash
+#[derive(Debug, Default)]
+struct Chunker {
+	/// The rolling hash of the bytes seen since the last boundary
+	hash: u64,
+	/// How many bytes have been seen since the last boundary
+	length: usize,
+}
+
+impl Chunker {
+	/// Feed bytes to the chunker, returning how many of them belong to the current chunk if it ends among them
+	///
+	/// # Arguments
+	///
+	/// * `data` - The bytes following those already seen
+	fn next_boundary(&mut self, data: &[u8]) -> Option<usize> {
+		for (i, byte) in data.iter().enumerate() {
+			self.hash = (self.hash << 1).wrapping_add(GEAR[*byte as usize]);
+			self.length += 1;
+			if self.length >= MAX_CHUNK_SIZE
+				|| (self.length >= MIN_CHUNK_SIZE && self.hash & BOUNDARY_MASK == 0)
+			{
+				*self = Chunker::default();
+				return Some(i + 1);
+			}
+		}
+		None
+	}
+}
+
+/// How much deduplication saved while writing an archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+	/// The number of bytes given to the deduplicator
+	pub input_size: u64,
+	/// The number of bytes written by the deduplicator
+	pub output_size: u64,
+	/// The number of chunks the input was cut into
+	pub chunks: u64,
+	/// The number of chunks replaced with a reference to an earlier copy
+	pub duplicate_chunks: u64,
+}
+
+/// Deduplicates TAR data as it is written
+pub struct DedupWriter<W: Write> {
+	/// Where the deduplicated data is written to
+	inner: W,
+	/// Finds the boundaries between chunks
+	chunker: Chunker,
+	/// Bytes written that have not yet been assigned to a chunk
+	pending: Vec<u8>,
+	/// How many of the pending bytes have been given to the chunker
+	scanned: usize,
+	/// The number of each chunk stored so far, by the hash of its contents
+	chunks: HashMap<blake3::Hash, u32>,
+	/// How much deduplication has saved so far
+	stats: DedupStats,
+}
+
+impl<W: Write> DedupWriter<W> {
+	/// Deduplicate data before writing it to another writer
+	///
+	/// # Arguments
+	///
+	/// * `inner` - Where the deduplicated data is written to
+	pub fn new(inner: W) -> DedupWriter<W> {
+		DedupWriter {
+			inner,
+			chunker: Chunker::default(),
+			pending: Vec::with_capacity(MAX_CHUNK_SIZE),
+			scanned: 0,
+			chunks: HashMap::new(),
+			stats: DedupStats::default(),
+		}
+	}
+
+	/// How much deduplication has saved so far
+	pub fn stats(&self) -> DedupStats {
+		self.stats
+	}
+
+	/// Store a chunk, or a reference to it if it has been stored before
+	///
+	/// # Arguments
+	///
+	/// * `chunk` - The contents of the chunk
+	fn write_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+		let hash = blake3::hash(chunk);
+		self.stats.chunks += 1;
+		match self.chunks.get(&hash) {
+			Some(number) => {
+				self.inner.write_all(&[REFERENCE_TAG])?;
+				self.inner.write_all(&number.to_le_bytes())?;
+				self.stats.duplicate_chunks += 1;
+				self.stats.output_size += 5;
+			}
+			None => {
+				let number = u32::try_from(self.chunks.len())
+					.map_err(|_| Error::other("Too many distinct chunks to deduplicate"))?;
+				self.chunks.insert(hash, number);
+				self.inner.write_all(&[CHUNK_TAG])?;
+				self.inner.write_all(&(chunk.len() as u32).to_le_bytes())?;
+				self.inner.write_all(chunk)?;
+				self.stats.output_size += 5 + chunk.len() as u64;
+			}
+		}
+		Ok(())
+	}
+
+	/// Store any remaining data and mark the end of the TAR data, returning the underlying writer and how much deduplication saved
+	pub fn finish(mut self) -> Result<(W, DedupStats)> {
+		let pending = std::mem::take(&mut self.pending);
+		if !pending.is_empty() {
+			self.write_chunk(&pending)?;
+		}
+		self.inner.write_all(&[END_TAG])?;
+		self.stats.output_size += 1;
+		Ok((self.inner, self.stats))
+	}
+}
+
+impl<W: Write> Write for DedupWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		self.pending.extend_from_slice(buf);
+		let pending = std::mem::take(&mut self.pending);
+		let mut chunk_start = 0;
+		while let Some(length) = self.chunker.next_boundary(&pending[self.scanned..]) {
+			let chunk_end = self.scanned + length;
+			self.write_chunk(&pending[chunk_start..chunk_end])?;
+			chunk_start = chunk_end;
+			self.scanned = chunk_end;
+		}
+		self.pending = pending;
+		self.pending.drain(..chunk_start);
+		self.scanned = self.pending.len();
+		self.stats.input_size += buf.len() as u64;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Rebuilds deduplicated TAR data as it is read
+///
+/// Every distinct chunk is kept in an anonymous temporary file, so that repeats can be copied from it without holding the whole archive in memory.
+pub struct DedupReader<R: Read> {
+	/// The deduplicated data
+	inner: R,
+	/// Holds the contents of every chunk read so far
+	store: crate::Scratch,
+	/// Where each chunk starts in the store, and how long it is
+	chunks: Vec<(u64, u32)>,
+	/// The length of the store
+	store_size: u64,
+	/// The contents of the chunk currently being read
+	buffer: Vec<u8>,
+	/// How much of the current chunk has been read
+	position: usize,
+	/// Whether the end of the TAR data has been reached
+	finished: bool,
+}
+
+impl<R: Read> DedupReader<R> {
+	/// Rebuild deduplicated data read from another reader
+	///
+	/// # Arguments
+	///
+	/// * `inner` - The deduplicated data
+	pub fn new(inner: R) -> Result<DedupReader<R>> {
+		Ok(DedupReader {
+			inner,
+			store: crate::scratch()?,
+			chunks: Vec::new(),
+			store_size: 0,
+			buffer: Vec::with_capacity(MAX_CHUNK_SIZE),
+			position: 0,
+			finished: false,
+		})
+	}
+
+	/// Read the next record, returning `false` once the end of the TAR data has been reached
+	fn next_chunk(&mut self) -> Result<bool> {
+		let mut tag = [0u8; 1];
+		self.inner.read_exact(&mut tag)?;
+		let mut value = [0u8; 4];
+		match tag[0] {
+			END_TAG => return Ok(false),
+			CHUNK_TAG => {
+				self.inner.read_exact(&mut value)?;
+				let length = u32::from_le_bytes(value);
+				if length as usize > MAX_CHUNK_SIZE {
+					return Err(Error::new(
+						ErrorKind::InvalidData,
+						format!("Deduplicated chunk is {length} bytes long, which is larger than the largest chunk"),
+					));
+				}
+				self.buffer.resize(length as usize, 0);
+				self.inner.read_exact(&mut self.buffer)?;
+				self.store.seek(SeekFrom::Start(self.store_size))?;
+				self.store.write_all(&self.buffer)?;
+				self.chunks.push((self.store_size, length));
+				self.store_size += length as u64;
+			}
+			REFERENCE_TAG => {
+				self.inner.read_exact(&mut value)?;
+				let number = u32::from_le_bytes(value);
+				let (offset, length) =
+					*self.chunks.get(number as usize).ok_or_else(|| {
+						Error::new(
+						ErrorKind::InvalidData,
+						format!("Deduplicated data refers to chunk {number}, which has not been stored"),
+					)
+					})?;
+				self.buffer.resize(length as usize, 0);
+				self.store.seek(SeekFrom::Start(offset))?;
+				self.store.read_exact(&mut self.buffer)?;
+			}
+			tag => {
+				return Err(Error::new(
+					ErrorKind::InvalidData,
+					format!("Unknown deduplication record {tag:#04x}"),
+				))
+			}
+		}
+		self.position = 0;
+		Ok(true)
+	}
+}
+
+impl<R: Read> Read for DedupReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		while self.position == self.buffer.len() {
+			if self.finished || !self.next_chunk()? {
+				self.finished = true;
+				return Ok(0);
+			}
+		}
+		let length = buf.len().min(self.buffer.len() - self.position);
+		buf[..length].copy_from_slice(&self.buffer[self.position..self.position + length]);
+		self.position += length;
+		Ok(length)
+	}
+}
@@ -0,0 +1,475 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Dictionary-trained compression.
+//!
+//! Small files compress poorly with LZ4, since there is little earlier data for matches to refer to.
+//! A dictionary is a sample of content typical of the files being archived; every block of the archive is compressed as if the dictionary came right before it, so even the first bytes of a block can be matched against it.
+//!
+//! Archives written with a dictionary use the [`Codec::Lz4DictBlocks`](crate::header::Codec::Lz4DictBlocks) codec: the TAR data is cut into blocks of [`BLOCK_SIZE`] bytes, and each block is compressed independently against the dictionary.
+//! Every block is stored as its little-endian compressed length and uncompressed length (four bytes each) followed by the compressed data, and the body ends with a block whose lengths are both zero.
+//!
+//! The identifier of the dictionary is recorded in the archive header, and the dictionary itself may be embedded there too.
+//! Dictionaries are stored on disk as raw bytes, the same format used by the reference `lz4` tool.
+
+use crate::header::ArchiveHeader;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// The number of uncompressed bytes in each block
+///
+/// LZ4 can only refer to data up to 64 KiB back, so in larger blocks the dictionary would be out of reach for most of the block.
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+/// The largest useful dictionary size
+pub const MAX_DICTIONARY_SIZE: usize = 64 * 1024;
+
+/// Header metadata key holding the identifier of the dictionary an archive was written with
+pub(crate) const DICTIONARY_ID_KEY: &str = "larz.dict.id";
+
+/// Header metadata key holding an embedded dictionary
+pub(crate) const DICTIONARY_KEY: &str = "larz.dict";
+
+/// The length of the substrings counted when training a dictionary
+const GRAM_SIZE: usize = 8;
+
+/// The length of the segments a trained dictionary is assembled from
+const SEGMENT_SIZE: usize = 64;
+
+/// The most sample data considered when training a dictionary
+const MAX_SAMPLE_SIZE: usize = 8 * 1024 * 1024;
+
+/// An error raised when an archive cannot be decoded with the available dictionary
+///
+/// This is the inner error of the `std::io::Error` returned while reading the archive; retrieve it with `std::io::Error::get_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DictionaryError {
+	/// The archive needs a dictionary, but none was given or embedded in the archive
+	Missing {
+		/// The identifier of the dictionary the archive was written with
+		expected: u32,
+	},
+	/// The given dictionary is not the one the archive was written with
+	Mismatch {
+		/// The identifier of the dictionary the archive was written with
+		expected: u32,
+		/// The identifier of the given dictionary
+		actual: u32,
+	},
+}
+
+impl fmt::Display for DictionaryError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			DictionaryError::Missing { expected } => write!(
+				f,
+				"Archive was compressed with dictionary {expected:08x}, but no dictionary was given"
+			),
+			DictionaryError::Mismatch { expected, actual } => write!(
+				f,
+				"Archive was compressed with dictionary {expected:08x}, but dictionary {actual:08x} was given"
+			),
+		}
+	}
+}
+
+impl std::error::Error for DictionaryError {}
+
+/// A compression dictionary
+#[derive(Clone, PartialEq, Eq)]
+pub struct Dictionary {
+	/// The raw contents of the dictionary
+	bytes: Vec<u8>,
+}
+
+impl fmt::Debug for Dictionary {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Dictionary")
+			.field("id", &format_args!("{:08x}", self.id()))
+			.field("len", &self.bytes.len())
+			.finish()
+	}
+}
+
+impl Dictionary {
+	/// Create a dictionary from raw bytes
+	///
+	/// Only the last [`MAX_DICTIONARY_SIZE`] bytes are kept, as anything before them could never be referred to.
+	///
+	/// # Arguments
+	///
+	/// * `bytes` - The contents of the dictionary
+	pub fn new(mut bytes: Vec<u8>) -> Dictionary {
+		if bytes.len() > MAX_DICTIONARY_SIZE {
+			bytes.drain(..bytes.len() - MAX_DICTIONARY_SIZE);
+		}
+		Dictionary { bytes }
+	}
+
+	/// Read a dictionary from a file
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the dictionary file
+	pub fn read(path: &Path) -> Result<Dictionary> {
+		Ok(Dictionary::new(std::fs::read(path)?))
+	}
+
+	/// Write this dictionary to a file
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to write the dictionary to
+	pub fn write(&self, path: &Path) -> Result<()> {
+		std::fs::write(path, &self.bytes)
+	}
+
+	/// The raw contents of this dictionary
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.bytes
+	}
+
+	/// The identifier of this dictionary, derived from its contents
+	pub fn id(&self) -> u32 {
+		let hash = blake3::hash(&self.bytes);
+		u32::from_le_bytes(hash.as_bytes()[..4].try_into().unwrap())
+	}
+
+	/// Record this dictionary in the header of an archive written with it
+	///
+	/// # Arguments
+	///
+	/// * `header` - The header of the archive
+	///
+	/// * `embed` - Whether to store the dictionary itself in the header, so the archive can be extracted without it
+	pub(crate) fn store(&self, header: &mut ArchiveHeader, embed: bool) {
+		header.metadata.insert(
+			DICTIONARY_ID_KEY.to_owned(),
+			self.id().to_le_bytes().to_vec(),
+		);
+		if embed {
+			header
+				.metadata
+				.insert(DICTIONARY_KEY.to_owned(), self.bytes.clone());
+		}
+	}
+
+	/// Find the dictionary needed to read an archive, checking that it is the one the archive was written with
+	///
+	/// A given dictionary takes precedence over one embedded in the archive.
+	///
+	/// # Arguments
+	///
+	/// * `header` - The header of the archive
+	///
+	/// * `given` - The dictionary given by the user, if any
+	pub(crate) fn load(header: &ArchiveHeader, given: Option<&Dictionary>) -> Result<Dictionary> {
+		let expected = header
+			.metadata
+			.get(DICTIONARY_ID_KEY)
+			.and_then(|id| id.as_slice().try_into().ok())
+			.map(u32::from_le_bytes)
+			.ok_or_else(|| {
+				Error::new(
+					ErrorKind::InvalidData,
+					"Archive does not record which dictionary it was written with",
+				)
+			})?;
+		let dictionary = match given {
+			Some(dictionary) => dictionary.clone(),
+			None => header
+				.metadata
+				.get(DICTIONARY_KEY)
+				.map(|bytes| Dictionary::new(bytes.clone()))
+				.ok_or_else(|| {
+					Error::new(
+						ErrorKind::InvalidInput,
+						DictionaryError::Missing { expected },
+					)
+				})?,
+		};
+		let actual = dictionary.id();
+		match actual == expected {
+			true => Ok(dictionary),
+			false => Err(Error::new(
+				ErrorKind::InvalidInput,
+				DictionaryError::Mismatch { expected, actual },
+			)),
+		}
+	}
+
+	/// Train a dictionary from sample files
+	///
+	/// Directories are searched for sample files recursively. Samples should be typical of the files that will be archived with the dictionary.
+	///
+	/// # Arguments
+	///
+	/// * `paths` - A list of paths pointing to sample files or directories of sample files
+	///
+	/// * `size` - The size of the dictionary, at most [`MAX_DICTIONARY_SIZE`]
+	///
+	/// # Examples
+	///
+	/// ```rust,no_run
+	/// use larz::dictionary::Dictionary;
+	/// use std::path::PathBuf;
+	///
+	/// let dictionary = Dictionary::train(&[PathBuf::from("path/to/samples")], 64 * 1024).unwrap();
+	/// dictionary.write(&PathBuf::from("path/to/dictionary")).unwrap();
+	/// ```
+	pub fn train(paths: &[PathBuf], size: usize) -> Result<Dictionary> {
+		let mut samples = Vec::new();
+		let mut total = 0;
+		let mut stack: Vec<PathBuf> = paths.iter().rev().cloned().collect();
+		while let Some(path) = stack.pop() {
+			if total >= MAX_SAMPLE_SIZE {
+				break;
+			}
+			if path.is_dir() {
+				let mut children = std::fs::read_dir(&path)?
+					.map(|child| child.map(|child| child.path()))
+					.collect::<Result<Vec<_>>>()?;
+				children.sort_unstable_by(|a, b| b.cmp(a));
+				stack.extend(children);
+			} else {
+				let sample = std::fs::read(&path)?;
+				total += sample.len();
+				samples.push(sample);
+			}
+		}
+		Ok(Dictionary::train_from_samples(&samples, size))
+	}
+
+	/// Train a dictionary from samples held in memory
+	///
+	/// The dictionary is assembled from the segments of the samples whose substrings occur in the most samples, with the most useful segments placed last, where they are cheapest to refer to.
+	///
+	/// # Arguments
+	///
+	/// * `samples` - The contents of the sample files
+	///
+	/// * `size` - The size of the dictionary, at most [`MAX_DICTIONARY_SIZE`]
+	pub fn train_from_samples(samples: &[Vec<u8>], size: usize) -> Dictionary {
+		let size = size.min(MAX_DICTIONARY_SIZE);
+
+		// Count how many samples each substring occurs in
+		let mut frequencies: HashMap<&[u8], u32> = HashMap::new();
+		for sample in samples {
+			let mut seen: Vec<&[u8]> = sample.windows(GRAM_SIZE).collect();
+			seen.sort_unstable();
+			seen.dedup();
+			for gram in seen {
+				*frequencies.entry(gram).or_default() += 1;
+			}
+		}
+
+		// Substrings only found in one sample are not worth keeping
+		let score = |segment: &[u8], frequencies: &HashMap<&[u8], u32>| -> u64 {
+			segment
+				.windows(GRAM_SIZE)
+				.map(|gram| match frequencies.get(gram) {
+					Some(&count) if count > 1 => count as u64,
+					_ => 0,
+				})
+				.sum()
+		};
+		let mut candidates: Vec<(u64, &[u8])> = samples
+			.iter()
+			.flat_map(|sample| sample.chunks(SEGMENT_SIZE))
+			.filter(|segment| segment.len() >= GRAM_SIZE)
+			.map(|segment| (score(segment, &frequencies), segment))
+			.filter(|(score, _)| *score > 0)
+			.collect();
+		candidates.sort_unstable_by_key(|a| a.0);
+
+		// Pick segments greedily; once a substring is in the dictionary, other segments gain nothing from it
+		let mut chosen: Vec<&[u8]> = Vec::new();
+		let mut chosen_size = 0;
+		while chosen_size < size {
+			let Some((stale_score, segment)) = candidates.pop() else {
+				break;
+			};
+			let current_score = score(segment, &frequencies);
+			if current_score == 0 {
+				continue;
+			}
+			if current_score < stale_score
+				&& candidates
+					.last()
+					.is_some_and(|(next_score, _)| current_score < *next_score)
+			{
+				let position = candidates.partition_point(|(score, _)| *score < current_score);
+				candidates.insert(position, (current_score, segment));
+				continue;
+			}
+			for gram in segment.windows(GRAM_SIZE) {
+				frequencies.remove(gram);
+			}
+			chosen_size += segment.len();
+			chosen.push(segment);
+		}
+
+		let mut bytes: Vec<u8> = chosen.into_iter().rev().flatten().copied().collect();
+		if bytes.len() > size {
+			bytes.drain(..bytes.len() - size);
+		}
+		Dictionary::new(bytes)
+	}
+}
+
+/// Compresses the body of an archive in independent blocks, using a dictionary
+pub struct DictionaryWriter<W: Write> {
+	/// Where compressed blocks are written to
+	inner: W,
+	/// The dictionary
+	dictionary: Dictionary,
+	/// Uncompressed data waiting to be compressed
+	buffer: Vec<u8>,
+}
+
+impl<W: Write> DictionaryWriter<W> {
+	/// Prepare to compress the body of an archive
+	///
+	/// # Arguments
+	///
+	/// * `inner` - Where the compressed body is written to
+	///
+	/// * `dictionary` - The dictionary to compress with
+	pub fn new(inner: W, dictionary: Dictionary) -> DictionaryWriter<W> {
+		DictionaryWriter {
+			inner,
+			dictionary,
+			buffer: Vec::with_capacity(BLOCK_SIZE),
+		}
+	}
+
+	/// Compress and write out the buffered data as one block
+	fn write_block(&mut self) -> Result<()> {
+		let compressed =
+			lz4_flex::block::compress_with_dict(&self.buffer, self.dictionary.as_bytes());
+		self.inner
+			.write_all(&(compressed.len() as u32).to_le_bytes())?;
+		self.inner
+			.write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+		self.inner.write_all(&compressed)?;
+		self.buffer.clear();
+		Ok(())
+	}
+
+	/// Write out any buffered data and the end marker, returning the underlying writer
+	pub fn finish(mut self) -> Result<W> {
+		if !self.buffer.is_empty() {
+			self.write_block()?;
+		}
+		self.inner.write_all(&[0u8; 8])?;
+		self.inner.flush()?;
+		Ok(self.inner)
+	}
+}
+
+impl<W: Write> Write for DictionaryWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		let length = buf.len().min(BLOCK_SIZE - self.buffer.len());
+		self.buffer.extend_from_slice(&buf[..length]);
+		if self.buffer.len() == BLOCK_SIZE {
+			self.write_block()?;
+		}
+		Ok(length)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Decompresses the body of an archive written with a dictionary
+pub struct DictionaryReader<R: Read> {
+	/// Where compressed blocks are read from
+	inner: R,
+	/// The dictionary
+	dictionary: Dictionary,
+	/// The current decompressed block
+	block: Vec<u8>,
+	/// How much of `block` has been returned
+	position: usize,
+	/// Whether the end marker has been read
+	finished: bool,
+}
+
+impl<R: Read> DictionaryReader<R> {
+	/// Prepare to decompress the body of an archive
+	///
+	/// # Arguments
+	///
+	/// * `inner` - The compressed body
+	///
+	/// * `dictionary` - The dictionary the archive was compressed with
+	pub fn new(inner: R, dictionary: Dictionary) -> DictionaryReader<R> {
+		DictionaryReader {
+			inner,
+			dictionary,
+			block: Vec::new(),
+			position: 0,
+			finished: false,
+		}
+	}
+
+	/// Read and decompress the next block
+	fn read_block(&mut self) -> Result<()> {
+		let mut lengths = [0u8; 8];
+		self.inner.read_exact(&mut lengths)?;
+		let compressed_length = u32::from_le_bytes(lengths[..4].try_into().unwrap()) as usize;
+		let length = u32::from_le_bytes(lengths[4..].try_into().unwrap()) as usize;
+		if compressed_length == 0 && length == 0 {
+			self.finished = true;
+			self.block.clear();
+		} else {
+			if length > BLOCK_SIZE
+				|| compressed_length > lz4_flex::block::get_maximum_output_size(BLOCK_SIZE)
+			{
+				return Err(Error::new(
+					ErrorKind::InvalidData,
+					"Archive contains an oversized block",
+				));
+			}
+			let mut compressed = vec![0u8; compressed_length];
+			self.inner.read_exact(&mut compressed)?;
+			self.block = lz4_flex::block::decompress_with_dict(
+				&compressed,
+				length,
+				self.dictionary.as_bytes(),
+			)
+			.map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+		}
+		self.position = 0;
+		Ok(())
+	}
+}
+
+impl<R: Read> Read for DictionaryReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		while self.position == self.block.len() {
+			if self.finished {
+				return Ok(0);
+			}
+			self.read_block()?;
+		}
+		let length = buf.len().min(self.block.len() - self.position);
+		buf[..length].copy_from_slice(&self.block[self.position..self.position + length]);
+		self.position += length;
+		Ok(length)
+	}
+}
@@ -0,0 +1,609 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Comparing archives and directories.
+//!
+//! Either side of a comparison may be an archive or a directory.
+//! A directory is compared as if it had been compressed by larz, so its contents are matched against the entries at the root of the archive.
+//! Symbolic links within a directory are read as they were archived: followed by default, as [`Symlinks::Follow`] does, or compared as links with [`Symlinks::Store`].
+//! Entries are matched by path, then compared by type, size, permissions, modification time, the target of symbolic links and the hash of their contents.
+//! The contents of files are only compared when their sizes match; the hashes recorded in archives are used where they are present.
+//! The modification times of directories are not compared, as they change whenever their contents do, and are not restored on extraction.
+
+use crate::header::Codec;
+use crate::reader::ArchiveReader;
+use crate::{append, ExtractOptions, Symlinks};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Result;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The type of an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+	/// A regular file
+	File,
+	/// A directory
+	Directory,
+	/// A symbolic link
+	Symlink,
+	/// Anything else, such as a hard link or a device file
+	Other,
+}
+
+impl EntryKind {
+	/// The name of the type, as it is written in reports
+	pub fn name(self) -> &'static str {
+		match self {
+			EntryKind::File => "file",
+			EntryKind::Directory => "directory",
+			EntryKind::Symlink => "symlink",
+			EntryKind::Other => "other",
+		}
+	}
+}
+
+/// A way in which an entry differs between the two sides of a comparison
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+	/// The entry changed type; nothing else about it is compared
+	Type {
+		/// The type on the old side
+		old: EntryKind,
+		/// The type on the new side
+		new: EntryKind,
+	},
+	/// The size of a file changed
+	Size {
+		/// The size on the old side, in bytes
+		old: u64,
+		/// The size on the new side, in bytes
+		new: u64,
+	},
+	/// The permissions of the entry changed
+	Mode {
+		/// The permissions on the old side
+		old: u32,
+		/// The permissions on the new side
+		new: u32,
+	},
+	/// The modification time of an entry other than a directory changed
+	Mtime {
+		/// The modification time on the old side, in seconds since the Unix epoch
+		old: u64,
+		/// The modification time on the new side, in seconds since the Unix epoch
+		new: u64,
+	},
+	/// The target of a symbolic link changed
+	LinkTarget {
+		/// The target on the old side
+		old: PathBuf,
+		/// The target on the new side
+		new: PathBuf,
+	},
+	/// The contents of a file changed, while its size stayed the same
+	Content,
+}
+
+/// An entry present on both sides of a comparison, which differs between them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Modified {
+	/// The path of the entry
+	pub path: PathBuf,
+	/// How the entry differs
+	pub differences: Vec<Difference>,
+}
+
+/// The differences between two archives or directories
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffReport {
+	/// Entries only on the new side
+	pub added: Vec<PathBuf>,
+	/// Entries only on the old side
+	pub removed: Vec<PathBuf>,
+	/// Entries on both sides which differ
+	pub modified: Vec<Modified>,
+}
+
+impl DiffReport {
+	/// Whether the two sides are the same
+	pub fn is_empty(&self) -> bool {
+		self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+	}
+
+	/// Write the report as a JSON object
+	///
+	/// The object has `added` and `removed` arrays of paths, and a `modified` array of objects with a `path` and an array of `differences`.
+	/// Each difference has a `field` (one of `type`, `size`, `mode`, `mtime`, `link_target` or `content`), and the `old` and `new` values of the field, except for `content`.
+	/// Paths & link targets are strings if they are valid Unicode, and arrays of their bytes otherwise; see [`crate::escape_path`] for how they are written on Windows.
+	pub fn to_json(&self) -> String {
+		let paths = |paths: &[PathBuf]| {
+			paths
+				.iter()
+				.map(|path| json_path(path))
+				.collect::<Vec<_>>()
+				.join(",")
+		};
+		let modified = self
+			.modified
+			.iter()
+			.map(|modified| {
+				let differences = modified
+					.differences
+					.iter()
+					.map(|difference| match difference {
+						Difference::Type { old, new } => format!(
+							r#"{{"field":"type","old":{},"new":{}}}"#,
+							json_string(old.name()),
+							json_string(new.name())
+						),
+						Difference::Size { old, new } => {
+							format!(r#"{{"field":"size","old":{old},"new":{new}}}"#)
+						}
+						Difference::Mode { old, new } => {
+							format!(r#"{{"field":"mode","old":{old},"new":{new}}}"#)
+						}
+						Difference::Mtime { old, new } => {
+							format!(r#"{{"field":"mtime","old":{old},"new":{new}}}"#)
+						}
+						Difference::LinkTarget { old, new } => format!(
+							r#"{{"field":"link_target","old":{},"new":{}}}"#,
+							json_path(old),
+							json_path(new)
+						),
+						Difference::Content => r#"{"field":"content"}"#.to_owned(),
+					})
+					.collect::<Vec<_>>()
+					.join(",");
+				format!(
+					r#"{{"path":{},"differences":[{differences}]}}"#,
+					json_path(&modified.path)
+				)
+			})
+			.collect::<Vec<_>>()
+			.join(",");
+		format!(
+			r#"{{"added":[{}],"removed":[{}],"modified":[{modified}]}}"#,
+			paths(&self.added),
+			paths(&self.removed)
+		)
+	}
+}
+
+impl fmt::Display for DiffReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for path in &self.added {
+			writeln!(f, "+ {}", crate::escape_path(path))?;
+		}
+		for path in &self.removed {
+			writeln!(f, "- {}", crate::escape_path(path))?;
+		}
+		for modified in &self.modified {
+			let differences = modified
+				.differences
+				.iter()
+				.map(|difference| match difference {
+					Difference::Type { old, new } => {
+						format!("type {} → {}", old.name(), new.name())
+					}
+					Difference::Size { old, new } => format!("size {old} → {new}"),
+					Difference::Mode { old, new } => format!("mode {old:o} → {new:o}"),
+					Difference::Mtime { old, new } => format!("mtime {old} → {new}"),
+					Difference::LinkTarget { old, new } => format!(
+						"link target '{}' → '{}'",
+						crate::escape_path(old),
+						crate::escape_path(new)
+					),
+					Difference::Content => "content".to_owned(),
+				})
+				.collect::<Vec<_>>()
+				.join(", ");
+			writeln!(f, "~ {}: {differences}", crate::escape_path(&modified.path))?;
+		}
+		write!(
+			f,
+			"{} added, {} removed, {} modified",
+			self.added.len(),
+			self.removed.len(),
+			self.modified.len()
+		)
+	}
+}
+
+/// Compare two archives or directories
+///
+/// # Arguments
+///
+/// * `old` - Path to the old archive or directory
+///
+/// * `new` - Path to the new archive or directory
+///
+/// * `legacy_codec` - The codec to assume if an archive has no header, as written by larz 0.3 and earlier
+///
+/// * `options` - Options controlling how the archives are read
+///
+/// * `symlinks` - How symbolic links within a directory are read, which should match how they were archived
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::diff::diff;
+/// use larz::header::Codec;
+/// use larz::{ExtractOptions, Symlinks};
+/// use std::path::PathBuf;
+///
+/// let report = diff(&PathBuf::from("path/to/old.larz"), &PathBuf::from("path/to/directory"), Codec::Lz4Frame, &ExtractOptions::default(), Symlinks::Follow);
+/// ```
+pub fn diff(
+	old: &Path,
+	new: &Path,
+	legacy_codec: Codec,
+	options: &ExtractOptions,
+	symlinks: Symlinks,
+) -> Result<DiffReport> {
+	let mut old = Side::read(old, legacy_codec, options, symlinks)?;
+	let mut new = Side::read(new, legacy_codec, options, symlinks)?;
+
+	let mut report = DiffReport::default();
+	for path in old.entries.keys() {
+		if !new.entries.contains_key(path) {
+			report.removed.push(path.clone());
+		}
+	}
+	for path in new.entries.keys() {
+		if !old.entries.contains_key(path) {
+			report.added.push(path.clone());
+		}
+	}
+	let common: Vec<PathBuf> = old
+		.entries
+		.keys()
+		.filter(|path| new.entries.contains_key(*path))
+		.cloned()
+		.collect();
+	for path in common {
+		let differences = compare(&path, &mut old, &mut new)?;
+		if !differences.is_empty() {
+			report.modified.push(Modified { path, differences });
+		}
+	}
+	Ok(report)
+}
+
+/// Compare an entry present on both sides
+///
+/// # Arguments
+///
+/// * `path` - The path of the entry
+///
+/// * `old` - The old side
+///
+/// * `new` - The new side
+fn compare(path: &Path, old: &mut Side, new: &mut Side) -> Result<Vec<Difference>> {
+	let (old_entry, new_entry) = (&old.entries[path], &new.entries[path]);
+	if old_entry.kind != new_entry.kind {
+		return Ok(vec![Difference::Type {
+			old: old_entry.kind,
+			new: new_entry.kind,
+		}]);
+	}
+	let mut differences = Vec::new();
+	if old_entry.kind == EntryKind::File && old_entry.size != new_entry.size {
+		differences.push(Difference::Size {
+			old: old_entry.size,
+			new: new_entry.size,
+		});
+	}
+	if old_entry.mode != new_entry.mode {
+		differences.push(Difference::Mode {
+			old: old_entry.mode,
+			new: new_entry.mode,
+		});
+	}
+	if old_entry.kind != EntryKind::Directory && old_entry.mtime != new_entry.mtime {
+		differences.push(Difference::Mtime {
+			old: old_entry.mtime,
+			new: new_entry.mtime,
+		});
+	}
+	if old_entry.link_target != new_entry.link_target {
+		differences.push(Difference::LinkTarget {
+			old: old_entry.link_target.clone().unwrap_or_default(),
+			new: new_entry.link_target.clone().unwrap_or_default(),
+		});
+	}
+	let same_size = old_entry.kind == EntryKind::File && old_entry.size == new_entry.size;
+	if same_size && old.hash(path)? != new.hash(path)? {
+		differences.push(Difference::Content);
+	}
+	Ok(differences)
+}
+
+/// What is compared about an entry
+struct EntryInfo {
+	/// The type of the entry
+	kind: EntryKind,
+	/// The size of the entry's contents in bytes
+	size: u64,
+	/// The permissions of the entry
+	mode: u32,
+	/// When the entry was last modified, in seconds since the Unix epoch
+	mtime: u64,
+	/// The target of a symbolic link
+	link_target: Option<PathBuf>,
+	/// The hash of a file's contents, if it is known
+	hash: Option<blake3::Hash>,
+	/// Where a file in a directory is on disk, so that it can be hashed if needed
+	fs_path: Option<PathBuf>,
+}
+
+/// One side of a comparison
+struct Side {
+	/// Every entry, by path
+	entries: BTreeMap<PathBuf, EntryInfo>,
+}
+
+impl Side {
+	/// Read the entries of an archive or directory
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the archive or directory
+	///
+	/// * `legacy_codec` - The codec to assume if an archive has no header
+	///
+	/// * `options` - Options controlling how an archive is read
+	///
+	/// * `symlinks` - How symbolic links within a directory are read
+	fn read(
+		path: &Path,
+		legacy_codec: Codec,
+		options: &ExtractOptions,
+		symlinks: Symlinks,
+	) -> Result<Side> {
+		match path.is_dir() {
+			true => Side::read_directory(path, symlinks),
+			false => Side::read_archive(path, legacy_codec, options),
+		}
+	}
+
+	/// Read the entries of an archive, hashing the files which have no recorded hash
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the archive, or to the first of its volumes
+	///
+	/// * `legacy_codec` - The codec to assume if the archive has no header
+	///
+	/// * `options` - Options controlling how the archive is read
+	fn read_archive(path: &Path, legacy_codec: Codec, options: &ExtractOptions) -> Result<Side> {
+		let mut reader = ArchiveReader::open(path, legacy_codec, options)?;
+		let mut entries: BTreeMap<PathBuf, EntryInfo> = BTreeMap::new();
+		for entry in reader.entries()? {
+			let mut entry = entry?;
+			let entry_type = entry.entry_type();
+			// A hard link has the contents of the file it links to, which is stored before it
+			let linked = match entry_type.is_hard_link() {
+				true => entry.link_name()?.and_then(|target| {
+					entries
+						.get(
+							&target
+								.components()
+								.filter(|component| *component != Component::CurDir)
+								.collect::<PathBuf>(),
+						)
+						.filter(|target| target.kind == EntryKind::File)
+						.map(|target| (target.size, target.hash))
+				}),
+				false => None,
+			};
+			let kind = if entry_type.is_file() || entry_type.is_gnu_sparse() || linked.is_some() {
+				EntryKind::File
+			} else if entry_type.is_dir() {
+				EntryKind::Directory
+			} else if entry_type.is_symlink() {
+				EntryKind::Symlink
+			} else {
+				EntryKind::Other
+			};
+			let hash = match (kind, entry.recorded_hash()) {
+				_ if linked.is_some() => linked.and_then(|(_, hash)| hash),
+				(EntryKind::File, None) => {
+					let mut hasher = blake3::Hasher::new();
+					std::io::copy(&mut entry, &mut hasher)?;
+					Some(hasher.finalize())
+				}
+				(_, recorded_hash) => recorded_hash,
+			};
+			entries.insert(
+				// Directories are stored with a trailing slash, which is not part of their path
+				entry.path().components().collect(),
+				EntryInfo {
+					kind,
+					size: match linked {
+						Some((size, _)) => size,
+						None if kind == EntryKind::File => entry.size(),
+						None => 0,
+					},
+					mode: entry.mode()? & 0o7777,
+					mtime: entry.mtime()?,
+					link_target: match kind {
+						EntryKind::Symlink => entry.link_name()?,
+						_ => None,
+					},
+					hash,
+					fs_path: None,
+				},
+			);
+		}
+		Ok(Side { entries })
+	}
+
+	/// Read the entries of a directory, as they would be stored by compressing it
+	///
+	/// # Arguments
+	///
+	/// * `root` - Path to the directory
+	///
+	/// * `symlinks` - How symbolic links within the directory are read
+	fn read_directory(root: &Path, symlinks: Symlinks) -> Result<Side> {
+		let mut entries = BTreeMap::new();
+		let mut ancestors = append::Ancestors::default();
+		let mut stack = vec![(root.to_path_buf(), PathBuf::from("."), None)];
+		while let Some((fs_path, name, parent)) = stack.pop() {
+			let metadata = match (name == Path::new("."), symlinks) {
+				(true, _) => std::fs::metadata(&fs_path)?,
+				(false, Symlinks::Store) => std::fs::symlink_metadata(&fs_path)?,
+				// Links that lead nowhere cannot be archived by following them, so they are compared as links
+				(false, Symlinks::Follow) => match std::fs::metadata(&fs_path) {
+					Ok(metadata) => metadata,
+					Err(_) => std::fs::symlink_metadata(&fs_path)?,
+				},
+			};
+			let kind = if metadata.is_dir() {
+				EntryKind::Directory
+			} else if metadata.is_file() {
+				EntryKind::File
+			} else if metadata.is_symlink() {
+				EntryKind::Symlink
+			} else {
+				EntryKind::Other
+			};
+			// A directory reached again through a symbolic link beneath it is archived without its contents
+			let index = match kind {
+				EntryKind::Directory => ancestors.enter(parent, &metadata),
+				_ => None,
+			};
+			if let Some(index) = index {
+				for child in std::fs::read_dir(&fs_path)? {
+					let child = child?.file_name();
+					let child_name = match name == Path::new(".") {
+						true => PathBuf::from(&child),
+						false => name.join(&child),
+					};
+					stack.push((fs_path.join(&child), child_name, Some(index)));
+				}
+			}
+			entries.insert(
+				name,
+				EntryInfo {
+					kind,
+					size: if kind == EntryKind::File {
+						metadata.len()
+					} else {
+						0
+					},
+					mode: mode(&metadata),
+					mtime: metadata
+						.modified()?
+						.duration_since(UNIX_EPOCH)
+						.map_or(0, |elapsed| elapsed.as_secs()),
+					link_target: match kind {
+						EntryKind::Symlink => Some(std::fs::read_link(&fs_path)?),
+						_ => None,
+					},
+					hash: None,
+					fs_path: Some(fs_path),
+				},
+			);
+		}
+		Ok(Side { entries })
+	}
+
+	/// Get the hash of a file's contents, hashing it now if it is in a directory
+	///
+	/// # Arguments
+	///
+	/// * `path` - The path of the file
+	fn hash(&mut self, path: &Path) -> Result<Option<blake3::Hash>> {
+		let entry = self.entries.get_mut(path).expect("Entry is on this side");
+		if entry.hash.is_none() {
+			if let Some(fs_path) = &entry.fs_path {
+				entry.hash = Some(crate::integrity::hash_file(fs_path)?);
+			}
+		}
+		Ok(entry.hash)
+	}
+}
+
+/// The permissions of a file on disk
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(unix)]
+fn mode(metadata: &std::fs::Metadata) -> u32 {
+	use std::os::unix::fs::PermissionsExt;
+	metadata.permissions().mode() & 0o7777
+}
+
+/// The permissions of a file on disk
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(not(unix))]
+fn mode(metadata: &std::fs::Metadata) -> u32 {
+	match metadata.permissions().readonly() {
+		true => 0o444,
+		false => 0o644,
+	}
+}
+
+/// Write a path for JSON, as a string if it is valid Unicode, or otherwise as an array of its bytes (or, on platforms where paths are not bytes, as an escaped string)
+///
+/// # Arguments
+///
+/// * `path` - The path to write
+fn json_path(path: &Path) -> String {
+	if let Some(path) = path.to_str() {
+		return json_string(path);
+	}
+	#[cfg(unix)]
+	{
+		let bytes: Vec<String> = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str())
+			.iter()
+			.map(u8::to_string)
+			.collect();
+		format!("[{}]", bytes.join(","))
+	}
+	#[cfg(not(unix))]
+	json_string(&crate::escape_path(path))
+}
+
+/// Quote a string for JSON
+///
+/// # Arguments
+///
+/// * `value` - The string to quote
+fn json_string(value: &str) -> String {
+	let mut quoted = String::with_capacity(value.len() + 2);
+	quoted.push('"');
+	for character in value.chars() {
+		match character {
+			'"' => quoted.push_str("\\\""),
+			'\\' => quoted.push_str("\\\\"),
+			'\n' => quoted.push_str("\\n"),
+			'\r' => quoted.push_str("\\r"),
+			'\t' => quoted.push_str("\\t"),
+			character if character < ' ' => {
+				quoted.push_str(&format!("\\u{:04x}", character as u32))
+			}
+			character => quoted.push(character),
+		}
+	}
+	quoted.push('"');
+	quoted
+}
@@ -0,0 +1,452 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::format;
+use crate::incremental;
+use crate::integrity::{self, HashingReader, IntegrityError};
+use crate::journal::ExtractJournal;
+use crate::ExtractOptions;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// Unpack an uncompressed archive into a directory, verifying the contents of each file as it is written
+///
+/// Returns the paths the archive records as deleted, if it is a listed-incremental backup.
+///
+/// # Arguments
+///
+/// * `reader` - The uncompressed TAR data
+///
+/// * `output_path` - Path to write the extracted files to
+///
+/// * `options` - Options controlling how the archive is read
+///
+/// * `journal` - The checkpoint journal of the output directory, if extraction can be resumed; entries it records as extracted are skipped, as is the whole archive if it was extracted in full
+pub(crate) fn unpack<R: Read>(
+	reader: R,
+	output_path: &Path,
+	options: &ExtractOptions,
+	mut journal: Option<&mut ExtractJournal>,
+) -> Result<Vec<PathBuf>> {
+	if journal
+		.as_deref_mut()
+		.is_some_and(|journal| !journal.begin_archive())
+	{
+		return Ok(Vec::new());
+	}
+	if output_path.symlink_metadata().is_err() {
+		std::fs::create_dir_all(output_path)?;
+	}
+	let output_path = &output_path
+		.canonicalize()
+		.unwrap_or(output_path.to_path_buf());
+
+	let (reader, window) = HashingReader::new(reader);
+	let mut archive = tar::Archive::new(reader);
+	let mut directories = Vec::new();
+	let mut mismatched = Vec::new();
+	let mut deleted = Vec::new();
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		if incremental::is_deletions(&mut entry)? {
+			deleted.extend(incremental::read_deletions(&mut entry)?);
+			continue;
+		}
+		// Files with holes in PAX archives are named by their PAX records rather than their headers
+		let sparse = format::pax_sparse(&mut entry)?;
+		let member = match &sparse {
+			Some(sparse) => sparse.name.clone(),
+			None => entry.path()?.into_owned(),
+		};
+		if let Some(journal) = journal.as_deref() {
+			if journal.extracted(&member) {
+				continue;
+			}
+		}
+		let entry_type = entry.header().entry_type();
+		// Directories are created last, so their permissions do not prevent their contents from being written
+		if entry_type == tar::EntryType::Directory {
+			directories.push(entry);
+			continue;
+		}
+
+		let mismatches = mismatched.len();
+		let expected = match options.skip_verification {
+			true => None,
+			false => integrity::recorded_hash(&mut entry)?,
+		};
+		let unpacked = match (expected, &sparse) {
+			(expected, Some(sparse)) => {
+				// Only the regions of sparse files holding data are stored, so they are hashed once they have been written out
+				let destination = unpack_pax_sparse(&mut entry, output_path, &member, sparse)?;
+				if let (Some(expected), Some(destination)) = (expected, &destination) {
+					if integrity::hash_file(destination)? != expected {
+						mismatched.push(member.clone());
+					}
+				}
+				destination.is_some()
+			}
+			(Some(expected), None) if entry_type.is_file() => {
+				window
+					.borrow_mut()
+					.begin(entry.raw_file_position(), entry.size());
+				let unpacked = entry.unpack_in(output_path)?;
+				let actual = window.borrow_mut().end();
+				if unpacked && actual != Some(expected) {
+					mismatched.push(member.clone());
+				}
+				unpacked
+			}
+			(Some(expected), None) if entry_type.is_gnu_sparse() => {
+				// Only the non-empty parts of sparse files are stored, so they are hashed once they have been written out
+				let unpacked = entry.unpack_in(output_path)?;
+				if unpacked {
+					if let Some(destination) = member_destination(output_path, &member) {
+						if integrity::hash_file(&destination)? != expected {
+							mismatched.push(member.clone());
+						}
+					}
+				}
+				unpacked
+			}
+			_ if entry_type.is_hard_link() => {
+				// Links are not made over existing files, such as those restored from an earlier backup, so they are removed first
+				if let Some(destination) = member_destination(output_path, &member) {
+					let inside = destination
+						.parent()
+						.and_then(|parent| parent.canonicalize().ok())
+						.is_some_and(|parent| parent.starts_with(output_path));
+					let existing = destination.symlink_metadata();
+					if inside && existing.is_ok_and(|existing| !existing.is_dir()) {
+						std::fs::remove_file(&destination)?;
+					}
+				}
+				entry.unpack_in(output_path)?
+			}
+			_ if matches!(
+				entry_type,
+				tar::EntryType::Fifo | tar::EntryType::Char | tar::EntryType::Block
+			) =>
+			{
+				// Special files are unpacked as empty files, which are replaced once their destination has been checked
+				let unpacked = entry.unpack_in(output_path)?;
+				if unpacked {
+					if let Some(destination) = member_destination(output_path, &member) {
+						make_special(&destination, entry.header())?;
+					}
+				}
+				unpacked
+			}
+			_ => entry.unpack_in(output_path)?,
+		};
+		if unpacked {
+			restore_recorded_mtime(output_path, &member, &mut entry)?;
+		}
+		// Files that do not match their hash are extracted again when resuming
+		if let Some(journal) = journal.as_deref_mut() {
+			if mismatched.len() == mismatches {
+				// Files are synced to disk before they are recorded, so the journal never lists a file that could still be lost
+				if unpacked
+					&& (entry_type.is_file()
+						|| entry_type.is_contiguous()
+						|| entry_type.is_gnu_sparse())
+				{
+					if let Some(destination) = member_destination(output_path, &member) {
+						std::fs::File::open(destination)?.sync_data()?;
+					}
+				}
+				journal.record(&member, entry.size())?;
+			}
+		}
+		if let Some(progress) = &options.progress {
+			progress.report(&member, entry.size());
+		}
+	}
+
+	directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+	for mut directory in directories {
+		if directory.unpack_in(output_path)? {
+			let member = directory.path()?.into_owned();
+			restore_recorded_mtime(output_path, &member, &mut directory)?;
+		}
+		if let Some(journal) = journal.as_deref_mut() {
+			journal.record(&directory.path()?, 0)?;
+		}
+		if let Some(progress) = &options.progress {
+			progress.report(&directory.path()?, 0);
+		}
+	}
+
+	match mismatched.is_empty() {
+		true => Ok(deleted),
+		false => Err(Error::new(
+			ErrorKind::InvalidData,
+			IntegrityError {
+				members: mismatched,
+			},
+		)),
+	}
+}
+
+/// Give an extracted member the modification time recorded for it, if it is recorded more precisely than its header can
+///
+/// # Arguments
+///
+/// * `output_path` - Path the archive is being extracted to
+///
+/// * `member` - The path of the member within the archive
+///
+/// * `entry` - The entry of the extracted member
+fn restore_recorded_mtime<R: Read>(
+	output_path: &Path,
+	member: &Path,
+	entry: &mut tar::Entry<'_, R>,
+) -> Result<()> {
+	let mtime = format::recorded_mtime(entry)?;
+	if let Some(destination) = member_destination(output_path, member) {
+		restore_mtime(&destination, entry.header().entry_type(), mtime)?;
+	}
+	Ok(())
+}
+
+/// Write out a file stored as a PAX sparse entry, leaving holes where it holds no data
+///
+/// Returns where the file was written, or `None` if it was not because its path leaves the output directory.
+///
+/// # Arguments
+///
+/// * `entry` - The entry of the file, with its map not yet read
+///
+/// * `output_path` - Path the archive is being extracted to
+///
+/// * `member` - The path of the file within the archive
+///
+/// * `sparse` - The path and size of the file, as its PAX records give them
+fn unpack_pax_sparse<R: Read>(
+	entry: &mut tar::Entry<'_, R>,
+	output_path: &Path,
+	member: &Path,
+	sparse: &format::PaxSparse,
+) -> Result<Option<PathBuf>> {
+	let Some((destination, mut file)) = create_sparse(output_path, member)? else {
+		return Ok(None);
+	};
+	let regions = format::read_sparse_map(entry, sparse.real_size)?;
+	for (offset, length) in regions {
+		file.seek(SeekFrom::Start(offset))?;
+		if std::io::copy(&mut entry.by_ref().take(length), &mut file)? != length {
+			return Err(sparse_truncated(member));
+		}
+	}
+	finish_sparse(&file, entry.header(), sparse.real_size)?;
+	Ok(Some(destination))
+}
+
+/// Create the file a PAX sparse entry is written out to, replacing whatever is in the way
+///
+/// Returns where the file is created, or `None` if it is not because its path leaves the output directory.
+///
+/// # Arguments
+///
+/// * `output_path` - Path the archive is being extracted to
+///
+/// * `member` - The path of the file within the archive
+pub(crate) fn create_sparse(
+	output_path: &Path,
+	member: &Path,
+) -> Result<Option<(PathBuf, std::fs::File)>> {
+	let Some(destination) = member_destination(output_path, member) else {
+		return Ok(None);
+	};
+	let Some(parent) = destination.parent().filter(|_| destination != *output_path) else {
+		return Ok(None);
+	};
+	std::fs::create_dir_all(parent)?;
+	if !parent.canonicalize()?.starts_with(output_path) {
+		return Err(Error::new(
+			ErrorKind::InvalidData,
+			format!(
+				"'{}' would be extracted outside of the output directory",
+				crate::escape_path(member)
+			),
+		));
+	}
+	// Files are not written through existing links, so whatever is in the way is removed first
+	if destination
+		.symlink_metadata()
+		.is_ok_and(|existing| !existing.is_dir())
+	{
+		std::fs::remove_file(&destination)?;
+	}
+	let file = std::fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.open(&destination)?;
+	Ok(Some((destination, file)))
+}
+
+/// Give a file written out from a PAX sparse entry its size, permissions and modification time
+///
+/// # Arguments
+///
+/// * `file` - The file, with the regions holding data written
+///
+/// * `header` - The header of the file's entry
+///
+/// * `real_size` - The size of the file, holes included
+pub(crate) fn finish_sparse(
+	file: &std::fs::File,
+	header: &tar::Header,
+	real_size: u64,
+) -> Result<()> {
+	file.set_len(real_size)?;
+	set_mode(file, header.mode()?)?;
+	if let Ok(mtime) = header.mtime() {
+		let mtime = filetime::FileTime::from_unix_time(mtime as i64, 0);
+		filetime::set_file_handle_times(file, Some(mtime), Some(mtime))?;
+	}
+	Ok(())
+}
+
+/// The error raised when a PAX sparse entry holds less data than its map records
+///
+/// # Arguments
+///
+/// * `member` - The path of the file within the archive
+pub(crate) fn sparse_truncated(member: &Path) -> Error {
+	Error::new(
+		ErrorKind::UnexpectedEof,
+		format!(
+			"'{}' is shorter than its map records",
+			crate::escape_path(member)
+		),
+	)
+}
+
+/// Give an extracted file the permissions recorded for it, leaving out those beyond the permission bits, as `tar::Entry::unpack_in` does
+///
+/// # Arguments
+///
+/// * `file` - The extracted file
+///
+/// * `mode` - The mode recorded in the file's header
+#[cfg(unix)]
+fn set_mode(file: &std::fs::File, mode: u32) -> Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+	file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))
+}
+
+/// Give an extracted file the permissions recorded for it
+///
+/// Only whether the file can be written is kept on this platform.
+///
+/// # Arguments
+///
+/// * `file` - The extracted file
+///
+/// * `mode` - The mode recorded in the file's header
+#[cfg(not(unix))]
+fn set_mode(file: &std::fs::File, mode: u32) -> Result<()> {
+	let mut permissions = file.metadata()?.permissions();
+	permissions.set_readonly(mode & 0o200 != 0o200);
+	file.set_permissions(permissions)
+}
+
+/// Give an extracted file a modification time recorded for it, unless it is a link
+///
+/// # Arguments
+///
+/// * `destination` - Path to the extracted file
+///
+/// * `entry_type` - The type of the file's entry
+///
+/// * `mtime` - The seconds since the Unix epoch and the nanoseconds past them, if they were recorded
+pub(crate) fn restore_mtime(
+	destination: &Path,
+	entry_type: tar::EntryType,
+	mtime: Option<(i64, u32)>,
+) -> Result<()> {
+	match mtime {
+		Some((seconds, nanoseconds)) if !entry_type.is_symlink() && !entry_type.is_hard_link() => {
+			filetime::set_file_mtime(
+				destination,
+				filetime::FileTime::from_unix_time(seconds, nanoseconds),
+			)
+		}
+		_ => Ok(()),
+	}
+}
+
+/// Replace a file with the FIFO or device node an archive records
+///
+/// # Arguments
+///
+/// * `destination` - Path to the file
+///
+/// * `header` - The header of the special file's entry
+#[cfg(unix)]
+pub(crate) fn make_special(destination: &Path, header: &tar::Header) -> Result<()> {
+	use std::os::unix::ffi::OsStrExt;
+	let kind = match header.entry_type() {
+		tar::EntryType::Fifo => libc::S_IFIFO,
+		tar::EntryType::Char => libc::S_IFCHR,
+		_ => libc::S_IFBLK,
+	};
+	let device = libc::makedev(
+		header.device_major()?.unwrap_or(0) as _,
+		header.device_minor()?.unwrap_or(0) as _,
+	);
+	let path = std::ffi::CString::new(destination.as_os_str().as_bytes())?;
+	std::fs::remove_file(destination)?;
+	let mode = kind | (header.mode()? & 0o7777) as libc::mode_t;
+	match unsafe { libc::mknod(path.as_ptr(), mode, device) } {
+		0 => Ok(()),
+		_ => Err(Error::last_os_error()),
+	}
+}
+
+/// Replace a file with the FIFO or device node an archive records
+///
+/// Special files only exist on Unix-like platforms, so they are left as empty files elsewhere.
+///
+/// # Arguments
+///
+/// * `destination` - Path to the file
+///
+/// * `header` - The header of the special file's entry
+#[cfg(not(unix))]
+pub(crate) fn make_special(_destination: &Path, _header: &tar::Header) -> Result<()> {
+	Ok(())
+}
+
+/// Get where a member of an archive is extracted to, mirroring the path sanitisation performed by `tar::Entry::unpack_in`
+///
+/// Returns `None` if the member would not be extracted because its path leaves the output directory.
+///
+/// # Arguments
+///
+/// * `output_path` - Path the archive is being extracted to
+///
+/// * `member` - The path of the member within the archive
+pub(crate) fn member_destination(output_path: &Path, member: &Path) -> Option<PathBuf> {
+	let mut destination = output_path.to_path_buf();
+	for component in member.components() {
+		match component {
+			Component::Prefix(..) | Component::RootDir | Component::CurDir => continue,
+			Component::ParentDir => return None,
+			Component::Normal(part) => destination.push(part),
+		}
+	}
+	Some(destination)
+}
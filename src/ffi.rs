@@ -0,0 +1,824 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! The C interface to `liblarz.so` and `liblarz.a`.
+//!
+//! C and C++ programs include `larz.h` (generated from this module with `cbindgen`, see `header.sh`) and link with `pkg-config --libs larz`.
+//!
+//! Archives are written and read through jobs: a job is created with `larz_compress_job_new` or `larz_extract_job_new`, configured with the `larz_*_job_set_*` functions, given its input paths, then run.
+//! Jobs are opaque handles owned by the caller, and must be released with the matching `larz_*_job_free` function.
+//!
+//! Every fallible function returns a [`Status`].
+//! When it is not [`Status::Ok`], a description of the error can be retrieved with [`larz_last_error_message`] on the same thread.
+//!
+//! ```c
+//! #include <larz.h>
+//! #include <stdio.h>
+//!
+//! int main(void) {
+//!     LarzCompressJob *job = larz_compress_job_new("backup.larz");
+//!     larz_compress_job_add_path(job, "documents");
+//!     if (larz_compress_job_run(job, NULL) != LARZ_STATUS_OK) {
+//!         fprintf(stderr, "%s\n", larz_last_error_message());
+//!     }
+//!     larz_compress_job_free(job);
+//! }
+//! ```
+
+use crate::dictionary::DictionaryError;
+use crate::incremental::IncrementalError;
+use crate::integrity::IntegrityError;
+use crate::volume::VolumeError;
+use crate::{
+	ArchiveStats, CompressOptions, ExtractOptions, Progress, SpecialFiles, Symlinks, TarFormat,
+};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::io::{Error, ErrorKind};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+thread_local! {
+	/// The description of the last error raised on this thread
+	static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// The outcome of a call through the C interface
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+	/// The call succeeded
+	Ok = 0,
+	/// A required pointer was null, or a string was not valid
+	InvalidArgument = 1,
+	/// A file or directory could not be found
+	NotFound = 2,
+	/// A file or directory could not be accessed
+	PermissionDenied = 3,
+	/// Reading or writing failed
+	Io = 4,
+	/// The archive is malformed, corrupted or truncated
+	InvalidData = 5,
+	/// Extracted files do not match the hashes recorded in the archive
+	Integrity = 6,
+	/// The archive could not be decrypted; the passphrase is wrong, or the archive is corrupted
+	Decryption = 7,
+	/// The archive's signature is missing, untrusted or invalid
+	Signature = 8,
+	/// The dictionary needed to decompress the archive is missing or does not match
+	Dictionary = 9,
+	/// The archives of a listed-incremental backup are not given in order
+	Incremental = 10,
+	/// A volume of a multi-volume archive is missing or truncated
+	Volume = 11,
+	/// The operation needs a feature this build of the library does not include
+	Unsupported = 12,
+	/// The library panicked
+	Panic = 13,
+}
+
+/// Called as each member of an archive is written or extracted, with the member's path within the archive, its size in bytes, and the `user_data` given with the callback
+///
+/// The callback is called on the thread running the job, and the path is only valid until it returns.
+/// A null callback reports no progress.
+pub type ProgressCallback =
+	Option<extern "C" fn(member: *const c_char, size: u64, user_data: *mut c_void)>;
+
+/// A job writing paths into an archive
+pub struct CompressJob {
+	/// The files and directories to archive
+	paths: Vec<PathBuf>,
+	/// Where the archive is written to
+	output_path: PathBuf,
+	/// Perform the job solely in memory
+	in_memory: bool,
+	/// Options controlling how the archive is written
+	options: CompressOptions,
+}
+
+/// A job extracting archives into a directory
+pub struct ExtractJob {
+	/// The archives to extract
+	paths: Vec<PathBuf>,
+	/// Where the archives are extracted to
+	output_path: PathBuf,
+	/// Perform the job solely in memory
+	in_memory: bool,
+	/// Options controlling how the archives are read
+	options: ExtractOptions,
+}
+
+/// A pointer passed back to a C callback
+///
+/// The caller is responsible for making `user_data` safe to use from the thread running the job.
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+/// Get the description of the last error raised on the calling thread
+///
+/// Returns a null pointer if no error has been raised.
+/// The string is owned by the library, and is valid until the next call on the same thread that fails.
+#[no_mangle]
+pub extern "C" fn larz_last_error_message() -> *const c_char {
+	LAST_ERROR.with(|last_error| {
+		last_error
+			.borrow()
+			.as_ref()
+			.map_or(std::ptr::null(), |message| message.as_ptr())
+	})
+}
+
+/// Get the version of the library, as a string such as `"0.3.2"`
+#[no_mangle]
+pub extern "C" fn larz_version() -> *const c_char {
+	concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// Create a job writing an archive
+///
+/// Returns a null pointer if `output_path` is null or is not a valid path.
+///
+/// # Safety
+///
+/// `output_path` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_new(output_path: *const c_char) -> *mut CompressJob {
+	match path_from_c(output_path) {
+		Ok(output_path) => Box::into_raw(Box::new(CompressJob {
+			paths: Vec::new(),
+			output_path,
+			in_memory: false,
+			options: CompressOptions::default(),
+		})),
+		Err(e) => {
+			set_last_error(&e);
+			std::ptr::null_mut()
+		}
+	}
+}
+
+/// Add a file or directory to be archived
+///
+/// The contents of directories are stored at the root of the archive, and files are stored under their file name.
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`], and `path` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_add_path(
+	job: *mut CompressJob,
+	path: *const c_char,
+) -> Status {
+	with_job(job, |job| {
+		job.paths.push(path_from_c(path)?);
+		Ok(())
+	})
+}
+
+/// Perform the job solely in memory, rather than streaming the archive
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_in_memory(
+	job: *mut CompressJob,
+	in_memory: bool,
+) -> Status {
+	with_job(job, |job| {
+		job.in_memory = in_memory;
+		Ok(())
+	})
+}
+
+/// Store repeated regions of the archived files only once
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_deduplicate(
+	job: *mut CompressJob,
+	deduplicate: bool,
+) -> Status {
+	with_job(job, |job| {
+		job.options.deduplicate = deduplicate;
+		Ok(())
+	})
+}
+
+/// Store files with identical contents only once, as hard links to the first of them
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_link_duplicates(
+	job: *mut CompressJob,
+	link_duplicates: bool,
+) -> Status {
+	with_job(job, |job| {
+		job.options.link_duplicates = link_duplicates;
+		Ok(())
+	})
+}
+
+/// Choose whether symbolic links are followed or stored as links
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_symlinks(
+	job: *mut CompressJob,
+	symlinks: Symlinks,
+) -> Status {
+	with_job(job, |job| {
+		job.options.walk.symlinks = symlinks;
+		Ok(())
+	})
+}
+
+/// Do not descend into directories on a different filesystem from the directories being archived
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_one_file_system(
+	job: *mut CompressJob,
+	one_file_system: bool,
+) -> Status {
+	with_job(job, |job| {
+		job.options.walk.one_file_system = one_file_system;
+		Ok(())
+	})
+}
+
+/// Do not descend more than `max_depth` levels beneath the directories being archived, or descend without limit if `max_depth` is negative
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_max_depth(
+	job: *mut CompressJob,
+	max_depth: i64,
+) -> Status {
+	with_job(job, |job| {
+		job.options.walk.max_depth = usize::try_from(max_depth).ok();
+		Ok(())
+	})
+}
+
+/// Choose whether FIFOs, sockets & device nodes are stored, skipped or refused
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_special_files(
+	job: *mut CompressJob,
+	special_files: SpecialFiles,
+) -> Status {
+	with_job(job, |job| {
+		job.options.walk.special_files = special_files;
+		Ok(())
+	})
+}
+
+/// Choose the TAR format the archive is written in
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_format(
+	job: *mut CompressJob,
+	format: TarFormat,
+) -> Status {
+	with_job(job, |job| {
+		job.options.format = format;
+		Ok(())
+	})
+}
+
+/// Split the archive into volumes of at most `volume_size` bytes, or write a single file if `volume_size` is zero
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_volume_size(
+	job: *mut CompressJob,
+	volume_size: u64,
+) -> Status {
+	with_job(job, |job| {
+		job.options.volume_size = (volume_size != 0).then_some(volume_size);
+		Ok(())
+	})
+}
+
+/// Choose whether the job fails rather than replacing an archive that already exists
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_no_clobber(
+	job: *mut CompressJob,
+	no_clobber: bool,
+) -> Status {
+	with_job(job, |job| {
+		job.options.no_clobber = no_clobber;
+		Ok(())
+	})
+}
+
+/// Choose whether the job keeps a checkpoint journal, continuing from the last checkpoint if an earlier run was interrupted
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_resume(
+	job: *mut CompressJob,
+	resume: bool,
+) -> Status {
+	with_job(job, |job| {
+		job.options.resume = resume;
+		Ok(())
+	})
+}
+
+/// Encrypt the archive with a key derived from a passphrase, or leave it unencrypted if `passphrase` is null
+///
+/// Returns [`Status::Unsupported`] if the library was built without encryption.
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`], and `passphrase` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_passphrase(
+	job: *mut CompressJob,
+	passphrase: *const c_char,
+) -> Status {
+	with_job(job, |_job| {
+		#[cfg(feature = "encryption")]
+		{
+			_job.options.passphrase = passphrase_from_c(passphrase);
+			Ok(())
+		}
+		#[cfg(not(feature = "encryption"))]
+		{
+			let _ = passphrase;
+			Err(unsupported("encryption"))
+		}
+	})
+}
+
+/// Call `callback` as each member is written to the archive, or stop reporting progress if `callback` is null
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`], and `user_data` must remain valid until the job is freed.
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_set_progress(
+	job: *mut CompressJob,
+	callback: ProgressCallback,
+	user_data: *mut c_void,
+) -> Status {
+	with_job(job, |job| {
+		job.options.progress = callback.map(|callback| progress_from_c(callback, user_data));
+		Ok(())
+	})
+}
+
+/// Write the archive
+///
+/// If `stats` is not null, the sizes of the archive are written to it.
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`], and `stats` must be null or point to writable memory for an [`ArchiveStats`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_run(
+	job: *mut CompressJob,
+	stats: *mut ArchiveStats,
+) -> Status {
+	with_job(job, |job| {
+		let paths = job.paths.clone();
+		let output_path = job.output_path.clone();
+		let archive_stats = match job.in_memory {
+			true => crate::compress_archive_memory_with_options::<std::io::Sink>(
+				paths,
+				output_path,
+				&job.options,
+				None,
+			)?,
+			#[cfg(feature = "streaming")]
+			false => crate::compress_archive_streaming_with_options::<std::io::Sink>(
+				paths,
+				output_path,
+				&job.options,
+				None,
+			)?,
+			#[cfg(not(feature = "streaming"))]
+			false => return Err(unsupported("streaming")),
+		};
+		if !stats.is_null() {
+			*stats = archive_stats;
+		}
+		Ok(())
+	})
+}
+
+/// Free a job writing an archive
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_compress_job_new`] that has not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn larz_compress_job_free(job: *mut CompressJob) {
+	if !job.is_null() {
+		drop(Box::from_raw(job));
+	}
+}
+
+/// Create a job extracting archives
+///
+/// Returns a null pointer if `output_path` is null or is not a valid path.
+///
+/// # Safety
+///
+/// `output_path` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_new(output_path: *const c_char) -> *mut ExtractJob {
+	match path_from_c(output_path) {
+		Ok(output_path) => Box::into_raw(Box::new(ExtractJob {
+			paths: Vec::new(),
+			output_path,
+			in_memory: false,
+			options: ExtractOptions::default(),
+		})),
+		Err(e) => {
+			set_last_error(&e);
+			std::ptr::null_mut()
+		}
+	}
+}
+
+/// Add an archive to be extracted
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`], and `path` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_add_path(
+	job: *mut ExtractJob,
+	path: *const c_char,
+) -> Status {
+	with_job(job, |job| {
+		job.paths.push(path_from_c(path)?);
+		Ok(())
+	})
+}
+
+/// Perform the job solely in memory; archives written by larz 0.3 and earlier are treated as in-memory archives
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_set_in_memory(
+	job: *mut ExtractJob,
+	in_memory: bool,
+) -> Status {
+	with_job(job, |job| {
+		job.in_memory = in_memory;
+		Ok(())
+	})
+}
+
+/// Do not check extracted files against the hashes recorded in the archive
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_set_skip_verification(
+	job: *mut ExtractJob,
+	skip_verification: bool,
+) -> Status {
+	with_job(job, |job| {
+		job.options.skip_verification = skip_verification;
+		Ok(())
+	})
+}
+
+/// Extract into a staging directory beside the output directory, which replaces it only once every archive has been extracted & verified
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_set_atomic(job: *mut ExtractJob, atomic: bool) -> Status {
+	with_job(job, |job| {
+		job.options.atomic = atomic;
+		Ok(())
+	})
+}
+
+/// Choose whether the job keeps a checkpoint journal, skipping whatever an earlier run that was interrupted already extracted
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_set_resume(job: *mut ExtractJob, resume: bool) -> Status {
+	with_job(job, |job| {
+		job.options.resume = resume;
+		Ok(())
+	})
+}
+
+/// Decrypt encrypted archives with a passphrase, or forget the passphrase if `passphrase` is null
+///
+/// Returns [`Status::Unsupported`] if the library was built without encryption.
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`], and `passphrase` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_set_passphrase(
+	job: *mut ExtractJob,
+	passphrase: *const c_char,
+) -> Status {
+	with_job(job, |_job| {
+		#[cfg(feature = "encryption")]
+		{
+			_job.options.passphrase = passphrase_from_c(passphrase);
+			Ok(())
+		}
+		#[cfg(not(feature = "encryption"))]
+		{
+			let _ = passphrase;
+			Err(unsupported("encryption"))
+		}
+	})
+}
+
+/// Refuse to extract archives unless they are signed with the public key in the file at `key_path`, or accept unsigned archives if `key_path` is null
+///
+/// Returns [`Status::Unsupported`] if the library was built without signing.
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`], and `key_path` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_set_verifying_key(
+	job: *mut ExtractJob,
+	key_path: *const c_char,
+) -> Status {
+	with_job(job, |_job| {
+		#[cfg(feature = "signing")]
+		{
+			_job.options.verifying_key = match key_path.is_null() {
+				true => None,
+				false => Some(crate::signing::read_verifying_key(&path_from_c(key_path)?)?),
+			};
+			Ok(())
+		}
+		#[cfg(not(feature = "signing"))]
+		{
+			let _ = key_path;
+			Err(unsupported("signing"))
+		}
+	})
+}
+
+/// Call `callback` as each member is extracted, or stop reporting progress if `callback` is null
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`], and `user_data` must remain valid until the job is freed.
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_set_progress(
+	job: *mut ExtractJob,
+	callback: ProgressCallback,
+	user_data: *mut c_void,
+) -> Status {
+	with_job(job, |job| {
+		job.options.progress = callback.map(|callback| progress_from_c(callback, user_data));
+		Ok(())
+	})
+}
+
+/// Extract the archives
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`].
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_run(job: *mut ExtractJob) -> Status {
+	with_job(job, |job| {
+		let paths = job.paths.clone();
+		let output_path = job.output_path.clone();
+		match job.in_memory {
+			true => crate::extract_archive_memory_with_options(paths, output_path, &job.options),
+			#[cfg(feature = "streaming")]
+			false => crate::extract_archive_streaming_with_options(paths, output_path, &job.options),
+			#[cfg(not(feature = "streaming"))]
+			false => Err(unsupported("streaming")),
+		}
+	})
+}
+
+/// Free a job extracting archives
+///
+/// # Safety
+///
+/// `job` must be null or a job returned by [`larz_extract_job_new`] that has not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn larz_extract_job_free(job: *mut ExtractJob) {
+	if !job.is_null() {
+		drop(Box::from_raw(job));
+	}
+}
+
+/// Run a function on a job, recording any error it returns or any panic it raises
+///
+/// # Arguments
+///
+/// * `job` - A job passed in from C
+///
+/// * `f` - The function to run on the job
+///
+/// # Safety
+///
+/// `job` must be null or point to a live job.
+unsafe fn with_job<J>(job: *mut J, f: impl FnOnce(&mut J) -> std::io::Result<()>) -> Status {
+	let Some(job) = job.as_mut() else {
+		set_last_error(&Error::new(ErrorKind::InvalidInput, "The job is null"));
+		return Status::InvalidArgument;
+	};
+	match catch_unwind(AssertUnwindSafe(|| f(job))) {
+		Ok(Ok(())) => Status::Ok,
+		Ok(Err(e)) => {
+			set_last_error(&e);
+			status_of(&e)
+		}
+		Err(panic) => {
+			let message = panic
+				.downcast_ref::<&str>()
+				.map(|message| message.to_string())
+				.or_else(|| panic.downcast_ref::<String>().cloned())
+				.unwrap_or_else(|| "The library panicked".to_string());
+			set_last_error(&Error::other(message));
+			Status::Panic
+		}
+	}
+}
+
+/// Record an error as the last error raised on this thread
+///
+/// # Arguments
+///
+/// * `e` - The error raised
+fn set_last_error(e: &Error) {
+	let message = CString::new(e.to_string().replace('\0', "")).unwrap_or_default();
+	LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+/// Get the status describing an error
+///
+/// # Arguments
+///
+/// * `e` - The error raised
+fn status_of(e: &Error) -> Status {
+	if let Some(inner) = e.get_ref() {
+		if inner.is::<IntegrityError>() {
+			return Status::Integrity;
+		}
+		#[cfg(feature = "encryption")]
+		if inner.is::<crate::crypto::DecryptionError>() {
+			return Status::Decryption;
+		}
+		#[cfg(feature = "signing")]
+		if inner.is::<crate::signing::SignatureError>() {
+			return Status::Signature;
+		}
+		if inner.is::<DictionaryError>() {
+			return Status::Dictionary;
+		}
+		if inner.is::<IncrementalError>() {
+			return Status::Incremental;
+		}
+		if inner.is::<VolumeError>() {
+			return Status::Volume;
+		}
+	}
+	match e.kind() {
+		ErrorKind::InvalidInput => Status::InvalidArgument,
+		ErrorKind::NotFound => Status::NotFound,
+		ErrorKind::PermissionDenied => Status::PermissionDenied,
+		ErrorKind::InvalidData | ErrorKind::UnexpectedEof => Status::InvalidData,
+		ErrorKind::Unsupported => Status::Unsupported,
+		_ => Status::Io,
+	}
+}
+
+/// The error raised when a function needs a feature the library was built without
+///
+/// # Arguments
+///
+/// * `feature` - The name of the missing feature
+#[cfg(not(all(feature = "encryption", feature = "signing", feature = "streaming")))]
+fn unsupported(feature: &str) -> Error {
+	Error::new(
+		ErrorKind::Unsupported,
+		format!("This build of larz does not include the `{feature}` feature"),
+	)
+}
+
+/// Convert a C string to a path
+///
+/// # Arguments
+///
+/// * `path` - A NUL-terminated string, or a null pointer
+///
+/// # Safety
+///
+/// `path` must be null or point to a NUL-terminated string.
+unsafe fn path_from_c(path: *const c_char) -> std::io::Result<PathBuf> {
+	if path.is_null() {
+		return Err(Error::new(ErrorKind::InvalidInput, "The path is null"));
+	}
+	let bytes = CStr::from_ptr(path).to_bytes();
+	#[cfg(unix)]
+	{
+		use std::os::unix::ffi::OsStrExt;
+		Ok(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+	}
+	#[cfg(not(unix))]
+	{
+		std::str::from_utf8(bytes)
+			.map(PathBuf::from)
+			.map_err(|_| Error::new(ErrorKind::InvalidInput, "The path is not valid UTF-8"))
+	}
+}
+
+/// Convert a C string to a passphrase
+///
+/// # Arguments
+///
+/// * `passphrase` - A NUL-terminated string, or a null pointer
+///
+/// # Safety
+///
+/// `passphrase` must be null or point to a NUL-terminated string.
+#[cfg(feature = "encryption")]
+unsafe fn passphrase_from_c(passphrase: *const c_char) -> Option<crate::crypto::Passphrase> {
+	(!passphrase.is_null())
+		.then(|| crate::crypto::Passphrase::new(CStr::from_ptr(passphrase).to_bytes()))
+}
+
+/// Report progress to a C callback
+///
+/// # Arguments
+///
+/// * `callback` - The function to call as each member is written or extracted
+///
+/// * `user_data` - Passed back to the callback unchanged
+fn progress_from_c(
+	callback: extern "C" fn(*const c_char, u64, *mut c_void),
+	user_data: *mut c_void,
+) -> Progress {
+	let user_data = UserData(user_data);
+	Progress::new(move |member: &Path, size| {
+		// Capture the whole wrapper, rather than just the pointer inside it, so the closure can be sent between threads
+		let user_data = user_data;
+		#[cfg(unix)]
+		let member = {
+			use std::os::unix::ffi::OsStrExt;
+			CString::new(member.as_os_str().as_bytes()).unwrap_or_default()
+		};
+		#[cfg(not(unix))]
+		let member = CString::new(member.to_string_lossy().into_owned()).unwrap_or_default();
+		callback(member.as_ptr(), size, user_data.0);
+	})
+}
@@ -0,0 +1,755 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Writing entries with the headers of the chosen TAR format.
+//!
+//! GNU headers are written by [`tar::Builder`], which stores long paths & link targets in GNU extension entries.
+//! Ustar and PAX headers are filled in here instead, so that nothing outside those formats is written; PAX records are written for whatever the ustar header cannot hold.
+//! Files with holes are written to PAX archives in the 1.0 sparse format that GNU tar uses: the entry holds a map of the regions holding data followed by their contents, and is named by PAX records.
+
+use crate::integrity::HASH_PAX_KEY;
+use crate::TarFormat;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The largest size a ustar header can hold, in bytes
+const MAX_USTAR_SIZE: u64 = 0o77777777777;
+
+/// The PAX record holding the path of an entry
+const PATH_PAX_KEY: &str = "path";
+
+/// The PAX record holding the target of a link
+const LINKPATH_PAX_KEY: &str = "linkpath";
+
+/// The PAX record holding the size of an entry
+const SIZE_PAX_KEY: &str = "size";
+
+/// The PAX record holding the modification time of an entry
+pub(crate) const MTIME_PAX_KEY: &str = "mtime";
+
+/// The PAX record holding the major version of the sparse format an entry is written in
+const SPARSE_MAJOR_PAX_KEY: &str = "GNU.sparse.major";
+
+/// The PAX record holding the minor version of the sparse format an entry is written in
+const SPARSE_MINOR_PAX_KEY: &str = "GNU.sparse.minor";
+
+/// The PAX record holding the path of a sparse entry, which is named by a placeholder in its header
+const SPARSE_NAME_PAX_KEY: &str = "GNU.sparse.name";
+
+/// The PAX record holding the size of a sparse file, holes included
+const SPARSE_REALSIZE_PAX_KEY: &str = "GNU.sparse.realsize";
+
+/// The size of a block of a TAR archive, to which the map of a sparse entry is padded
+const BLOCK_SIZE: usize = 512;
+
+/// The most digits a number in the map of a sparse entry may have
+const MAX_MAP_DIGITS: usize = 20;
+
+/// An empty header for an entry
+///
+/// # Arguments
+///
+/// * `format` - The format the archive is written in
+pub(crate) fn new_header(format: TarFormat) -> tar::Header {
+	match format {
+		TarFormat::Gnu => tar::Header::new_gnu(),
+		TarFormat::Ustar | TarFormat::Pax => tar::Header::new_ustar(),
+	}
+}
+
+/// Append an entry to an archive, writing whichever extended headers its format needs
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `format` - The format the archive is written in
+///
+/// * `header` - The header of the entry, with its type, size & metadata already set
+///
+/// * `name` - The path of the entry within the archive
+///
+/// * `link` - The target of the entry, if it is a link
+///
+/// * `modified` - When the entry was last modified, if it is known more precisely than the header records
+///
+/// * `hash` - The hash of the entry's contents, if it is a regular file
+///
+/// * `data` - The contents of the entry
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn append<T: Write>(
+	tar: &mut tar::Builder<T>,
+	format: TarFormat,
+	header: &mut tar::Header,
+	name: &Path,
+	link: Option<&Path>,
+	modified: Option<SystemTime>,
+	hash: Option<blake3::Hash>,
+	data: impl Read,
+) -> Result<()> {
+	let records = extensions(format, header, name, link, modified, hash)?;
+	if !records.is_empty() {
+		tar.append_pax_extensions(records.iter().map(|(key, value)| (*key, value.as_slice())))?;
+	}
+	match (format, link) {
+		(TarFormat::Gnu, Some(link)) => tar.append_link(header, name, link),
+		(TarFormat::Gnu, None) => tar.append_data(header, name, data),
+		(TarFormat::Ustar | TarFormat::Pax, _) => {
+			header.set_cksum();
+			tar.append(header, data)
+		}
+	}
+}
+
+/// Fill in the path & link target of a ustar or PAX header, returning the PAX records the entry needs
+///
+/// GNU headers have only the hash of their contents recorded, as their paths & link targets are set as they are written.
+///
+/// # Arguments
+///
+/// * `format` - The format the archive is written in
+///
+/// * `header` - The header of the entry, with its type, size & metadata already set
+///
+/// * `name` - The path of the entry within the archive
+///
+/// * `link` - The target of the entry, if it is a link
+///
+/// * `modified` - When the entry was last modified, if it is known more precisely than the header records
+///
+/// * `hash` - The hash of the entry's contents, if it is a regular file
+pub(crate) fn extensions(
+	format: TarFormat,
+	header: &mut tar::Header,
+	name: &Path,
+	link: Option<&Path>,
+	modified: Option<SystemTime>,
+	hash: Option<blake3::Hash>,
+) -> Result<Vec<(&'static str, Vec<u8>)>> {
+	let mut records = records(format, modified, hash);
+	if format == TarFormat::Gnu {
+		return Ok(records);
+	}
+	let pax = format == TarFormat::Pax;
+
+	let path = member_bytes(name)?;
+	if let Err(e) = header.set_path(name) {
+		if !pax || path.len() <= header.as_old().name.len() {
+			return Err(unfit(e, name, "path"));
+		}
+		if let Some(ustar) = header.as_ustar_mut() {
+			ustar.prefix.fill(0);
+		}
+		truncate_into(&mut header.as_old_mut().name, &path);
+		records.push((PATH_PAX_KEY, path));
+	}
+	if let Some(link) = link {
+		if let Err(e) = header.set_link_name(link) {
+			let target = path_bytes(link)?;
+			if !pax || target.len() <= header.as_old().linkname.len() {
+				return Err(unfit(e, name, "link target"));
+			}
+			truncate_into(&mut header.as_old_mut().linkname, &target);
+			records.push((LINKPATH_PAX_KEY, target));
+		}
+	}
+	let size = header.entry_size()?;
+	if size > MAX_USTAR_SIZE {
+		if !pax {
+			return Err(Error::new(
+				ErrorKind::InvalidInput,
+				format!(
+					"'{}' is too large for a ustar archive",
+					crate::escape_path(name)
+				),
+			));
+		}
+		records.push((SIZE_PAX_KEY, size.to_string().into_bytes()));
+	}
+	Ok(records)
+}
+
+/// The PAX records of an entry that do not depend on its header: the hash of its contents, and its modification time if it is more precise than a header can hold
+///
+/// Ustar archives have no PAX records, so nothing is returned for them, and only PAX archives record modification times to less than a second.
+///
+/// # Arguments
+///
+/// * `format` - The format the archive is written in
+///
+/// * `modified` - When the entry was last modified, if it is known more precisely than the header records
+///
+/// * `hash` - The hash of the entry's contents, if it is a regular file
+pub(crate) fn records(
+	format: TarFormat,
+	modified: Option<SystemTime>,
+	hash: Option<blake3::Hash>,
+) -> Vec<(&'static str, Vec<u8>)> {
+	let mut records = Vec::new();
+	if format == TarFormat::Ustar {
+		return records;
+	}
+	if let Some(hash) = hash {
+		records.push((HASH_PAX_KEY, hash.to_hex().as_bytes().to_vec()));
+	}
+	if let Some(elapsed) = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()) {
+		if format == TarFormat::Pax && elapsed.subsec_nanos() != 0 {
+			let fraction = format!("{:09}", elapsed.subsec_nanos());
+			records.push((
+				MTIME_PAX_KEY,
+				format!("{}.{}", elapsed.as_secs(), fraction.trim_end_matches('0')).into_bytes(),
+			));
+		}
+	}
+	records
+}
+
+/// Get the modification time recorded for an archive entry, if it is recorded more precisely than its header can
+///
+/// Returns the seconds since the Unix epoch and the nanoseconds past them.
+///
+/// # Arguments
+///
+/// * `entry` - An entry being read from an archive
+pub(crate) fn recorded_mtime<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Option<(i64, u32)>> {
+	let Some(extensions) = entry.pax_extensions()? else {
+		return Ok(None);
+	};
+	for extension in extensions {
+		let extension = extension?;
+		if extension.key_bytes() == MTIME_PAX_KEY.as_bytes() {
+			return Ok(extension.value().ok().and_then(parse_mtime));
+		}
+	}
+	Ok(None)
+}
+
+/// Parse a PAX modification time, such as `1700000000.25`
+///
+/// # Arguments
+///
+/// * `value` - The value of the `mtime` record
+pub(crate) fn parse_mtime(value: &str) -> Option<(i64, u32)> {
+	let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+	let seconds = seconds.parse().ok()?;
+	let digits = fraction.get(..fraction.len().min(9))?;
+	if !digits.bytes().all(|digit| digit.is_ascii_digit()) {
+		return None;
+	}
+	let nanoseconds = format!("{digits:0<9}").parse().ok()?;
+	Some((seconds, nanoseconds))
+}
+
+/// Append a file with holes to a PAX archive as a sparse entry, storing only the regions holding data
+///
+/// The entry is named by a placeholder in its header, so that readers that do not understand sparse entries extract its map & data to a file of their own rather than over the file.
+///
+/// # Arguments
+///
+/// * `tar` - The archive being written
+///
+/// * `header` - The header of the file, with its metadata already set
+///
+/// * `name` - The path of the file within the archive
+///
+/// * `real_size` - The size of the file, holes included
+///
+/// * `modified` - When the file was last modified, if it is known more precisely than the header records
+///
+/// * `hash` - The hash of the file's contents, holes included
+///
+/// * `regions` - The offset and length of each region of the file holding data
+///
+/// * `data` - The contents of those regions, one after another
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn append_pax_sparse<T: Write>(
+	tar: &mut tar::Builder<T>,
+	header: &mut tar::Header,
+	name: &Path,
+	real_size: u64,
+	modified: Option<SystemTime>,
+	hash: blake3::Hash,
+	regions: &[(u64, u64)],
+	data: impl Read,
+) -> Result<()> {
+	let (records, map) = pax_sparse_extensions(header, name, real_size, modified, hash, regions)?;
+	tar.append_pax_extensions(records.iter().map(|(key, value)| (*key, value.as_slice())))?;
+	tar.append(header, map.as_slice().chain(data))
+}
+
+/// Fill in the header of a PAX sparse entry, returning the PAX records it needs and the map to write before the data of its regions
+///
+/// # Arguments
+///
+/// * `header` - The header of the file, with its metadata already set
+///
+/// * `name` - The path of the file within the archive
+///
+/// * `real_size` - The size of the file, holes included
+///
+/// * `modified` - When the file was last modified, if it is known more precisely than the header records
+///
+/// * `hash` - The hash of the file's contents, holes included
+///
+/// * `regions` - The offset and length of each region of the file holding data
+#[allow(clippy::type_complexity)]
+pub(crate) fn pax_sparse_extensions(
+	header: &mut tar::Header,
+	name: &Path,
+	real_size: u64,
+	modified: Option<SystemTime>,
+	hash: blake3::Hash,
+	regions: &[(u64, u64)],
+) -> Result<(Vec<(&'static str, Vec<u8>)>, Vec<u8>)> {
+	let map = sparse_map(regions);
+	header.set_entry_type(tar::EntryType::Regular);
+	header.set_size(map.len() as u64 + regions.iter().map(|(_, length)| length).sum::<u64>());
+	let mut records = extensions(
+		TarFormat::Pax,
+		header,
+		&sparse_placeholder(name),
+		None,
+		modified,
+		Some(hash),
+	)?;
+	records.extend(sparse_records(name, real_size)?);
+	header.set_cksum();
+	Ok((records, map))
+}
+
+/// The PAX records naming a sparse entry and giving the size of its file
+///
+/// # Arguments
+///
+/// * `name` - The path of the file within the archive
+///
+/// * `real_size` - The size of the file, holes included
+fn sparse_records(name: &Path, real_size: u64) -> Result<Vec<(&'static str, Vec<u8>)>> {
+	Ok(vec![
+		(SPARSE_MAJOR_PAX_KEY, b"1".to_vec()),
+		(SPARSE_MINOR_PAX_KEY, b"0".to_vec()),
+		(SPARSE_NAME_PAX_KEY, member_bytes(name)?),
+		(SPARSE_REALSIZE_PAX_KEY, real_size.to_string().into_bytes()),
+	])
+}
+
+/// The path a sparse entry's header is given in place of the path of its file, as GNU tar names it
+///
+/// # Arguments
+///
+/// * `name` - The path of the file within the archive
+fn sparse_placeholder(name: &Path) -> PathBuf {
+	let placeholder = Path::new("GNUSparseFile.0").join(name.file_name().unwrap_or_default());
+	match name.parent() {
+		Some(parent) => parent.join(placeholder),
+		None => placeholder,
+	}
+}
+
+/// The map at the start of a sparse entry's data: the number of regions holding data, then the offset and length of each, one number per line, padded to a whole block
+///
+/// # Arguments
+///
+/// * `regions` - The offset and length of each region of the file holding data
+fn sparse_map(regions: &[(u64, u64)]) -> Vec<u8> {
+	let mut map = format!("{}\n", regions.len());
+	for (offset, length) in regions {
+		map.push_str(&format!("{offset}\n{length}\n"));
+	}
+	let mut map = map.into_bytes();
+	map.resize(map.len().next_multiple_of(BLOCK_SIZE), 0);
+	map
+}
+
+/// The path and size of a file stored as a PAX sparse entry
+#[derive(Debug, Clone)]
+pub(crate) struct PaxSparse {
+	/// The path of the file within the archive
+	pub(crate) name: PathBuf,
+	/// The path of the file within the archive, as it is stored
+	pub(crate) name_bytes: Vec<u8>,
+	/// The size of the file, holes included
+	pub(crate) real_size: u64,
+}
+
+#[cfg(any(feature = "python", feature = "wasm"))]
+impl PaxSparse {
+	/// Read the map at the start of the entry's data, returning a reader over the file's contents
+	///
+	/// # Arguments
+	///
+	/// * `data` - The data of the entry
+	pub(crate) fn expand<R: Read>(&self, mut data: R) -> Result<SparseReader<R>> {
+		let regions = read_sparse_map(&mut data, self.real_size)?;
+		Ok(SparseReader {
+			data,
+			contents: SparseContents::new(regions, self.real_size),
+		})
+	}
+}
+
+/// Get the path and size of the file an entry holds, if it is a PAX sparse entry
+///
+/// # Arguments
+///
+/// * `entry` - An entry being read from an archive
+pub(crate) fn pax_sparse<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Option<PaxSparse>> {
+	let Some(extensions) = entry.pax_extensions()? else {
+		return Ok(None);
+	};
+	let records = extensions
+		.map(|extension| {
+			extension.map(|extension| {
+				(
+					extension.key_bytes().to_vec(),
+					extension.value_bytes().to_vec(),
+				)
+			})
+		})
+		.collect::<Result<Vec<_>>>()?;
+	parse_pax_sparse(
+		records
+			.iter()
+			.map(|(key, value)| (key.as_slice(), value.as_slice())),
+	)
+}
+
+/// Get the path and size of the file a sparse entry holds from its PAX records, if they describe one
+///
+/// # Arguments
+///
+/// * `records` - The key and value of each of the entry's PAX records
+pub(crate) fn parse_pax_sparse<'a>(
+	records: impl IntoIterator<Item = (&'a [u8], &'a [u8])>,
+) -> Result<Option<PaxSparse>> {
+	let (mut major, mut name, mut real_size) = (None, None, None);
+	for (key, value) in records {
+		match key {
+			key if key == SPARSE_MAJOR_PAX_KEY.as_bytes() => major = Some(value),
+			key if key == SPARSE_NAME_PAX_KEY.as_bytes() => name = Some(value),
+			key if key == SPARSE_REALSIZE_PAX_KEY.as_bytes() => real_size = Some(value),
+			_ => {}
+		}
+	}
+	let (Some(major), Some(name), Some(real_size)) = (major, name, real_size) else {
+		return Ok(None);
+	};
+	let invalid = |what: &str| {
+		Error::new(
+			ErrorKind::InvalidData,
+			format!("sparse entry has an invalid {what}"),
+		)
+	};
+	if major != b"1" {
+		return Err(Error::new(
+			ErrorKind::Unsupported,
+			format!(
+				"sparse entries of version {} are not supported",
+				String::from_utf8_lossy(major)
+			),
+		));
+	}
+	let real_size = std::str::from_utf8(real_size)
+		.ok()
+		.and_then(|real_size| real_size.parse().ok())
+		.ok_or_else(|| invalid("size"))?;
+	Ok(Some(PaxSparse {
+		name: bytes_path(name).ok_or_else(|| invalid("path"))?,
+		name_bytes: name.to_vec(),
+		real_size,
+	}))
+}
+
+/// Read the map at the start of a PAX sparse entry's data, leaving the data of its regions to be read
+///
+/// # Arguments
+///
+/// * `data` - The data of the entry
+///
+/// * `real_size` - The size of the file, holes included
+pub(crate) fn read_sparse_map(data: &mut impl Read, real_size: u64) -> Result<Vec<(u64, u64)>> {
+	let mut map = SparseMap::new(real_size);
+	loop {
+		let mut block = [0u8; BLOCK_SIZE];
+		data.read_exact(&mut block)?;
+		if let Some(regions) = map.feed(&block)? {
+			return Ok(regions);
+		}
+	}
+}
+
+/// Parses the map at the start of a PAX sparse entry's data, a block at a time
+///
+/// The map ends part-way through a block, and the rest of that block is padding, so the data of the entry's regions starts at the block after the last one fed.
+#[derive(Debug)]
+pub(crate) struct SparseMap {
+	/// The numbers in the map read so far
+	numbers: Vec<u64>,
+	/// The digits of a number whose end has not been read yet
+	partial: Vec<u8>,
+	/// The size of the file, holes included
+	real_size: u64,
+}
+
+impl SparseMap {
+	/// Begin parsing a map
+	///
+	/// # Arguments
+	///
+	/// * `real_size` - The size of the file, holes included
+	pub(crate) fn new(real_size: u64) -> SparseMap {
+		SparseMap {
+			numbers: Vec::new(),
+			partial: Vec::new(),
+			real_size,
+		}
+	}
+
+	/// Parse the next block of the map, returning the offset and length of each region holding data once the whole map has been read
+	///
+	/// # Arguments
+	///
+	/// * `block` - The next block of the entry's data
+	pub(crate) fn feed(&mut self, block: &[u8]) -> Result<Option<Vec<(u64, u64)>>> {
+		let invalid = || Error::new(ErrorKind::InvalidData, "sparse entry has an invalid map");
+		for line in block.split_inclusive(|byte| *byte == b'\n') {
+			let Some(digits) = line.strip_suffix(b"\n") else {
+				self.partial.extend_from_slice(line);
+				if self.partial.len() > MAX_MAP_DIGITS {
+					return Err(invalid());
+				}
+				break;
+			};
+			self.partial.extend_from_slice(digits);
+			let number = std::str::from_utf8(&self.partial)
+				.ok()
+				.filter(|number| {
+					!number.is_empty() && number.bytes().all(|digit| digit.is_ascii_digit())
+				})
+				.and_then(|number| number.parse().ok())
+				.ok_or_else(invalid)?;
+			self.partial.clear();
+			self.numbers.push(number);
+
+			let count = self.numbers[0];
+			if (self.numbers.len() as u64 - 1) / 2 == count {
+				let mut regions = Vec::new();
+				let mut end = 0;
+				for pair in self.numbers[1..].chunks(2) {
+					let (offset, length) = (pair[0], pair[1]);
+					let region_end = offset.checked_add(length).ok_or_else(invalid)?;
+					if offset < end || region_end > self.real_size {
+						return Err(invalid());
+					}
+					regions.push((offset, length));
+					end = region_end;
+				}
+				return Ok(Some(regions));
+			}
+		}
+		Ok(None)
+	}
+}
+
+/// The contents of a sparse file, expanded from the regions holding data stored in its entry
+///
+/// Holes are read as zeros, up to the size of the file.
+#[derive(Debug)]
+pub(crate) struct SparseContents {
+	/// The offset and length of each region holding data not yet read
+	regions: VecDeque<(u64, u64)>,
+	/// How much of the file has been read
+	position: u64,
+	/// The size of the file, holes included
+	real_size: u64,
+}
+
+impl SparseContents {
+	/// Begin reading a sparse file
+	///
+	/// # Arguments
+	///
+	/// * `regions` - The offset and length of each region of the file holding data, as read by [`read_sparse_map`]
+	///
+	/// * `real_size` - The size of the file, holes included
+	pub(crate) fn new(regions: Vec<(u64, u64)>, real_size: u64) -> SparseContents {
+		SparseContents {
+			regions: regions.into(),
+			position: 0,
+			real_size,
+		}
+	}
+
+	/// Read the file's contents, taking the data of its regions from its entry
+	///
+	/// # Arguments
+	///
+	/// * `data` - The data of the entry, following its map
+	///
+	/// * `buf` - The buffer to read into
+	pub(crate) fn read(&mut self, data: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+		while self
+			.regions
+			.front()
+			.is_some_and(|(offset, length)| *length == 0 || *offset + *length <= self.position)
+		{
+			self.regions.pop_front();
+		}
+		let remaining = self.real_size.saturating_sub(self.position);
+		if buf.is_empty() || remaining == 0 {
+			return Ok(0);
+		}
+		let read = match self.regions.front_mut() {
+			Some((offset, length)) if *offset <= self.position => {
+				let wanted = buf.len().min((*length).try_into().unwrap_or(usize::MAX));
+				let read = data.read(&mut buf[..wanted])?;
+				if read == 0 {
+					return Err(Error::new(
+						ErrorKind::UnexpectedEof,
+						"sparse entry is shorter than its map records",
+					));
+				}
+				*offset += read as u64;
+				*length -= read as u64;
+				read
+			}
+			region => {
+				// Holes read as zeros, up to the next region holding data
+				let hole = region.map_or(remaining, |(offset, _)| *offset - self.position);
+				let read = buf
+					.len()
+					.min(hole.min(remaining).try_into().unwrap_or(usize::MAX));
+				buf[..read].fill(0);
+				read
+			}
+		};
+		self.position += read as u64;
+		Ok(read)
+	}
+}
+
+/// Reads the contents of a sparse file from its entry, as [`SparseContents`] expands them
+#[cfg(any(feature = "python", feature = "wasm"))]
+pub(crate) struct SparseReader<R: Read> {
+	/// The data of the entry, following its map
+	data: R,
+	/// The regions of the file yet to be read
+	contents: SparseContents,
+}
+
+#[cfg(any(feature = "python", feature = "wasm"))]
+impl<R: Read> Read for SparseReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		self.contents.read(&mut self.data, buf)
+	}
+}
+
+/// Describe a path or link target that cannot be written in the chosen format
+///
+/// # Arguments
+///
+/// * `e` - The error raised when setting it in a header
+///
+/// * `name` - The path of the entry within the archive
+///
+/// * `what` - What could not be written
+fn unfit(e: Error, name: &Path, what: &str) -> Error {
+	Error::new(
+		ErrorKind::InvalidInput,
+		format!(
+			"Could not store the {what} of '{}': {e}",
+			crate::escape_path(name)
+		),
+	)
+}
+
+/// Fill a header field with as much of a value as fits, as a placeholder for readers that do not understand PAX records
+///
+/// # Arguments
+///
+/// * `field` - The header field
+///
+/// * `value` - The value that does not fit in it
+fn truncate_into(field: &mut [u8], value: &[u8]) {
+	let length = value.len().min(field.len());
+	field.fill(0);
+	field[..length].copy_from_slice(&value[..length]);
+}
+
+/// The path of an entry as a header stores it, without any `.` components
+///
+/// # Arguments
+///
+/// * `name` - The path of the entry within the archive
+fn member_bytes(name: &Path) -> Result<Vec<u8>> {
+	let parts = name
+		.components()
+		.filter(|component| *component != Component::CurDir)
+		.map(|component| path_bytes(Path::new(component.as_os_str())))
+		.collect::<Result<Vec<_>>>()?;
+	Ok(parts.join(&b'/'))
+}
+
+/// The path stored in an archive as bytes
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes of the path
+#[cfg(unix)]
+fn bytes_path(bytes: &[u8]) -> Option<PathBuf> {
+	use std::os::unix::ffi::OsStrExt;
+	Some(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+/// The path stored in an archive as bytes
+///
+/// Paths that are not valid Unicode cannot be represented on this platform.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes of the path
+#[cfg(not(unix))]
+fn bytes_path(bytes: &[u8]) -> Option<PathBuf> {
+	std::str::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// The bytes of a path, as they are stored in an archive
+///
+/// # Arguments
+///
+/// * `path` - The path
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Result<Vec<u8>> {
+	use std::os::unix::ffi::OsStrExt;
+	Ok(path.as_os_str().as_bytes().to_vec())
+}
+
+/// The bytes of a path, as they are stored in an archive
+///
+/// # Arguments
+///
+/// * `path` - The path
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Result<Vec<u8>> {
+	path.to_str()
+		.map(|path| path.replace('\\', "/").into_bytes())
+		.ok_or_else(|| {
+			Error::new(
+				ErrorKind::InvalidInput,
+				format!("'{}' is not valid Unicode", crate::escape_path(path)),
+			)
+		})
+}
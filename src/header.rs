@@ -0,0 +1,291 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! The container header written at the start of every `larz` archive.
+//!
+//! Archives written by larz 0.3 and earlier have no header: streaming archives begin directly with an LZ4 frame, and in-memory archives begin with the little-endian length of the uncompressed data.
+//! Readers must keep accepting those files, so the header starts with a magic number that cannot be mistaken for either of them.
+//!
+//! ## Layout
+//!
+//! | Field           | Size     | Description                                           |
+//! | --------------- | -------- | ----------------------------------------------------- |
+//! | Magic           | 8 bytes  | [`MAGIC`]                                             |
+//! | Version         | 1 byte   | The format version; see [`FORMAT_VERSION`]            |
+//! | Codec           | 1 byte   | How the archive body is compressed; see [`Codec`]     |
+//! | Flags           | 2 bytes  | Little-endian feature flags; see [`Flags`]            |
+//! | Metadata length | 4 bytes  | Little-endian length of the metadata section          |
+//! | Metadata        | variable | Key/value pairs; see [`ArchiveHeader::metadata`]      |
+//!
+//! Each metadata pair is encoded as a one-byte key length, the UTF-8 key, a four-byte little-endian value length, and the value.
+
+use std::collections::BTreeMap;
+use std::io::{Chain, Cursor, Error, ErrorKind, Read, Result, Write};
+
+/// The bytes every versioned `larz` archive starts with
+///
+/// The first byte is outside of the ASCII range and the trailing bytes mimic the PNG signature, which catches files mangled by newline conversion.
+pub const MAGIC: [u8; 8] = *b"\x89LARZ\r\n\x1a";
+
+/// A reader positioned at the start of an archive body, as returned by [`ArchiveHeader::read_from`]
+pub type BodyReader<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// The newest format version this build of larz can read and write
+pub const FORMAT_VERSION: u8 = 1;
+
+/// The size of the fixed part of the header, excluding the metadata section
+pub const FIXED_HEADER_SIZE: usize = MAGIC.len() + 1 + 1 + 2 + 4;
+
+/// The codec used to compress the body of an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+	/// The body is an LZ4 frame, as written by the streaming functions
+	Lz4Frame,
+	/// The body is a single LZ4 block prefixed with its uncompressed size, as written by the in-memory functions
+	Lz4Block,
+	/// The body is a series of LZ4 blocks compressed with a dictionary; see [`crate::dictionary`]
+	Lz4DictBlocks,
+}
+
+impl Codec {
+	/// The identifier of this codec in the header
+	pub fn id(self) -> u8 {
+		match self {
+			Codec::Lz4Frame => 0,
+			Codec::Lz4Block => 1,
+			Codec::Lz4DictBlocks => 2,
+		}
+	}
+
+	/// Look up a codec by its identifier in the header
+	///
+	/// # Arguments
+	///
+	/// * `id` - The identifier of the codec
+	pub fn from_id(id: u8) -> Option<Codec> {
+		match id {
+			0 => Some(Codec::Lz4Frame),
+			1 => Some(Codec::Lz4Block),
+			2 => Some(Codec::Lz4DictBlocks),
+			_ => None,
+		}
+	}
+}
+
+/// Feature flags describing how the body of an archive was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags(u16);
+
+impl Flags {
+	/// The archive can be read at arbitrary entry offsets
+	pub const SEEKABLE: Flags = Flags(1 << 0);
+	/// The compressed body is encrypted
+	pub const ENCRYPTED: Flags = Flags(1 << 1);
+	/// The compressed body carries checksums of its contents
+	pub const CHECKSUMMED: Flags = Flags(1 << 2);
+	/// Repeated chunks of the TAR data are stored once; see [`crate::dedup`]
+	pub const DEDUPLICATED: Flags = Flags(1 << 3);
+
+	/// Every flag understood by this build of larz
+	const KNOWN: u16 =
+		Self::SEEKABLE.0 | Self::ENCRYPTED.0 | Self::CHECKSUMMED.0 | Self::DEDUPLICATED.0;
+
+	/// No flags set
+	pub fn empty() -> Flags {
+		Flags(0)
+	}
+
+	/// The raw bits of these flags
+	pub fn bits(self) -> u16 {
+		self.0
+	}
+
+	/// Whether all of the given flags are set
+	///
+	/// # Arguments
+	///
+	/// * `other` - The flags to check for
+	pub fn contains(self, other: Flags) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	/// Set the given flags
+	///
+	/// # Arguments
+	///
+	/// * `other` - The flags to set
+	pub fn insert(&mut self, other: Flags) {
+		self.0 |= other.0;
+	}
+}
+
+/// The header of a versioned `larz` archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveHeader {
+	/// The format version the archive was written with
+	pub version: u8,
+	/// The codec used to compress the body of the archive
+	pub codec: Codec,
+	/// Feature flags describing the body of the archive
+	pub flags: Flags,
+	/// Optional key/value pairs describing the archive
+	///
+	/// Keys are at most 255 bytes long. Keys beginning with `larz.` are reserved for larz itself.
+	pub metadata: BTreeMap<String, Vec<u8>>,
+}
+
+impl ArchiveHeader {
+	/// Create a header for a new archive, with no flags or metadata
+	///
+	/// # Arguments
+	///
+	/// * `codec` - The codec the body of the archive will be compressed with
+	pub fn new(codec: Codec) -> ArchiveHeader {
+		ArchiveHeader {
+			version: FORMAT_VERSION,
+			codec,
+			flags: Flags::empty(),
+			metadata: BTreeMap::new(),
+		}
+	}
+
+	/// Write this header
+	///
+	/// # Arguments
+	///
+	/// * `writer` - Where to write the header to
+	pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+		let mut metadata = Vec::new();
+		for (key, value) in &self.metadata {
+			let key_length = u8::try_from(key.len()).map_err(|_| {
+				Error::new(
+					ErrorKind::InvalidInput,
+					format!("Metadata key '{key}' is longer than 255 bytes"),
+				)
+			})?;
+			let value_length = u32::try_from(value.len()).map_err(|_| {
+				Error::new(
+					ErrorKind::InvalidInput,
+					format!("Metadata value for '{key}' is too large"),
+				)
+			})?;
+			metadata.push(key_length);
+			metadata.extend_from_slice(key.as_bytes());
+			metadata.extend_from_slice(&value_length.to_le_bytes());
+			metadata.extend_from_slice(value);
+		}
+		let metadata_length = u32::try_from(metadata.len())
+			.map_err(|_| Error::new(ErrorKind::InvalidInput, "Metadata section is too large"))?;
+
+		writer.write_all(&MAGIC)?;
+		writer.write_all(&[self.version, self.codec.id()])?;
+		writer.write_all(&self.flags.bits().to_le_bytes())?;
+		writer.write_all(&metadata_length.to_le_bytes())?;
+		writer.write_all(&metadata)?;
+		Ok(())
+	}
+
+	/// Read the header at the start of an archive, if there is one
+	///
+	/// Returns the header, or `None` if the archive was written by larz 0.3 or earlier, along with a reader positioned at the start of the archive body.
+	///
+	/// # Arguments
+	///
+	/// * `reader` - The archive to read the header from
+	pub fn read_from<R: Read>(mut reader: R) -> Result<(Option<ArchiveHeader>, BodyReader<R>)> {
+		let mut magic = Vec::with_capacity(MAGIC.len());
+		(&mut reader)
+			.take(MAGIC.len() as u64)
+			.read_to_end(&mut magic)?;
+		if magic != MAGIC {
+			// Header-less archive; hand back the bytes we looked at
+			return Ok((None, Cursor::new(magic).chain(reader)));
+		}
+
+		let mut fixed = [0u8; FIXED_HEADER_SIZE - MAGIC.len()];
+		reader.read_exact(&mut fixed)?;
+		let version = fixed[0];
+		if version == 0 || version > FORMAT_VERSION {
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				format!(
+					"Archive format version {version} is not supported (newest supported version is {FORMAT_VERSION})"
+				),
+			));
+		}
+		let codec = Codec::from_id(fixed[1]).ok_or_else(|| {
+			Error::new(
+				ErrorKind::InvalidData,
+				format!("Archive uses an unknown codec ({})", fixed[1]),
+			)
+		})?;
+		let flags = u16::from_le_bytes([fixed[2], fixed[3]]);
+		if flags & !Flags::KNOWN != 0 {
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				format!("Archive uses unknown features (flags {flags:#06x})"),
+			));
+		}
+		let metadata_length = u32::from_le_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+		let mut metadata_bytes = Vec::new();
+		(&mut reader)
+			.take(metadata_length as u64)
+			.read_to_end(&mut metadata_bytes)?;
+		if metadata_bytes.len() != metadata_length as usize {
+			return Err(Error::new(
+				ErrorKind::UnexpectedEof,
+				"Archive header is truncated",
+			));
+		}
+
+		let header = ArchiveHeader {
+			version,
+			codec,
+			flags: Flags(flags),
+			metadata: parse_metadata(&metadata_bytes)?,
+		};
+		Ok((Some(header), Cursor::new(Vec::new()).chain(reader)))
+	}
+}
+
+/// Parse the metadata section of a header
+///
+/// # Arguments
+///
+/// * `bytes` - The encoded metadata section
+fn parse_metadata(mut bytes: &[u8]) -> Result<BTreeMap<String, Vec<u8>>> {
+	let malformed = || {
+		Error::new(
+			ErrorKind::InvalidData,
+			"Archive header metadata is malformed",
+		)
+	};
+	let mut metadata = BTreeMap::new();
+	while let Some((&key_length, rest)) = bytes.split_first() {
+		let key_length = key_length as usize;
+		if rest.len() < key_length + 4 {
+			return Err(malformed());
+		}
+		let key = std::str::from_utf8(&rest[..key_length]).map_err(|_| malformed())?;
+		let value_length =
+			u32::from_le_bytes(rest[key_length..key_length + 4].try_into().unwrap()) as usize;
+		let rest = &rest[key_length + 4..];
+		if rest.len() < value_length {
+			return Err(malformed());
+		}
+		metadata.insert(key.to_owned(), rest[..value_length].to_vec());
+		bytes = &rest[value_length..];
+	}
+	Ok(metadata)
+}
@@ -0,0 +1,609 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Listed-incremental backups.
+//!
+//! Like GNU tar's `--listed-incremental`, an incremental backup is written with a snapshot file describing every file archived so far.
+//! If the snapshot file does not exist, a full backup is written; otherwise, only files that are new or whose inode, size or modification time have changed are archived, and files that have since been removed are recorded as deleted.
+//! Either way, the snapshot file is updated once the archive has been written, ready for the next backup.
+//!
+//! Each backup is given a unique identifier, recorded in the snapshot file and in the archive header along with the identifier of the backup it follows.
+//! A full backup and its incrementals can then be restored in order with [`crate::restore_archives`], which refuses archives that do not follow one another.
+//!
+//! Deletions are recorded as a member named [`DELETIONS_MEMBER`], marked with the [`DELETIONS_PAX_KEY`] PAX extended header, listing the deleted paths separated by NUL bytes.
+//! Ordinary extraction skips this member.
+//!
+//! ## Snapshot files
+//!
+//! | Field        | Size     | Description                                   |
+//! | ------------ | -------- | --------------------------------------------- |
+//! | Magic        | 8 bytes  | `LARZSNP1`                                    |
+//! | Identifier   | 16 bytes | The identifier of the backup                  |
+//! | Entry count  | 8 bytes  | Little-endian number of entries that follow   |
+//! | Entries      | variable | One per archived path                         |
+//!
+//! Each entry is the little-endian inode (8 bytes), size (8 bytes), modification time in seconds (8 bytes, signed) and nanoseconds (4 bytes), the BLAKE3 hash of the file's contents (32 bytes, all zero for directories), and the path within the archive as a four-byte little-endian length followed by its bytes.
+
+use crate::header::ArchiveHeader;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The bytes every snapshot file starts with
+const SNAPSHOT_MAGIC: &[u8; 8] = b"LARZSNP1";
+
+/// Header metadata key holding the identifier of an incremental backup
+pub(crate) const BACKUP_ID_KEY: &str = "larz.incremental.id";
+
+/// Header metadata key holding the identifier of the backup an incremental backup follows
+pub(crate) const BASE_ID_KEY: &str = "larz.incremental.base";
+
+/// The PAX extended header keyword marking the list of deleted paths
+pub const DELETIONS_PAX_KEY: &str = "LARZ.deletions";
+
+/// The name of the member listing deleted paths
+pub const DELETIONS_MEMBER: &str = ".larz-deletions";
+
+/// The reason a chain of backups cannot be restored
+///
+/// This is the inner error of the `std::io::Error` returned by [`crate::restore_archives`]; retrieve it with `std::io::Error::get_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IncrementalError {
+	/// The archive was not written as part of a listed-incremental backup
+	NotIncremental(PathBuf),
+	/// The first archive is an incremental backup rather than a full one
+	NotFull(PathBuf),
+	/// The archive does not follow the archive before it
+	OutOfOrder(PathBuf),
+}
+
+impl fmt::Display for IncrementalError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			IncrementalError::NotIncremental(path) => write!(
+				f,
+				"'{}' is not part of a listed-incremental backup",
+				crate::escape_path(path)
+			),
+			IncrementalError::NotFull(path) => write!(
+				f,
+				"'{}' is an incremental backup; restoring must start from a full backup",
+				crate::escape_path(path)
+			),
+			IncrementalError::OutOfOrder(path) => write!(
+				f,
+				"'{}' does not follow the archive before it",
+				crate::escape_path(path)
+			),
+		}
+	}
+}
+
+impl std::error::Error for IncrementalError {}
+
+/// What a snapshot records about an archived path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotEntry {
+	/// The inode of the file, or zero on platforms without inodes
+	pub inode: u64,
+	/// The size of the file, in bytes
+	pub size: u64,
+	/// The modification time of the file, in seconds since the Unix epoch
+	pub mtime_seconds: i64,
+	/// The sub-second part of the modification time of the file, in nanoseconds
+	pub mtime_nanoseconds: u32,
+	/// The hash of the file's contents, if it is a regular file
+	pub hash: Option<blake3::Hash>,
+}
+
+impl SnapshotEntry {
+	/// Describe a file from its metadata
+	///
+	/// # Arguments
+	///
+	/// * `metadata` - The metadata of the file
+	///
+	/// * `hash` - The hash of the file's contents, if it is a regular file
+	pub fn new(metadata: &Metadata, hash: Option<blake3::Hash>) -> SnapshotEntry {
+		let (mtime_seconds, mtime_nanoseconds) = match metadata.modified() {
+			Ok(modified) => match modified.duration_since(UNIX_EPOCH) {
+				Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+				Err(before) => {
+					let before = before.duration();
+					match before.subsec_nanos() {
+						0 => (-(before.as_secs() as i64), 0),
+						nanoseconds => {
+							(-(before.as_secs() as i64) - 1, 1_000_000_000 - nanoseconds)
+						}
+					}
+				}
+			},
+			Err(_) => (0, 0),
+		};
+		SnapshotEntry {
+			inode: inode(metadata),
+			size: metadata.len(),
+			mtime_seconds,
+			mtime_nanoseconds,
+			hash,
+		}
+	}
+
+	/// Whether a file appears unchanged since this entry was recorded, judging by its inode, size and modification time
+	///
+	/// # Arguments
+	///
+	/// * `other` - A description of the file as it is now
+	pub fn unchanged(&self, other: &SnapshotEntry) -> bool {
+		self.inode == other.inode
+			&& self.size == other.size
+			&& self.mtime_seconds == other.mtime_seconds
+			&& self.mtime_nanoseconds == other.mtime_nanoseconds
+	}
+}
+
+/// The state of a tree of files as of a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+	/// The identifier of the backup
+	pub id: [u8; 16],
+	/// Every archived path, by its path within the archive
+	pub entries: BTreeMap<PathBuf, SnapshotEntry>,
+}
+
+impl Snapshot {
+	/// Create an empty snapshot for a new backup
+	pub fn new() -> Snapshot {
+		Snapshot {
+			id: new_backup_id(),
+			entries: BTreeMap::new(),
+		}
+	}
+
+	/// Read a snapshot file
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the snapshot file
+	pub fn read(path: &Path) -> Result<Snapshot> {
+		let malformed = || {
+			Error::new(
+				ErrorKind::InvalidData,
+				format!(
+					"'{}' is not a valid snapshot file",
+					crate::escape_path(path)
+				),
+			)
+		};
+		let mut f = BufReader::new(File::open(path)?);
+		let mut magic = [0u8; 8];
+		f.read_exact(&mut magic).map_err(|_| malformed())?;
+		if &magic != SNAPSHOT_MAGIC {
+			return Err(malformed());
+		}
+		let mut id = [0u8; 16];
+		f.read_exact(&mut id).map_err(|_| malformed())?;
+		let count = read_u64(&mut f).map_err(|_| malformed())?;
+
+		let mut entries = BTreeMap::new();
+		for _ in 0..count {
+			let inode = read_u64(&mut f).map_err(|_| malformed())?;
+			let size = read_u64(&mut f).map_err(|_| malformed())?;
+			let mtime_seconds = read_u64(&mut f).map_err(|_| malformed())? as i64;
+			let mut nanoseconds = [0u8; 4];
+			f.read_exact(&mut nanoseconds).map_err(|_| malformed())?;
+			let mut hash = [0u8; 32];
+			f.read_exact(&mut hash).map_err(|_| malformed())?;
+			let mut path_length = [0u8; 4];
+			f.read_exact(&mut path_length).map_err(|_| malformed())?;
+			let mut member = vec![0u8; u32::from_le_bytes(path_length) as usize];
+			f.read_exact(&mut member).map_err(|_| malformed())?;
+			entries.insert(
+				bytes_to_path(member).ok_or_else(malformed)?,
+				SnapshotEntry {
+					inode,
+					size,
+					mtime_seconds,
+					mtime_nanoseconds: u32::from_le_bytes(nanoseconds),
+					hash: (hash != [0u8; 32]).then(|| blake3::Hash::from_bytes(hash)),
+				},
+			);
+		}
+		Ok(Snapshot { id, entries })
+	}
+
+	/// Write a snapshot file, replacing any existing one only once the new one has been written in full
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the snapshot file
+	pub fn write(&self, path: &Path) -> Result<()> {
+		let mut temporary_path = path.as_os_str().to_owned();
+		temporary_path.push(".tmp");
+		let temporary_path = PathBuf::from(temporary_path);
+
+		let mut f = BufWriter::new(File::create(&temporary_path)?);
+		f.write_all(SNAPSHOT_MAGIC)?;
+		f.write_all(&self.id)?;
+		f.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+		for (member, entry) in &self.entries {
+			let member = path_to_bytes(member);
+			f.write_all(&entry.inode.to_le_bytes())?;
+			f.write_all(&entry.size.to_le_bytes())?;
+			f.write_all(&entry.mtime_seconds.to_le_bytes())?;
+			f.write_all(&entry.mtime_nanoseconds.to_le_bytes())?;
+			f.write_all(
+				entry
+					.hash
+					.as_ref()
+					.map_or(&[0u8; 32], |hash| hash.as_bytes()),
+			)?;
+			f.write_all(&(member.len() as u32).to_le_bytes())?;
+			f.write_all(&member)?;
+		}
+		f.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+		std::fs::rename(&temporary_path, path)
+	}
+}
+
+impl Default for Snapshot {
+	fn default() -> Snapshot {
+		Snapshot::new()
+	}
+}
+
+/// Tracks which files need archiving during a listed-incremental backup
+#[derive(Debug)]
+pub(crate) struct Incremental {
+	/// Path to the snapshot file
+	path: PathBuf,
+	/// The snapshot of the previous backup, if this is not a full backup
+	base: Option<Snapshot>,
+	/// The snapshot of this backup
+	next: Snapshot,
+}
+
+impl Incremental {
+	/// Begin a backup, reading the snapshot of the previous backup if there is one
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the snapshot file
+	pub(crate) fn begin(path: &Path) -> Result<Incremental> {
+		let base = match Snapshot::read(path) {
+			Ok(snapshot) => Some(snapshot),
+			Err(e) if e.kind() == ErrorKind::NotFound => None,
+			Err(e) => return Err(e),
+		};
+		Ok(Incremental {
+			path: path.to_path_buf(),
+			base,
+			next: Snapshot::new(),
+		})
+	}
+
+	/// Record the identifiers of this backup and the one it follows in the header of the archive
+	///
+	/// # Arguments
+	///
+	/// * `header` - The header of the archive being written
+	pub(crate) fn store(&self, header: &mut ArchiveHeader) {
+		header
+			.metadata
+			.insert(BACKUP_ID_KEY.to_owned(), self.next.id.to_vec());
+		if let Some(base) = &self.base {
+			header
+				.metadata
+				.insert(BASE_ID_KEY.to_owned(), base.id.to_vec());
+		}
+	}
+
+	/// Check whether a regular file can be left out of this backup, recording it as unchanged if so
+	///
+	/// # Arguments
+	///
+	/// * `member` - The path of the file within the archive
+	///
+	/// * `metadata` - The metadata of the file
+	pub(crate) fn carry_over(&mut self, member: &Path, metadata: &Metadata) -> bool {
+		let current = SnapshotEntry::new(metadata, None);
+		let Some(previous) = self.base.as_ref().and_then(|base| base.entries.get(member)) else {
+			return false;
+		};
+		if previous.hash.is_none() || !previous.unchanged(&current) {
+			return false;
+		}
+		self.next
+			.entries
+			.insert(member.to_path_buf(), previous.clone());
+		true
+	}
+
+	/// Record a path archived in this backup
+	///
+	/// # Arguments
+	///
+	/// * `member` - The path within the archive
+	///
+	/// * `metadata` - The metadata of the file
+	///
+	/// * `hash` - The hash of the file's contents, if it is a regular file
+	pub(crate) fn record(
+		&mut self,
+		member: &Path,
+		metadata: &Metadata,
+		hash: Option<blake3::Hash>,
+	) {
+		self.next
+			.entries
+			.insert(member.to_path_buf(), SnapshotEntry::new(metadata, hash));
+	}
+
+	/// Append the list of paths deleted since the previous backup to the archive, if there are any
+	///
+	/// # Arguments
+	///
+	/// * `tar` - The archive being written
+	///
+	/// * `format` - The format the archive is written in
+	pub(crate) fn append_deletions<T: Write>(
+		&self,
+		tar: &mut tar::Builder<T>,
+		format: crate::TarFormat,
+	) -> Result<()> {
+		let Some(base) = &self.base else {
+			return Ok(());
+		};
+		let deleted: Vec<&PathBuf> = base
+			.entries
+			.keys()
+			.filter(|member| !self.next.entries.contains_key(*member))
+			.collect();
+		if deleted.is_empty() {
+			return Ok(());
+		}
+
+		let mut contents = Vec::new();
+		for member in deleted {
+			contents.extend_from_slice(&path_to_bytes(member));
+			contents.push(0);
+		}
+		tar.append_pax_extensions([(DELETIONS_PAX_KEY, b"1".as_slice())])?;
+		let mut header = crate::format::new_header(format);
+		header.set_entry_type(tar::EntryType::Regular);
+		header.set_size(contents.len() as u64);
+		header.set_mode(0o644);
+		header.set_mtime(
+			std::time::SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map_or(0, |since| since.as_secs()),
+		);
+		tar.append_data(&mut header, DELETIONS_MEMBER, contents.as_slice())
+	}
+
+	/// Write the snapshot of this backup, once the archive has been written
+	pub(crate) fn finish(self) -> Result<()> {
+		self.next.write(&self.path)
+	}
+}
+
+/// Check whether an archive member is the list of paths deleted since the previous backup
+///
+/// # Arguments
+///
+/// * `entry` - An entry being read from an archive
+pub fn is_deletions<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<bool> {
+	if entry.path_bytes().as_ref() != DELETIONS_MEMBER.as_bytes() {
+		return Ok(false);
+	}
+	let Some(extensions) = entry.pax_extensions()? else {
+		return Ok(false);
+	};
+	for extension in extensions {
+		if extension?.key_bytes() == DELETIONS_PAX_KEY.as_bytes() {
+			return Ok(true);
+		}
+	}
+	Ok(false)
+}
+
+/// Read the paths listed in a deletions member
+///
+/// # Arguments
+///
+/// * `entry` - The deletions member
+pub fn read_deletions<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Vec<PathBuf>> {
+	let mut contents = Vec::new();
+	entry.read_to_end(&mut contents)?;
+	contents
+		.split(|byte| *byte == 0)
+		.filter(|member| !member.is_empty())
+		.map(|member| {
+			bytes_to_path(member.to_vec()).ok_or_else(|| {
+				Error::new(
+					ErrorKind::InvalidData,
+					"The list of deleted paths is malformed",
+				)
+			})
+		})
+		.collect()
+}
+
+/// Check that an archive can be restored after the one before it, returning the identifier of the backup it holds
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to the archive
+///
+/// * `header` - The header of the archive, if it has one
+///
+/// * `previous` - The identifier of the backup restored before this one, if this is the first
+pub(crate) fn check_chain(
+	archive_path: &Path,
+	header: Option<&ArchiveHeader>,
+	previous: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+	let failure = |error: IncrementalError| Error::new(ErrorKind::InvalidInput, error);
+	let Some(id) = header.and_then(|header| header.metadata.get(BACKUP_ID_KEY)) else {
+		return Err(failure(IncrementalError::NotIncremental(
+			archive_path.to_path_buf(),
+		)));
+	};
+	let base = header.and_then(|header| header.metadata.get(BASE_ID_KEY));
+	match (previous, base) {
+		(None, None) => Ok(id.clone()),
+		(None, Some(_)) => Err(failure(IncrementalError::NotFull(
+			archive_path.to_path_buf(),
+		))),
+		(Some(previous), Some(base)) if previous == base.as_slice() => Ok(id.clone()),
+		(Some(_), _) => Err(failure(IncrementalError::OutOfOrder(
+			archive_path.to_path_buf(),
+		))),
+	}
+}
+
+/// Remove paths deleted since the previous backup from a restored tree
+///
+/// # Arguments
+///
+/// * `output_path` - Path the archives are being restored to
+///
+/// * `deleted` - The paths within the archive that were deleted
+pub(crate) fn apply_deletions(output_path: &Path, deleted: &[PathBuf]) -> Result<()> {
+	let root = output_path.canonicalize()?;
+	// Remove the contents of directories before the directories themselves
+	let mut deleted = deleted.to_vec();
+	deleted.sort_unstable_by(|a, b| b.cmp(a));
+	for member in deleted {
+		let Some(destination) = crate::extract::member_destination(output_path, &member) else {
+			continue;
+		};
+		if destination == output_path {
+			continue;
+		}
+		// Paths reached through a symbolic link may lie outside the output directory, so they are skipped
+		let inside = destination
+			.parent()
+			.and_then(|parent| parent.canonicalize().ok())
+			.is_some_and(|parent| parent.starts_with(&root));
+		if !inside {
+			continue;
+		}
+		let removed = match destination.symlink_metadata() {
+			Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&destination),
+			Ok(_) => std::fs::remove_file(&destination),
+			Err(e) => Err(e),
+		};
+		match removed {
+			Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+			_ => (),
+		}
+	}
+	Ok(())
+}
+
+/// Generate a new backup identifier
+///
+/// The identifier is derived from the current time, the process and a per-process counter, so that it is unique without needing a source of randomness.
+fn new_backup_id() -> [u8; 16] {
+	use std::sync::atomic::{AtomicU64, Ordering};
+	static COUNTER: AtomicU64 = AtomicU64::new(0);
+	let mut hasher = blake3::Hasher::new();
+	hasher.update(b"larz backup id\n");
+	let now = std::time::SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default();
+	hasher.update(&now.as_nanos().to_le_bytes());
+	hasher.update(&std::process::id().to_le_bytes());
+	hasher.update(&COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+	let mut id = [0u8; 16];
+	id.copy_from_slice(&hasher.finalize().as_bytes()[..16]);
+	id
+}
+
+/// Read a little-endian unsigned 64-bit integer
+///
+/// # Arguments
+///
+/// * `reader` - Where to read the integer from
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+	let mut bytes = [0u8; 8];
+	reader.read_exact(&mut bytes)?;
+	Ok(u64::from_le_bytes(bytes))
+}
+
+/// Get the inode of a file, or zero on platforms without inodes
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+	std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+/// Get the inode of a file, or zero on platforms without inodes
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the file
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+	0
+}
+
+/// Encode a path as bytes
+///
+/// # Arguments
+///
+/// * `path` - The path to encode
+#[cfg(unix)]
+pub(crate) fn path_to_bytes(path: &Path) -> Vec<u8> {
+	std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()).to_vec()
+}
+
+/// Encode a path as bytes
+///
+/// # Arguments
+///
+/// * `path` - The path to encode
+#[cfg(not(unix))]
+pub(crate) fn path_to_bytes(path: &Path) -> Vec<u8> {
+	path.to_string_lossy().into_owned().into_bytes()
+}
+
+/// Decode a path encoded with `path_to_bytes`
+///
+/// # Arguments
+///
+/// * `bytes` - The encoded path
+#[cfg(unix)]
+pub(crate) fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+	Some(PathBuf::from(
+		<std::ffi::OsString as std::os::unix::ffi::OsStringExt>::from_vec(bytes),
+	))
+}
+
+/// Decode a path encoded with `path_to_bytes`
+///
+/// # Arguments
+///
+/// * `bytes` - The encoded path
+#[cfg(not(unix))]
+pub(crate) fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+	String::from_utf8(bytes).ok().map(PathBuf::from)
+}
@@ -0,0 +1,164 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Per-entry content hashes.
+//!
+//! When a regular file is archived, the BLAKE3 hash of its contents is stored in a PAX extended header (under [`HASH_PAX_KEY`]) preceding the entry.
+//! On extraction, each file is hashed as it is written out and compared against the recorded hash, so a corrupt member can be named rather than only detecting that the archive as a whole is damaged.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Result};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The PAX extended header keyword holding the BLAKE3 hash of an entry's contents, as 64 hexadecimal digits
+pub const HASH_PAX_KEY: &str = "LARZ.blake3";
+
+/// An error raised when extracted files do not match the hashes recorded in the archive
+///
+/// This is the inner error of the `std::io::Error` returned by extraction; retrieve it with `std::io::Error::get_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegrityError {
+	/// The paths, within the archive, of every member whose contents did not match its recorded hash
+	pub members: Vec<PathBuf>,
+}
+
+impl fmt::Display for IntegrityError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{} member(s) do not match their recorded hashes:",
+			self.members.len()
+		)?;
+		for member in &self.members {
+			write!(f, "\n  {}", crate::escape_path(member))?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Compute the BLAKE3 hash of a file's contents
+///
+/// # Arguments
+///
+/// * `path` - Path to the file
+pub fn hash_file(path: &Path) -> Result<blake3::Hash> {
+	let mut hasher = blake3::Hasher::new();
+	std::io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+	Ok(hasher.finalize())
+}
+
+/// Get the hash recorded for an archive entry, if there is one
+///
+/// # Arguments
+///
+/// * `entry` - An entry being read from an archive
+pub fn recorded_hash<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Option<blake3::Hash>> {
+	let Some(extensions) = entry.pax_extensions()? else {
+		return Ok(None);
+	};
+	for extension in extensions {
+		let extension = extension?;
+		if extension.key_bytes() == HASH_PAX_KEY.as_bytes() {
+			return Ok(extension
+				.value()
+				.ok()
+				.and_then(|value| blake3::Hash::from_hex(value).ok()));
+		}
+	}
+	Ok(None)
+}
+
+/// The range of the uncompressed archive currently being hashed
+#[derive(Default)]
+pub(crate) struct HashWindow {
+	/// How many bytes of the archive have been read so far
+	position: u64,
+	/// Where the hashed range starts
+	start: u64,
+	/// Where the hashed range ends
+	end: u64,
+	/// The hash of the bytes read from the range so far
+	hasher: Option<blake3::Hasher>,
+}
+
+impl HashWindow {
+	/// Start hashing a range of the archive
+	///
+	/// # Arguments
+	///
+	/// * `start` - Where the range starts
+	///
+	/// * `length` - How long the range is
+	pub(crate) fn begin(&mut self, start: u64, length: u64) {
+		self.start = start;
+		self.end = start + length;
+		self.hasher = Some(blake3::Hasher::new());
+	}
+
+	/// Stop hashing, returning the hash of the range if all of it was read
+	pub(crate) fn end(&mut self) -> Option<blake3::Hash> {
+		let hasher = self.hasher.take()?;
+		(self.position >= self.end).then(|| hasher.finalize())
+	}
+}
+
+/// Hashes a window of an archive as it is read
+///
+/// The window is shared with the extraction loop, which moves it to the data of each entry before the entry is unpacked.
+pub(crate) struct HashingReader<R: Read> {
+	/// The uncompressed archive
+	inner: R,
+	/// The range being hashed
+	window: Rc<RefCell<HashWindow>>,
+}
+
+impl<R: Read> HashingReader<R> {
+	/// Wrap an uncompressed archive so that parts of it can be hashed as it is read
+	///
+	/// # Arguments
+	///
+	/// * `inner` - The uncompressed archive
+	pub(crate) fn new(inner: R) -> (HashingReader<R>, Rc<RefCell<HashWindow>>) {
+		let window = Rc::new(RefCell::new(HashWindow::default()));
+		(
+			HashingReader {
+				inner,
+				window: window.clone(),
+			},
+			window,
+		)
+	}
+}
+
+impl<R: Read> Read for HashingReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		let length = self.inner.read(buf)?;
+		let mut window = self.window.borrow_mut();
+		let read_start = window.position;
+		let read_end = read_start + length as u64;
+		let (start, end) = (window.start.max(read_start), window.end.min(read_end));
+		if let Some(hasher) = window.hasher.as_mut() {
+			if start < end {
+				hasher.update(&buf[(start - read_start) as usize..(end - read_start) as usize]);
+			}
+		}
+		window.position = read_end;
+		Ok(length)
+	}
+}
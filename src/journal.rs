@@ -0,0 +1,561 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Checkpoint journals, from which interrupted compression & extraction can be resumed.
+//!
+//! When [`crate::CompressOptions::resume`] is set, an archive is written to a partial file beside it (eg, `.backup.larz.partial`) instead of a temporary file, and a journal is kept beside it (eg, `.backup.larz.journal`).
+//! Whenever at least [`CHECKPOINT_INTERVAL`] bytes of TAR data have been written since the last checkpoint, the LZ4 frame being written is ended at the end of the current entry, and the partial file is synced to disk.
+//! The entries written since the last checkpoint are then recorded in the journal, followed by the length of the partial file.
+//! The archive is therefore a series of independent LZ4 frames, each ending at an entry boundary, which are read one after another.
+//! If writing fails or is interrupted, both files are kept; resuming cuts the partial file back to the last checkpoint and continues in a new frame, leaving out the entries the journal records.
+//!
+//! When [`crate::ExtractOptions::resume`] is set, a journal is kept beside the output directory in the same way, recording each entry once it has been extracted, verified & synced to disk, and the end of each archive.
+//! The journal is synced whenever at least [`CHECKPOINT_INTERVAL`] bytes have been extracted since it last was, and at the end of each archive, so an interruption loses at most that much progress.
+//! Resuming skips the archives that were extracted in full, and the entries already extracted from the next one.
+//!
+//! A journal is removed once its archive has been written or extracted in full.
+//! Resuming refuses a journal kept with different options, or, when extracting, for different archives; the same paths should be archived again when resuming compression.
+//!
+//! ## Journal files
+//!
+//! | Field       | Size     | Description                                           |
+//! | ----------- | -------- | ----------------------------------------------------- |
+//! | Magic       | 8 bytes  | `LARZJNL1`                                            |
+//! | Fingerprint | 32 bytes | The BLAKE3 hash of the options or archives of the run |
+//! | Records     | variable | One per entry, checkpoint or archive                  |
+//!
+//! Each record begins with a one-byte tag:
+//! - `E` - An entry, followed by the BLAKE3 hash of its contents (32 bytes, all zero unless it is a file stored in full) and its path within the archive as a four-byte little-endian length followed by its bytes
+//! - `C` - A checkpoint, followed by the little-endian lengths of the partial file (8 bytes) and of the TAR data it holds (8 bytes)
+//! - `A` - The end of an archive that has been extracted in full
+//!
+//! Entries recorded after the last checkpoint of a compression journal are ignored, as is a record cut short by an interruption.
+
+// Only streaming archives can be resumed, so compression journals are never opened without the `streaming` feature
+#![cfg_attr(not(feature = "streaming"), allow(dead_code))]
+
+use crate::incremental::{bytes_to_path, path_to_bytes};
+use crate::CompressOptions;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The bytes every journal file starts with
+const JOURNAL_MAGIC: &[u8; 8] = b"LARZJNL1";
+
+/// The length of the magic & fingerprint at the start of a journal file
+const JOURNAL_HEADER_LENGTH: u64 = JOURNAL_MAGIC.len() as u64 + 32;
+
+/// The tag of a record of an entry
+const ENTRY_TAG: u8 = b'E';
+
+/// The tag of a record of a checkpoint
+const CHECKPOINT_TAG: u8 = b'C';
+
+/// The tag of a record of the end of an archive
+const ARCHIVE_TAG: u8 = b'A';
+
+/// How many bytes of TAR data are written between checkpoints, at least
+pub const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// Where the TAR data of an archive being written with a checkpoint journal goes
+pub(crate) trait Checkpoint: Write {
+	/// How many bytes of TAR data have been written
+	fn tar_offset(&self) -> u64;
+
+	/// End the LZ4 frame being written and sync the archive to disk, returning how many bytes of it have been written
+	fn checkpoint(&mut self) -> Result<u64>;
+}
+
+/// Get the path of the checkpoint journal kept while writing an archive or extracting to a directory
+///
+/// # Arguments
+///
+/// * `path` - Path to the archive being written, or to the directory being extracted to
+pub fn journal_path(path: &Path) -> Result<PathBuf> {
+	let path = match path.file_name() {
+		Some(_) => path.to_path_buf(),
+		None => path.canonicalize()?,
+	};
+	crate::atomic::hidden_path(&path, "journal")
+}
+
+/// A record read from a journal
+enum Record {
+	/// An entry, and the hash of its contents if it is a file stored in full
+	Entry(PathBuf, Option<blake3::Hash>),
+	/// A checkpoint, with the lengths of the partial file and of the TAR data it holds
+	Checkpoint(u64, u64),
+	/// The end of an archive extracted in full
+	Archive,
+}
+
+/// A journal file being appended to
+struct Journal {
+	/// The journal file
+	file: File,
+	/// Path to the journal file
+	path: PathBuf,
+}
+
+impl Journal {
+	/// Open a journal, creating it if it does not exist, and read the records it holds
+	///
+	/// Returns the journal and each record, along with the length of the journal up to the end of that record.
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the journal file
+	///
+	/// * `fingerprint` - The fingerprint of the run the journal is kept for
+	fn open(path: PathBuf, fingerprint: blake3::Hash) -> Result<(Journal, Vec<(Record, u64)>)> {
+		let f = match File::open(&path) {
+			Ok(f) => f,
+			Err(e) if e.kind() == ErrorKind::NotFound => {
+				let mut file = File::create(&path)?;
+				file.write_all(JOURNAL_MAGIC)?;
+				file.write_all(fingerprint.as_bytes())?;
+				file.sync_all()?;
+				return Ok((Journal { file, path }, Vec::new()));
+			}
+			Err(e) => return Err(e),
+		};
+		let malformed = || {
+			Error::new(
+				ErrorKind::InvalidData,
+				format!(
+					"'{}' is not a valid journal file",
+					crate::escape_path(&path)
+				),
+			)
+		};
+		let mut f = BufReader::new(f);
+		let mut magic = [0u8; 8];
+		f.read_exact(&mut magic).map_err(|_| malformed())?;
+		if &magic != JOURNAL_MAGIC {
+			return Err(malformed());
+		}
+		let mut recorded = [0u8; 32];
+		f.read_exact(&mut recorded).map_err(|_| malformed())?;
+		if recorded != *fingerprint.as_bytes() {
+			return Err(Error::new(
+				ErrorKind::InvalidInput,
+				format!(
+					"'{}' was kept with different options or archives; remove it to start again",
+					crate::escape_path(&path)
+				),
+			));
+		}
+
+		let mut records = Vec::new();
+		let mut length = JOURNAL_HEADER_LENGTH;
+		while let Some((record, record_length)) =
+			read_record(&mut f).map_err(|e| match e.kind() {
+				ErrorKind::InvalidData => malformed(),
+				_ => e,
+			})? {
+			length += record_length;
+			records.push((record, length));
+		}
+		let mut file = OpenOptions::new().write(true).open(&path)?;
+		file.seek(SeekFrom::End(0))?;
+		Ok((Journal { file, path }, records))
+	}
+
+	/// Discard everything in the journal past the given length, so that new records follow on from it
+	///
+	/// # Arguments
+	///
+	/// * `length` - The length of the journal to keep
+	fn truncate(&mut self, length: u64) -> Result<()> {
+		self.file.set_len(length)?;
+		self.file.seek(SeekFrom::End(0))?;
+		Ok(())
+	}
+
+	/// Append records to the journal
+	///
+	/// # Arguments
+	///
+	/// * `records` - The encoded records
+	fn append(&mut self, records: &[u8]) -> Result<()> {
+		self.file.write_all(records)
+	}
+
+	/// Remove the journal, once the run it was kept for is complete
+	fn remove(self) -> Result<()> {
+		drop(self.file);
+		std::fs::remove_file(&self.path)
+	}
+}
+
+/// The checkpoint journal of an archive being written
+pub(crate) struct CompressJournal {
+	/// The journal file
+	journal: Journal,
+	/// The entries written before the checkpoint being resumed from, and the hashes of those that are files stored in full
+	completed: HashMap<PathBuf, Option<blake3::Hash>>,
+	/// The lengths of the partial file and of the TAR data it holds, as of the checkpoint being resumed from
+	resumed: Option<(u64, u64)>,
+	/// The records of the entries written since the last checkpoint
+	pending: Vec<u8>,
+	/// How many bytes of TAR data had been written as of the last checkpoint
+	tar_offset: u64,
+}
+
+impl CompressJournal {
+	/// Open the journal of an archive, ready to resume from its last checkpoint if it has one
+	///
+	/// # Arguments
+	///
+	/// * `archive_path` - Path to the archive being written
+	///
+	/// * `options` - Options controlling how the archive is written
+	pub(crate) fn open(archive_path: &Path, options: &CompressOptions) -> Result<CompressJournal> {
+		check_resumable(options)?;
+		let (mut journal, records) = Journal::open(
+			journal_path(archive_path)?,
+			compression_fingerprint(options),
+		)?;
+		let mut completed = HashMap::new();
+		let mut entries = Vec::new();
+		let mut resumed = None;
+		let mut length = JOURNAL_HEADER_LENGTH;
+		for (record, end) in records {
+			match record {
+				Record::Entry(member, hash) => entries.push((member, hash)),
+				Record::Checkpoint(archive_offset, tar_offset) => {
+					completed.extend(entries.drain(..));
+					resumed = Some((archive_offset, tar_offset));
+					length = end;
+				}
+				Record::Archive => continue,
+			}
+		}
+		journal.truncate(length)?;
+		Ok(CompressJournal {
+			journal,
+			completed,
+			resumed,
+			pending: Vec::new(),
+			tar_offset: resumed.map_or(0, |(_, tar_offset)| tar_offset),
+		})
+	}
+
+	/// The lengths of the partial file and of the TAR data it holds as of the last checkpoint, if there is one to resume from
+	pub(crate) fn resumed(&self) -> Option<(u64, u64)> {
+		self.resumed
+	}
+
+	/// Whether an entry was written before the checkpoint being resumed from, and the hash of its contents if it is a file stored in full
+	///
+	/// # Arguments
+	///
+	/// * `member` - The path of the entry within the archive
+	pub(crate) fn completed(&self, member: &Path) -> Option<Option<blake3::Hash>> {
+		self.completed.get(member).copied()
+	}
+
+	/// Record that an entry has been written, taking a checkpoint if enough has been written since the last one
+	///
+	/// # Arguments
+	///
+	/// * `tar` - The archive being written
+	///
+	/// * `member` - The path of the entry within the archive
+	///
+	/// * `hash` - The hash of the entry's contents, if it is a file stored in full
+	pub(crate) fn record<T: Checkpoint>(
+		&mut self,
+		tar: &mut tar::Builder<T>,
+		member: &Path,
+		hash: Option<blake3::Hash>,
+	) -> Result<()> {
+		encode_entry(&mut self.pending, member, hash);
+		let tar_offset = tar.get_ref().tar_offset();
+		if tar_offset - self.tar_offset < CHECKPOINT_INTERVAL {
+			return Ok(());
+		}
+		let archive_offset = tar.get_mut().checkpoint()?;
+		self.pending.push(CHECKPOINT_TAG);
+		self.pending.extend(archive_offset.to_le_bytes());
+		self.pending.extend(tar_offset.to_le_bytes());
+		self.journal.append(&self.pending)?;
+		self.journal.file.sync_data()?;
+		self.pending.clear();
+		self.tar_offset = tar_offset;
+		Ok(())
+	}
+
+	/// Remove the journal, once the archive has been written in full
+	pub(crate) fn finish(self) -> Result<()> {
+		self.journal.remove()
+	}
+}
+
+/// The checkpoint journal of a directory being extracted to
+pub(crate) struct ExtractJournal {
+	/// The journal file
+	journal: Journal,
+	/// How many archives have been extracted in full
+	archives_extracted: usize,
+	/// How many archives have been begun, including those skipped
+	archives_begun: usize,
+	/// The entries already extracted from the first archive not extracted in full
+	extracted: HashSet<PathBuf>,
+	/// How many bytes have been extracted since the journal was last synced to disk
+	unsynced: u64,
+}
+
+impl ExtractJournal {
+	/// Open the journal of a directory being extracted to, ready to resume from where it left off
+	///
+	/// # Arguments
+	///
+	/// * `output_path` - Path to the directory being extracted to
+	///
+	/// * `paths` - A list of paths pointing to the archives being extracted
+	pub(crate) fn open(output_path: &Path, paths: &[PathBuf]) -> Result<ExtractJournal> {
+		let (mut journal, records) =
+			Journal::open(journal_path(output_path)?, extraction_fingerprint(paths))?;
+		let mut archives_extracted = 0;
+		let mut extracted = HashSet::new();
+		let mut length = JOURNAL_HEADER_LENGTH;
+		for (record, end) in records {
+			match record {
+				Record::Entry(member, _) => {
+					extracted.insert(member);
+				}
+				Record::Archive => {
+					archives_extracted += 1;
+					extracted.clear();
+				}
+				Record::Checkpoint(..) => continue,
+			}
+			length = end;
+		}
+		journal.truncate(length)?;
+		Ok(ExtractJournal {
+			journal,
+			archives_extracted,
+			archives_begun: 0,
+			extracted,
+			unsynced: 0,
+		})
+	}
+
+	/// Move on to the next archive, returning whether it still needs extracting
+	pub(crate) fn begin_archive(&mut self) -> bool {
+		self.archives_begun += 1;
+		self.archives_begun > self.archives_extracted
+	}
+
+	/// Whether an entry of the archive being extracted was extracted by an earlier run
+	///
+	/// # Arguments
+	///
+	/// * `member` - The path of the entry within the archive
+	pub(crate) fn extracted(&self, member: &Path) -> bool {
+		self.extracted.contains(member)
+	}
+
+	/// Record that an entry has been extracted & verified, syncing the journal if enough has been extracted since it last was
+	///
+	/// The contents of the entry must already be synced to disk.
+	///
+	/// # Arguments
+	///
+	/// * `member` - The path of the entry within the archive
+	///
+	/// * `size` - The size of the entry's contents
+	pub(crate) fn record(&mut self, member: &Path, size: u64) -> Result<()> {
+		let mut record = Vec::new();
+		encode_entry(&mut record, member, None);
+		self.journal.append(&record)?;
+		self.unsynced += size;
+		if self.unsynced >= CHECKPOINT_INTERVAL {
+			self.journal.file.sync_data()?;
+			self.unsynced = 0;
+		}
+		Ok(())
+	}
+
+	/// Record that the archive being extracted has been extracted in full
+	pub(crate) fn end_archive(&mut self) -> Result<()> {
+		if self.archives_begun <= self.archives_extracted {
+			return Ok(());
+		}
+		self.journal.append(&[ARCHIVE_TAG])?;
+		self.journal.file.sync_data()?;
+		self.unsynced = 0;
+		self.archives_extracted = self.archives_begun;
+		self.extracted.clear();
+		Ok(())
+	}
+
+	/// Remove the journal, once every archive has been extracted in full
+	pub(crate) fn finish(self) -> Result<()> {
+		self.journal.remove()
+	}
+}
+
+/// Refuse to keep a journal for an archive whose body cannot be resumed part-way through
+///
+/// # Arguments
+///
+/// * `options` - Options controlling how the archive is written
+fn check_resumable(options: &CompressOptions) -> Result<()> {
+	#[cfg(feature = "encryption")]
+	let encrypted = options.passphrase.is_some();
+	#[cfg(not(feature = "encryption"))]
+	let encrypted = false;
+	match encrypted
+		|| options.dictionary.is_some()
+		|| options.deduplicate
+		|| options.snapshot.is_some()
+		|| options.volume_size.is_some()
+	{
+		true => Err(Error::new(
+			ErrorKind::Unsupported,
+			"Encryption, dictionaries, deduplication, incremental backups and volumes are not supported when resuming",
+		)),
+		false => Ok(()),
+	}
+}
+
+/// The fingerprint of the options an archive is written with, which must not change when resuming
+///
+/// # Arguments
+///
+/// * `options` - Options controlling how the archive is written
+fn compression_fingerprint(options: &CompressOptions) -> blake3::Hash {
+	let walk = &options.walk;
+	let mut hasher = blake3::Hasher::new();
+	hasher.update(b"larz compression journal\n");
+	hasher.update(&[
+		options.format as u8,
+		options.link_duplicates as u8,
+		walk.symlinks as u8,
+		walk.one_file_system as u8,
+		walk.special_files as u8,
+	]);
+	hasher.update(
+		&walk
+			.max_depth
+			.map_or(u64::MAX, |max_depth| max_depth as u64)
+			.to_le_bytes(),
+	);
+	hasher.finalize()
+}
+
+/// The fingerprint of the archives being extracted, which must not change when resuming
+///
+/// Each archive is identified by its path, size and modification time.
+///
+/// # Arguments
+///
+/// * `paths` - A list of paths pointing to the archives being extracted
+fn extraction_fingerprint(paths: &[PathBuf]) -> blake3::Hash {
+	let mut hasher = blake3::Hasher::new();
+	hasher.update(b"larz extraction journal\n");
+	for path in paths {
+		let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+		let path_bytes = path.as_os_str().as_encoded_bytes();
+		hasher.update(&(path_bytes.len() as u64).to_le_bytes());
+		hasher.update(path_bytes);
+		let metadata = std::fs::metadata(&path).ok();
+		let modified = metadata
+			.as_ref()
+			.and_then(|metadata| metadata.modified().ok())
+			.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+			.unwrap_or_default();
+		hasher.update(&metadata.map_or(0, |metadata| metadata.len()).to_le_bytes());
+		hasher.update(&modified.as_nanos().to_le_bytes());
+	}
+	hasher.finalize()
+}
+
+/// Encode the record of an entry
+///
+/// # Arguments
+///
+/// * `records` - Where the record is written to
+///
+/// * `member` - The path of the entry within the archive
+///
+/// * `hash` - The hash of the entry's contents, if it is a file stored in full
+fn encode_entry(records: &mut Vec<u8>, member: &Path, hash: Option<blake3::Hash>) {
+	let member = path_to_bytes(member);
+	records.push(ENTRY_TAG);
+	records.extend_from_slice(hash.as_ref().map_or(&[0u8; 32], |hash| hash.as_bytes()));
+	records.extend((member.len() as u32).to_le_bytes());
+	records.extend(member);
+}
+
+/// Read the next record of a journal, returning it along with its length
+///
+/// Returns `None` at the end of the journal, or if the last record was cut short.
+///
+/// # Arguments
+///
+/// * `reader` - Where the records are read from
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<(Record, u64)>> {
+	let cut_short = |e: Error| match e.kind() {
+		ErrorKind::UnexpectedEof => Ok(None),
+		_ => Err(e),
+	};
+	let mut tag = [0u8; 1];
+	if let Err(e) = reader.read_exact(&mut tag) {
+		return cut_short(e);
+	}
+	match tag[0] {
+		ENTRY_TAG => {
+			let mut hash = [0u8; 32];
+			let mut path_length = [0u8; 4];
+			if let Err(e) = reader
+				.read_exact(&mut hash)
+				.and_then(|_| reader.read_exact(&mut path_length))
+			{
+				return cut_short(e);
+			}
+			let mut member = vec![0u8; u32::from_le_bytes(path_length) as usize];
+			if let Err(e) = reader.read_exact(&mut member) {
+				return cut_short(e);
+			}
+			let length = 1 + 32 + 4 + member.len() as u64;
+			let member =
+				bytes_to_path(member).ok_or_else(|| Error::from(ErrorKind::InvalidData))?;
+			let hash = (hash != [0u8; 32]).then(|| blake3::Hash::from_bytes(hash));
+			Ok(Some((Record::Entry(member, hash), length)))
+		}
+		CHECKPOINT_TAG => {
+			let mut offsets = [0u8; 16];
+			if let Err(e) = reader.read_exact(&mut offsets) {
+				return cut_short(e);
+			}
+			let (archive_offset, tar_offset) = offsets.split_at(8);
+			Ok(Some((
+				Record::Checkpoint(
+					u64::from_le_bytes(archive_offset.try_into().unwrap()),
+					u64::from_le_bytes(tar_offset.try_into().unwrap()),
+				),
+				1 + 16,
+			)))
+		}
+		ARCHIVE_TAG => Ok(Some((Record::Archive, 1))),
+		_ => Err(Error::from(ErrorKind::InvalidData)),
+	}
+}
@@ -14,7 +14,7 @@
 
 //! # larz
 //! A simple, fast, and efficient file archiver and compressor.
-//! larz creates archives in the [GNU TAR](https://en.wikipedia.org/wiki/Tar_(computing)#File_format) format, and compresses them using [LZ4](https://en.wikipedia.org/wiki/LZ4_(compression_algorithm)).
+//! larz creates archives in the [TAR](https://en.wikipedia.org/wiki/Tar_(computing)#File_format) format, using PAX headers unless another [`TarFormat`] is chosen, and compresses them using [LZ4](https://en.wikipedia.org/wiki/LZ4_(compression_algorithm)).
 //!
 //! ## Usage
 //!
@@ -43,13 +43,30 @@
 //! extract_archive_memory(paths, output_path);
 //! ```
 //!
+//! ### Reading & writing archives entry by entry
+//! Archives can also be built from data held in memory or behind a reader, without writing it to disk first; see [`writer::ArchiveWriter`].
+//! Likewise, the entries of an archive can be read one at a time without extracting them; see [`reader::ArchiveReader`].
+//!
+//! ## Format
+//! Archives begin with a small versioned header (see [`header`]) naming the codec and features used to write them.
+//! Archives written by larz 0.3 and earlier, which have no header, can still be extracted.
+//! Unless the format is ustar, files with holes, such as disk images, are stored as sparse entries holding only their data, and are extracted with their holes intact; holes are found using `SEEK_DATA` and `SEEK_HOLE` on Linux, Android and FreeBSD.
+//! Before compression, repeated regions of the archived files can be stored only once (see [`dedup`]), and the TAR data can be compressed against a trained dictionary (see [`dictionary`]).
+//!
 //! ## Features
 //! - `safe` - Ensures that compression and decompression are performed in a memory-safe manner. This is enabled by default.
 //! - `streaming` - larz supports streaming compression and decompression using the LZ4 frame format. This means that larz can compress and decompress files with larger sizes, without having to load the entire file into memory. This is enabled by default.
+//! - `encryption` - Archives can be encrypted with a passphrase, using XChaCha20-Poly1305 with a key derived through Argon2id. See [`crypto`] for details.
+//! - `signing` - Archives can be signed with Ed25519 keys, and extraction can refuse archives that are not signed with a trusted key. See [`signing`] for details.
+//! - `async` - Archives can be written to and read from asynchronous I/O, using Tokio. See [`asynchronous`] for details.
+//! - `python` - Builds a Python extension module with maturin, exposing compression, extraction, listing and testing of archives. See [`python`] for details.
+//! - `serve` - The members of an archive can be served over HTTP without extracting it, with directory listings, `ETag`s and range requests. See [`serve`] for details.
+//! - `wasm` - Builds archives from, and extracts them to, JavaScript byte arrays when compiled to WebAssembly with `wasm-bindgen`. See [`wasm`] for details.
 //!
 //! ## Installation
 //! Run `cargo add larz` to add larz to your `Cargo.toml` file.
 //! If you intend on using `larz` as a tool, run `cargo install larz`.
+//! C and C++ programs can link against `liblarz` using the `include/larz.h` header and the `larz.pc` pkg-config file; see [`ffi`] for details.
 //!
 //! ## License
 //! larz is licensed under the [GNU Affero General Public License](https://www.gnu.org/licenses/agpl-3.0.en.html).
@@ -66,11 +83,257 @@
 
 #![warn(missing_docs)]
 
+mod append;
+mod atomic;
+mod extract;
+mod format;
+
+/// Asynchronous compression and extraction
+#[cfg(feature = "async")]
+pub mod asynchronous;
+/// Password-based authenticated encryption of archives
+#[cfg(feature = "encryption")]
+pub mod crypto;
+/// Content-defined chunking deduplication
+pub mod dedup;
+/// Dictionary-trained compression
+pub mod dictionary;
+/// Comparing archives and directories
+pub mod diff;
+/// The C interface
+pub mod ffi;
+/// The container header written at the start of every archive
+pub mod header;
+/// Listed-incremental backups
+pub mod incremental;
+/// Per-entry content hashes
+pub mod integrity;
+/// Checkpoint journals for resuming interrupted runs
+pub mod journal;
+/// Python bindings
+#[cfg(feature = "python")]
+pub mod python;
+/// Reading archives one entry at a time
+pub mod reader;
+/// Serving the contents of archives over HTTP
+#[cfg(feature = "serve")]
+pub mod serve;
+/// Ed25519 signing and signature verification of archives
+#[cfg(feature = "signing")]
+pub mod signing;
+/// Multi-volume archives
+pub mod volume;
+/// WebAssembly bindings
+#[cfg(feature = "wasm")]
+pub mod wasm;
+/// Writing archives one entry at a time
+pub mod writer;
+
+use header::{ArchiveHeader, Codec, Flags};
 use std::fs::File;
 use std::io::BufReader;
 use std::io::BufWriter;
+use std::io::Read;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Options controlling how archives are written
+#[derive(Debug, Clone, Default)]
+pub struct CompressOptions {
+	/// Store repeated regions of the archived files only once
+	pub deduplicate: bool,
+	/// Store files with identical contents only once, as hard links to the first of them, so that they are extracted as links to the same file
+	///
+	/// Files which are hard links to each other on disk are always stored this way.
+	pub link_duplicates: bool,
+	/// Compress the archive with this dictionary
+	pub dictionary: Option<dictionary::Dictionary>,
+	/// Store the dictionary in the archive, so it can be extracted without it
+	pub embed_dictionary: bool,
+	/// Encrypt the archive with a key derived from this passphrase
+	#[cfg(feature = "encryption")]
+	pub passphrase: Option<crypto::Passphrase>,
+	/// Write a listed-incremental backup, reading and then updating this snapshot file
+	pub snapshot: Option<PathBuf>,
+	/// Split the archive into volumes of at most this many bytes
+	pub volume_size: Option<u64>,
+	/// Fail rather than replace an archive that already exists
+	///
+	/// Either way, the archive is written to a temporary file that only replaces the existing one once it is complete.
+	pub no_clobber: bool,
+	/// Keep a checkpoint journal while writing the archive, resuming from the last checkpoint of an earlier run that was interrupted
+	///
+	/// Only streaming archives that are not encrypted, deduplicated, compressed with a dictionary, split into volumes or written as incremental backups can be resumed; see [`journal`].
+	pub resume: bool,
+	/// How directories are walked when they are archived
+	pub walk: WalkOptions,
+	/// The TAR format the archive is written in
+	pub format: TarFormat,
+	/// Called as each member is written to the archive
+	pub progress: Option<Progress>,
+}
+
+/// Options controlling how directories are walked when they are archived
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalkOptions {
+	/// How symbolic links are archived
+	pub symlinks: Symlinks,
+	/// Do not descend into directories on a different filesystem from the directory being archived
+	///
+	/// Mount points are stored as empty directories.
+	pub one_file_system: bool,
+	/// Do not descend more than this many levels beneath the directories being archived
+	///
+	/// Directories at the deepest level are stored without their contents, and a depth of zero stores only the directories themselves.
+	pub max_depth: Option<usize>,
+	/// How FIFOs, sockets & device nodes are archived
+	pub special_files: SpecialFiles,
+}
+
+/// The TAR formats archives can be written in
+///
+/// Unless the format is ustar, the hashes of files are recorded in PAX extended headers, so that they can be checked when extracted.
+/// Files with holes are written as sparse entries, in the 1.0 format GNU tar writes in PAX archives, and are stored in full in ustar archives.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TarFormat {
+	/// POSIX.1-1988 ustar headers, refusing paths, link targets and files too long or too large for them
+	Ustar,
+	/// GNU headers, storing long paths & link targets in GNU extension entries, and large sizes in binary
+	Gnu,
+	/// POSIX.1-2001 PAX headers, storing long paths & link targets, large sizes and sub-second modification times in extended headers
+	#[default]
+	Pax,
+}
+
+/// How symbolic links are archived
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Symlinks {
+	/// Store the files & directories symbolic links point to, as if they were at the link's path
+	///
+	/// A link to a directory it is inside is stored as an empty directory, rather than followed around the loop.
+	#[default]
+	Follow,
+	/// Store symbolic links as links
+	Store,
+}
+
+/// How FIFOs, sockets & device nodes are archived
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpecialFiles {
+	/// Store FIFOs & device nodes; sockets cannot be stored in an archive, and are skipped
+	#[default]
+	Store,
+	/// Leave special files out of the archive
+	Skip,
+	/// Fail when a special file is found
+	Error,
+}
+
+/// The sizes of an archive at each stage of writing it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArchiveStats {
+	/// The size of the uncompressed TAR data
+	pub uncompressed_size: u64,
+	/// The size of the TAR data after deduplication, or the size of the TAR data if it was not deduplicated
+	pub deduplicated_size: u64,
+	/// The size of the archive file, or the total size of its volumes
+	pub archive_size: u64,
+	/// The number of files the archive was written to
+	pub volumes: u32,
+}
+
+impl ArchiveStats {
+	/// How many times smaller the archive is than the uncompressed TAR data
+	pub fn compression_ratio(&self) -> f64 {
+		self.uncompressed_size as f64 / self.archive_size.max(1) as f64
+	}
+
+	/// How many bytes deduplication removed from the TAR data before it was compressed
+	pub fn dedup_savings(&self) -> u64 {
+		self.uncompressed_size
+			.saturating_sub(self.deduplicated_size)
+	}
+}
+
+/// Options controlling how archives are read
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+	/// Do not check extracted files against the hashes recorded in the archive
+	pub skip_verification: bool,
+	/// The dictionary to decompress archives with, rather than the one embedded in the archive
+	pub dictionary: Option<dictionary::Dictionary>,
+	/// The passphrase to decrypt encrypted archives with
+	#[cfg(feature = "encryption")]
+	pub passphrase: Option<crypto::Passphrase>,
+	/// Refuse to extract archives unless they are signed with this key
+	///
+	/// Every archive is verified before anything is extracted.
+	#[cfg(feature = "signing")]
+	pub verifying_key: Option<signing::VerifyingKey>,
+	/// Extract into a hidden staging directory beside the output path, which replaces it only once every archive has been extracted & verified
+	///
+	/// Whatever was at the output path is removed, rather than merged with the extracted files; if extraction fails, it is left untouched.
+	pub atomic: bool,
+	/// Keep a checkpoint journal while extracting, skipping whatever an earlier run that was interrupted already extracted
+	///
+	/// Extraction cannot be both atomic and resumed; see [`journal`].
+	pub resume: bool,
+	/// Called as each member is extracted from the archive
+	pub progress: Option<Progress>,
+}
+
+/// A callback reporting the progress of writing or extracting an archive
+///
+/// It is called once for each member of the archive, with the member's path within the archive and its size in bytes.
+#[derive(Clone)]
+pub struct Progress(Arc<ProgressFn>);
+
+/// The function called by [`Progress`]
+type ProgressFn = dyn Fn(&Path, u64) + Send + Sync;
+
+impl Progress {
+	/// Report progress by calling a function
+	///
+	/// # Arguments
+	///
+	/// * `callback` - Called with the path and size of each member of the archive
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// use larz::{CompressOptions, Progress};
+	///
+	/// let options = CompressOptions {
+	///     progress: Some(Progress::new(|member, size| println!("{} ({size} bytes)", member.display()))),
+	///     ..Default::default()
+	/// };
+	/// ```
+	pub fn new(callback: impl Fn(&Path, u64) + Send + Sync + 'static) -> Progress {
+		Progress(Arc::new(callback))
+	}
+
+	/// Report that a member of the archive has been written or extracted
+	///
+	/// # Arguments
+	///
+	/// * `member` - The path of the member within the archive
+	///
+	/// * `size` - The size of the member in bytes
+	pub(crate) fn report(&self, member: &Path, size: u64) {
+		(self.0)(member, size)
+	}
+}
+
+impl std::fmt::Debug for Progress {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("Progress")
+	}
+}
 
 /// Archive & compress a file or set of files
 ///
@@ -102,37 +365,51 @@ use std::path::PathBuf;
 pub fn compress_archive_streaming<W: Write>(
 	paths: Vec<PathBuf>,
 	output_path: PathBuf,
-	mut optional_logger: Option<&mut BufWriter<W>>,
+	optional_logger: Option<&mut BufWriter<W>>,
 ) {
-	std::fs::create_dir_all(output_path.parent().unwrap()).unwrap();
-
-	let f = File::create(&output_path).expect("Unable to create file");
-	let buf = BufWriter::new(f);
-	let compressor = lz4_flex::frame::FrameEncoder::new(buf);
-	let mut tar = tar::Builder::new(compressor);
-
-	for fs_path in paths {
-		if let Some(ref mut logger) = optional_logger {
-			writeln!(logger, "Compressing '{}' … ", fs_path.to_string_lossy()).unwrap();
-		}
-		match fs_path.is_dir() {
-			true => {
-				tar.append_dir_all(".", fs_path)
-					.expect("Failed to write to archive");
-			}
-			false => {
-				tar.append_path(fs_path)
-					.expect("Failed to write to archive");
-			}
-		}
-	}
+	compress_archive_streaming_with_options(
+		paths,
+		output_path,
+		&CompressOptions::default(),
+		optional_logger,
+	)
+	.expect("Failed to write to archive");
+}
 
-	let tar_compressor = tar.into_inner().expect("Unable to finish writing archive");
-	tar_compressor
-		.finish()
-		.expect("Unable to finish with compression")
-		.flush()
-		.unwrap();
+/// Archive & compress a file or set of files, with the given options
+///
+/// # Arguments
+///
+/// * `paths` - A list of paths pointing to files or directories intended to be archived
+///
+/// * `output_path` - Path to write the archive to
+///
+/// * `options` - Options controlling how the archive is written
+///
+/// * `optional_logger` - An optional `BufWriter` to log information to
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::{compress_archive_streaming_with_options, CompressOptions};
+/// use std::path::PathBuf;
+/// use std::io::StdoutLock;
+///
+/// let paths = vec![PathBuf::from("path/to/file"), PathBuf::from("path/to/directory")];
+/// let output_path = PathBuf::from("path/to/output.larz");
+///
+/// compress_archive_streaming_with_options::<StdoutLock>(paths, output_path, &CompressOptions::default(), None);
+/// ```
+#[cfg(feature = "streaming")]
+pub fn compress_archive_streaming_with_options<W: Write>(
+	paths: Vec<PathBuf>,
+	output_path: PathBuf,
+	options: &CompressOptions,
+	optional_logger: Option<&mut BufWriter<W>>,
+) -> std::io::Result<ArchiveStats> {
+	let mut writer = writer::ArchiveWriter::create(output_path, options)?;
+	writer.append_paths(paths, optional_logger)?;
+	writer.finish()
 }
 
 /// Extract & decompress an existing archive
@@ -160,15 +437,51 @@ pub fn compress_archive_streaming<W: Write>(
 /// ```
 #[cfg(feature = "streaming")]
 pub fn extract_archive_streaming(paths: Vec<PathBuf>, output_path: PathBuf) {
-	std::fs::create_dir_all(&output_path).unwrap();
+	extract_archive_streaming_with_options(paths, output_path, &ExtractOptions::default())
+		.expect("Could not extract archive");
+}
 
-	for file_path in paths {
-		let f = std::fs::File::open(file_path).expect("Could not read archive file");
-		let buf = BufReader::new(f);
-		let extractor = lz4_flex::frame::FrameDecoder::new(buf);
-		let mut tar = tar::Archive::new(extractor);
-		tar.unpack(&output_path).expect("Could not extract archive");
-	}
+/// Extract & decompress an existing archive, with the given options
+///
+/// # Arguments
+///
+/// * `paths` - A list of paths pointing to `larz` archives
+///
+/// * `output_path` - Path to write the extracted files to
+///
+/// * `options` - Options controlling how the archive is read
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::{extract_archive_streaming_with_options, ExtractOptions};
+/// use std::path::PathBuf;
+///
+/// let paths = vec![PathBuf::from("path/to/archive.larz")];
+/// let output_path = PathBuf::from("path/to/output");
+///
+/// extract_archive_streaming_with_options(paths, output_path, &ExtractOptions::default());
+/// ```
+#[cfg(feature = "streaming")]
+pub fn extract_archive_streaming_with_options(
+	paths: Vec<PathBuf>,
+	output_path: PathBuf,
+	options: &ExtractOptions,
+) -> std::io::Result<()> {
+	let archives = paths
+		.iter()
+		.map(|file_path| open_verified(file_path, options))
+		.collect::<std::io::Result<Vec<_>>>()?;
+	extract_into(&output_path, &paths, options, |output_path, mut journal| {
+		for archive in archives {
+			let (_, extractor) = open_archive_reader(archive, Codec::Lz4Frame, options)?;
+			extract::unpack(extractor, output_path, options, journal.as_deref_mut())?;
+			if let Some(journal) = journal.as_deref_mut() {
+				journal.end_archive()?;
+			}
+		}
+		Ok(())
+	})
 }
 
 /// Archive & compress a file or set of files, in memory
@@ -200,42 +513,50 @@ pub fn extract_archive_streaming(paths: Vec<PathBuf>, output_path: PathBuf) {
 pub fn compress_archive_memory<W: Write>(
 	paths: Vec<PathBuf>,
 	output_path: PathBuf,
-	mut optional_logger: Option<&mut BufWriter<W>>,
+	optional_logger: Option<&mut BufWriter<W>>,
 ) {
-	std::fs::create_dir_all(output_path.parent().unwrap()).unwrap();
-
-	let buf_tar: BufWriter<Vec<u8>> = BufWriter::new(Vec::new());
-	let mut tar = tar::Builder::new(buf_tar);
-
-	for fs_path in paths {
-		if let Some(ref mut logger) = optional_logger {
-			writeln!(logger, "Compressing '{}' … ", fs_path.to_string_lossy()).unwrap();
-		}
-		match fs_path.is_dir() {
-			true => {
-				tar.append_dir_all(".", fs_path)
-					.expect("Failed to write to archive");
-			}
-			false => {
-				tar.append_path(fs_path)
-					.expect("Failed to write to archive");
-			}
-		}
-	}
-
-	tar.finish().expect("Unable to finish with compression");
-
-	let mut buf_tar_again = tar.into_inner().unwrap();
-	buf_tar_again.flush().unwrap();
+	compress_archive_memory_with_options(
+		paths,
+		output_path,
+		&CompressOptions::default(),
+		optional_logger,
+	)
+	.expect("Failed to write to archive");
+}
 
-	std::fs::create_dir_all(output_path.parent().unwrap()).unwrap();
-	let f = File::create(&output_path).expect("Unable to create file");
-	let mut buf = BufWriter::new(f);
-	buf.write_all(&lz4_flex::block::compress_prepend_size(
-		&buf_tar_again.into_inner().unwrap(),
-	))
-	.unwrap_or_else(|_| panic!("Could not write data to {}", output_path.to_string_lossy())); // Write data to file
-	buf.flush().unwrap();
+/// Archive & compress a file or set of files in memory, with the given options
+///
+/// # Arguments
+///
+/// * `paths` - A list of paths pointing to files or directories intended to be archived
+///
+/// * `output_path` - Path to write the archive to
+///
+/// * `options` - Options controlling how the archive is written
+///
+/// * `optional_logger` - An optional `BufWriter` to log information to
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::{compress_archive_memory_with_options, CompressOptions};
+/// use std::path::PathBuf;
+/// use std::io::StdoutLock;
+///
+/// let paths = vec![PathBuf::from("path/to/file"), PathBuf::from("path/to/directory")];
+/// let output_path = PathBuf::from("path/to/output.larz");
+///
+/// compress_archive_memory_with_options::<StdoutLock>(paths, output_path, &CompressOptions::default(), None);
+/// ```
+pub fn compress_archive_memory_with_options<W: Write>(
+	paths: Vec<PathBuf>,
+	output_path: PathBuf,
+	options: &CompressOptions,
+	optional_logger: Option<&mut BufWriter<W>>,
+) -> std::io::Result<ArchiveStats> {
+	let mut writer = writer::ArchiveWriter::create_in_memory(output_path, options)?;
+	writer.append_paths(paths, optional_logger)?;
+	writer.finish()
 }
 
 /// Extract & decompress an existing archive, in memory
@@ -262,14 +583,1002 @@ pub fn compress_archive_memory<W: Write>(
 /// extract_archive_memory(paths, output_path);
 /// ```
 pub fn extract_archive_memory(paths: Vec<PathBuf>, output_path: PathBuf) {
-	std::fs::create_dir_all(&output_path).unwrap();
-
-	for file_path in paths {
-		let compressed = std::fs::read(file_path).expect("Could not read archive file");
-		let archive = lz4_flex::decompress_size_prepended(&compressed)
-			.expect("Could not decompress archive file");
-		let archive_bytes = &archive[..];
-		let mut tar = tar::Archive::new(archive_bytes);
-		tar.unpack(&output_path).expect("Could not extract archive");
+	extract_archive_memory_with_options(paths, output_path, &ExtractOptions::default())
+		.expect("Could not extract archive");
+}
+
+/// Extract & decompress an existing archive in memory, with the given options
+///
+/// # Arguments
+///
+/// * `paths` - A list of paths pointing to `larz` archives
+///
+/// * `output_path` - Path to write the extracted files to
+///
+/// * `options` - Options controlling how the archive is read
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::{extract_archive_memory_with_options, ExtractOptions};
+/// use std::path::PathBuf;
+///
+/// let paths = vec![PathBuf::from("path/to/archive.larz")];
+/// let output_path = PathBuf::from("path/to/output");
+///
+/// extract_archive_memory_with_options(paths, output_path, &ExtractOptions::default());
+/// ```
+pub fn extract_archive_memory_with_options(
+	paths: Vec<PathBuf>,
+	output_path: PathBuf,
+	options: &ExtractOptions,
+) -> std::io::Result<()> {
+	let archives = paths
+		.iter()
+		.map(|file_path| open_verified(file_path, options))
+		.collect::<std::io::Result<Vec<_>>>()?;
+	extract_into(&output_path, &paths, options, |output_path, mut journal| {
+		for archive in archives {
+			let (_, extractor) = open_archive_reader(archive, Codec::Lz4Block, options)?;
+			extract::unpack(extractor, output_path, options, journal.as_deref_mut())?;
+			if let Some(journal) = journal.as_deref_mut() {
+				journal.end_archive()?;
+			}
+		}
+		Ok(())
+	})
+}
+
+/// Restore a full backup followed by a chain of listed-incremental backups
+///
+/// The archives are extracted in order, removing the files each incremental backup records as deleted.
+/// Every archive must have been written with a snapshot file, starting with a full backup, and each must follow the one before it.
+///
+/// # Arguments
+///
+/// * `paths` - A list of paths pointing to `larz` archives, starting with the full backup
+///
+/// * `output_path` - Path to restore the files to
+///
+/// * `options` - Options controlling how the archives are read
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::{restore_archives, ExtractOptions};
+/// use std::path::PathBuf;
+///
+/// let paths = vec![PathBuf::from("path/to/full.larz"), PathBuf::from("path/to/incremental.larz")];
+/// let output_path = PathBuf::from("path/to/output");
+///
+/// restore_archives(paths, output_path, &ExtractOptions::default());
+/// ```
+pub fn restore_archives(
+	paths: Vec<PathBuf>,
+	output_path: PathBuf,
+	options: &ExtractOptions,
+) -> std::io::Result<()> {
+	let mut archives = paths
+		.iter()
+		.map(|file_path| open_verified(file_path, options))
+		.collect::<std::io::Result<Vec<_>>>()?;
+
+	// Check the whole chain before anything is written
+	let mut previous = None;
+	for (file_path, archive) in paths.iter().zip(&mut archives) {
+		let (header, _) = ArchiveHeader::read_from(&mut *archive)?;
+		previous = Some(incremental::check_chain(
+			file_path,
+			header.as_ref(),
+			previous.as_deref(),
+		)?);
+		std::io::Seek::rewind(archive)?;
+	}
+
+	extract_into(&output_path, &paths, options, |output_path, mut journal| {
+		for archive in archives {
+			let (_, extractor) = open_archive_reader(archive, Codec::Lz4Frame, options)?;
+			let deleted = extract::unpack(extractor, output_path, options, journal.as_deref_mut())?;
+			incremental::apply_deletions(output_path, &deleted)?;
+			if let Some(journal) = journal.as_deref_mut() {
+				journal.end_archive()?;
+			}
+		}
+		Ok(())
+	})
+}
+
+/// Extract into a directory, or into a staging directory that then replaces it if extraction is atomic
+///
+/// If extraction is resumed, the journal of the directory is removed once every archive has been extracted.
+///
+/// # Arguments
+///
+/// * `output_path` - Path to write the extracted files to
+///
+/// * `paths` - A list of paths pointing to the archives being extracted
+///
+/// * `options` - Options controlling how the archives are read
+///
+/// * `extract` - Extracts the archives into the directory it is given, recording them in the journal if there is one
+pub(crate) fn extract_into(
+	output_path: &Path,
+	paths: &[PathBuf],
+	options: &ExtractOptions,
+	extract: impl FnOnce(&Path, Option<&mut journal::ExtractJournal>) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+	match (options.atomic, options.resume) {
+		(false, false) => {
+			std::fs::create_dir_all(output_path)?;
+			extract(output_path, None)
+		}
+		(false, true) => {
+			std::fs::create_dir_all(output_path)?;
+			let mut journal = journal::ExtractJournal::open(output_path, paths)?;
+			extract(output_path, Some(&mut journal))?;
+			journal.finish()
+		}
+		(true, false) => {
+			let staging = atomic::StagingDir::create(output_path)?;
+			extract(staging.path(), None)?;
+			staging.commit()
+		}
+		(true, true) => Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			"Atomic extraction cannot be resumed",
+		)),
+	}
+}
+
+/// List the paths of the members of an archive
+///
+/// # Arguments
+///
+/// * `path` - Path to a `larz` archive, or to the first of its volumes
+///
+/// * `legacy_codec` - The codec to assume if the archive has no header, as written by larz 0.3 and earlier
+///
+/// * `options` - Options controlling how the archive is read
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::{header::Codec, list_archive, ExtractOptions};
+/// use std::path::PathBuf;
+///
+/// let members = list_archive(&PathBuf::from("path/to/archive.larz"), Codec::Lz4Frame, &ExtractOptions::default());
+/// ```
+pub fn list_archive(
+	path: &std::path::Path,
+	legacy_codec: Codec,
+	options: &ExtractOptions,
+) -> std::io::Result<Vec<PathBuf>> {
+	let mut reader = reader::ArchiveReader::open(path, legacy_codec, options)?;
+	let mut members = Vec::new();
+	for entry in reader.entries()? {
+		members.push(entry?.path().to_path_buf());
+	}
+	Ok(members)
+}
+
+/// Check that an archive can be read in full, and that its files match the hashes recorded in it, without extracting it
+///
+/// # Arguments
+///
+/// * `path` - Path to a `larz` archive, or to the first of its volumes
+///
+/// * `legacy_codec` - The codec to assume if the archive has no header, as written by larz 0.3 and earlier
+///
+/// * `options` - Options controlling how the archive is read
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::{header::Codec, test_archive, ExtractOptions};
+/// use std::path::PathBuf;
+///
+/// let result = test_archive(&PathBuf::from("path/to/archive.larz"), Codec::Lz4Frame, &ExtractOptions::default());
+/// ```
+pub fn test_archive(
+	path: &std::path::Path,
+	legacy_codec: Codec,
+	options: &ExtractOptions,
+) -> std::io::Result<()> {
+	let mut reader = reader::ArchiveReader::open(path, legacy_codec, options)?;
+	let mut mismatched = Vec::new();
+	for entry in reader.entries()? {
+		let mut entry = entry?;
+		match std::io::copy(&mut entry, &mut std::io::sink()) {
+			Err(e)
+				if e.get_ref()
+					.is_some_and(|e| e.is::<integrity::IntegrityError>()) =>
+			{
+				mismatched.push(entry.path().to_path_buf());
+			}
+			result => {
+				result?;
+			}
+		}
+		if let Some(progress) = &options.progress {
+			progress.report(entry.path(), entry.size());
+		}
+	}
+	match mismatched.is_empty() {
+		true => Ok(()),
+		false => Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			integrity::IntegrityError {
+				members: mismatched,
+			},
+		)),
+	}
+}
+
+/// Write the contents of members of an archive, one after another, without extracting it
+///
+/// Members are written in the order they are named, which need not be the order they are stored in; members stored before their turn are held in a scratch file until then.
+/// Hard links are written with the contents of the files they link to.
+/// An error is returned if a member is missing from the archive or is not a file, once every member found has been written.
+///
+/// # Arguments
+///
+/// * `path` - Path to a `larz` archive, or to the first of its volumes
+///
+/// * `members` - The paths of the members within the archive
+///
+/// * `legacy_codec` - The codec to assume if the archive has no header, as written by larz 0.3 and earlier
+///
+/// * `options` - Options controlling how the archive is read
+///
+/// * `output` - Where to write the members' contents
+///
+/// # Examples
+///
+/// ```rust
+/// use larz::{cat_archive, header::Codec, ExtractOptions};
+/// use std::path::PathBuf;
+///
+/// let result = cat_archive(&PathBuf::from("path/to/archive.larz"), &[PathBuf::from("etc/hosts")], Codec::Lz4Frame, &ExtractOptions::default(), &mut std::io::stdout());
+/// ```
+pub fn cat_archive<W: Write>(
+	path: &std::path::Path,
+	members: &[PathBuf],
+	legacy_codec: Codec,
+	options: &ExtractOptions,
+	output: &mut W,
+) -> std::io::Result<()> {
+	let members: Vec<PathBuf> = members.iter().map(|member| member_key(member)).collect();
+	let is_file = |entry_type: tar::EntryType| {
+		entry_type.is_file() || entry_type.is_contiguous() || entry_type.is_gnu_sparse()
+	};
+	let mut reader = reader::ArchiveReader::open(path, legacy_codec, options)?;
+	let mut spooled = std::collections::HashMap::new();
+	let mut links = std::collections::HashMap::new();
+	let mut scratch = None;
+	let mut unsuitable = Vec::new();
+	let mut next = 0;
+	for entry in reader.entries()? {
+		if next == members.len() {
+			break;
+		}
+		let mut entry = entry?;
+		let key = member_key(entry.path());
+		if !members[next..].contains(&key) || spooled.contains_key(&key) {
+			continue;
+		}
+		let entry_type = entry.entry_type();
+		if entry_type.is_hard_link() {
+			if let Some(target) = entry.link_name()? {
+				links.insert(key, member_key(&target));
+			}
+		} else if !is_file(entry_type) {
+			unsuitable.push(key.clone());
+			spooled.insert(key, None);
+		} else if members[next] == key && !members[next + 1..].contains(&key) {
+			std::io::copy(&mut entry, output)?;
+			next += 1;
+		} else {
+			let span = spool(&mut scratch, &mut entry)?;
+			spooled.insert(key, Some(span));
+		}
+
+		// Write any members whose turn has come, having been stored earlier
+		while let Some(span) = members.get(next).and_then(|member| spooled.get(member)) {
+			copy_spooled(&mut scratch, *span, output)?;
+			next += 1;
+		}
+	}
+
+	// Hard links have the contents of the files they link to, which are stored before them, so those are read again
+	if !links.is_empty() {
+		let mut reader = reader::ArchiveReader::open(path, legacy_codec, options)?;
+		for entry in reader.entries()? {
+			let mut entry = entry?;
+			let key = member_key(entry.path());
+			if !is_file(entry.entry_type()) || !links.values().any(|target| *target == key) {
+				continue;
+			}
+			let span = spool(&mut scratch, &mut entry)?;
+			links.retain(|member, target| {
+				let linked = *target == key;
+				if linked {
+					spooled.insert(member.clone(), Some(span));
+				}
+				!linked
+			});
+			if links.is_empty() {
+				break;
+			}
+		}
+	}
+
+	// Write the members found after one that is missing
+	let mut missing = Vec::new();
+	for member in &members[next..] {
+		match spooled.get(member) {
+			Some(span) => copy_spooled(&mut scratch, *span, output)?,
+			None => missing.push(format!("'{}'", escape_path(member))),
+		}
+	}
+	output.flush()?;
+
+	if !unsuitable.is_empty() {
+		let unsuitable: Vec<String> = unsuitable
+			.iter()
+			.map(|member| format!("'{}'", escape_path(member)))
+			.collect();
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			format!("Not a file in the archive: {}", unsuitable.join(", ")),
+		));
+	}
+	match missing.is_empty() {
+		true => Ok(()),
+		false => Err(std::io::Error::new(
+			std::io::ErrorKind::NotFound,
+			format!("Not found in the archive: {}", missing.join(", ")),
+		)),
+	}
+}
+
+/// Hold the contents of a member in a scratch file, until it is its turn to be written
+///
+/// Returns where the member's contents are in the scratch file, as an offset and a length.
+///
+/// # Arguments
+///
+/// * `scratch` - The scratch file holding members' contents, which is created if there is none yet
+///
+/// * `member` - A reader over the member's contents
+fn spool<R: Read>(scratch: &mut Option<Scratch>, member: &mut R) -> std::io::Result<(u64, u64)> {
+	use std::io::{Seek, SeekFrom};
+
+	let scratch = match scratch.as_mut() {
+		Some(scratch) => scratch,
+		None => scratch.insert(crate::scratch()?),
+	};
+	let offset = scratch.seek(SeekFrom::End(0))?;
+	let length = std::io::copy(member, scratch)?;
+	Ok((offset, length))
+}
+
+/// Write the contents of a member held in a scratch file
+///
+/// # Arguments
+///
+/// * `scratch` - The scratch file holding members' contents
+///
+/// * `span` - Where the member's contents are in the scratch file, as an offset and a length; or `None` if it was not held, as it is not a file
+///
+/// * `output` - Where to write the member's contents
+fn copy_spooled<W: Write>(
+	scratch: &mut Option<Scratch>,
+	span: Option<(u64, u64)>,
+	output: &mut W,
+) -> std::io::Result<()> {
+	use std::io::{Seek, SeekFrom};
+
+	if let (Some((offset, length)), Some(scratch)) = (span, scratch.as_mut()) {
+		scratch.seek(SeekFrom::Start(offset))?;
+		std::io::copy(&mut Read::take(&mut *scratch, length), output)?;
+	}
+	Ok(())
+}
+
+/// The path of a member within an archive, without any leading `/` or `.` components, so that it can be compared with others
+///
+/// # Arguments
+///
+/// * `path` - The path of the member
+fn member_key(path: &std::path::Path) -> PathBuf {
+	path.components()
+		.filter(|component| matches!(component, std::path::Component::Normal(_)))
+		.collect()
+}
+
+/// Show a path so that no two paths look alike, even if they are not valid Unicode
+///
+/// Backslashes are doubled, control characters are written as `\n`, `\r`, `\t` or `\u{…}`, and bytes that are not valid UTF-8 are written as `\x…`.
+/// Windows separates paths with backslashes, so they are left alone there, and unpaired surrogates are written as `\u{…}` instead.
+///
+/// # Arguments
+///
+/// * `path` - The path to show
+pub fn escape_path(path: &Path) -> String {
+	let mut escaped = String::new();
+	#[cfg(unix)]
+	for chunk in std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()).utf8_chunks() {
+		for character in chunk.valid().chars() {
+			escape_char(&mut escaped, character);
+		}
+		for byte in chunk.invalid() {
+			escaped.push_str(&format!("\\x{byte:02x}"));
+		}
+	}
+	#[cfg(windows)]
+	for unit in char::decode_utf16(std::os::windows::ffi::OsStrExt::encode_wide(
+		path.as_os_str(),
+	)) {
+		match unit {
+			Ok('\\') => escaped.push('\\'),
+			Ok(character) => escape_char(&mut escaped, character),
+			Err(e) => escaped.push_str(&format!("\\u{{{:x}}}", e.unpaired_surrogate())),
+		}
+	}
+	#[cfg(not(any(unix, windows)))]
+	for character in path.to_string_lossy().chars() {
+		escape_char(&mut escaped, character);
+	}
+	escaped
+}
+
+/// Append a character to an escaped path, escaping it if it is a backslash or a control character
+///
+/// # Arguments
+///
+/// * `escaped` - The escaped path so far
+///
+/// * `character` - The character to append
+fn escape_char(escaped: &mut String, character: char) {
+	match character {
+		'\\' => escaped.push_str("\\\\"),
+		'\n' => escaped.push_str("\\n"),
+		'\r' => escaped.push_str("\\r"),
+		'\t' => escaped.push_str("\\t"),
+		character if character.is_control() => {
+			escaped.push_str(&format!("\\u{{{:x}}}", character as u32))
+		}
+		character => escaped.push(character),
+	}
+}
+
+/// Remove the temporary files of any archives still being written, and the staging directories of any atomic extractions
+///
+/// Archives are written to a temporary file beside them, which replaces the archive once it is complete and is removed if writing it fails; atomic extraction does the same with a directory.
+/// A process exiting on a signal does not get the chance to remove them, so call this from its signal handler first.
+pub fn remove_temporary_files() {
+	atomic::remove_pending();
+}
+
+/// Open an archive, returning its header and a reader over the uncompressed TAR data
+///
+/// Versioned archives are decoded using the codec named in their header, regardless of `legacy_codec`.
+///
+/// # Arguments
+///
+/// * `path` - Path to a `larz` archive
+///
+/// * `legacy_codec` - The codec to assume if the archive has no header, as written by larz 0.3 and earlier
+///
+/// * `options` - Options controlling how the archive is read
+pub fn open_archive(
+	path: &std::path::Path,
+	legacy_codec: Codec,
+	options: &ExtractOptions,
+) -> std::io::Result<(Option<ArchiveHeader>, Box<dyn Read>)> {
+	open_archive_reader(open_archive_file(path)?, legacy_codec, options)
+}
+
+/// Open an archive being read from a reader, returning its header and a reader over the uncompressed TAR data
+///
+/// Versioned archives are decoded using the codec named in their header, regardless of `legacy_codec`.
+///
+/// # Arguments
+///
+/// * `archive` - A reader over the bytes of a `larz` archive
+///
+/// * `legacy_codec` - The codec to assume if the archive has no header, as written by larz 0.3 and earlier
+///
+/// * `options` - Options controlling how the archive is read
+pub fn open_archive_reader<R: Read + 'static>(
+	archive: R,
+	legacy_codec: Codec,
+	options: &ExtractOptions,
+) -> std::io::Result<(Option<ArchiveHeader>, Box<dyn Read>)> {
+	let (header, body) = ArchiveHeader::read_from(archive)?;
+	let codec = header.as_ref().map_or(legacy_codec, |header| header.codec);
+	let mut body: Box<dyn Read> = match header {
+		Some(ref header) if header.flags.contains(Flags::ENCRYPTED) => {
+			decrypt_body(body, header, options)?
+		}
+		_ => Box::new(body),
+	};
+	let reader: Box<dyn Read> = match codec {
+		#[cfg(feature = "streaming")]
+		Codec::Lz4Frame => Box::new(FramesDecoder(lz4_flex::frame::FrameDecoder::new(
+			BufReader::new(body),
+		))),
+		#[cfg(not(feature = "streaming"))]
+		Codec::Lz4Frame => {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::Unsupported,
+				"Streaming archives require the `streaming` feature",
+			))
+		}
+		Codec::Lz4Block => {
+			let mut compressed = Vec::new();
+			body.read_to_end(&mut compressed)?;
+			let archive = lz4_flex::decompress_size_prepended(&compressed)
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+			Box::new(std::io::Cursor::new(archive))
+		}
+		Codec::Lz4DictBlocks => {
+			let header = header.as_ref().ok_or_else(|| {
+				std::io::Error::new(
+					std::io::ErrorKind::InvalidInput,
+					"Archives compressed with a dictionary always have a header",
+				)
+			})?;
+			let dictionary = dictionary::Dictionary::load(header, options.dictionary.as_ref())?;
+			Box::new(dictionary::DictionaryReader::new(body, dictionary))
+		}
+	};
+	let reader: Box<dyn Read> = match header {
+		Some(ref header) if header.flags.contains(Flags::DEDUPLICATED) => {
+			Box::new(dedup::DedupReader::new(reader)?)
+		}
+		_ => reader,
+	};
+	Ok((header, reader))
+}
+
+/// The file holding an archive, or its volumes joined together
+pub(crate) trait ArchiveContents: Read + std::io::Seek {}
+
+impl<F: Read + std::io::Seek> ArchiveContents for F {}
+
+/// Open the file holding an archive, joining its volumes together if it has been split into volumes
+///
+/// # Arguments
+///
+/// * `path` - Path to a `larz` archive, or to the first of its volumes
+pub(crate) fn open_archive_file(
+	path: &std::path::Path,
+) -> std::io::Result<BufReader<Box<dyn ArchiveContents>>> {
+	let file: Box<dyn ArchiveContents> = match volume::first_volume(path) {
+		Some(first_volume) => Box::new(volume::VolumeReader::open(&first_volume)?),
+		None => Box::new(File::open(path)?),
+	};
+	Ok(BufReader::new(file))
+}
+
+/// Create the header of an archive being written
+///
+/// # Arguments
+///
+/// * `codec` - The codec used to compress the body of the archive
+///
+/// * `options` - Options controlling how the archive is written
+///
+/// * `incremental` - The state of the listed-incremental backup being written, if any
+fn new_header(
+	codec: Codec,
+	options: &CompressOptions,
+	incremental: Option<&incremental::Incremental>,
+) -> ArchiveHeader {
+	let mut header = ArchiveHeader::new(codec);
+	if let Some(volume_size) = options.volume_size {
+		header.metadata.insert(
+			volume::VOLUME_SIZE_KEY.to_owned(),
+			volume_size.to_le_bytes().to_vec(),
+		);
+	}
+	if let Some(incremental) = incremental {
+		incremental.store(&mut header);
+	}
+	if options.deduplicate {
+		header.flags.insert(Flags::DEDUPLICATED);
+	}
+	if let Some(dictionary) = &options.dictionary {
+		dictionary.store(&mut header, options.embed_dictionary);
+	}
+	header
+}
+
+/// Open the file holding an archive to extract it, first checking its signature if a trusted key was given
+///
+/// The signature is checked against the same handles the archive is then read from, so the archive cannot be swapped in between.
+///
+/// # Arguments
+///
+/// * `path` - Path to a `larz` archive, or to the first of its volumes
+///
+/// * `options` - Options controlling how the archive is read
+pub(crate) fn open_verified(
+	path: &Path,
+	#[allow(unused_variables)] options: &ExtractOptions,
+) -> std::io::Result<BufReader<Box<dyn ArchiveContents>>> {
+	#[allow(unused_mut)]
+	let mut archive = open_archive_file(path)?;
+	#[cfg(feature = "signing")]
+	if let Some(verifying_key) = &options.verifying_key {
+		signing::verify_reader(path, &mut archive, verifying_key)?;
+		std::io::Seek::rewind(&mut archive)?;
+	}
+	Ok(archive)
+}
+
+/// Wrap the body of an encrypted archive so that it is decrypted as it is read
+///
+/// # Arguments
+///
+/// * `body` - The encrypted body of the archive
+///
+/// * `header` - The header of the archive
+///
+/// * `options` - Options controlling how the archive is read
+#[cfg(feature = "encryption")]
+fn decrypt_body<R: Read + 'static>(
+	body: R,
+	header: &ArchiveHeader,
+	options: &ExtractOptions,
+) -> std::io::Result<Box<dyn Read>> {
+	let passphrase = options.passphrase.as_ref().ok_or_else(|| {
+		std::io::Error::new(
+			std::io::ErrorKind::PermissionDenied,
+			"Archive is encrypted; a passphrase is required",
+		)
+	})?;
+	Ok(Box::new(crypto::DecryptReader::new(
+		body, passphrase, header,
+	)?))
+}
+
+/// Wrap the body of an encrypted archive so that it is decrypted as it is read
+///
+/// # Arguments
+///
+/// * `body` - The encrypted body of the archive
+///
+/// * `header` - The header of the archive
+///
+/// * `options` - Options controlling how the archive is read
+#[cfg(not(feature = "encryption"))]
+fn decrypt_body<R: Read + 'static>(
+	_body: R,
+	_header: &ArchiveHeader,
+	_options: &ExtractOptions,
+) -> std::io::Result<Box<dyn Read>> {
+	Err(std::io::Error::new(
+		std::io::ErrorKind::Unsupported,
+		"Encrypted archives require the `encryption` feature",
+	))
+}
+
+/// An anonymous temporary file, holding data too large to keep in memory
+///
+/// WebAssembly has no filesystem, so the data is kept in memory there.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type Scratch = File;
+
+/// An anonymous temporary file, holding data too large to keep in memory
+///
+/// WebAssembly has no filesystem, so the data is kept in memory there.
+#[cfg(target_arch = "wasm32")]
+pub(crate) type Scratch = std::io::Cursor<Vec<u8>>;
+
+/// Create an empty [`Scratch`] file, which is deleted once it is dropped
+pub(crate) fn scratch() -> std::io::Result<Scratch> {
+	#[cfg(not(target_arch = "wasm32"))]
+	return tempfile::tempfile();
+	#[cfg(target_arch = "wasm32")]
+	return Ok(std::io::Cursor::new(Vec::new()));
+}
+
+/// Where the TAR data of an archive is written to, before it is compressed
+enum TarWriter<W: Write> {
+	/// The TAR data is compressed as-is, counting its size
+	Plain(W, u64),
+	/// Repeated chunks of the TAR data are stored once
+	Deduplicated(dedup::DedupWriter<W>),
+}
+
+impl<W: Write> TarWriter<W> {
+	/// Prepare to write TAR data
+	///
+	/// # Arguments
+	///
+	/// * `inner` - Where the TAR data is written to
+	///
+	/// * `deduplicate` - Whether to deduplicate the TAR data
+	fn new(inner: W, deduplicate: bool) -> TarWriter<W> {
+		match deduplicate {
+			true => TarWriter::Deduplicated(dedup::DedupWriter::new(inner)),
+			false => TarWriter::Plain(inner, 0),
+		}
+	}
+
+	/// Finish writing the TAR data, returning the underlying writer and the sizes of the data written
+	fn finish(self) -> std::io::Result<(W, ArchiveStats)> {
+		match self {
+			TarWriter::Plain(inner, size) => Ok((
+				inner,
+				ArchiveStats {
+					uncompressed_size: size,
+					deduplicated_size: size,
+					..ArchiveStats::default()
+				},
+			)),
+			TarWriter::Deduplicated(deduplicator) => {
+				let (inner, stats) = deduplicator.finish()?;
+				Ok((
+					inner,
+					ArchiveStats {
+						uncompressed_size: stats.input_size,
+						deduplicated_size: stats.output_size,
+						..ArchiveStats::default()
+					},
+				))
+			}
+		}
+	}
+}
+
+impl<W: Write> Write for TarWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			TarWriter::Plain(inner, size) => {
+				let length = inner.write(buf)?;
+				*size += length as u64;
+				Ok(length)
+			}
+			TarWriter::Deduplicated(deduplicator) => deduplicator.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			TarWriter::Plain(inner, _) => inner.flush(),
+			TarWriter::Deduplicated(deduplicator) => deduplicator.flush(),
+		}
+	}
+}
+
+/// Compresses the TAR data of a streaming archive
+#[cfg(feature = "streaming")]
+enum Compressor<W: Write> {
+	/// Compress into an LZ4 frame
+	Frame(Box<lz4_flex::frame::FrameEncoder<W>>),
+	/// Compress into LZ4 blocks using a dictionary
+	Dictionary(dictionary::DictionaryWriter<W>),
+}
+
+#[cfg(feature = "streaming")]
+impl<W: Write> Compressor<W> {
+	/// Finish compressing, returning the underlying writer
+	fn finish(self) -> std::io::Result<W> {
+		match self {
+			Compressor::Frame(encoder) => encoder.finish().map_err(std::io::Error::other),
+			Compressor::Dictionary(encoder) => encoder.finish(),
+		}
+	}
+
+	/// End the LZ4 frame being written, so that what follows is compressed independently of it, returning the underlying writer
+	fn end_frame(&mut self) -> std::io::Result<&mut W> {
+		match self {
+			Compressor::Frame(encoder) => {
+				encoder.try_finish().map_err(std::io::Error::other)?;
+				Ok(encoder.get_mut())
+			}
+			Compressor::Dictionary(_) => Err(std::io::Error::new(
+				std::io::ErrorKind::Unsupported,
+				"Archives compressed with a dictionary are not written in frames",
+			)),
+		}
+	}
+}
+
+#[cfg(feature = "streaming")]
+impl<W: Write> Write for Compressor<W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			Compressor::Frame(encoder) => encoder.write(buf),
+			Compressor::Dictionary(encoder) => encoder.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			Compressor::Frame(encoder) => encoder.flush(),
+			Compressor::Dictionary(encoder) => encoder.flush(),
+		}
+	}
+}
+
+/// Decompresses a series of LZ4 frames, one after another
+///
+/// [`lz4_flex::frame::FrameDecoder`] stops at the end of each frame, so it is asked for more until the compressed data runs out.
+#[cfg(feature = "streaming")]
+struct FramesDecoder<R: std::io::BufRead>(lz4_flex::frame::FrameDecoder<R>);
+
+#[cfg(feature = "streaming")]
+impl<R: std::io::BufRead> Read for FramesDecoder<R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		loop {
+			let length = self.0.read(buf)?;
+			if length != 0 || buf.is_empty() || self.0.get_mut().fill_buf()?.is_empty() {
+				return Ok(length);
+			}
+		}
+	}
+}
+
+/// The file or files an archive is written to
+enum ArchiveFile {
+	/// The archive is written to a single file
+	Single(BufWriter<atomic::AtomicFile>),
+	/// The archive is split into volumes
+	Split(volume::VolumeWriter),
+}
+
+impl ArchiveFile {
+	/// Create the file or files an archive is written to
+	///
+	/// # Arguments
+	///
+	/// * `output_path` - Path to write the archive to
+	///
+	/// * `options` - Options controlling how the archive is written
+	fn create(
+		output_path: &std::path::Path,
+		options: &CompressOptions,
+	) -> std::io::Result<ArchiveFile> {
+		Ok(match options.volume_size {
+			Some(volume_size) => ArchiveFile::Split(volume::VolumeWriter::create(
+				output_path,
+				volume_size,
+				options.no_clobber,
+			)?),
+			None => ArchiveFile::Single(BufWriter::new(atomic::AtomicFile::create(
+				output_path,
+				options.no_clobber,
+			)?)),
+		})
+	}
+
+	/// Create the file an archive is written to, or reopen it to resume writing it, keeping it if writing fails
+	///
+	/// # Arguments
+	///
+	/// * `output_path` - Path to write the archive to
+	///
+	/// * `options` - Options controlling how the archive is written
+	///
+	/// * `offset` - How much of the partial archive to keep, or `None` to start it again
+	#[cfg(feature = "streaming")]
+	fn resume(
+		output_path: &std::path::Path,
+		options: &CompressOptions,
+		offset: Option<u64>,
+	) -> std::io::Result<ArchiveFile> {
+		Ok(ArchiveFile::Single(BufWriter::new(
+			atomic::AtomicFile::resume(output_path, options.no_clobber, offset)?,
+		)))
+	}
+
+	/// Sync what has been written so far to disk, returning its length
+	#[cfg(feature = "streaming")]
+	fn sync(&mut self) -> std::io::Result<u64> {
+		match self {
+			ArchiveFile::Single(f) => {
+				f.flush()?;
+				f.get_mut().sync()
+			}
+			ArchiveFile::Split(_) => Err(std::io::Error::new(
+				std::io::ErrorKind::Unsupported,
+				"Archives split into volumes cannot be resumed",
+			)),
+		}
+	}
+
+	/// Finish writing the archive, replacing any existing one, and returning the number of files written and their total size
+	fn finish(self) -> std::io::Result<(u32, u64)> {
+		match self {
+			ArchiveFile::Single(f) => {
+				let f = f.into_inner().map_err(|e| e.into_error())?.commit()?;
+				Ok((1, f.metadata()?.len()))
+			}
+			ArchiveFile::Split(volumes) => volumes.finish(),
+		}
+	}
+}
+
+impl Write for ArchiveFile {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			ArchiveFile::Single(f) => f.write(buf),
+			ArchiveFile::Split(volumes) => volumes.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			ArchiveFile::Single(f) => f.flush(),
+			ArchiveFile::Split(volumes) => volumes.flush(),
+		}
+	}
+}
+
+/// Where the compressed body of an archive is written to
+enum BodyWriter<W: Write> {
+	/// The body is written as-is
+	Plain(W),
+	/// The body is encrypted as it is written
+	#[cfg(feature = "encryption")]
+	Encrypted(crypto::EncryptWriter<W>),
+}
+
+impl<W: Write> BodyWriter<W> {
+	/// Write the header of an archive, and prepare to write its body
+	///
+	/// # Arguments
+	///
+	/// * `inner` - Where the archive is written to
+	///
+	/// * `header` - The header of the archive
+	///
+	/// * `options` - Options controlling how the archive is written
+	fn begin(
+		mut inner: W,
+		#[allow(unused_mut)] mut header: ArchiveHeader,
+		options: &CompressOptions,
+	) -> std::io::Result<BodyWriter<W>> {
+		#[cfg(feature = "encryption")]
+		if let Some(passphrase) = &options.passphrase {
+			let mut encryptor = crypto::EncryptWriter::new(inner, passphrase, &mut header)?;
+			header.write_to(encryptor.get_mut())?;
+			return Ok(BodyWriter::Encrypted(encryptor));
+		}
+		#[cfg(not(feature = "encryption"))]
+		let _ = options;
+		header.write_to(&mut inner)?;
+		Ok(BodyWriter::Plain(inner))
+	}
+
+	/// Finish writing the body, returning the underlying writer
+	fn finish(self) -> std::io::Result<W> {
+		match self {
+			BodyWriter::Plain(inner) => Ok(inner),
+			#[cfg(feature = "encryption")]
+			BodyWriter::Encrypted(encryptor) => encryptor.finish(),
+		}
+	}
+}
+
+impl<W: Write> Write for BodyWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			BodyWriter::Plain(inner) => inner.write(buf),
+			#[cfg(feature = "encryption")]
+			BodyWriter::Encrypted(encryptor) => encryptor.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			BodyWriter::Plain(inner) => inner.flush(),
+			#[cfg(feature = "encryption")]
+			BodyWriter::Encrypted(encryptor) => encryptor.flush(),
+		}
 	}
 }
@@ -15,11 +15,15 @@
 
 use clap::{arg, crate_version, value_parser, ArgMatches, Command};
 use clean_path::Clean;
+use larz::crypto::Passphrase;
+use larz::dictionary::Dictionary;
+use larz::header::Codec;
+use larz::signing;
 use lazy_static::lazy_static;
 use mimalloc::MiMalloc;
 use std::{
 	io::{BufWriter, StdoutLock, Write},
-	path::PathBuf,
+	path::{Path, PathBuf},
 };
 use ticky::Stopwatch;
 
@@ -41,20 +45,135 @@ lazy_static! {
 	  .about("Archive & compress a file or set of files")
 	  .arg(arg!(<PATH> "Path to a file or directory").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(1))
 	  .arg(arg!(<OUT> "Specify an output file path for the archive").required(true).value_parser(value_parser!(PathBuf)).num_args(1))
-	  .arg(arg!(-m --memory "Perform this operation solely in memory")))
+	  .arg(arg!(-m --memory "Perform this operation solely in memory"))
+	  .arg(arg!(-f --force "Replace an existing archive; this is the default, and the flag is accepted for symmetry with --no-clobber"))
+	  .arg(arg!(-n --"no-clobber" "Never replace an existing archive").conflicts_with("force"))
+	  .arg(arg!(--resume "Keep a checkpoint journal while writing, and continue from the last checkpoint if an earlier run was interrupted").conflicts_with_all(["memory", "dedup", "volume-size", "listed-incremental", "dictionary", "passphrase-prompt", "passphrase-file", "passphrase-env"]))
+	  .arg(arg!(-p --"passphrase-prompt" "Encrypt the archive with a passphrase entered at a prompt").conflicts_with_all(["passphrase-file", "passphrase-env"]))
+	  .arg(arg!(--"passphrase-file" <FILE> "Encrypt the archive with a passphrase read from a file").value_parser(value_parser!(PathBuf)).conflicts_with("passphrase-env"))
+	  .arg(arg!(--"passphrase-env" <VARIABLE> "Encrypt the archive with a passphrase read from an environment variable"))
+	  .arg(arg!(--dedup "Store repeated regions of the archived files only once"))
+	  .arg(arg!(--"link-duplicates" "Store files with identical contents only once, extracting them as hard links to the same file"))
+	  .arg(arg!(--"store-symlinks" "Store symbolic links as links, rather than the files & directories they point to"))
+	  .arg(arg!(--"one-file-system" "Do not descend into directories on a different filesystem; mount points are stored as empty directories"))
+	  .arg(arg!(--"max-depth" <DEPTH> "Do not descend more than this many levels beneath the given directories").value_parser(value_parser!(usize)))
+	  .arg(arg!(--"special-files" <POLICY> "Whether to store, skip or refuse FIFOs, sockets & device nodes").value_parser(["store", "skip", "error"]).default_value("store"))
+	  .arg(arg!(--format <FORMAT> "The TAR format to write: ustar, GNU, or PAX with sub-second modification times").value_parser(["ustar", "gnu", "pax"]).default_value("pax"))
+	  .arg(arg!(--"volume-size" <SIZE> "Split the archive into volumes of at most this size (eg, '4G' or '2000M'), named OUT.001, OUT.002, …").value_parser(parse_size))
+	  .arg(arg!(-g --"listed-incremental" <SNAPSHOT> "Only archive files that are new or changed since the backup recorded in this snapshot file, then update it").value_parser(value_parser!(PathBuf)))
+	  .arg(arg!(-d --dictionary <DICTIONARY> "Compress the archive with a dictionary trained by 'larz train-dict'").value_parser(value_parser!(PathBuf)))
+	  .arg(arg!(--"embed-dictionary" "Store the dictionary in the archive, so it can be extracted without it").requires("dictionary")))
 	.subcommand(Command::new("extract")
 	  .about("Extract & decompress an archive")
-	  .arg(arg!(<PATH> "Path to an archive file").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(1))
+	  .arg(arg!(<PATH> "Path to an archive file, or the first of its volumes").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(1))
 	  .arg(arg!(<OUT> "Specify an output directory path for the extracted contents").required(true).value_parser(value_parser!(PathBuf)).num_args(1))
-	  .arg(arg!(-m --memory "Perform this operation solely in memory")))
-  .get_matches_from(wild::args());
+	  .arg(arg!(-m --memory "Perform this operation solely in memory"))
+	  .arg(arg!(--atomic "Extract into a staging directory that replaces the output directory only once everything is extracted & verified"))
+	  .arg(arg!(--resume "Keep a checkpoint journal while extracting, and skip whatever an earlier run that was interrupted already extracted").conflicts_with("atomic"))
+	  .arg(arg!(-p --"passphrase-prompt" "Decrypt the archive with a passphrase entered at a prompt").conflicts_with_all(["passphrase-file", "passphrase-env"]))
+	  .arg(arg!(--"passphrase-file" <FILE> "Decrypt the archive with a passphrase read from a file").value_parser(value_parser!(PathBuf)).conflicts_with("passphrase-env"))
+	  .arg(arg!(--"passphrase-env" <VARIABLE> "Decrypt the archive with a passphrase read from an environment variable"))
+	  .arg(arg!(--"verify-key" <KEY> "Refuse to extract archives unless they are signed with this public key").value_parser(value_parser!(PathBuf)))
+	  .arg(arg!(--"no-verify" "Do not check extracted files against the hashes recorded in the archive"))
+	  .arg(arg!(-d --dictionary <DICTIONARY> "Decompress the archive with this dictionary, rather than the one embedded in it").value_parser(value_parser!(PathBuf))))
+	.subcommand(Command::new("list")
+	  .about("List the contents of an archive")
+	  .arg(arg!(<PATH> "Path to an archive file, or the first of its volumes").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(1))
+	  .arg(arg!(-m --memory "Treat archives written by larz 0.3 and earlier as in-memory archives"))
+	  .arg(arg!(-p --"passphrase-prompt" "Decrypt the archive with a passphrase entered at a prompt").conflicts_with_all(["passphrase-file", "passphrase-env"]))
+	  .arg(arg!(--"passphrase-file" <FILE> "Decrypt the archive with a passphrase read from a file").value_parser(value_parser!(PathBuf)).conflicts_with("passphrase-env"))
+	  .arg(arg!(--"passphrase-env" <VARIABLE> "Decrypt the archive with a passphrase read from an environment variable"))
+	  .arg(arg!(--"verify-key" <KEY> "Refuse to list archives unless they are signed with this public key").value_parser(value_parser!(PathBuf)))
+	  .arg(arg!(--"no-verify" "Do not check files against the hashes recorded in the archive"))
+	  .arg(arg!(-d --dictionary <DICTIONARY> "Decompress the archive with this dictionary, rather than the one embedded in it").value_parser(value_parser!(PathBuf))))
+	.subcommand(Command::new("test")
+	  .about("Check that an archive can be read in full, and that its files match the hashes recorded in it, without extracting it")
+	  .arg(arg!(<PATH> "Path to an archive file, or the first of its volumes").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(1))
+	  .arg(arg!(-m --memory "Treat archives written by larz 0.3 and earlier as in-memory archives"))
+	  .arg(arg!(-p --"passphrase-prompt" "Decrypt the archive with a passphrase entered at a prompt").conflicts_with_all(["passphrase-file", "passphrase-env"]))
+	  .arg(arg!(--"passphrase-file" <FILE> "Decrypt the archive with a passphrase read from a file").value_parser(value_parser!(PathBuf)).conflicts_with("passphrase-env"))
+	  .arg(arg!(--"passphrase-env" <VARIABLE> "Decrypt the archive with a passphrase read from an environment variable"))
+	  .arg(arg!(--"verify-key" <KEY> "Refuse to test archives unless they are signed with this public key").value_parser(value_parser!(PathBuf)))
+	  .arg(arg!(--"no-verify" "Only check that the archive can be read, not that its files match their recorded hashes"))
+	  .arg(arg!(-d --dictionary <DICTIONARY> "Decompress the archive with this dictionary, rather than the one embedded in it").value_parser(value_parser!(PathBuf))))
+	.subcommand(Command::new("cat")
+	  .about("Write the contents of files in an archive to standard output, in the order they are named")
+	  .arg(arg!(<PATH> "Path to an archive file, or the first of its volumes").required(true).value_parser(value_parser!(PathBuf)).display_order(1))
+	  .arg(arg!(<MEMBER> "Path to a file within the archive").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(2))
+	  .arg(arg!(-m --memory "Treat archives written by larz 0.3 and earlier as in-memory archives"))
+	  .arg(arg!(-p --"passphrase-prompt" "Decrypt the archive with a passphrase entered at a prompt").conflicts_with_all(["passphrase-file", "passphrase-env"]))
+	  .arg(arg!(--"passphrase-file" <FILE> "Decrypt the archive with a passphrase read from a file").value_parser(value_parser!(PathBuf)).conflicts_with("passphrase-env"))
+	  .arg(arg!(--"passphrase-env" <VARIABLE> "Decrypt the archive with a passphrase read from an environment variable"))
+	  .arg(arg!(--"verify-key" <KEY> "Refuse to read archives unless they are signed with this public key").value_parser(value_parser!(PathBuf)))
+	  .arg(arg!(--"no-verify" "Do not check files against the hashes recorded in the archive"))
+	  .arg(arg!(-d --dictionary <DICTIONARY> "Decompress the archive with this dictionary, rather than the one embedded in it").value_parser(value_parser!(PathBuf))))
+	.subcommand(Command::new("diff")
+	  .about("Compare two archives, or an archive and a directory, exiting with a status of 1 if they differ, or 2 if they could not be compared")
+	  .arg(arg!(<OLD> "Path to the old archive file or directory").required(true).value_parser(value_parser!(PathBuf)).display_order(1))
+	  .arg(arg!(<NEW> "Path to the new archive file or directory").required(true).value_parser(value_parser!(PathBuf)).display_order(2))
+	  .arg(arg!(--json "Write the differences as a JSON object"))
+	  .arg(arg!(--"store-symlinks" "Compare symbolic links in directories as links, as archives written with --store-symlinks hold them, rather than the files & directories they point to"))
+	  .arg(arg!(-m --memory "Treat archives written by larz 0.3 and earlier as in-memory archives"))
+	  .arg(arg!(-p --"passphrase-prompt" "Decrypt the archives with a passphrase entered at a prompt").conflicts_with_all(["passphrase-file", "passphrase-env"]))
+	  .arg(arg!(--"passphrase-file" <FILE> "Decrypt the archives with a passphrase read from a file").value_parser(value_parser!(PathBuf)).conflicts_with("passphrase-env"))
+	  .arg(arg!(--"passphrase-env" <VARIABLE> "Decrypt the archives with a passphrase read from an environment variable"))
+	  .arg(arg!(--"verify-key" <KEY> "Refuse to compare archives unless they are signed with this public key").value_parser(value_parser!(PathBuf)))
+	  .arg(arg!(--"no-verify" "Do not check files against the hashes recorded in the archives"))
+	  .arg(arg!(-d --dictionary <DICTIONARY> "Decompress the archives with this dictionary, rather than the one embedded in them").value_parser(value_parser!(PathBuf))))
+	.subcommand(Command::new("serve")
+	  .about("Serve the contents of an archive over HTTP")
+	  .arg(arg!(<PATH> "Path to an archive file, or the first of its volumes").required(true).value_parser(value_parser!(PathBuf)).display_order(1))
+	  .arg(arg!(-a --address <ADDRESS> "The address to listen on").default_value("127.0.0.1:8080"))
+	  .arg(arg!(-m --memory "Treat archives written by larz 0.3 and earlier as in-memory archives"))
+	  .arg(arg!(-p --"passphrase-prompt" "Decrypt the archive with a passphrase entered at a prompt").conflicts_with_all(["passphrase-file", "passphrase-env"]))
+	  .arg(arg!(--"passphrase-file" <FILE> "Decrypt the archive with a passphrase read from a file").value_parser(value_parser!(PathBuf)).conflicts_with("passphrase-env"))
+	  .arg(arg!(--"passphrase-env" <VARIABLE> "Decrypt the archive with a passphrase read from an environment variable"))
+	  .arg(arg!(--"verify-key" <KEY> "Refuse to serve archives unless they are signed with this public key").value_parser(value_parser!(PathBuf)))
+	  .arg(arg!(--"no-verify" "Do not check files against the hashes recorded in the archive"))
+	  .arg(arg!(-d --dictionary <DICTIONARY> "Decompress the archive with this dictionary, rather than the one embedded in it").value_parser(value_parser!(PathBuf))))
+	.subcommand(Command::new("restore")
+	  .about("Restore a full backup followed by a chain of incremental backups, in order")
+	  .arg(arg!(<PATH> "Path to an archive file, starting with the full backup").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(1))
+	  .arg(arg!(<OUT> "Specify an output directory path for the restored contents").required(true).value_parser(value_parser!(PathBuf)).num_args(1))
+	  .arg(arg!(--atomic "Restore into a staging directory that replaces the output directory only once every archive is restored & verified"))
+	  .arg(arg!(--resume "Keep a checkpoint journal while restoring, and skip whatever an earlier run that was interrupted already restored").conflicts_with("atomic"))
+	  .arg(arg!(-p --"passphrase-prompt" "Decrypt the archives with a passphrase entered at a prompt").conflicts_with_all(["passphrase-file", "passphrase-env"]))
+	  .arg(arg!(--"passphrase-file" <FILE> "Decrypt the archives with a passphrase read from a file").value_parser(value_parser!(PathBuf)).conflicts_with("passphrase-env"))
+	  .arg(arg!(--"passphrase-env" <VARIABLE> "Decrypt the archives with a passphrase read from an environment variable"))
+	  .arg(arg!(--"verify-key" <KEY> "Refuse to restore archives unless they are signed with this public key").value_parser(value_parser!(PathBuf)))
+	  .arg(arg!(--"no-verify" "Do not check restored files against the hashes recorded in the archives"))
+	  .arg(arg!(-d --dictionary <DICTIONARY> "Decompress the archives with this dictionary, rather than the one embedded in them").value_parser(value_parser!(PathBuf))))
+	.subcommand(Command::new("train-dict")
+	  .about("Train a compression dictionary from sample files")
+	  .arg(arg!(<PATH> "Path to a sample file or directory of sample files").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(1))
+	  .arg(arg!(<OUT> "Specify an output file path for the dictionary").required(true).value_parser(value_parser!(PathBuf)).num_args(1))
+	  .arg(arg!(-s --size <BYTES> "The size of the dictionary, in bytes").value_parser(value_parser!(usize)).default_value("65536")))
+	.subcommand(Command::new("keygen")
+	  .about("Generate a key pair for signing archives")
+	  .arg(arg!(<OUT> "Specify an output file path for the secret key; the public key is written alongside it with a '.pub' extension").required(true).value_parser(value_parser!(PathBuf)).num_args(1)))
+	.subcommand(Command::new("sign")
+	  .about("Sign an archive, writing a detached signature file next to it")
+	  .arg(arg!(<PATH> "Path to an archive file").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(1))
+	  .arg(arg!(-k --key <KEY> "Path to the secret key to sign with").required(true).value_parser(value_parser!(PathBuf))))
+	.subcommand(Command::new("verify-signature")
+	  .about("Verify the detached signature of an archive")
+	  .arg(arg!(<PATH> "Path to an archive file").required(true).value_parser(value_parser!(PathBuf)).num_args(1..).display_order(1))
+	  .arg(arg!(-k --key <KEY> "Path to the public key the archive should be signed with").required(true).value_parser(value_parser!(PathBuf))))
+  .get_matches_from(wild::args_os());
 
 }
 
 /// The main function of larz's CLI
 fn main() {
-	std::panic::set_hook(Box::new(|e| {
-		println!(
+	// Machine-readable output, and the contents of archived files, are written alone
+	let quiet = match MATCHES.subcommand() {
+		Some(("diff", diff_matches)) => diff_matches.get_flag("json"),
+		Some(("cat", _)) => true,
+		_ => false,
+	};
+
+	std::panic::set_hook(Box::new(move |e| {
+		let message = format!(
 			"{}\nDefined in: {}:{}:{}",
 			format!("{}", e.message().unwrap())
 				.replace("called `Result::unwrap()` on an `Err` value", "Error"),
@@ -62,16 +181,22 @@ fn main() {
 			e.location().unwrap().line(),
 			e.location().unwrap().column()
 		);
+		match quiet {
+			true => eprintln!("{message}"),
+			false => println!("{message}"),
+		}
 	}));
 
-	println!(
-		"
+	if !quiet {
+		println!(
+			"
     larz  Copyright (C) 2021-2023  Emil Sayahi
     This program comes with ABSOLUTELY NO WARRANTY; for details type `larz show -w'.
     This is free software, and you are welcome to redistribute it
     under certain conditions; type `larz show -c' for details.
     "
-	);
+		);
+	}
 
 	match MATCHES.subcommand() {
 		Some(("show", show_matches)) => {
@@ -83,6 +208,36 @@ fn main() {
 		Some(("extract", extract_matches)) => {
 			extract(extract_matches);
 		}
+		Some(("list", list_matches)) => {
+			list(list_matches);
+		}
+		Some(("test", test_matches)) => {
+			test(test_matches);
+		}
+		Some(("cat", cat_matches)) => {
+			cat(cat_matches);
+		}
+		Some(("diff", diff_matches)) => {
+			diff(diff_matches);
+		}
+		Some(("serve", serve_matches)) => {
+			serve(serve_matches);
+		}
+		Some(("restore", restore_matches)) => {
+			restore(restore_matches);
+		}
+		Some(("train-dict", train_matches)) => {
+			train_dict(train_matches);
+		}
+		Some(("keygen", keygen_matches)) => {
+			keygen(keygen_matches);
+		}
+		Some(("sign", sign_matches)) => {
+			sign(sign_matches);
+		}
+		Some(("verify-signature", verify_matches)) => {
+			verify_signature(verify_matches);
+		}
 		None => println!("larz {}", crate_version!()),
 		_ => unreachable!(), // If all subcommands are defined above, anything else is unreachable!()
 	}
@@ -97,6 +252,22 @@ fn main() {
 /// `out` - Specify an output directory path for the extracted contents
 ///
 /// `memory` - Perform this operation solely in memory
+///
+/// `passphrase-prompt` - Decrypt the archive with a passphrase entered at a prompt
+///
+/// `passphrase-file` - Decrypt the archive with a passphrase read from a file
+///
+/// `passphrase-env` - Decrypt the archive with a passphrase read from an environment variable
+///
+/// `verify-key` - Refuse to extract archives unless they are signed with this public key
+///
+/// `no-verify` - Do not check extracted files against the hashes recorded in the archive
+///
+/// `dictionary` - Decompress the archive with this dictionary, rather than the one embedded in it
+///
+/// `atomic` - Extract into a staging directory that replaces the output directory only once everything is extracted & verified
+///
+/// `resume` - Keep a checkpoint journal while extracting, and skip whatever an earlier run that was interrupted already extracted
 fn extract(matches: &clap::ArgMatches) {
 	let paths: Vec<PathBuf> = matches
 		.get_many::<PathBuf>("PATH")
@@ -109,6 +280,14 @@ fn extract(matches: &clap::ArgMatches) {
 		.unwrap();
 	let output_pathbuf = get_absolute_path(output_pathbuf_input.to_owned());
 	let in_memory = matches.get_flag("memory");
+	let options = larz::ExtractOptions {
+		atomic: matches.get_flag("atomic"),
+		resume: matches.get_flag("resume"),
+		..get_extract_options(matches)
+	};
+	if options.atomic {
+		remove_temporary_files_on_interrupt();
+	}
 
 	let stdout = std::io::stdout();
 	let lock = stdout.lock();
@@ -117,22 +296,362 @@ fn extract(matches: &clap::ArgMatches) {
 	let absolute_output_pathbuf = get_absolute_path(output_pathbuf.clone());
 
 	if in_memory {
-		larz::extract_archive_memory(paths, absolute_output_pathbuf);
+		larz::extract_archive_memory_with_options(paths, absolute_output_pathbuf, &options)
+			.or_exit("❌ Could not extract archive");
 	} else {
-		larz::extract_archive_streaming(paths, absolute_output_pathbuf);
+		larz::extract_archive_streaming_with_options(paths, absolute_output_pathbuf, &options)
+			.or_exit("❌ Could not extract archive");
 	}
 
 	timer.stop();
 	writeln!(
 		buf_out,
 		"\n⏰ Extracted archive(s) to filesystem (path: '{}') in {:.2} seconds.",
-		output_pathbuf.to_string_lossy(),
+		larz::escape_path(&output_pathbuf),
+		timer.elapsed_s()
+	)
+	.unwrap();
+	buf_out.flush().unwrap();
+}
+
+/// List the contents of an archive
+///
+/// # Arguments
+///
+/// `PATH` - Path to an archive file, or the first of its volumes
+///
+/// `memory` - Treat archives written by larz 0.3 and earlier as in-memory archives
+///
+/// `passphrase-prompt` - Decrypt the archive with a passphrase entered at a prompt
+///
+/// `passphrase-file` - Decrypt the archive with a passphrase read from a file
+///
+/// `passphrase-env` - Decrypt the archive with a passphrase read from an environment variable
+///
+/// `verify-key` - Refuse to list archives unless they are signed with this public key
+///
+/// `dictionary` - Decompress the archive with this dictionary, rather than the one embedded in it
+fn list(matches: &clap::ArgMatches) {
+	let paths: Vec<PathBuf> = matches
+		.get_many::<PathBuf>("PATH")
+		.expect("❌ No file paths were given")
+		.map(|p| get_absolute_path(p.to_owned()))
+		.collect();
+	let legacy_codec = match matches.get_flag("memory") {
+		true => Codec::Lz4Block,
+		false => Codec::Lz4Frame,
+	};
+	let options = get_extract_options(matches);
+
+	let stdout = std::io::stdout();
+	let mut buf_out = BufWriter::new(stdout.lock());
+	for path in paths {
+		let members =
+			larz::list_archive(&path, legacy_codec, &options).or_exit("❌ Could not list archive");
+		for member in members {
+			writeln!(buf_out, "{}", larz::escape_path(&member)).unwrap();
+		}
+	}
+	buf_out.flush().unwrap();
+}
+
+/// Check that an archive can be read in full, and that its files match the hashes recorded in it, without extracting it
+///
+/// # Arguments
+///
+/// `PATH` - Path to an archive file, or the first of its volumes
+///
+/// `memory` - Treat archives written by larz 0.3 and earlier as in-memory archives
+///
+/// `passphrase-prompt` - Decrypt the archive with a passphrase entered at a prompt
+///
+/// `passphrase-file` - Decrypt the archive with a passphrase read from a file
+///
+/// `passphrase-env` - Decrypt the archive with a passphrase read from an environment variable
+///
+/// `verify-key` - Refuse to test archives unless they are signed with this public key
+///
+/// `no-verify` - Only check that the archive can be read, not that its files match their recorded hashes
+///
+/// `dictionary` - Decompress the archive with this dictionary, rather than the one embedded in it
+fn test(matches: &clap::ArgMatches) {
+	let paths: Vec<PathBuf> = matches
+		.get_many::<PathBuf>("PATH")
+		.expect("❌ No file paths were given")
+		.map(|p| get_absolute_path(p.to_owned()))
+		.collect();
+	let legacy_codec = match matches.get_flag("memory") {
+		true => Codec::Lz4Block,
+		false => Codec::Lz4Frame,
+	};
+	let options = get_extract_options(matches);
+
+	let stdout = std::io::stdout();
+	let mut buf_out = BufWriter::new(stdout.lock());
+	let mut timer = Stopwatch::start_new();
+	for path in &paths {
+		larz::test_archive(path, legacy_codec, &options).or_exit("❌ Archive is damaged");
+		writeln!(buf_out, "✅ '{}' is intact.", larz::escape_path(path)).unwrap();
+	}
+	timer.stop();
+	writeln!(
+		buf_out,
+		"\n⏰ Tested {} archive(s) in {:.2} seconds.",
+		paths.len(),
 		timer.elapsed_s()
 	)
 	.unwrap();
 	buf_out.flush().unwrap();
 }
 
+/// Write the contents of files in an archive to standard output, in the order they are named
+///
+/// # Arguments
+///
+/// `PATH` - Path to an archive file, or the first of its volumes
+///
+/// `MEMBER` - Path to a file within the archive
+///
+/// `memory` - Treat archives written by larz 0.3 and earlier as in-memory archives
+///
+/// `passphrase-prompt` - Decrypt the archive with a passphrase entered at a prompt
+///
+/// `passphrase-file` - Decrypt the archive with a passphrase read from a file
+///
+/// `passphrase-env` - Decrypt the archive with a passphrase read from an environment variable
+///
+/// `verify-key` - Refuse to read archives unless they are signed with this public key
+///
+/// `no-verify` - Do not check files against the hashes recorded in the archive
+///
+/// `dictionary` - Decompress the archive with this dictionary, rather than the one embedded in it
+fn cat(matches: &clap::ArgMatches) {
+	let path = get_absolute_path(
+		matches
+			.get_one::<PathBuf>("PATH")
+			.expect("❌ No file path was given")
+			.to_owned(),
+	);
+	let members: Vec<PathBuf> = matches
+		.get_many::<PathBuf>("MEMBER")
+		.expect("❌ No members were given")
+		.cloned()
+		.collect();
+	let legacy_codec = match matches.get_flag("memory") {
+		true => Codec::Lz4Block,
+		false => Codec::Lz4Frame,
+	};
+	let options = get_extract_options(matches);
+
+	let stdout = std::io::stdout();
+	let mut buf_out = BufWriter::new(stdout.lock());
+	match larz::cat_archive(&path, &members, legacy_codec, &options, &mut buf_out) {
+		// The reader has stopped reading, as `head` does
+		Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+		Err(e) => {
+			// Whatever was read before the error is still written out
+			let _ = buf_out.flush();
+			fail(format!("❌ Could not read from archive: {e}"))
+		}
+		Ok(()) => {}
+	}
+}
+
+/// Compare two archives, or an archive and a directory, exiting with a status of 1 if they differ, or 2 if they could not be compared
+///
+/// # Arguments
+///
+/// `OLD` - Path to the old archive file or directory
+///
+/// `NEW` - Path to the new archive file or directory
+///
+/// `json` - Write the differences as a JSON object
+///
+/// `store-symlinks` - Compare symbolic links in directories as links, as archives written with --store-symlinks hold them, rather than the files & directories they point to
+///
+/// `memory` - Treat archives written by larz 0.3 and earlier as in-memory archives
+///
+/// `passphrase-prompt` - Decrypt the archives with a passphrase entered at a prompt
+///
+/// `passphrase-file` - Decrypt the archives with a passphrase read from a file
+///
+/// `passphrase-env` - Decrypt the archives with a passphrase read from an environment variable
+///
+/// `verify-key` - Refuse to compare archives unless they are signed with this public key
+///
+/// `no-verify` - Do not check files against the hashes recorded in the archives
+///
+/// `dictionary` - Decompress the archives with this dictionary, rather than the one embedded in them
+fn diff(matches: &clap::ArgMatches) {
+	let old = get_absolute_path(
+		matches
+			.get_one::<PathBuf>("OLD")
+			.expect("❌ No old path was given")
+			.to_owned(),
+	);
+	let new = get_absolute_path(
+		matches
+			.get_one::<PathBuf>("NEW")
+			.expect("❌ No new path was given")
+			.to_owned(),
+	);
+	let legacy_codec = match matches.get_flag("memory") {
+		true => Codec::Lz4Block,
+		false => Codec::Lz4Frame,
+	};
+	let options = get_extract_options(matches);
+	let symlinks = match matches.get_flag("store-symlinks") {
+		true => larz::Symlinks::Store,
+		false => larz::Symlinks::Follow,
+	};
+
+	// Differences are reported with a status of 1, so failures are told apart by a status of 2
+	let report =
+		larz::diff::diff(&old, &new, legacy_codec, &options, symlinks).unwrap_or_else(|e| {
+			eprintln!("❌ Could not compare: {e}");
+			std::process::exit(2);
+		});
+	let stdout = std::io::stdout();
+	let mut buf_out = BufWriter::new(stdout.lock());
+	match matches.get_flag("json") {
+		true => writeln!(buf_out, "{}", report.to_json()).unwrap(),
+		false => writeln!(buf_out, "{report}").unwrap(),
+	}
+	buf_out.flush().unwrap();
+	if !report.is_empty() {
+		std::process::exit(1);
+	}
+}
+
+/// Serve the contents of an archive over HTTP
+///
+/// # Arguments
+///
+/// `PATH` - Path to an archive file, or the first of its volumes
+///
+/// `address` - The address to listen on
+///
+/// `memory` - Treat archives written by larz 0.3 and earlier as in-memory archives
+///
+/// `passphrase-prompt` - Decrypt the archive with a passphrase entered at a prompt
+///
+/// `passphrase-file` - Decrypt the archive with a passphrase read from a file
+///
+/// `passphrase-env` - Decrypt the archive with a passphrase read from an environment variable
+///
+/// `verify-key` - Refuse to serve archives unless they are signed with this public key
+///
+/// `no-verify` - Do not check files against the hashes recorded in the archive
+///
+/// `dictionary` - Decompress the archive with this dictionary, rather than the one embedded in it
+fn serve(matches: &clap::ArgMatches) {
+	let path = get_absolute_path(
+		matches
+			.get_one::<PathBuf>("PATH")
+			.expect("❌ No file path was given")
+			.to_owned(),
+	);
+	let address = matches
+		.get_one::<String>("address")
+		.expect("❌ No address was given");
+	let legacy_codec = match matches.get_flag("memory") {
+		true => Codec::Lz4Block,
+		false => Codec::Lz4Frame,
+	};
+	let options = get_extract_options(matches);
+
+	let mut timer = Stopwatch::start_new();
+	let server = larz::serve::Server::open(&path, address, legacy_codec, &options)
+		.or_exit("❌ Could not serve archive");
+	timer.stop();
+	println!(
+		"🌐 Serving '{}' at http://{}/ (read in {:.2} seconds); press Ctrl+C to stop.",
+		larz::escape_path(&path),
+		server
+			.local_addr()
+			.map_or_else(|| address.to_owned(), |address| address.to_string()),
+		timer.elapsed_s()
+	);
+	server.run().or_exit("❌ Could not serve archive");
+}
+
+/// Restore a full backup followed by a chain of incremental backups, in order
+///
+/// # Arguments
+///
+/// `PATH` - Path to an archive file, starting with the full backup
+///
+/// `out` - Specify an output directory path for the restored contents
+///
+/// `passphrase-prompt` - Decrypt the archives with a passphrase entered at a prompt
+///
+/// `passphrase-file` - Decrypt the archives with a passphrase read from a file
+///
+/// `passphrase-env` - Decrypt the archives with a passphrase read from an environment variable
+///
+/// `verify-key` - Refuse to restore archives unless they are signed with this public key
+///
+/// `no-verify` - Do not check restored files against the hashes recorded in the archives
+///
+/// `dictionary` - Decompress the archives with this dictionary, rather than the one embedded in them
+///
+/// `atomic` - Restore into a staging directory that replaces the output directory only once every archive is restored & verified
+///
+/// `resume` - Keep a checkpoint journal while restoring, and skip whatever an earlier run that was interrupted already restored
+fn restore(matches: &clap::ArgMatches) {
+	let paths: Vec<PathBuf> = matches
+		.get_many::<PathBuf>("PATH")
+		.expect("❌ No file paths were given")
+		.map(|p| get_absolute_path(p.to_owned()))
+		.collect();
+	let output_pathbuf = get_absolute_path(
+		matches
+			.get_one::<PathBuf>("OUT")
+			.expect("❌ No output path was given")
+			.to_owned(),
+	);
+	let options = larz::ExtractOptions {
+		atomic: matches.get_flag("atomic"),
+		resume: matches.get_flag("resume"),
+		..get_extract_options(matches)
+	};
+	if options.atomic {
+		remove_temporary_files_on_interrupt();
+	}
+
+	let mut timer = Stopwatch::start_new();
+	let archive_count = paths.len();
+	larz::restore_archives(paths, output_pathbuf.clone(), &options)
+		.or_exit("❌ Could not restore archives");
+	timer.stop();
+	println!(
+		"⏰ Restored {} archive(s) to filesystem (path: '{}') in {:.2} seconds.",
+		archive_count,
+		larz::escape_path(&output_pathbuf),
+		timer.elapsed_s()
+	);
+}
+
+/// Get the options to read archives with from the arguments given to a subcommand
+///
+/// # Arguments
+///
+/// * `matches` - The arguments given to the subcommand
+fn get_extract_options(matches: &clap::ArgMatches) -> larz::ExtractOptions {
+	larz::ExtractOptions {
+		skip_verification: matches.get_flag("no-verify"),
+		dictionary: get_dictionary(matches),
+		passphrase: get_passphrase(matches, false),
+		verifying_key: matches.get_one::<PathBuf>("verify-key").map(|key_path| {
+			signing::read_verifying_key(&get_absolute_path(key_path.to_owned()))
+				.or_exit("❌ Could not read the public key")
+		}),
+		atomic: false,
+		resume: false,
+		progress: None,
+	}
+}
+
 /// Archive & compress a file or set of files
 ///
 /// # Arguments
@@ -142,6 +661,40 @@ fn extract(matches: &clap::ArgMatches) {
 /// `out` - Specify an output file path for the archive
 ///
 /// `memory` - Perform this operation solely in memory
+///
+/// `passphrase-prompt` - Encrypt the archive with a passphrase entered at a prompt
+///
+/// `passphrase-file` - Encrypt the archive with a passphrase read from a file
+///
+/// `passphrase-env` - Encrypt the archive with a passphrase read from an environment variable
+///
+/// `dictionary` - Compress the archive with a dictionary trained by 'larz train-dict'
+///
+/// `embed-dictionary` - Store the dictionary in the archive, so it can be extracted without it
+///
+/// `dedup` - Store repeated regions of the archived files only once
+///
+/// `link-duplicates` - Store files with identical contents only once, extracting them as hard links to the same file
+///
+/// `store-symlinks` - Store symbolic links as links, rather than the files & directories they point to
+///
+/// `one-file-system` - Do not descend into directories on a different filesystem
+///
+/// `max-depth` - Do not descend more than this many levels beneath the given directories
+///
+/// `special-files` - Whether to store, skip or refuse FIFOs, sockets & device nodes
+///
+/// `format` - The TAR format to write: ustar, GNU, or PAX with sub-second modification times
+///
+/// `volume-size` - Split the archive into volumes of at most this size (eg, '4G' or '2000M'), named OUT.001, OUT.002, …
+///
+/// `listed-incremental` - Only archive files that are new or changed since the backup recorded in this snapshot file, then update it
+///
+/// `force` - Replace an existing archive; this is the default, and the flag is accepted for symmetry with `no-clobber`
+///
+/// `no-clobber` - Never replace an existing archive
+///
+/// `resume` - Keep a checkpoint journal while writing, and continue from the last checkpoint if an earlier run was interrupted
 fn compress(matches: &clap::ArgMatches) {
 	let paths: Vec<PathBuf> = matches
 		.get_many::<PathBuf>("PATH")
@@ -154,6 +707,44 @@ fn compress(matches: &clap::ArgMatches) {
 		.unwrap();
 	let output_pathbuf = get_absolute_path(output_pathbuf_input.to_owned());
 	let in_memory = matches.get_flag("memory");
+	let options = larz::CompressOptions {
+		deduplicate: matches.get_flag("dedup"),
+		link_duplicates: matches.get_flag("link-duplicates"),
+		walk: larz::WalkOptions {
+			symlinks: match matches.get_flag("store-symlinks") {
+				true => larz::Symlinks::Store,
+				false => larz::Symlinks::Follow,
+			},
+			one_file_system: matches.get_flag("one-file-system"),
+			max_depth: matches.get_one::<usize>("max-depth").copied(),
+			special_files: match matches
+				.get_one::<String>("special-files")
+				.map(String::as_str)
+			{
+				Some("skip") => larz::SpecialFiles::Skip,
+				Some("error") => larz::SpecialFiles::Error,
+				_ => larz::SpecialFiles::Store,
+			},
+		},
+		format: match matches.get_one::<String>("format").map(String::as_str) {
+			Some("ustar") => larz::TarFormat::Ustar,
+			Some("gnu") => larz::TarFormat::Gnu,
+			_ => larz::TarFormat::Pax,
+		},
+		dictionary: get_dictionary(matches),
+		embed_dictionary: matches.get_flag("embed-dictionary"),
+		passphrase: get_passphrase(matches, true),
+		snapshot: matches
+			.get_one::<PathBuf>("listed-incremental")
+			.map(|path| get_absolute_path(path.to_owned())),
+		volume_size: matches.get_one::<u64>("volume-size").copied(),
+		no_clobber: matches.get_flag("no-clobber"),
+		resume: matches.get_flag("resume"),
+		progress: None,
+	};
+
+	// The archive is written to a temporary file until it is complete
+	remove_temporary_files_on_interrupt();
 
 	let stdout = std::io::stdout();
 	let lock = stdout.lock();
@@ -162,26 +753,305 @@ fn compress(matches: &clap::ArgMatches) {
 	let mut timer = Stopwatch::start_new();
 
 	let output_pathbuf_clone = output_pathbuf.clone();
-	let output_file_name = output_pathbuf_clone.file_stem().unwrap().to_str().unwrap();
+	let output_file_name = larz::escape_path(Path::new(
+		output_pathbuf_clone.file_stem().unwrap_or_default(),
+	));
 
-	if in_memory {
-		larz::compress_archive_memory::<StdoutLock>(paths, output_pathbuf, Some(&mut buf_out));
+	let stats = if in_memory {
+		larz::compress_archive_memory_with_options::<StdoutLock>(
+			paths,
+			output_pathbuf,
+			&options,
+			Some(&mut buf_out),
+		)
+		.or_exit("❌ Could not write archive")
 	} else {
-		larz::compress_archive_streaming::<StdoutLock>(paths, output_pathbuf, Some(&mut buf_out));
-	}
+		larz::compress_archive_streaming_with_options::<StdoutLock>(
+			paths,
+			output_pathbuf,
+			&options,
+			Some(&mut buf_out),
+		)
+		.or_exit("❌ Could not write archive")
+	};
 
 	timer.stop();
 	writeln!(
 		buf_out,
 		"\n⏰ Wrote archive '{}' to filesystem (path: '{}') in {:.2} seconds.",
 		output_file_name,
-		output_pathbuf_clone.to_string_lossy(),
+		larz::escape_path(&output_pathbuf_clone),
 		timer.elapsed_s()
 	)
 	.unwrap();
+	write!(
+		buf_out,
+		"📦 Compressed {} bytes into {} bytes (compression ratio: {:.2}:1)",
+		stats.uncompressed_size,
+		stats.archive_size,
+		stats.compression_ratio()
+	)
+	.unwrap();
+	if options.deduplicate {
+		write!(
+			buf_out,
+			"; deduplication saved {} bytes ({:.1}%)",
+			stats.dedup_savings(),
+			stats.dedup_savings() as f64 * 100.0 / stats.uncompressed_size.max(1) as f64
+		)
+		.unwrap();
+	}
+	writeln!(buf_out, ".").unwrap();
+	if options.volume_size.is_some() {
+		writeln!(
+			buf_out,
+			"💾 Split the archive into {} volume(s).",
+			stats.volumes
+		)
+		.unwrap();
+	}
 	buf_out.flush().unwrap();
 }
 
+/// Train a compression dictionary from sample files
+///
+/// # Arguments
+///
+/// `PATH` - Path to a sample file or directory of sample files
+///
+/// `OUT` - Specify an output file path for the dictionary
+///
+/// `size` - The size of the dictionary, in bytes
+fn train_dict(matches: &clap::ArgMatches) {
+	let paths: Vec<PathBuf> = matches
+		.get_many::<PathBuf>("PATH")
+		.expect("❌ No file paths were given")
+		.map(|p| get_absolute_path(p.to_owned()))
+		.collect();
+	let output_pathbuf = get_absolute_path(
+		matches
+			.get_one::<PathBuf>("OUT")
+			.expect("❌ No output path was given")
+			.to_owned(),
+	);
+	let size = *matches.get_one::<usize>("size").unwrap();
+
+	let mut timer = Stopwatch::start_new();
+	let dictionary = Dictionary::train(&paths, size).or_exit("❌ Could not train dictionary");
+	dictionary
+		.write(&output_pathbuf)
+		.or_exit("❌ Could not write dictionary");
+	timer.stop();
+	println!(
+		"⏰ Wrote dictionary {:08x} ({} bytes) to filesystem (path: '{}') in {:.2} seconds.",
+		dictionary.id(),
+		dictionary.as_bytes().len(),
+		larz::escape_path(&output_pathbuf),
+		timer.elapsed_s()
+	);
+}
+
+/// Parse a size given on the command line, in bytes or with a binary unit suffix (K, M, G or T)
+///
+/// # Arguments
+///
+/// * `size` - The size, as given on the command line
+fn parse_size(size: &str) -> Result<u64, String> {
+	let size = size.trim();
+	let digits = size
+		.find(|c: char| !c.is_ascii_digit())
+		.unwrap_or(size.len());
+	let (number, unit) = size.split_at(digits);
+	let number: u64 = number
+		.parse()
+		.map_err(|_| format!("'{size}' is not a valid size"))?;
+	let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+		"" | "B" => 1,
+		"K" | "KB" | "KIB" => 1 << 10,
+		"M" | "MB" | "MIB" => 1 << 20,
+		"G" | "GB" | "GIB" => 1 << 30,
+		"T" | "TB" | "TIB" => 1 << 40,
+		_ => return Err(format!("'{unit}' is not a valid unit of size")),
+	};
+	match number.checked_mul(multiplier) {
+		Some(0) => Err("The size must be larger than zero".to_owned()),
+		Some(size) => Ok(size),
+		None => Err(format!("'{size}' is too large")),
+	}
+}
+
+/// Get the dictionary to compress or decompress an archive with, if one was given
+///
+/// # Arguments
+///
+/// * `matches` - The arguments given to the subcommand
+fn get_dictionary(matches: &clap::ArgMatches) -> Option<Dictionary> {
+	matches.get_one::<PathBuf>("dictionary").map(|path| {
+		Dictionary::read(&get_absolute_path(path.to_owned()))
+			.or_exit("❌ Could not read the dictionary")
+	})
+}
+
+/// Generate a key pair for signing archives
+///
+/// # Arguments
+///
+/// `OUT` - Specify an output file path for the secret key; the public key is written alongside it with a '.pub' extension
+fn keygen(matches: &clap::ArgMatches) {
+	let secret_key_path = get_absolute_path(
+		matches
+			.get_one::<PathBuf>("OUT")
+			.expect("❌ No output path was given")
+			.to_owned(),
+	);
+	let mut public_key_path = secret_key_path.clone().into_os_string();
+	public_key_path.push(".pub");
+	let public_key_path = PathBuf::from(public_key_path);
+
+	let signing_key = signing::generate_signing_key().or_exit("❌ Could not generate a key");
+	signing::write_signing_key(&secret_key_path, &signing_key)
+		.or_exit("❌ Could not write the secret key");
+	signing::write_verifying_key(&public_key_path, &signing_key.verifying_key())
+		.or_exit("❌ Could not write the public key");
+	println!(
+		"🔑 Wrote secret key to '{}' and public key to '{}'.",
+		larz::escape_path(&secret_key_path),
+		larz::escape_path(&public_key_path)
+	);
+}
+
+/// Sign an archive, writing a detached signature file next to it
+///
+/// # Arguments
+///
+/// `PATH` - Path to an archive file
+///
+/// `key` - Path to the secret key to sign with
+fn sign(matches: &clap::ArgMatches) {
+	let key_path = matches
+		.get_one::<PathBuf>("key")
+		.expect("❌ No secret key was given");
+	let signing_key = signing::read_signing_key(&get_absolute_path(key_path.to_owned()))
+		.or_exit("❌ Could not read the secret key");
+	for path in matches
+		.get_many::<PathBuf>("PATH")
+		.expect("❌ No file paths were given")
+	{
+		let archive_path = get_absolute_path(path.to_owned());
+		let signature_path =
+			signing::sign_archive(&archive_path, &signing_key).or_exit("❌ Could not sign archive");
+		println!(
+			"🔏 Signed '{}' (signature: '{}').",
+			larz::escape_path(&archive_path),
+			larz::escape_path(&signature_path)
+		);
+	}
+}
+
+/// Verify the detached signature of an archive
+///
+/// # Arguments
+///
+/// `PATH` - Path to an archive file
+///
+/// `key` - Path to the public key the archive should be signed with
+fn verify_signature(matches: &clap::ArgMatches) {
+	let key_path = matches
+		.get_one::<PathBuf>("key")
+		.expect("❌ No public key was given");
+	let verifying_key = signing::read_verifying_key(&get_absolute_path(key_path.to_owned()))
+		.or_exit("❌ Could not read the public key");
+	for path in matches
+		.get_many::<PathBuf>("PATH")
+		.expect("❌ No file paths were given")
+	{
+		let archive_path = get_absolute_path(path.to_owned());
+		signing::verify_archive(&archive_path, &verifying_key)
+			.or_exit("❌ Signature verification failed");
+		println!(
+			"✅ '{}' is signed with the given key.",
+			larz::escape_path(&archive_path)
+		);
+	}
+}
+
+/// Get the passphrase to encrypt or decrypt an archive with, if one was given
+///
+/// # Arguments
+///
+/// * `matches` - The arguments given to the subcommand
+///
+/// * `confirm` - Whether a passphrase entered at a prompt should be asked for twice
+fn get_passphrase(matches: &clap::ArgMatches, confirm: bool) -> Option<Passphrase> {
+	if let Some(passphrase_file) = matches.get_one::<PathBuf>("passphrase-file") {
+		let mut passphrase =
+			std::fs::read(passphrase_file).or_exit("❌ Could not read the passphrase file");
+		// Editors usually end files with a newline, which is not meant to be part of the passphrase
+		if passphrase.ends_with(b"\n") {
+			passphrase.pop();
+			if passphrase.ends_with(b"\r") {
+				passphrase.pop();
+			}
+		}
+		Some(Passphrase::new(passphrase))
+	} else if let Some(variable) = matches.get_one::<String>("passphrase-env") {
+		let passphrase = std::env::var(variable).unwrap_or_else(|_| {
+			fail(format!(
+				"❌ The environment variable '{variable}' is not set or is not valid Unicode"
+			))
+		});
+		Some(Passphrase::new(passphrase))
+	} else if matches.get_flag("passphrase-prompt") {
+		let passphrase = rpassword::prompt_password("🔑 Passphrase: ")
+			.or_exit("❌ Could not read the passphrase");
+		if confirm {
+			let confirmation = rpassword::prompt_password("🔑 Confirm passphrase: ")
+				.or_exit("❌ Could not read the passphrase");
+			if passphrase != confirmation {
+				fail("❌ The passphrases do not match");
+			}
+		}
+		Some(Passphrase::new(passphrase))
+	} else {
+		None
+	}
+}
+
+/// Print why larz cannot go on to standard error, and exit with a non-zero status
+///
+/// # Arguments
+///
+/// * `message` - What went wrong
+fn fail(message: impl std::fmt::Display) -> ! {
+	eprintln!("{message}");
+	std::process::exit(1);
+}
+
+/// Exiting with an error message, rather than panicking, when something could not be done
+trait OrExit<T> {
+	/// Get the value, or print what could not be done and why to standard error, and exit with a non-zero status
+	///
+	/// # Arguments
+	///
+	/// * `message` - What could not be done
+	fn or_exit(self, message: &str) -> T;
+}
+
+impl<T, E: std::fmt::Display> OrExit<T> for Result<T, E> {
+	fn or_exit(self, message: &str) -> T {
+		self.unwrap_or_else(|e| fail(format!("{message}: {e}")))
+	}
+}
+
+/// Remove any temporary files & staging directories before exiting, if larz is interrupted
+fn remove_temporary_files_on_interrupt() {
+	ctrlc::set_handler(|| {
+		larz::remove_temporary_files();
+		std::process::exit(130);
+	})
+	.or_exit("❌ Could not handle interrupts");
+}
+
 /// Get an absolute, canonical path from a `PathBuf`
 ///
 /// # Arguments
@@ -199,13 +1069,8 @@ fn get_absolute_path(path: PathBuf) -> PathBuf {
 		} else if cleaned_pathbuf.starts_with("~") {
 			let home_dir = home::home_dir();
 			if let Some(home_dir_pathbuf) = home_dir {
-				let mut raw_str = cleaned_pathbuf.to_string_lossy().to_string();
-				raw_str = raw_str.replacen(
-					'~',
-					home_dir_pathbuf.to_string_lossy().to_string().as_str(),
-					1,
-				);
-				PathBuf::from(raw_str)
+				// Joined component by component, so that paths which are not valid Unicode are kept intact
+				home_dir_pathbuf.join(cleaned_pathbuf.strip_prefix("~").unwrap())
 			} else {
 				cleaned_pathbuf
 			}
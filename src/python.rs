@@ -0,0 +1,646 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Python bindings, built into a wheel with [maturin](https://www.maturin.rs).
+//!
+//! Run `maturin develop` (or `maturin build --release` for a wheel) in the repository root, then:
+//!
+//! ```python
+//! import larz
+//!
+//! stats = larz.compress(["documents"], "backup.larz", deduplicate=True)
+//! print(stats.compression_ratio())
+//! print(larz.list("backup.larz"))
+//! larz.test("backup.larz")
+//! with larz.open_entry("backup.larz", "report.txt") as entry:
+//!     print(entry.read().decode())
+//! larz.extract(["backup.larz"], "restored")
+//! ```
+//!
+//! Errors are raised as subclasses of `larz.LarzError` mirroring the Rust error types, such as `larz.IntegrityError`; other I/O errors are raised as the matching `OSError` subclass.
+//! The GIL is released while archives are being written, extracted or tested.
+
+use crate::header::Codec;
+use crate::{dictionary, format, incremental, integrity, CompressOptions, ExtractOptions};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::io::{Error, ErrorKind, Read, Take};
+use std::path::PathBuf;
+
+create_exception!(
+	larz,
+	LarzError,
+	PyException,
+	"The base class of the errors raised by larz"
+);
+create_exception!(
+	larz,
+	IntegrityError,
+	LarzError,
+	"Files in the archive do not match the hashes recorded in it; their paths are in the `members` attribute"
+);
+create_exception!(
+	larz,
+	DecryptionError,
+	LarzError,
+	"The archive could not be decrypted; the passphrase is wrong, or the archive is corrupted"
+);
+create_exception!(
+	larz,
+	SignatureError,
+	LarzError,
+	"The archive's signature is missing, untrusted or invalid"
+);
+create_exception!(
+	larz,
+	DictionaryError,
+	LarzError,
+	"The dictionary needed to decompress the archive is missing or does not match"
+);
+create_exception!(
+	larz,
+	IncrementalError,
+	LarzError,
+	"The archives of a listed-incremental backup are not given in order"
+);
+create_exception!(
+	larz,
+	VolumeError,
+	LarzError,
+	"A volume of a multi-volume archive is missing or truncated"
+);
+
+/// The sizes of an archive at each stage of writing it
+#[pyclass(module = "larz", frozen, get_all, skip_from_py_object)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveStats {
+	/// The size of the uncompressed TAR data
+	uncompressed_size: u64,
+	/// The size of the TAR data after deduplication, or the size of the TAR data if it was not deduplicated
+	deduplicated_size: u64,
+	/// The size of the archive file, or the total size of its volumes
+	archive_size: u64,
+	/// The number of files the archive was written to
+	volumes: u32,
+}
+
+#[pymethods]
+impl ArchiveStats {
+	/// How many times smaller the archive is than the uncompressed TAR data
+	fn compression_ratio(&self) -> f64 {
+		self.stats().compression_ratio()
+	}
+
+	/// How many bytes deduplication removed from the TAR data before it was compressed
+	fn dedup_savings(&self) -> u64 {
+		self.stats().dedup_savings()
+	}
+
+	fn __repr__(&self) -> String {
+		format!(
+			"ArchiveStats(uncompressed_size={}, deduplicated_size={}, archive_size={}, volumes={})",
+			self.uncompressed_size, self.deduplicated_size, self.archive_size, self.volumes
+		)
+	}
+}
+
+impl ArchiveStats {
+	/// Convert back to the statistics returned by the Rust API
+	fn stats(&self) -> crate::ArchiveStats {
+		crate::ArchiveStats {
+			uncompressed_size: self.uncompressed_size,
+			deduplicated_size: self.deduplicated_size,
+			archive_size: self.archive_size,
+			volumes: self.volumes,
+		}
+	}
+}
+
+impl From<crate::ArchiveStats> for ArchiveStats {
+	fn from(stats: crate::ArchiveStats) -> ArchiveStats {
+		ArchiveStats {
+			uncompressed_size: stats.uncompressed_size,
+			deduplicated_size: stats.deduplicated_size,
+			archive_size: stats.archive_size,
+			volumes: stats.volumes,
+		}
+	}
+}
+
+/// A file-like object reading the contents of a single member of an archive as it is decompressed
+///
+/// Once the whole member has been read, its contents are checked against the hash recorded in the archive, raising `IntegrityError` if they do not match.
+#[pyclass(module = "larz", unsendable)]
+pub struct EntryReader {
+	/// The decompressed contents of the member, or `None` once closed
+	reader: Option<Take<Box<dyn Read>>>,
+	/// The hash of the contents read so far
+	hasher: blake3::Hasher,
+	/// The hash recorded for the member, if it is to be verified
+	expected: Option<blake3::Hash>,
+	/// The path of the member within the archive
+	#[pyo3(get)]
+	name: PathBuf,
+	/// The size of the member in bytes
+	#[pyo3(get)]
+	size: u64,
+}
+
+#[pymethods]
+impl EntryReader {
+	/// Read up to `size` bytes, or the rest of the member if `size` is negative or omitted
+	#[pyo3(signature = (size = -1))]
+	fn read<'py>(&mut self, py: Python<'py>, size: i64) -> PyResult<Bound<'py, PyBytes>> {
+		let reader = self
+			.reader
+			.as_mut()
+			.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "I/O operation on closed entry"))
+			.map_err(|e| to_py_err(py, e))?;
+		let mut buffer = Vec::new();
+		let result = match u64::try_from(size) {
+			Ok(size) => reader.by_ref().take(size).read_to_end(&mut buffer),
+			Err(_) => reader.read_to_end(&mut buffer),
+		};
+		result.map_err(|e| to_py_err(py, e))?;
+		self.hasher.update(&buffer);
+		if reader.limit() == 0 {
+			if let Some(expected) = self.expected.take() {
+				if self.hasher.finalize() != expected {
+					let e = Error::new(
+						ErrorKind::InvalidData,
+						integrity::IntegrityError {
+							members: vec![self.name.clone()],
+						},
+					);
+					return Err(to_py_err(py, e));
+				}
+			}
+		}
+		Ok(PyBytes::new(py, &buffer))
+	}
+
+	/// Entries can always be read
+	fn readable(&self) -> bool {
+		true
+	}
+
+	/// Entries cannot be written to
+	fn writable(&self) -> bool {
+		false
+	}
+
+	/// Entries are read in order, and cannot be seeked
+	fn seekable(&self) -> bool {
+		false
+	}
+
+	/// Close the archive; further reads raise an error
+	fn close(&mut self) {
+		self.reader = None;
+	}
+
+	/// Whether the entry has been closed
+	#[getter]
+	fn closed(&self) -> bool {
+		self.reader.is_none()
+	}
+
+	fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+		slf
+	}
+
+	#[pyo3(signature = (*_args))]
+	fn __exit__(&mut self, _args: &Bound<'_, pyo3::types::PyTuple>) -> bool {
+		self.close();
+		false
+	}
+}
+
+/// Archive & compress files and directories, returning the sizes of the archive
+///
+/// The contents of directories are stored at the root of the archive, and files are stored under their file name.
+#[pyfunction]
+#[pyo3(signature = (paths, output, *, in_memory = false, deduplicate = false, link_duplicates = false, follow_symlinks = true, one_file_system = false, max_depth = None, special_files = "store", format = "pax", passphrase = None, dictionary = None, embed_dictionary = false, snapshot = None, volume_size = None, no_clobber = false, resume = false))]
+#[allow(clippy::too_many_arguments)]
+fn compress(
+	py: Python<'_>,
+	paths: Vec<PathBuf>,
+	output: PathBuf,
+	in_memory: bool,
+	deduplicate: bool,
+	link_duplicates: bool,
+	follow_symlinks: bool,
+	one_file_system: bool,
+	max_depth: Option<usize>,
+	special_files: &str,
+	format: &str,
+	passphrase: Option<String>,
+	dictionary: Option<PathBuf>,
+	embed_dictionary: bool,
+	snapshot: Option<PathBuf>,
+	volume_size: Option<u64>,
+	no_clobber: bool,
+	resume: bool,
+) -> PyResult<ArchiveStats> {
+	let special_files = match special_files {
+		"store" => crate::SpecialFiles::Store,
+		"skip" => crate::SpecialFiles::Skip,
+		"error" => crate::SpecialFiles::Error,
+		_ => {
+			return Err(PyValueError::new_err(
+				"special_files must be 'store', 'skip' or 'error'",
+			))
+		}
+	};
+	let format = match format {
+		"ustar" => crate::TarFormat::Ustar,
+		"gnu" => crate::TarFormat::Gnu,
+		"pax" => crate::TarFormat::Pax,
+		_ => {
+			return Err(PyValueError::new_err(
+				"format must be 'ustar', 'gnu' or 'pax'",
+			))
+		}
+	};
+	let result = py.detach(|| {
+		let options = CompressOptions {
+			deduplicate,
+			link_duplicates,
+			walk: crate::WalkOptions {
+				symlinks: match follow_symlinks {
+					true => crate::Symlinks::Follow,
+					false => crate::Symlinks::Store,
+				},
+				one_file_system,
+				max_depth,
+				special_files,
+			},
+			format,
+			dictionary: dictionary
+				.as_deref()
+				.map(dictionary::Dictionary::read)
+				.transpose()?,
+			embed_dictionary,
+			#[cfg(feature = "encryption")]
+			passphrase: passphrase.map(crate::crypto::Passphrase::new),
+			snapshot,
+			volume_size,
+			no_clobber,
+			resume,
+			progress: None,
+		};
+		#[cfg(not(feature = "encryption"))]
+		if passphrase.is_some() {
+			return Err(unsupported("encryption"));
+		}
+		match in_memory {
+			true => crate::compress_archive_memory_with_options::<std::io::Sink>(
+				paths, output, &options, None,
+			),
+			false => crate::compress_archive_streaming_with_options::<std::io::Sink>(
+				paths, output, &options, None,
+			),
+		}
+	});
+	result.map(ArchiveStats::from).map_err(|e| to_py_err(py, e))
+}
+
+/// Extract & decompress archives into a directory
+#[pyfunction]
+#[pyo3(signature = (paths, output, *, in_memory = false, skip_verification = false, passphrase = None, dictionary = None, verifying_key = None, atomic = false, resume = false))]
+#[allow(clippy::too_many_arguments)]
+fn extract(
+	py: Python<'_>,
+	paths: Vec<PathBuf>,
+	output: PathBuf,
+	in_memory: bool,
+	skip_verification: bool,
+	passphrase: Option<String>,
+	dictionary: Option<PathBuf>,
+	verifying_key: Option<PathBuf>,
+	atomic: bool,
+	resume: bool,
+) -> PyResult<()> {
+	let result = py.detach(|| {
+		let options = ExtractOptions {
+			atomic,
+			resume,
+			..extract_options(skip_verification, passphrase, dictionary, verifying_key)?
+		};
+		match in_memory {
+			true => crate::extract_archive_memory_with_options(paths, output, &options),
+			false => crate::extract_archive_streaming_with_options(paths, output, &options),
+		}
+	});
+	result.map_err(|e| to_py_err(py, e))
+}
+
+/// List the paths of the members of an archive
+#[pyfunction(name = "list")]
+#[pyo3(signature = (path, *, in_memory = false, passphrase = None, dictionary = None, verifying_key = None))]
+fn list_members(
+	py: Python<'_>,
+	path: PathBuf,
+	in_memory: bool,
+	passphrase: Option<String>,
+	dictionary: Option<PathBuf>,
+	verifying_key: Option<PathBuf>,
+) -> PyResult<Vec<PathBuf>> {
+	let result = py.detach(|| {
+		let options = extract_options(false, passphrase, dictionary, verifying_key)?;
+		crate::list_archive(&path, legacy_codec(in_memory), &options)
+	});
+	result.map_err(|e| to_py_err(py, e))
+}
+
+/// Check that an archive can be read in full, and that its files match the hashes recorded in it, without extracting it
+#[pyfunction(name = "test")]
+#[pyo3(signature = (path, *, in_memory = false, passphrase = None, dictionary = None, verifying_key = None))]
+fn test_members(
+	py: Python<'_>,
+	path: PathBuf,
+	in_memory: bool,
+	passphrase: Option<String>,
+	dictionary: Option<PathBuf>,
+	verifying_key: Option<PathBuf>,
+) -> PyResult<()> {
+	let result = py.detach(|| {
+		let options = extract_options(false, passphrase, dictionary, verifying_key)?;
+		crate::test_archive(&path, legacy_codec(in_memory), &options)
+	});
+	result.map_err(|e| to_py_err(py, e))
+}
+
+/// Open a single member of an archive for reading, without extracting the rest of it
+#[pyfunction]
+#[pyo3(signature = (path, member, *, in_memory = false, skip_verification = false, passphrase = None, dictionary = None, verifying_key = None))]
+#[allow(clippy::too_many_arguments)]
+fn open_entry(
+	py: Python<'_>,
+	path: PathBuf,
+	member: PathBuf,
+	in_memory: bool,
+	skip_verification: bool,
+	passphrase: Option<String>,
+	dictionary: Option<PathBuf>,
+	verifying_key: Option<PathBuf>,
+) -> PyResult<EntryReader> {
+	let options = extract_options(skip_verification, passphrase, dictionary, verifying_key)
+		.map_err(|e| to_py_err(py, e))?;
+	find_entry(&path, &member, legacy_codec(in_memory), &options).map_err(|e| to_py_err(py, e))
+}
+
+/// Find a member of an archive, returning a reader positioned at the start of its contents
+///
+/// # Arguments
+///
+/// * `path` - Path to a `larz` archive
+///
+/// * `member` - The path of the member within the archive
+///
+/// * `legacy_codec` - The codec to assume if the archive has no header
+///
+/// * `options` - Options controlling how the archive is read
+fn find_entry(
+	path: &std::path::Path,
+	member: &std::path::Path,
+	legacy_codec: Codec,
+	options: &ExtractOptions,
+) -> std::io::Result<EntryReader> {
+	let target = match locate_entry(path, member, legacy_codec, options)? {
+		Ok(reader) => return Ok(reader),
+		Err(target) => target,
+	};
+	// A hard link has the contents of the file it links to, which is stored before it, so the archive is read once more to find that file
+	match locate_entry(path, &target, legacy_codec, options)? {
+		Ok(reader) => Ok(reader),
+		Err(_) => Err(Error::new(
+			ErrorKind::InvalidData,
+			format!(
+				"'{}' is a hard link to another hard link",
+				crate::escape_path(member)
+			),
+		)),
+	}
+}
+
+/// Read an archive up to a member, returning a reader positioned at the start of its contents, or the target of the member if it is a hard link
+///
+/// # Arguments
+///
+/// * `path` - Path to a `larz` archive
+///
+/// * `member` - The path of the member within the archive
+///
+/// * `legacy_codec` - The codec to assume if the archive has no header
+///
+/// * `options` - Options controlling how the archive is read
+fn locate_entry(
+	path: &std::path::Path,
+	member: &std::path::Path,
+	legacy_codec: Codec,
+	options: &ExtractOptions,
+) -> std::io::Result<Result<EntryReader, PathBuf>> {
+	let (_, reader) =
+		crate::open_archive_reader(crate::open_verified(path, options)?, legacy_codec, options)?;
+	let mut archive = tar::Archive::new(reader);
+	let mut found = None;
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		// Files with holes in PAX archives are named by their PAX records rather than their headers
+		let sparse = format::pax_sparse(&mut entry)?;
+		let path = match &sparse {
+			Some(sparse) => sparse.name.clone(),
+			None => entry.path()?.into_owned(),
+		};
+		if path != member || incremental::is_deletions(&mut entry)? {
+			continue;
+		}
+		let entry_type = entry.header().entry_type();
+		if entry_type.is_hard_link() {
+			if let Some(target) = entry.link_name()? {
+				return Ok(Err(target
+					.components()
+					.filter(|component| *component != std::path::Component::CurDir)
+					.collect()));
+			}
+		}
+		if !entry_type.is_file() {
+			return Err(Error::new(
+				ErrorKind::InvalidInput,
+				format!("'{}' is not a regular file", crate::escape_path(member)),
+			));
+		}
+		let expected = match options.skip_verification {
+			true => None,
+			false => integrity::recorded_hash(&mut entry)?,
+		};
+		found = Some((entry.size(), expected, sparse));
+		break;
+	}
+	let (size, expected, sparse) = found.ok_or_else(|| {
+		Error::new(
+			ErrorKind::NotFound,
+			format!("'{}' is not in the archive", crate::escape_path(member)),
+		)
+	})?;
+	// The entry's contents have not been read yet, so the underlying reader is positioned at their start
+	let reader = archive.into_inner();
+	let (reader, size): (Box<dyn Read>, u64) = match sparse {
+		Some(sparse) => (
+			Box::new(sparse.expand(reader.take(size))?),
+			sparse.real_size,
+		),
+		None => (reader, size),
+	};
+	Ok(Ok(EntryReader {
+		reader: Some(reader.take(size)),
+		hasher: blake3::Hasher::new(),
+		expected,
+		name: member.to_path_buf(),
+		size,
+	}))
+}
+
+/// Get the options to read archives with from keyword arguments
+///
+/// # Arguments
+///
+/// * `skip_verification` - Do not check files against the hashes recorded in the archive
+///
+/// * `passphrase` - The passphrase to decrypt encrypted archives with
+///
+/// * `dictionary` - Path to the dictionary to decompress archives with
+///
+/// * `verifying_key` - Path to the public key archives must be signed with
+fn extract_options(
+	skip_verification: bool,
+	passphrase: Option<String>,
+	dictionary: Option<PathBuf>,
+	verifying_key: Option<PathBuf>,
+) -> std::io::Result<ExtractOptions> {
+	#[cfg(not(feature = "encryption"))]
+	if passphrase.is_some() {
+		return Err(unsupported("encryption"));
+	}
+	#[cfg(not(feature = "signing"))]
+	if verifying_key.is_some() {
+		return Err(unsupported("signing"));
+	}
+	Ok(ExtractOptions {
+		skip_verification,
+		dictionary: dictionary
+			.as_deref()
+			.map(dictionary::Dictionary::read)
+			.transpose()?,
+		#[cfg(feature = "encryption")]
+		passphrase: passphrase.map(crate::crypto::Passphrase::new),
+		#[cfg(feature = "signing")]
+		verifying_key: verifying_key
+			.as_deref()
+			.map(crate::signing::read_verifying_key)
+			.transpose()?,
+		atomic: false,
+		resume: false,
+		progress: None,
+	})
+}
+
+/// Get the codec to assume for archives written by larz 0.3 and earlier
+///
+/// # Arguments
+///
+/// * `in_memory` - Whether the archive was written in memory
+fn legacy_codec(in_memory: bool) -> Codec {
+	match in_memory {
+		true => Codec::Lz4Block,
+		false => Codec::Lz4Frame,
+	}
+}
+
+/// The error raised when an option needs a feature the module was built without
+///
+/// # Arguments
+///
+/// * `feature` - The name of the missing feature
+#[cfg(not(all(feature = "encryption", feature = "signing")))]
+fn unsupported(feature: &str) -> Error {
+	Error::new(
+		ErrorKind::Unsupported,
+		format!("This build of larz does not include the `{feature}` feature"),
+	)
+}
+
+/// Convert an error into the matching Python exception
+///
+/// # Arguments
+///
+/// * `py` - A token proving the GIL is held
+///
+/// * `e` - The error raised
+fn to_py_err(py: Python<'_>, e: Error) -> PyErr {
+	let message = e.to_string();
+	let Some(inner) = e.get_ref() else {
+		return e.into();
+	};
+	if let Some(integrity_error) = inner.downcast_ref::<integrity::IntegrityError>() {
+		let err = IntegrityError::new_err(message);
+		let _ = err
+			.value(py)
+			.setattr("members", integrity_error.members.clone());
+		return err;
+	}
+	#[cfg(feature = "encryption")]
+	if inner.is::<crate::crypto::DecryptionError>() {
+		return DecryptionError::new_err(message);
+	}
+	#[cfg(feature = "signing")]
+	if inner.is::<crate::signing::SignatureError>() {
+		return SignatureError::new_err(message);
+	}
+	if inner.is::<dictionary::DictionaryError>() {
+		return DictionaryError::new_err(message);
+	}
+	if inner.is::<incremental::IncrementalError>() {
+		return IncrementalError::new_err(message);
+	}
+	if inner.is::<crate::volume::VolumeError>() {
+		return VolumeError::new_err(message);
+	}
+	e.into()
+}
+
+/// Archive tool for efficient decompression
+#[pymodule]
+fn larz(m: &Bound<'_, PyModule>) -> PyResult<()> {
+	let py = m.py();
+	m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+	m.add("LarzError", py.get_type::<LarzError>())?;
+	m.add("IntegrityError", py.get_type::<IntegrityError>())?;
+	m.add("DecryptionError", py.get_type::<DecryptionError>())?;
+	m.add("SignatureError", py.get_type::<SignatureError>())?;
+	m.add("DictionaryError", py.get_type::<DictionaryError>())?;
+	m.add("IncrementalError", py.get_type::<IncrementalError>())?;
+	m.add("VolumeError", py.get_type::<VolumeError>())?;
+	m.add_class::<ArchiveStats>()?;
+	m.add_class::<EntryReader>()?;
+	m.add_function(wrap_pyfunction!(compress, m)?)?;
+	m.add_function(wrap_pyfunction!(extract, m)?)?;
+	m.add_function(wrap_pyfunction!(list_members, m)?)?;
+	m.add_function(wrap_pyfunction!(test_members, m)?)?;
+	m.add_function(wrap_pyfunction!(open_entry, m)?)?;
+	Ok(())
+}
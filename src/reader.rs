@@ -0,0 +1,310 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Reading archives one entry at a time.
+//!
+//! An [`ArchiveReader`] opens streaming and in-memory archives alike, and yields their entries lazily as the archive is decompressed.
+//! Each [`Entry`] exposes its metadata, and can be read like a file without extracting it to disk.
+//!
+//! ```rust,no_run
+//! use larz::header::Codec;
+//! use larz::reader::ArchiveReader;
+//! use larz::ExtractOptions;
+//! use std::io::Read;
+//! use std::path::PathBuf;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let mut reader = ArchiveReader::open(&PathBuf::from("path/to/archive.larz"), Codec::Lz4Frame, &ExtractOptions::default())?;
+//! for entry in reader.entries()? {
+//!     let mut entry = entry?;
+//!     if entry.entry_type().is_file() {
+//!         let mut contents = Vec::new();
+//!         entry.read_to_end(&mut contents)?;
+//!         println!("{} ({} bytes)", entry.path().display(), contents.len());
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::header::{ArchiveHeader, Codec};
+use crate::integrity::{self, IntegrityError};
+use crate::{format, incremental, ExtractOptions};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+
+/// Reads the entries of an archive
+pub struct ArchiveReader {
+	/// The header of the archive, if it has one
+	header: Option<ArchiveHeader>,
+	/// The uncompressed TAR data
+	archive: tar::Archive<Box<dyn Read>>,
+	/// Whether to check files against the hashes recorded in the archive
+	verify: bool,
+}
+
+impl ArchiveReader {
+	/// Open an archive file
+	///
+	/// If a trusted key is given in `options`, the archive's signature is verified first.
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to a `larz` archive, or to the first of its volumes
+	///
+	/// * `legacy_codec` - The codec to assume if the archive has no header, as written by larz 0.3 and earlier
+	///
+	/// * `options` - Options controlling how the archive is read
+	pub fn open(
+		path: &Path,
+		legacy_codec: Codec,
+		options: &ExtractOptions,
+	) -> Result<ArchiveReader> {
+		let (header, reader) = crate::open_archive_reader(
+			crate::open_verified(path, options)?,
+			legacy_codec,
+			options,
+		)?;
+		Ok(ArchiveReader::from_parts(header, reader, options))
+	}
+
+	/// Open an archive being read from a reader
+	///
+	/// Signatures are detached from archives, so they cannot be verified, and a trusted key in `options` is refused.
+	///
+	/// # Arguments
+	///
+	/// * `archive` - A reader over the bytes of a `larz` archive
+	///
+	/// * `legacy_codec` - The codec to assume if the archive has no header, as written by larz 0.3 and earlier
+	///
+	/// * `options` - Options controlling how the archive is read
+	pub fn new<R: Read + 'static>(
+		archive: R,
+		legacy_codec: Codec,
+		options: &ExtractOptions,
+	) -> Result<ArchiveReader> {
+		#[cfg(feature = "signing")]
+		if options.verifying_key.is_some() {
+			return Err(Error::new(
+				ErrorKind::Unsupported,
+				"Signatures cannot be verified when reading an archive from a reader",
+			));
+		}
+		let (header, reader) = crate::open_archive_reader(archive, legacy_codec, options)?;
+		Ok(ArchiveReader::from_parts(header, reader, options))
+	}
+
+	/// Read the entries of an opened archive
+	///
+	/// # Arguments
+	///
+	/// * `header` - The header of the archive, if it has one
+	///
+	/// * `reader` - A reader over the uncompressed TAR data
+	///
+	/// * `options` - Options controlling how the archive is read
+	fn from_parts(
+		header: Option<ArchiveHeader>,
+		reader: Box<dyn Read>,
+		options: &ExtractOptions,
+	) -> ArchiveReader {
+		ArchiveReader {
+			header,
+			archive: tar::Archive::new(reader),
+			verify: !options.skip_verification,
+		}
+	}
+
+	/// The header of the archive, or `None` if it was written by larz 0.3 or earlier
+	pub fn header(&self) -> Option<&ArchiveHeader> {
+		self.header.as_ref()
+	}
+
+	/// Iterate over the entries of the archive, in the order they are stored
+	///
+	/// The archive is read as the iterator advances, so each entry should be read before moving on to the next.
+	/// The list of files deleted by an incremental backup is not an entry.
+	pub fn entries(&mut self) -> Result<Entries<'_>> {
+		Ok(Entries {
+			inner: self.archive.entries()?,
+			verify: self.verify,
+		})
+	}
+}
+
+/// An iterator over the entries of an archive
+pub struct Entries<'a> {
+	/// The entries of the TAR data
+	inner: tar::Entries<'a, Box<dyn Read>>,
+	/// Whether to check files against the hashes recorded in the archive
+	verify: bool,
+}
+
+impl<'a> Iterator for Entries<'a> {
+	type Item = Result<Entry<'a>>;
+
+	fn next(&mut self) -> Option<Result<Entry<'a>>> {
+		loop {
+			let mut inner = match self.inner.next()? {
+				Ok(inner) => inner,
+				Err(e) => return Some(Err(e)),
+			};
+			match incremental::is_deletions(&mut inner) {
+				Ok(true) => continue,
+				Ok(false) => {}
+				Err(e) => return Some(Err(e)),
+			}
+			return Some(Entry::new(inner, self.verify));
+		}
+	}
+}
+
+/// An entry in an archive
+///
+/// Reading an entry reads its contents.
+/// Once a file has been read in full, its contents are checked against the hash recorded in the archive, and an [`IntegrityError`] is returned if they do not match.
+pub struct Entry<'a> {
+	/// The entry in the TAR data
+	inner: tar::Entry<'a, Box<dyn Read>>,
+	/// The path of the entry within the archive
+	path: PathBuf,
+	/// The path and size of the file, if it is stored as a PAX sparse entry
+	sparse: Option<format::PaxSparse>,
+	/// The regions of the file yet to be read, if it is stored as a PAX sparse entry
+	contents: Option<format::SparseContents>,
+	/// The hash recorded for the entry, if there is one
+	recorded_hash: Option<blake3::Hash>,
+	/// Hashes the contents of the entry as they are read, if they are being checked
+	hasher: Option<blake3::Hasher>,
+}
+
+impl<'a> Entry<'a> {
+	/// Prepare to read an entry
+	///
+	/// # Arguments
+	///
+	/// * `inner` - The entry in the TAR data
+	///
+	/// * `verify` - Whether to check the entry's contents against its recorded hash
+	fn new(mut inner: tar::Entry<'a, Box<dyn Read>>, verify: bool) -> Result<Entry<'a>> {
+		let recorded_hash = integrity::recorded_hash(&mut inner)?;
+		// Files with holes in PAX archives are named by their PAX records, and their data starts with a map of their regions
+		let sparse = format::pax_sparse(&mut inner)?;
+		let path = match &sparse {
+			Some(sparse) => sparse.name.clone(),
+			None => inner.path()?.into_owned(),
+		};
+		let contents = match &sparse {
+			Some(sparse) => Some(format::SparseContents::new(
+				format::read_sparse_map(&mut inner, sparse.real_size)?,
+				sparse.real_size,
+			)),
+			None => None,
+		};
+		Ok(Entry {
+			inner,
+			path,
+			sparse,
+			contents,
+			recorded_hash,
+			hasher: (verify && recorded_hash.is_some()).then(blake3::Hasher::new),
+		})
+	}
+
+	/// The path of the entry within the archive
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// The path of the entry within the archive, as it is stored
+	pub fn path_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+		match &self.sparse {
+			Some(sparse) => std::borrow::Cow::Borrowed(&sparse.name_bytes),
+			None => self.inner.path_bytes(),
+		}
+	}
+
+	/// The type of the entry, such as a regular file, a directory or a symbolic link
+	pub fn entry_type(&self) -> tar::EntryType {
+		self.inner.header().entry_type()
+	}
+
+	/// The size of the entry's contents in bytes
+	pub fn size(&self) -> u64 {
+		match &self.sparse {
+			Some(sparse) => sparse.real_size,
+			None => self.inner.size(),
+		}
+	}
+
+	/// The permissions of the entry
+	pub fn mode(&self) -> Result<u32> {
+		self.inner.header().mode()
+	}
+
+	/// When the entry was last modified, in seconds since the Unix epoch
+	pub fn mtime(&self) -> Result<u64> {
+		self.inner.header().mtime()
+	}
+
+	/// The ID of the user owning the entry
+	pub fn uid(&self) -> Result<u64> {
+		self.inner.header().uid()
+	}
+
+	/// The ID of the group owning the entry
+	pub fn gid(&self) -> Result<u64> {
+		self.inner.header().gid()
+	}
+
+	/// The path a link points to, if the entry is a symbolic or hard link
+	pub fn link_name(&self) -> Result<Option<PathBuf>> {
+		Ok(self.inner.link_name()?.map(|target| target.into_owned()))
+	}
+
+	/// The hash of the entry's contents recorded in the archive, if there is one
+	pub fn recorded_hash(&self) -> Option<blake3::Hash> {
+		self.recorded_hash
+	}
+
+	/// The TAR header of the entry
+	pub fn header(&self) -> &tar::Header {
+		self.inner.header()
+	}
+}
+
+impl Read for Entry<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		let length = match self.contents.as_mut() {
+			Some(contents) => contents.read(&mut self.inner, buf)?,
+			None => self.inner.read(buf)?,
+		};
+		if let Some(hasher) = self.hasher.as_mut() {
+			hasher.update(&buf[..length]);
+			if length == 0 && !buf.is_empty() {
+				let hash = self.hasher.take().map(|hasher| hasher.finalize());
+				if hash != self.recorded_hash {
+					return Err(Error::new(
+						ErrorKind::InvalidData,
+						IntegrityError {
+							members: vec![self.path.clone()],
+						},
+					));
+				}
+			}
+		}
+		Ok(length)
+	}
+}
@@ -0,0 +1,641 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Serving the contents of an archive over HTTP.
+//!
+//! A [`Server`] answers `GET` and `HEAD` requests for the members of an archive, as a static file server would for a directory.
+//! Files are sent with a content type guessed from their extension, an `ETag` taken from the hash recorded in the archive, and a `Last-Modified` date; conditional requests and single byte ranges are supported.
+//! Directories are served by their `index.html` file if they have one, or by a generated listing otherwise, and symbolic links are followed within the archive.
+//!
+//! The compressed data of an archive can only be read from start to finish, so the archive is read once when the server is opened.
+//! Every file is checked against its recorded hash, then kept in a scratch file alongside an index of where each member is, which is used to answer requests at random.
+//!
+//! ```rust,no_run
+//! use larz::header::Codec;
+//! use larz::serve::Server;
+//! use larz::ExtractOptions;
+//! use std::path::PathBuf;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let server = Server::open(&PathBuf::from("path/to/site.larz"), "127.0.0.1:8080", Codec::Lz4Frame, &ExtractOptions::default())?;
+//! server.run()
+//! # }
+//! ```
+
+use crate::header::Codec;
+use crate::reader::ArchiveReader;
+use crate::{ExtractOptions, Scratch};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+/// The most symbolic links followed while resolving a single path
+const MAX_LINKS: usize = 40;
+
+/// An HTTP server for the contents of an archive
+pub struct Server {
+	/// The listening HTTP server
+	http: tiny_http::Server,
+	/// The members of the archive
+	index: Arc<Index>,
+}
+
+impl Server {
+	/// Read an archive, and start listening for requests for its members
+	///
+	/// If a trusted key is given in `options`, the archive's signature is verified first.
+	///
+	/// # Arguments
+	///
+	/// * `archive_path` - Path to a `larz` archive, or to the first of its volumes
+	///
+	/// * `address` - The address to listen on, such as `127.0.0.1:8080`
+	///
+	/// * `legacy_codec` - The codec to assume if the archive has no header, as written by larz 0.3 and earlier
+	///
+	/// * `options` - Options controlling how the archive is read
+	pub fn open<A: ToSocketAddrs>(
+		archive_path: &Path,
+		address: A,
+		legacy_codec: Codec,
+		options: &ExtractOptions,
+	) -> Result<Server> {
+		let index = Index::read(archive_path, legacy_codec, options)?;
+		let http = tiny_http::Server::http(address).map_err(Error::other)?;
+		Ok(Server {
+			http,
+			index: Arc::new(index),
+		})
+	}
+
+	/// The address the server is listening on
+	pub fn local_addr(&self) -> Option<SocketAddr> {
+		self.http.server_addr().to_ip()
+	}
+
+	/// Answer requests until the server is closed, each on its own thread
+	pub fn run(&self) -> Result<()> {
+		for request in self.http.incoming_requests() {
+			let index = self.index.clone();
+			std::thread::spawn(move || {
+				let response = index.respond(&request);
+				// The client may have gone away
+				let _ = request.respond(response);
+			});
+		}
+		Ok(())
+	}
+}
+
+/// A member of an archive, as it is served
+enum Member {
+	/// A regular file
+	File {
+		/// Where the file's contents begin in the scratch file
+		offset: u64,
+		/// The size of the file in bytes
+		size: u64,
+		/// When the file was last modified, in seconds since the Unix epoch
+		mtime: u64,
+		/// The hash of the file's contents, in hexadecimal
+		hash: String,
+	},
+	/// A directory, which is only given a modification time if it is stored in the archive
+	Directory(Option<u64>),
+	/// A symbolic or hard link
+	Link {
+		/// The path the link points to
+		target: String,
+		/// Whether the link is a hard link, whose target is relative to the root of the archive rather than to the link
+		hard: bool,
+	},
+}
+
+/// The members of an archive, and the contents of its files
+struct Index {
+	/// The members of the archive, by their path within it
+	members: BTreeMap<String, Member>,
+	/// The contents of every file in the archive, one after another
+	contents: Mutex<Scratch>,
+}
+
+impl Index {
+	/// Read the members of an archive
+	///
+	/// # Arguments
+	///
+	/// * `archive_path` - Path to a `larz` archive, or to the first of its volumes
+	///
+	/// * `legacy_codec` - The codec to assume if the archive has no header, as written by larz 0.3 and earlier
+	///
+	/// * `options` - Options controlling how the archive is read
+	fn read(archive_path: &Path, legacy_codec: Codec, options: &ExtractOptions) -> Result<Index> {
+		let mut reader = ArchiveReader::open(archive_path, legacy_codec, options)?;
+		let mut contents = crate::scratch()?;
+		let mut members = BTreeMap::new();
+		members.insert(String::new(), Member::Directory(None));
+		let mut buffer = vec![0; 64 * 1024];
+
+		for entry in reader.entries()? {
+			let mut entry = entry?;
+			let Some(key) = member_key(entry.path()) else {
+				continue;
+			};
+			let entry_type = entry.entry_type();
+			let is_file =
+				entry_type.is_file() || entry_type.is_contiguous() || entry_type.is_gnu_sparse();
+			let member = if is_file {
+				let offset = contents.stream_position()?;
+				let mut hasher = entry.recorded_hash().is_none().then(blake3::Hasher::new);
+				loop {
+					let length = entry.read(&mut buffer)?;
+					if length == 0 {
+						break;
+					}
+					if let Some(hasher) = hasher.as_mut() {
+						hasher.update(&buffer[..length]);
+					}
+					contents.write_all(&buffer[..length])?;
+				}
+				let hash = match hasher {
+					Some(hasher) => hasher.finalize(),
+					None => entry.recorded_hash().unwrap(),
+				};
+				Member::File {
+					offset,
+					size: contents.stream_position()? - offset,
+					mtime: entry.mtime()?,
+					hash: hash.to_hex().to_string(),
+				}
+			} else if entry_type.is_dir() {
+				Member::Directory(Some(entry.mtime()?))
+			} else if entry_type.is_symlink() || entry_type.is_hard_link() {
+				let Some(target) = entry.link_name()? else {
+					continue;
+				};
+				Member::Link {
+					target: target.to_string_lossy().into_owned(),
+					hard: entry_type.is_hard_link(),
+				}
+			} else {
+				continue;
+			};
+
+			// Directories are not always stored before their contents, or at all
+			let mut parent = key.as_str();
+			while let Some((ancestor, _)) = parent.rsplit_once('/') {
+				members
+					.entry(ancestor.to_owned())
+					.or_insert(Member::Directory(None));
+				parent = ancestor;
+			}
+			members.insert(key, member);
+		}
+		contents.flush()?;
+
+		Ok(Index {
+			members,
+			contents: Mutex::new(contents),
+		})
+	}
+
+	/// Find the member at a path, following any symbolic links along the way
+	///
+	/// Returns the path of the member found, with links resolved, alongside the member itself.
+	///
+	/// # Arguments
+	///
+	/// * `path` - The path within the archive, with its components separated by `/`
+	fn resolve(&self, path: &str) -> Option<(String, &Member)> {
+		let mut pending: VecDeque<String> = path.split('/').map(str::to_owned).collect();
+		let mut resolved: Vec<String> = Vec::new();
+		let mut links = 0;
+		while let Some(component) = pending.pop_front() {
+			match component.as_str() {
+				"" | "." => continue,
+				".." => {
+					resolved.pop()?;
+					continue;
+				}
+				_ => resolved.push(component),
+			}
+			if let Member::Link { target, hard } = self.members.get(&resolved.join("/"))? {
+				links += 1;
+				if links > MAX_LINKS || target.starts_with('/') {
+					return None;
+				}
+				resolved.pop();
+				if *hard {
+					resolved.clear();
+				}
+				for component in target.rsplit('/') {
+					pending.push_front(component.to_owned());
+				}
+			}
+		}
+		let key = resolved.join("/");
+		let member = self.members.get(&key)?;
+		Some((key, member))
+	}
+
+	/// Answer a request
+	///
+	/// # Arguments
+	///
+	/// * `request` - A request for a member of the archive
+	fn respond(self: &Arc<Self>, request: &Request) -> Response<Box<dyn Read + Send>> {
+		if !matches!(request.method(), Method::Get | Method::Head) {
+			return text_response(405, "Method not allowed")
+				.with_header(header("Allow", "GET, HEAD"));
+		}
+		let url = request.url();
+		let url_path = url.split(['?', '#']).next().unwrap_or_default();
+		let Some(path) = percent_decode(url_path) else {
+			return text_response(400, "Bad request");
+		};
+		if path.split('/').any(|component| component == "..") {
+			return text_response(400, "Bad request");
+		}
+
+		match self.resolve(&path) {
+			Some((key, Member::Directory(mtime))) => {
+				if !url_path.ends_with('/') {
+					let components: Vec<&str> = path
+						.split('/')
+						.filter(|component| !component.is_empty() && *component != ".")
+						.collect();
+					let location = format!("/{}/", percent_encode(&components.join("/")));
+					return text_response(301, "Moved permanently")
+						.with_header(header("Location", &location));
+				}
+				let index_key = match key.is_empty() {
+					true => "index.html".to_owned(),
+					false => format!("{key}/index.html"),
+				};
+				match self.resolve(&index_key) {
+					Some((index_key, Member::File { .. })) => {
+						self.file_response(&index_key, request)
+					}
+					_ => self.listing_response(&key, *mtime),
+				}
+			}
+			Some((key, Member::File { .. })) => self.file_response(&key, request),
+			_ => text_response(404, "Not found"),
+		}
+	}
+
+	/// Answer a request for a file, or for part of one
+	///
+	/// # Arguments
+	///
+	/// * `key` - The path of the file within the archive
+	///
+	/// * `request` - The request for the file
+	fn file_response(
+		self: &Arc<Self>,
+		key: &str,
+		request: &Request,
+	) -> Response<Box<dyn Read + Send>> {
+		let Some(Member::File {
+			offset,
+			size,
+			mtime,
+			hash,
+		}) = self.members.get(key)
+		else {
+			return text_response(404, "Not found");
+		};
+		let etag = format!("\"{hash}\"");
+		let last_modified = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(*mtime));
+		let mut headers = vec![
+			header("ETag", &etag),
+			header("Last-Modified", &last_modified),
+			header("Accept-Ranges", "bytes"),
+		];
+
+		let not_modified = match request_header(request, "If-None-Match") {
+			Some(tags) => tags
+				.split(',')
+				.map(|tag| tag.trim().trim_start_matches("W/"))
+				.any(|tag| tag == "*" || tag == etag),
+			None => request_header(request, "If-Modified-Since")
+				.and_then(|since| httpdate::parse_http_date(since).ok())
+				.is_some_and(|since| since >= UNIX_EPOCH + Duration::from_secs(*mtime)),
+		};
+		if not_modified {
+			return empty_response(304, headers);
+		}
+
+		let mime = mime_guess::from_path(key).first_or_octet_stream();
+		let content_type =
+			match mime.type_() == mime_guess::mime::TEXT && mime.get_param("charset").is_none() {
+				true => format!("{mime}; charset=utf-8"),
+				false => mime.to_string(),
+			};
+		headers.push(header("Content-Type", &content_type));
+
+		// A range is only honoured if the file has not changed since the client last saw it
+		let range = request_header(request, "Range").filter(|_| {
+			request_header(request, "If-Range")
+				.is_none_or(|validator| validator == etag || validator == last_modified)
+		});
+		let (status, start, length) = match range.and_then(|range| parse_range(range, *size)) {
+			None => (200, 0, *size),
+			Some(Some((start, end))) => {
+				headers.push(header(
+					"Content-Range",
+					&format!("bytes {start}-{end}/{size}"),
+				));
+				(206, start, end - start + 1)
+			}
+			Some(None) => {
+				headers.push(header("Content-Range", &format!("bytes */{size}")));
+				return empty_response(416, headers);
+			}
+		};
+		let body = FileBody {
+			index: self.clone(),
+			position: offset + start,
+			remaining: length,
+		};
+		Response::new(
+			StatusCode(status),
+			headers,
+			Box::new(body),
+			Some(length as usize),
+			None,
+		)
+	}
+
+	/// Answer a request for a directory with a listing of its contents
+	///
+	/// # Arguments
+	///
+	/// * `key` - The path of the directory within the archive
+	///
+	/// * `mtime` - When the directory was last modified, in seconds since the Unix epoch, if it is known
+	fn listing_response(&self, key: &str, mtime: Option<u64>) -> Response<Box<dyn Read + Send>> {
+		let prefix = match key.is_empty() {
+			true => String::new(),
+			false => format!("{key}/"),
+		};
+		let title = html_escape(&format!("/{prefix}"));
+		let mut html = format!(
+			"<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {title}</title>\n</head>\n<body>\n<h1>Index of {title}</h1>\n<ul>\n"
+		);
+		if !key.is_empty() {
+			html.push_str("<li><a href=\"../\">../</a></li>\n");
+		}
+		let children = self
+			.members
+			.range(prefix.clone()..)
+			.skip_while(|(child, _)| child.is_empty())
+			.take_while(|(child, _)| child.starts_with(&prefix))
+			.filter(|(child, _)| !child[prefix.len()..].contains('/'));
+		for (child, member) in children {
+			let mut name = child[prefix.len()..].to_owned();
+			let is_directory = match member {
+				Member::Directory(_) => true,
+				Member::Link { .. } => {
+					matches!(self.resolve(child), Some((_, Member::Directory(_))))
+				}
+				Member::File { .. } => false,
+			};
+			if is_directory {
+				name.push('/');
+			}
+			html.push_str(&format!(
+				"<li><a href=\"{}\">{}</a></li>\n",
+				html_escape(&percent_encode(&name)),
+				html_escape(&name)
+			));
+		}
+		html.push_str("</ul>\n</body>\n</html>\n");
+
+		let mut response = Response::new(
+			StatusCode(200),
+			vec![header("Content-Type", "text/html; charset=utf-8")],
+			Box::new(Cursor::new(html.clone().into_bytes())) as Box<dyn Read + Send>,
+			Some(html.len()),
+			None,
+		);
+		if let Some(mtime) = mtime {
+			response.add_header(header(
+				"Last-Modified",
+				&httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(mtime)),
+			));
+		}
+		response
+	}
+}
+
+/// Reads part of a file from the scratch file of an index
+struct FileBody {
+	/// The index holding the file
+	index: Arc<Index>,
+	/// The position in the scratch file to read from next
+	position: u64,
+	/// The number of bytes left to read
+	remaining: u64,
+}
+
+impl Read for FileBody {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		if self.remaining == 0 || buf.is_empty() {
+			return Ok(0);
+		}
+		let wanted = buf
+			.len()
+			.min(self.remaining.min(usize::MAX as u64) as usize);
+		let mut contents = self
+			.index
+			.contents
+			.lock()
+			.map_err(|_| Error::other("The archive's contents are unavailable"))?;
+		contents.seek(SeekFrom::Start(self.position))?;
+		let length = contents.read(&mut buf[..wanted])?;
+		if length == 0 {
+			return Err(ErrorKind::UnexpectedEof.into());
+		}
+		self.position += length as u64;
+		self.remaining -= length as u64;
+		Ok(length)
+	}
+}
+
+/// The path of a member within an archive, with its components separated by `/`
+///
+/// Returns `None` for paths that could not be requested, such as those leaving the archive.
+///
+/// # Arguments
+///
+/// * `path` - The path of the member, as it is stored
+fn member_key(path: &Path) -> Option<String> {
+	let mut components = Vec::new();
+	for component in path.components() {
+		match component {
+			Component::Normal(name) => components.push(name.to_string_lossy()),
+			Component::CurDir => {}
+			_ => return None,
+		}
+	}
+	(!components.is_empty()).then(|| components.join("/"))
+}
+
+/// Parse the value of a `Range` header
+///
+/// Returns `None` if the header should be ignored, and the whole file served; `Some(None)` if the range cannot be satisfied; or the first and last byte of the range otherwise.
+/// Requests for more than one range are answered with the whole file.
+///
+/// # Arguments
+///
+/// * `range` - The value of the `Range` header
+///
+/// * `size` - The size of the requested file in bytes
+fn parse_range(range: &str, size: u64) -> Option<Option<(u64, u64)>> {
+	let range = range.trim().strip_prefix("bytes=")?;
+	if range.contains(',') {
+		return None;
+	}
+	let (start, end) = range.split_once('-')?;
+	let (start, end) = (start.trim(), end.trim());
+	if start.is_empty() {
+		let suffix: u64 = end.parse().ok()?;
+		return Some((suffix > 0 && size > 0).then(|| (size.saturating_sub(suffix), size - 1)));
+	}
+	let start: u64 = start.parse().ok()?;
+	let end = match end.is_empty() {
+		true => u64::MAX,
+		false => end.parse().ok().filter(|end| *end >= start)?,
+	};
+	Some((start < size).then(|| (start, end.min(size - 1))))
+}
+
+/// The value of a request header, if it was sent
+///
+/// # Arguments
+///
+/// * `request` - The request
+///
+/// * `name` - The name of the header
+fn request_header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+	request
+		.headers()
+		.iter()
+		.find(|header| header.field.equiv(name))
+		.map(|header| header.value.as_str())
+}
+
+/// Build a response header
+///
+/// # Arguments
+///
+/// * `name` - The name of the header
+///
+/// * `value` - The value of the header, which must be ASCII
+fn header(name: &str, value: &str) -> Header {
+	Header::from_bytes(name, value).expect("Header values are ASCII")
+}
+
+/// A response with a short plain-text body
+///
+/// # Arguments
+///
+/// * `status` - The status code of the response
+///
+/// * `text` - The body of the response
+fn text_response(status: u16, text: &'static str) -> Response<Box<dyn Read + Send>> {
+	Response::new(
+		StatusCode(status),
+		vec![header("Content-Type", "text/plain; charset=utf-8")],
+		Box::new(text.as_bytes()),
+		Some(text.len()),
+		None,
+	)
+}
+
+/// A response without a body
+///
+/// # Arguments
+///
+/// * `status` - The status code of the response
+///
+/// * `headers` - The headers of the response
+fn empty_response(status: u16, headers: Vec<Header>) -> Response<Box<dyn Read + Send>> {
+	Response::new(
+		StatusCode(status),
+		headers,
+		Box::new(std::io::empty()),
+		Some(0),
+		None,
+	)
+}
+
+/// Decode the percent-encoded path of a URL
+///
+/// Returns `None` if the path is not validly encoded UTF-8.
+///
+/// # Arguments
+///
+/// * `path` - The path of a URL
+fn percent_decode(path: &str) -> Option<String> {
+	let mut decoded = Vec::with_capacity(path.len());
+	let mut bytes = path.bytes();
+	while let Some(byte) = bytes.next() {
+		match byte {
+			b'%' => {
+				let high = (bytes.next()? as char).to_digit(16)?;
+				let low = (bytes.next()? as char).to_digit(16)?;
+				decoded.push((high * 16 + low) as u8);
+			}
+			_ => decoded.push(byte),
+		}
+	}
+	String::from_utf8(decoded).ok()
+}
+
+/// Percent-encode a path for use in a URL, leaving its `/` separators as they are
+///
+/// # Arguments
+///
+/// * `path` - The path to encode
+fn percent_encode(path: &str) -> String {
+	let mut encoded = String::with_capacity(path.len());
+	for byte in path.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+				encoded.push(byte as char)
+			}
+			_ => encoded.push_str(&format!("%{byte:02X}")),
+		}
+	}
+	encoded
+}
+
+/// Escape text for use in HTML
+///
+/// # Arguments
+///
+/// * `text` - The text to escape
+fn html_escape(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+		.replace('\'', "&#39;")
+}
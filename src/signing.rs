@@ -0,0 +1,326 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Ed25519 signing and signature verification of archives.
+//!
+//! An archive is signed by hashing the whole file with BLAKE3 and signing the digest with an Ed25519 key.
+//! Archives split into volumes are hashed as the volumes joined together, so the signature covers every volume.
+//! The signature is written to a detached file next to the archive, named after it with a `.sig` extension appended (eg, `backup.larz.sig`).
+//!
+//! ## Signature files
+//!
+//! | Field      | Size     | Description                          |
+//! | ---------- | -------- | ------------------------------------ |
+//! | Magic      | 8 bytes  | `LARZSIG1`                           |
+//! | Public key | 32 bytes | The key the archive was signed with  |
+//! | Signature  | 64 bytes | The Ed25519 signature of the digest  |
+//!
+//! ## Key files
+//! Keys are stored as 64 hexadecimal digits followed by a newline. Secret keys hold the 32-byte Ed25519 seed, and public keys hold the 32-byte compressed point.
+
+pub use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+
+use ed25519_dalek::{Signer, SECRET_KEY_LENGTH};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+
+/// The bytes every signature file starts with
+const SIGNATURE_MAGIC: &[u8; 8] = b"LARZSIG1";
+
+/// Prefixed to the digest before signing, so that larz signatures cannot be confused with signatures made for other purposes
+const SIGNATURE_CONTEXT: &[u8] = b"larz archive signature v1\n";
+
+/// The length of a signature file
+const SIGNATURE_FILE_LENGTH: usize = SIGNATURE_MAGIC.len() + 32 + 64;
+
+/// The reason an archive's signature could not be verified
+///
+/// This is the inner error of the `std::io::Error` returned by [`verify_archive`]; retrieve it with `std::io::Error::get_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+	/// The archive has no signature file
+	Unsigned(PathBuf),
+	/// The signature file is not a valid `larz` signature
+	Malformed(PathBuf),
+	/// The archive was signed with a different key than the one trusted
+	UntrustedKey(PathBuf),
+	/// The signature does not match the contents of the archive
+	Invalid(PathBuf),
+}
+
+impl fmt::Display for SignatureError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SignatureError::Unsigned(path) => {
+				write!(f, "'{}' is not signed", crate::escape_path(path))
+			}
+			SignatureError::Malformed(path) => write!(
+				f,
+				"The signature of '{}' is malformed",
+				crate::escape_path(path)
+			),
+			SignatureError::UntrustedKey(path) => write!(
+				f,
+				"'{}' was signed with an untrusted key",
+				crate::escape_path(path)
+			),
+			SignatureError::Invalid(path) => write!(
+				f,
+				"The signature of '{}' does not match its contents",
+				crate::escape_path(path)
+			),
+		}
+	}
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Get the path of the detached signature file of an archive
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to a `larz` archive
+pub fn signature_path(archive_path: &Path) -> PathBuf {
+	let mut signature_path = OsString::from(archive_path.as_os_str());
+	signature_path.push(".sig");
+	PathBuf::from(signature_path)
+}
+
+/// Compute the BLAKE3 digest of an archive, across every volume if it is split into volumes
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to a `larz` archive, or to the first of its volumes
+pub fn archive_digest(archive_path: &Path) -> Result<blake3::Hash> {
+	digest(&mut crate::open_archive_file(archive_path)?)
+}
+
+/// Compute the BLAKE3 digest of an archive being read
+///
+/// # Arguments
+///
+/// * `archive` - The contents of the archive, read to the end
+fn digest<R: Read>(archive: &mut R) -> Result<blake3::Hash> {
+	let mut hasher = blake3::Hasher::new();
+	std::io::copy(archive, &mut hasher)?;
+	Ok(hasher.finalize())
+}
+
+/// The message signed for a given archive digest
+///
+/// # Arguments
+///
+/// * `digest` - The BLAKE3 digest of the archive
+fn signed_message(digest: &blake3::Hash) -> Vec<u8> {
+	[SIGNATURE_CONTEXT, digest.as_bytes()].concat()
+}
+
+/// Sign an archive, writing a detached signature file next to it
+///
+/// Returns the path of the signature file.
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to a `larz` archive
+///
+/// * `signing_key` - The secret key to sign the archive with
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use larz::signing::{read_signing_key, sign_archive};
+/// use std::path::PathBuf;
+///
+/// let signing_key = read_signing_key(&PathBuf::from("path/to/key")).unwrap();
+/// sign_archive(&PathBuf::from("path/to/archive.larz"), &signing_key).unwrap();
+/// ```
+pub fn sign_archive(archive_path: &Path, signing_key: &SigningKey) -> Result<PathBuf> {
+	let digest = archive_digest(archive_path)?;
+	let signature = signing_key.sign(&signed_message(&digest));
+
+	let mut contents = Vec::with_capacity(SIGNATURE_FILE_LENGTH);
+	contents.extend_from_slice(SIGNATURE_MAGIC);
+	contents.extend_from_slice(signing_key.verifying_key().as_bytes());
+	contents.extend_from_slice(&signature.to_bytes());
+	let signature_path = signature_path(archive_path);
+	std::fs::write(&signature_path, contents)?;
+	Ok(signature_path)
+}
+
+/// Verify the detached signature of an archive
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to a `larz` archive
+///
+/// * `verifying_key` - The public key the archive is expected to be signed with
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use larz::signing::{read_verifying_key, verify_archive};
+/// use std::path::PathBuf;
+///
+/// let verifying_key = read_verifying_key(&PathBuf::from("path/to/key.pub")).unwrap();
+/// verify_archive(&PathBuf::from("path/to/archive.larz"), &verifying_key).unwrap();
+/// ```
+pub fn verify_archive(archive_path: &Path, verifying_key: &VerifyingKey) -> Result<()> {
+	verify_reader(
+		archive_path,
+		&mut crate::open_archive_file(archive_path)?,
+		verifying_key,
+	)
+}
+
+/// Verify the detached signature of an archive against contents already opened for reading
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to the `larz` archive, next to which its signature file lies
+///
+/// * `archive` - The contents of the archive, read to the end
+///
+/// * `verifying_key` - The public key the archive is expected to be signed with
+pub(crate) fn verify_reader<R: Read>(
+	archive_path: &Path,
+	archive: &mut R,
+	verifying_key: &VerifyingKey,
+) -> Result<()> {
+	let failure = |error: SignatureError| Error::new(ErrorKind::InvalidData, error);
+	let archive_pathbuf = archive_path.to_path_buf();
+
+	let contents = match std::fs::read(signature_path(archive_path)) {
+		Ok(contents) => contents,
+		Err(e) if e.kind() == ErrorKind::NotFound => {
+			return Err(failure(SignatureError::Unsigned(archive_pathbuf)))
+		}
+		Err(e) => return Err(e),
+	};
+	if contents.len() != SIGNATURE_FILE_LENGTH || !contents.starts_with(SIGNATURE_MAGIC) {
+		return Err(failure(SignatureError::Malformed(archive_pathbuf)));
+	}
+	let (public_key, signature) = contents[SIGNATURE_MAGIC.len()..].split_at(32);
+	if public_key != verifying_key.as_bytes() {
+		return Err(failure(SignatureError::UntrustedKey(archive_pathbuf)));
+	}
+	let signature = Signature::from_slice(signature)
+		.map_err(|_| failure(SignatureError::Malformed(archive_pathbuf.clone())))?;
+
+	let digest = digest(archive)?;
+	verifying_key
+		.verify_strict(&signed_message(&digest), &signature)
+		.map_err(|_| failure(SignatureError::Invalid(archive_pathbuf)))
+}
+
+/// Generate a new secret key
+pub fn generate_signing_key() -> Result<SigningKey> {
+	let mut seed = [0u8; SECRET_KEY_LENGTH];
+	getrandom::getrandom(&mut seed).map_err(|e| Error::other(e.to_string()))?;
+	Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Write a secret key to a file
+///
+/// On Unix-like systems, the file is only readable by its owner.
+///
+/// # Arguments
+///
+/// * `path` - Path to write the key to
+///
+/// * `signing_key` - The secret key
+pub fn write_signing_key(path: &Path, signing_key: &SigningKey) -> Result<()> {
+	let mut options = std::fs::OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+	let mut f = options.open(path)?;
+	std::io::Write::write_all(&mut f, encode_key(signing_key.as_bytes()).as_bytes())
+}
+
+/// Write a public key to a file
+///
+/// # Arguments
+///
+/// * `path` - Path to write the key to
+///
+/// * `verifying_key` - The public key
+pub fn write_verifying_key(path: &Path, verifying_key: &VerifyingKey) -> Result<()> {
+	std::fs::write(path, encode_key(verifying_key.as_bytes()))
+}
+
+/// Read a secret key from a file
+///
+/// # Arguments
+///
+/// * `path` - Path to the key file
+pub fn read_signing_key(path: &Path) -> Result<SigningKey> {
+	Ok(SigningKey::from_bytes(&read_key(path)?))
+}
+
+/// Read a public key from a file
+///
+/// # Arguments
+///
+/// * `path` - Path to the key file
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey> {
+	VerifyingKey::from_bytes(&read_key(path)?).map_err(|_| {
+		Error::new(
+			ErrorKind::InvalidData,
+			format!("'{}' is not a valid public key", crate::escape_path(path)),
+		)
+	})
+}
+
+/// Encode a key as hexadecimal digits
+///
+/// # Arguments
+///
+/// * `key` - The raw bytes of the key
+fn encode_key(key: &[u8; 32]) -> String {
+	let mut encoded: String = key.iter().map(|byte| format!("{byte:02x}")).collect();
+	encoded.push('\n');
+	encoded
+}
+
+/// Read a key encoded as hexadecimal digits
+///
+/// # Arguments
+///
+/// * `path` - Path to the key file
+fn read_key(path: &Path) -> Result<[u8; 32]> {
+	let malformed = || {
+		Error::new(
+			ErrorKind::InvalidData,
+			format!("'{}' is not a valid key file", crate::escape_path(path)),
+		)
+	};
+	let mut encoded = String::new();
+	File::open(path)?
+		.take(1024)
+		.read_to_string(&mut encoded)
+		.map_err(|_| malformed())?;
+	let encoded = encoded.trim();
+	if encoded.len() != 64 || !encoded.is_ascii() {
+		return Err(malformed());
+	}
+	let mut key = [0u8; 32];
+	for (byte, digits) in key.iter_mut().zip(encoded.as_bytes().chunks(2)) {
+		*byte = u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16)
+			.map_err(|_| malformed())?;
+	}
+	Ok(key)
+}
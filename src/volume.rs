@@ -0,0 +1,429 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Multi-volume archives.
+//!
+//! An archive can be split into volumes no larger than a given size, for storage with limits on the size of a single file.
+//! The archive `name.larz` is then written as `name.larz.001`, `name.larz.002`, and so on; joined together, the volumes hold the archive exactly as it would otherwise be written, followed by a trailer.
+//! The volume size is recorded in the archive header, so every volume but the last can be checked to be exactly that size.
+//!
+//! The trailer is the magic bytes `LARZVEND` followed by the little-endian length of the archive, excluding the trailer (eight bytes).
+//! It is how the last volume is recognised: if it is absent, the set of volumes is incomplete.
+
+use crate::atomic::AtomicFile;
+use crate::header::ArchiveHeader;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Header metadata key holding the size of each volume, as a little-endian 64-bit integer
+pub(crate) const VOLUME_SIZE_KEY: &str = "larz.volume.size";
+
+/// The bytes the trailer of the last volume starts with
+const TRAILER_MAGIC: &[u8; 8] = b"LARZVEND";
+
+/// The length of the trailer
+const TRAILER_SIZE: u64 = TRAILER_MAGIC.len() as u64 + 8;
+
+/// The reason the volumes of an archive cannot be read
+///
+/// This is the inner error of the `std::io::Error` returned when opening a multi-volume archive; retrieve it with `std::io::Error::get_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VolumeError {
+	/// A volume could not be found
+	Missing(PathBuf),
+	/// A volume is shorter than it should be
+	Truncated {
+		/// Path to the volume
+		volume: PathBuf,
+		/// How long the volume should be, if it is known
+		expected: Option<u64>,
+		/// How long the volume is
+		actual: u64,
+	},
+}
+
+impl fmt::Display for VolumeError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			VolumeError::Missing(volume) => {
+				write!(f, "Volume '{}' is missing", crate::escape_path(volume))
+			}
+			VolumeError::Truncated {
+				volume,
+				expected: Some(expected),
+				actual,
+			} => write!(
+				f,
+				"Volume '{}' is truncated: it is {} bytes long, but should be {} bytes long",
+				crate::escape_path(volume),
+				actual,
+				expected
+			),
+			VolumeError::Truncated {
+				volume,
+				expected: None,
+				actual,
+			} => write!(
+				f,
+				"Volume '{}' is truncated: it is {} bytes long, and does not end the archive",
+				crate::escape_path(volume),
+				actual
+			),
+		}
+	}
+}
+
+impl std::error::Error for VolumeError {}
+
+/// Get the path of a volume of an archive
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to the archive, as if it were not split into volumes
+///
+/// * `number` - The number of the volume, starting from one
+pub fn volume_path(archive_path: &Path, number: u32) -> PathBuf {
+	let mut volume_path = archive_path.as_os_str().to_owned();
+	volume_path.push(format!(".{number:03}"));
+	PathBuf::from(volume_path)
+}
+
+/// Find the first volume of an archive, if it has been split into volumes
+///
+/// `path` may be the first volume itself, or the path of the archive as if it were not split into volumes.
+///
+/// # Arguments
+///
+/// * `path` - Path given for the archive
+pub fn first_volume(path: &Path) -> Option<PathBuf> {
+	if path.extension().is_some_and(|extension| extension == "001") {
+		return Some(path.to_path_buf());
+	}
+	let first_volume = volume_path(path, 1);
+	(!path.exists() && first_volume.exists()).then_some(first_volume)
+}
+
+/// Writes an archive split into volumes of a maximum size
+pub struct VolumeWriter {
+	/// Path to the archive, as if it were not split into volumes
+	archive_path: PathBuf,
+	/// The maximum size of each volume
+	volume_size: u64,
+	/// Fail rather than replace volumes that already exist
+	no_clobber: bool,
+	/// The number of the volume being written
+	number: u32,
+	/// The volumes written in full, which replace any existing volumes once the last is written
+	written_volumes: Vec<AtomicFile>,
+	/// The volume being written
+	current: BufWriter<AtomicFile>,
+	/// How many bytes have been written to the current volume
+	written: u64,
+	/// How many bytes have been written to all volumes
+	total: u64,
+}
+
+impl VolumeWriter {
+	/// Begin writing an archive split into volumes
+	///
+	/// # Arguments
+	///
+	/// * `archive_path` - Path to the archive, as if it were not split into volumes
+	///
+	/// * `volume_size` - The maximum size of each volume
+	///
+	/// * `no_clobber` - Fail rather than replace volumes that already exist
+	pub fn create(archive_path: &Path, volume_size: u64, no_clobber: bool) -> Result<VolumeWriter> {
+		if volume_size == 0 {
+			return Err(Error::new(
+				ErrorKind::InvalidInput,
+				"The volume size must be larger than zero",
+			));
+		}
+		Ok(VolumeWriter {
+			archive_path: archive_path.to_path_buf(),
+			volume_size,
+			no_clobber,
+			number: 1,
+			written_volumes: Vec::new(),
+			current: BufWriter::new(AtomicFile::create(
+				&volume_path(archive_path, 1),
+				no_clobber,
+			)?),
+			written: 0,
+			total: 0,
+		})
+	}
+
+	/// Write the trailer and finish writing the last volume, returning the number of volumes and their total size
+	///
+	/// The volumes replace any existing ones only once they have all been written, and any volumes left over from a longer archive are removed.
+	pub fn finish(mut self) -> Result<(u32, u64)> {
+		let mut trailer = TRAILER_MAGIC.to_vec();
+		trailer.extend_from_slice(&self.total.to_le_bytes());
+		self.write_all(&trailer)?;
+		let last = self.current.into_inner().map_err(|e| e.into_error())?;
+		for volume in self.written_volumes.into_iter().chain([last]) {
+			volume.commit()?;
+		}
+		for number in self.number + 1..=highest_volume(&self.archive_path)? {
+			match std::fs::remove_file(volume_path(&self.archive_path, number)) {
+				Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+				_ => {}
+			}
+		}
+		Ok((self.number, self.total))
+	}
+}
+
+impl Write for VolumeWriter {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		if self.written == self.volume_size {
+			self.number += 1;
+			let next = BufWriter::new(AtomicFile::create(
+				&volume_path(&self.archive_path, self.number),
+				self.no_clobber,
+			)?);
+			let written = std::mem::replace(&mut self.current, next);
+			self.written_volumes
+				.push(written.into_inner().map_err(|e| e.into_error())?);
+			self.written = 0;
+		}
+		let length = buf.len().min(
+			(self.volume_size - self.written)
+				.try_into()
+				.unwrap_or(usize::MAX),
+		);
+		let length = self.current.write(&buf[..length])?;
+		self.written += length as u64;
+		self.total += length as u64;
+		Ok(length)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.current.flush()
+	}
+}
+
+/// Reads an archive split into volumes, as if it were a single file
+///
+/// Every volume is opened up front, so the volumes read are the ones that were checked when the archive was opened.
+pub struct VolumeReader {
+	/// Every volume, opened for reading, and its length
+	volumes: Vec<(PathBuf, File, u64)>,
+	/// The index of the volume being read
+	index: usize,
+	/// The length of the archive, excluding the trailer
+	length: u64,
+	/// How many bytes of the archive are left to read, excluding the trailer
+	remaining: u64,
+}
+
+impl VolumeReader {
+	/// Open the volumes of an archive, checking that none are missing or truncated
+	///
+	/// # Arguments
+	///
+	/// * `first_volume` - Path to the first volume of the archive
+	pub fn open(first_volume: &Path) -> Result<VolumeReader> {
+		let archive_path = first_volume.with_extension("");
+		let failure = |error: VolumeError| Error::new(ErrorKind::InvalidData, error);
+
+		// Find the volumes, and make sure there is no gap among them
+		let mut volumes = Vec::new();
+		loop {
+			let path = volume_path(&archive_path, volumes.len() as u32 + 1);
+			match File::open(&path) {
+				Ok(f) => {
+					let length = f.metadata()?.len();
+					volumes.push((path, f, length));
+				}
+				Err(e) if e.kind() == ErrorKind::NotFound => break,
+				Err(e) => return Err(e),
+			}
+		}
+		if volumes.is_empty() {
+			return Err(Error::new(
+				ErrorKind::NotFound,
+				VolumeError::Missing(first_volume.to_path_buf()),
+			));
+		}
+		if highest_volume(&archive_path)? > volumes.len() as u32 {
+			return Err(failure(VolumeError::Missing(volume_path(
+				&archive_path,
+				volumes.len() as u32 + 1,
+			))));
+		}
+
+		// The size of every volume but the last is recorded in the header
+		let (first_path, _, first_length) = &volumes[0];
+		let (first_path, first_length) = (first_path.clone(), *first_length);
+		let (last_path, _, last_length) = &volumes[volumes.len() - 1];
+		let (last_path, last_length) = (last_path.clone(), *last_length);
+		let lengths: Vec<(PathBuf, u64)> = volumes
+			.iter()
+			.map(|(path, _, length)| (path.clone(), *length))
+			.collect();
+		let count = volumes.len() as u32;
+		let total: u64 = lengths.iter().map(|(_, length)| length).sum();
+		let mut reader = VolumeReader {
+			volumes,
+			index: 0,
+			length: total,
+			remaining: total,
+		};
+		let volume_size = ArchiveHeader::read_from(&mut reader)
+			.ok()
+			.and_then(|(header, _)| header)
+			.and_then(|header| header.metadata.get(VOLUME_SIZE_KEY).cloned())
+			.and_then(|value| <[u8; 8]>::try_from(value.as_slice()).ok())
+			.map(u64::from_le_bytes)
+			.ok_or_else(|| {
+				failure(VolumeError::Truncated {
+					volume: first_path,
+					expected: None,
+					actual: first_length,
+				})
+			})?;
+		for (path, length) in &lengths[..lengths.len() - 1] {
+			if *length != volume_size {
+				return Err(failure(VolumeError::Truncated {
+					volume: path.clone(),
+					expected: Some(volume_size),
+					actual: *length,
+				}));
+			}
+		}
+
+		// Only the last volume ends with the trailer
+		let complete = match total.checked_sub(TRAILER_SIZE) {
+			Some(archive_length) => {
+				reader.seek_to(archive_length)?;
+				let mut trailer = [0u8; TRAILER_SIZE as usize];
+				reader.read_exact(&mut trailer)?;
+				trailer.starts_with(TRAILER_MAGIC)
+					&& trailer[TRAILER_MAGIC.len()..] == archive_length.to_le_bytes()
+			}
+			None => false,
+		};
+		if !complete {
+			return Err(match last_length == volume_size {
+				true => failure(VolumeError::Missing(volume_path(&archive_path, count + 1))),
+				false => failure(VolumeError::Truncated {
+					volume: last_path,
+					expected: None,
+					actual: last_length,
+				}),
+			});
+		}
+
+		reader.length = total - TRAILER_SIZE;
+		reader.seek_to(0)?;
+		Ok(reader)
+	}
+
+	/// The paths of every volume
+	pub fn volumes(&self) -> impl Iterator<Item = &Path> {
+		self.volumes.iter().map(|(path, _, _)| path.as_path())
+	}
+
+	/// Move to a position in the joined volumes
+	///
+	/// # Arguments
+	///
+	/// * `position` - How far into the joined volumes to move
+	fn seek_to(&mut self, mut position: u64) -> Result<()> {
+		self.remaining = self.length.saturating_sub(position);
+		for (index, (_, f, length)) in self.volumes.iter_mut().enumerate() {
+			if position < *length {
+				f.seek(SeekFrom::Start(position))?;
+				self.index = index;
+				return Ok(());
+			}
+			position -= *length;
+		}
+		self.index = self.volumes.len();
+		Ok(())
+	}
+}
+
+impl Read for VolumeReader {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		let limit = buf
+			.len()
+			.min(self.remaining.try_into().unwrap_or(usize::MAX));
+		while limit > 0 && self.index < self.volumes.len() {
+			let length = self.volumes[self.index].1.read(&mut buf[..limit])?;
+			if length > 0 {
+				self.remaining -= length as u64;
+				return Ok(length);
+			}
+			self.index += 1;
+			if let Some((_, next, _)) = self.volumes.get_mut(self.index) {
+				next.seek(SeekFrom::Start(0))?;
+			}
+		}
+		Ok(0)
+	}
+}
+
+impl Seek for VolumeReader {
+	fn seek(&mut self, position: SeekFrom) -> Result<u64> {
+		let position = match position {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+			SeekFrom::Current(offset) => (self.length - self.remaining).checked_add_signed(offset),
+		}
+		.ok_or_else(|| {
+			Error::new(
+				ErrorKind::InvalidInput,
+				"Cannot seek before the start of the archive",
+			)
+		})?;
+		self.seek_to(position)?;
+		Ok(position)
+	}
+}
+
+/// Find the highest-numbered volume of an archive present next to the first
+///
+/// # Arguments
+///
+/// * `archive_path` - Path to the archive, as if it were not split into volumes
+fn highest_volume(archive_path: &Path) -> Result<u32> {
+	let (Some(parent), Some(name)) = (archive_path.parent(), archive_path.file_name()) else {
+		return Ok(0);
+	};
+	let parent = match parent.as_os_str().is_empty() {
+		true => Path::new("."),
+		false => parent,
+	};
+	let prefix = format!("{}.", name.to_string_lossy());
+	let mut highest = 0;
+	for child in std::fs::read_dir(parent)? {
+		let child = child?.file_name();
+		let child = child.to_string_lossy();
+		if let Some(number) = child.strip_prefix(&prefix) {
+			if number.len() >= 3 && number.bytes().all(|byte| byte.is_ascii_digit()) {
+				highest = highest.max(number.parse().unwrap_or(0));
+			}
+		}
+	}
+	Ok(highest)
+}
@@ -0,0 +1,229 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! WebAssembly bindings, for reading and writing archives in browsers and edge workers.
+//!
+//! There is no filesystem to work with, so archives are built from, and extracted to, `Uint8Array`s held in memory.
+//! Build the package with [`wasm-pack`](https://rustwasm.github.io/wasm-pack/), then:
+//!
+//! ```js
+//! import { compress, extract, list } from "larz";
+//!
+//! const archive = compress([["hello.txt", new TextEncoder().encode("Hello, world!")]]);
+//! console.log(list(archive));
+//! const files = extract(archive);
+//! console.log(new TextDecoder().decode(files.get("hello.txt")));
+//! ```
+//!
+//! Archives are written as streaming archives, and can be extracted by the `larz` command-line tool.
+//! Any archive without encryption, or with its dictionary embedded, can be listed and extracted; directories and links are left out of the extracted files.
+//! The hash of every extracted file is checked against the one recorded in the archive.
+//! Run the tests under Node with `wasm-pack test --node --features wasm`.
+
+use crate::header::{Codec, Flags};
+use crate::integrity;
+use crate::{
+	format, incremental, new_header, open_archive_reader, BodyWriter, CompressOptions, Compressor,
+	ExtractOptions, TarWriter,
+};
+use js_sys::{Array, Map, Uint8Array};
+use std::io::{Cursor, Error, ErrorKind, Read, Result};
+use std::path::Path;
+use wasm_bindgen::prelude::*;
+
+/// Build an archive from a list of files
+///
+/// # Arguments
+///
+/// * `files` - An iterable of `[name, bytes]` pairs, such as an array of pairs or a `Map`, where each name is a string and each file's contents are a `Uint8Array`
+#[wasm_bindgen]
+pub fn compress(files: &JsValue) -> std::result::Result<Vec<u8>, JsError> {
+	let files = js_sys::try_iter(files)
+		.ok()
+		.flatten()
+		.ok_or_else(|| JsError::new("Files must be given as an iterable of [name, bytes] pairs"))?;
+	let mut pairs = Vec::new();
+	for pair in files {
+		let pair = pair
+			.ok()
+			.and_then(|pair| pair.dyn_into::<Array>().ok())
+			.ok_or_else(|| JsError::new("Each file must be given as a [name, bytes] pair"))?;
+		let name = pair
+			.get(0)
+			.as_string()
+			.ok_or_else(|| JsError::new("The name of a file must be a string"))?;
+		let contents = pair
+			.get(1)
+			.dyn_into::<Uint8Array>()
+			.map_err(|_| JsError::new(&format!("The contents of '{name}' must be a Uint8Array")))?;
+		pairs.push((name, contents.to_vec()));
+	}
+	let mtime = (js_sys::Date::now() / 1000.0) as u64;
+	Ok(compress_files(pairs, mtime)?)
+}
+
+/// List the paths of the members of an archive
+///
+/// # Arguments
+///
+/// * `archive` - The bytes of a `larz` archive
+#[wasm_bindgen]
+pub fn list(archive: &[u8]) -> std::result::Result<Vec<String>, JsError> {
+	let mut members = Vec::new();
+	read_members(archive, |entry| {
+		members.push(member_name(entry)?);
+		Ok(())
+	})?;
+	Ok(members)
+}
+
+/// Extract the files in an archive, returning a `Map` from their paths to their contents
+///
+/// # Arguments
+///
+/// * `archive` - The bytes of a `larz` archive
+#[wasm_bindgen]
+pub fn extract(archive: &[u8]) -> std::result::Result<Map, JsError> {
+	let files = Map::new();
+	let mut mismatched = Vec::new();
+	read_members(archive, |entry| {
+		let entry_type = entry.header().entry_type();
+		// A hard link has the contents of the file it links to, which is stored before it
+		if entry_type.is_hard_link() {
+			if let Some(target) = entry.link_name_bytes() {
+				let target = String::from_utf8_lossy(&target);
+				let contents = files.get(&JsValue::from_str(target.trim_start_matches("./")));
+				if !contents.is_undefined() {
+					files.set(
+						&JsValue::from_str(&member_name(entry)?),
+						&Uint8Array::new(&contents),
+					);
+				}
+			}
+			return Ok(());
+		}
+		if !entry_type.is_file() && !entry_type.is_gnu_sparse() {
+			return Ok(());
+		}
+		let expected = integrity::recorded_hash(entry)?;
+		let name = member_name(entry)?;
+		// The size in the header is not trusted, so the buffer only grows as the contents are read
+		let mut contents = Vec::new();
+		match format::pax_sparse(entry)? {
+			Some(sparse) => sparse.expand(entry)?.read_to_end(&mut contents)?,
+			None => entry.read_to_end(&mut contents)?,
+		};
+		if expected.is_some_and(|expected| blake3::hash(&contents) != expected) {
+			mismatched.push(name.clone().into());
+		}
+		files.set(
+			&JsValue::from_str(&name),
+			&Uint8Array::from(contents.as_slice()),
+		);
+		Ok(())
+	})?;
+	match mismatched.is_empty() {
+		true => Ok(files),
+		false => Err(Error::new(
+			ErrorKind::InvalidData,
+			integrity::IntegrityError {
+				members: mismatched,
+			},
+		)
+		.into()),
+	}
+}
+
+/// Write an archive holding the given files
+///
+/// # Arguments
+///
+/// * `files` - The name and contents of each file
+///
+/// * `mtime` - The modification time given to every file, in seconds since the Unix epoch
+fn compress_files(files: Vec<(String, Vec<u8>)>, mtime: u64) -> Result<Vec<u8>> {
+	let options = CompressOptions::default();
+	let mut header = new_header(Codec::Lz4Frame, &options, None);
+	header.flags.insert(Flags::CHECKSUMMED);
+	let body = BodyWriter::begin(Vec::new(), header, &options)?;
+	let mut frame_info = lz4_flex::frame::FrameInfo::new();
+	frame_info.content_checksum = true;
+	let compressor = Compressor::Frame(Box::new(lz4_flex::frame::FrameEncoder::with_frame_info(
+		frame_info, body,
+	)));
+	let mut tar = tar::Builder::new(TarWriter::new(compressor, options.deduplicate));
+
+	for (name, contents) in files {
+		let mut file_header = format::new_header(options.format);
+		file_header.set_entry_type(tar::EntryType::Regular);
+		file_header.set_size(contents.len() as u64);
+		file_header.set_mode(0o644);
+		file_header.set_mtime(mtime);
+		format::append(
+			&mut tar,
+			options.format,
+			&mut file_header,
+			Path::new(&name),
+			None,
+			None,
+			Some(blake3::hash(&contents)),
+			contents.as_slice(),
+		)?;
+	}
+
+	let (compressor, _) = tar.into_inner()?.finish()?;
+	compressor.finish()?.finish()
+}
+
+/// Call a function with each member of an archive, other than the files deleted by an incremental backup
+///
+/// # Arguments
+///
+/// * `archive` - The bytes of a `larz` archive
+///
+/// * `visit` - Called with each member, in the order they are stored
+fn read_members(
+	archive: &[u8],
+	mut visit: impl FnMut(&mut tar::Entry<'_, Box<dyn Read>>) -> Result<()>,
+) -> Result<()> {
+	let (_, reader) = open_archive_reader(
+		Cursor::new(archive.to_vec()),
+		Codec::Lz4Frame,
+		&ExtractOptions::default(),
+	)?;
+	let mut tar = tar::Archive::new(reader);
+	for entry in tar.entries()? {
+		let mut entry = entry?;
+		if !incremental::is_deletions(&mut entry)? {
+			visit(&mut entry)?;
+		}
+	}
+	// Read past the end of the TAR data, so that the checksum of the compressed data is checked
+	std::io::copy(&mut tar.into_inner(), &mut std::io::sink())?;
+	Ok(())
+}
+
+/// The path of a member within an archive, as a string
+///
+/// Files with holes in PAX archives are named by their PAX records rather than their headers.
+///
+/// # Arguments
+///
+/// * `entry` - The member of the archive
+fn member_name<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<String> {
+	Ok(match format::pax_sparse(entry)? {
+		Some(sparse) => String::from_utf8_lossy(&sparse.name_bytes).into_owned(),
+		None => String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+	})
+}
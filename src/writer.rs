@@ -0,0 +1,580 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Writing archives one entry at a time.
+//!
+//! An [`ArchiveWriter`] builds an archive from files and directories on disk, as `compress_archive_*` does, and from entries that only exist in memory or behind a reader.
+//!
+//! ```rust,no_run
+//! use larz::writer::{ArchiveWriter, EntryMetadata};
+//! use larz::CompressOptions;
+//! use std::path::PathBuf;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let mut writer = ArchiveWriter::create(PathBuf::from("path/to/output.larz"), &CompressOptions::default())?;
+//! writer.add_path("path/to/directory")?;
+//! writer.add_dir("reports", &EntryMetadata::default())?;
+//! writer.add_bytes("reports/manifest.json", b"{}", &EntryMetadata::default())?;
+//! writer.add_symlink("latest.json", "reports/manifest.json", &EntryMetadata::default())?;
+//! let stats = writer.finish()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::header::Codec;
+use crate::journal::{Checkpoint, CompressJournal};
+use crate::{
+	append, format, incremental, new_header, ArchiveFile, ArchiveStats, BodyWriter,
+	CompressOptions, TarFormat, TarWriter,
+};
+use std::io::{BufWriter, Error, ErrorKind, Read, Result, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The metadata of an entry that is not read from disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EntryMetadata {
+	/// The permissions of the entry, or `None` for `0o644` on files, `0o755` on directories and `0o777` on symbolic links
+	pub mode: Option<u32>,
+	/// When the entry was last modified, in seconds since the Unix epoch, or `None` for the time it is added
+	pub mtime: Option<u64>,
+	/// The ID of the user owning the entry
+	pub uid: u64,
+	/// The ID of the group owning the entry
+	pub gid: u64,
+}
+
+/// Writes an archive one entry at a time
+///
+/// Entries are written through the same backends as `compress_archive_streaming_with_options` and `compress_archive_memory_with_options`, and the archive is not complete until [`ArchiveWriter::finish`] is called.
+/// Until then, it is written to a temporary file beside the output path, which is removed if the writer is dropped.
+/// When a snapshot file is given, only entries added with [`ArchiveWriter::add_path`] take part in the listed-incremental backup; other entries are always stored.
+/// When resuming, entries the checkpoint journal records as written are left out, whichever way they are added.
+pub struct ArchiveWriter {
+	/// The TAR data being written
+	tar: tar::Builder<Backend>,
+	/// Path to write the archive to
+	output_path: PathBuf,
+	/// Options controlling how the archive is written
+	options: CompressOptions,
+	/// The state of the listed-incremental backup being written, if any
+	incremental: Option<incremental::Incremental>,
+	/// The files added from disk so far, so that later links to them are stored as hard links
+	links: append::HardLinks,
+	/// The checkpoint journal of the archive, if it can be resumed
+	journal: Option<CompressJournal>,
+}
+
+/// Where an [`ArchiveWriter`] writes its TAR data to
+enum Backend {
+	/// The TAR data is compressed as it is written
+	#[cfg(feature = "streaming")]
+	Streaming(Box<TarWriter<crate::Compressor<BodyWriter<ArchiveFile>>>>),
+	/// The TAR data is held in memory, and compressed once it is complete
+	Memory(Vec<u8>),
+}
+
+impl Write for Backend {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		match self {
+			#[cfg(feature = "streaming")]
+			Backend::Streaming(tar) => tar.write(buf),
+			Backend::Memory(tar) => tar.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		match self {
+			#[cfg(feature = "streaming")]
+			Backend::Streaming(tar) => tar.flush(),
+			Backend::Memory(tar) => tar.flush(),
+		}
+	}
+}
+
+impl Checkpoint for Backend {
+	fn tar_offset(&self) -> u64 {
+		match self {
+			#[cfg(feature = "streaming")]
+			Backend::Streaming(tar) => match tar.as_ref() {
+				TarWriter::Plain(_, size) => *size,
+				TarWriter::Deduplicated(deduplicator) => deduplicator.stats().input_size,
+			},
+			Backend::Memory(tar) => tar.len() as u64,
+		}
+	}
+
+	fn checkpoint(&mut self) -> Result<u64> {
+		let unsupported = || {
+			Error::new(
+				ErrorKind::Unsupported,
+				"Only plain streaming archives can be resumed",
+			)
+		};
+		match self {
+			#[cfg(feature = "streaming")]
+			Backend::Streaming(tar) => match tar.as_mut() {
+				TarWriter::Plain(compressor, _) => match compressor.end_frame()? {
+					BodyWriter::Plain(f) => f.sync(),
+					#[cfg(feature = "encryption")]
+					BodyWriter::Encrypted(_) => Err(unsupported()),
+				},
+				TarWriter::Deduplicated(_) => Err(unsupported()),
+			},
+			Backend::Memory(_) => Err(unsupported()),
+		}
+	}
+}
+
+impl ArchiveWriter {
+	/// Start writing an archive, compressing it as it is written
+	///
+	/// # Arguments
+	///
+	/// * `output_path` - Path to write the archive to
+	///
+	/// * `options` - Options controlling how the archive is written
+	#[cfg(feature = "streaming")]
+	pub fn create(output_path: PathBuf, options: &CompressOptions) -> Result<ArchiveWriter> {
+		let incremental = begin_incremental(&output_path, options)?;
+		let journal = match options.resume {
+			true => Some(CompressJournal::open(&output_path, options)?),
+			false => None,
+		};
+		let resumed = journal.as_ref().and_then(CompressJournal::resumed);
+		let f = match journal {
+			Some(_) => ArchiveFile::resume(
+				&output_path,
+				options,
+				resumed.map(|(archive_offset, _)| archive_offset),
+			)?,
+			None => ArchiveFile::create(&output_path, options)?,
+		};
+		let compressor = match &options.dictionary {
+			Some(dictionary) => {
+				let header = new_header(Codec::Lz4DictBlocks, options, incremental.as_ref());
+				let body = BodyWriter::begin(f, header, options)?;
+				crate::Compressor::Dictionary(crate::dictionary::DictionaryWriter::new(
+					body,
+					dictionary.clone(),
+				))
+			}
+			None => {
+				let mut header = new_header(Codec::Lz4Frame, options, incremental.as_ref());
+				header.flags.insert(crate::header::Flags::CHECKSUMMED);
+				// A resumed archive already has its header, and continues in a new frame
+				let body = match resumed {
+					Some(_) => BodyWriter::Plain(f),
+					None => BodyWriter::begin(f, header, options)?,
+				};
+				let mut frame_info = lz4_flex::frame::FrameInfo::new();
+				frame_info.content_checksum = true;
+				crate::Compressor::Frame(Box::new(lz4_flex::frame::FrameEncoder::with_frame_info(
+					frame_info, body,
+				)))
+			}
+		};
+		let tar = match resumed {
+			Some((_, tar_offset)) => TarWriter::Plain(compressor, tar_offset),
+			None => TarWriter::new(compressor, options.deduplicate),
+		};
+		Ok(ArchiveWriter {
+			tar: tar::Builder::new(Backend::Streaming(Box::new(tar))),
+			output_path,
+			options: options.clone(),
+			incremental,
+			links: append::HardLinks::new(options.link_duplicates),
+			journal,
+		})
+	}
+
+	/// Start writing an archive, holding it in memory until it is compressed in one go
+	///
+	/// # Arguments
+	///
+	/// * `output_path` - Path to write the archive to
+	///
+	/// * `options` - Options controlling how the archive is written
+	pub fn create_in_memory(
+		output_path: PathBuf,
+		options: &CompressOptions,
+	) -> Result<ArchiveWriter> {
+		if options.resume {
+			return Err(Error::new(
+				ErrorKind::Unsupported,
+				"Archives compressed in memory cannot be resumed",
+			));
+		}
+		Ok(ArchiveWriter {
+			incremental: begin_incremental(&output_path, options)?,
+			tar: tar::Builder::new(Backend::Memory(Vec::new())),
+			output_path,
+			options: options.clone(),
+			links: append::HardLinks::new(options.link_duplicates),
+			journal: None,
+		})
+	}
+
+	/// Add a file or directory on disk to the archive
+	///
+	/// The contents of a directory are stored at the root of the archive, and a file is stored under its file name.
+	///
+	/// # Arguments
+	///
+	/// * `path` - Path to the file or directory
+	pub fn add_path(&mut self, path: impl AsRef<Path>) -> Result<()> {
+		self.append_paths::<std::io::Sink>(vec![path.as_ref().to_path_buf()], None)
+	}
+
+	/// Add files and directories on disk to the archive, as `compress_archive_*` does
+	///
+	/// # Arguments
+	///
+	/// * `paths` - A list of paths pointing to files or directories intended to be archived
+	///
+	/// * `optional_logger` - An optional `BufWriter` to log information to
+	pub(crate) fn append_paths<W: Write>(
+		&mut self,
+		paths: Vec<PathBuf>,
+		optional_logger: Option<&mut BufWriter<W>>,
+	) -> Result<()> {
+		append::append_paths(
+			&mut self.tar,
+			paths,
+			&self.options,
+			self.incremental.as_mut(),
+			&mut self.links,
+			self.journal.as_mut(),
+			optional_logger,
+		)
+	}
+
+	/// Add a file held in memory to the archive
+	///
+	/// # Arguments
+	///
+	/// * `name` - The path of the file within the archive
+	///
+	/// * `data` - The contents of the file
+	///
+	/// * `metadata` - The metadata of the file
+	pub fn add_bytes(
+		&mut self,
+		name: impl AsRef<Path>,
+		data: &[u8],
+		metadata: &EntryMetadata,
+	) -> Result<()> {
+		if self.completed(name.as_ref()) {
+			return Ok(());
+		}
+		self.append_file(
+			name.as_ref(),
+			data,
+			data.len() as u64,
+			blake3::hash(data),
+			metadata,
+		)
+	}
+
+	/// Add a file read from a reader to the archive
+	///
+	/// The hash of a file is recorded before its contents, so the contents are copied to an anonymous temporary file while they are hashed.
+	///
+	/// # Arguments
+	///
+	/// * `name` - The path of the file within the archive
+	///
+	/// * `reader` - A reader over the contents of the file; no more than `size` bytes are read from it
+	///
+	/// * `size` - The size of the file in bytes; it is an error for the reader to end sooner
+	///
+	/// * `metadata` - The metadata of the file
+	pub fn add_reader(
+		&mut self,
+		name: impl AsRef<Path>,
+		reader: impl Read,
+		size: u64,
+		metadata: &EntryMetadata,
+	) -> Result<()> {
+		let name = name.as_ref();
+		if self.completed(name) {
+			return Ok(());
+		}
+		let mut spooled = crate::scratch()?;
+		let copied = std::io::copy(&mut reader.take(size), &mut spooled)?;
+		if copied != size {
+			return Err(Error::new(
+				ErrorKind::UnexpectedEof,
+				format!(
+					"'{}' should be {size} bytes long, but only {copied} bytes could be read",
+					crate::escape_path(name)
+				),
+			));
+		}
+		spooled.rewind()?;
+		let mut hasher = blake3::Hasher::new();
+		std::io::copy(&mut spooled, &mut hasher)?;
+		spooled.rewind()?;
+		self.append_file(name, spooled, size, hasher.finalize(), metadata)
+	}
+
+	/// Add a symbolic link to the archive
+	///
+	/// # Arguments
+	///
+	/// * `name` - The path of the link within the archive
+	///
+	/// * `target` - The path the link points to
+	///
+	/// * `metadata` - The metadata of the link
+	pub fn add_symlink(
+		&mut self,
+		name: impl AsRef<Path>,
+		target: impl AsRef<Path>,
+		metadata: &EntryMetadata,
+	) -> Result<()> {
+		let name = name.as_ref();
+		if self.completed(name) {
+			return Ok(());
+		}
+		let format = self.options.format;
+		let mut header = entry_header(format, tar::EntryType::Symlink, 0, metadata, 0o777);
+		format::append(
+			&mut self.tar,
+			format,
+			&mut header,
+			name,
+			Some(target.as_ref()),
+			None,
+			None,
+			std::io::empty(),
+		)?;
+		self.record(name, None)?;
+		self.report(name, 0);
+		Ok(())
+	}
+
+	/// Add an empty directory to the archive
+	///
+	/// # Arguments
+	///
+	/// * `name` - The path of the directory within the archive
+	///
+	/// * `metadata` - The metadata of the directory
+	pub fn add_dir(&mut self, name: impl AsRef<Path>, metadata: &EntryMetadata) -> Result<()> {
+		let name = name.as_ref();
+		if self.completed(name) {
+			return Ok(());
+		}
+		let format = self.options.format;
+		let mut header = entry_header(format, tar::EntryType::Directory, 0, metadata, 0o755);
+		format::append(
+			&mut self.tar,
+			format,
+			&mut header,
+			name,
+			None,
+			None,
+			None,
+			std::io::empty(),
+		)?;
+		self.record(name, None)?;
+		self.report(name, 0);
+		Ok(())
+	}
+
+	/// Finish writing the archive, returning the sizes of the archive at each stage of writing it
+	pub fn finish(mut self) -> Result<ArchiveStats> {
+		if let Some(incremental) = &self.incremental {
+			incremental.append_deletions(&mut self.tar, self.options.format)?;
+		}
+		let (volumes, archive_size, stats) = match self.tar.into_inner()? {
+			#[cfg(feature = "streaming")]
+			Backend::Streaming(tar) => {
+				let (tar_compressor, stats) = tar.finish()?;
+				let (volumes, archive_size) = tar_compressor.finish()?.finish()?.finish()?;
+				(volumes, archive_size, stats)
+			}
+			Backend::Memory(tar) => {
+				let options = &self.options;
+				let mut staged_tar = TarWriter::new(Vec::new(), options.deduplicate);
+				staged_tar.write_all(&tar)?;
+				let (tar_bytes, stats) = staged_tar.finish()?;
+
+				let f = ArchiveFile::create(&self.output_path, options)?;
+				let (volumes, archive_size) = match &options.dictionary {
+					Some(dictionary) => {
+						let header =
+							new_header(Codec::Lz4DictBlocks, options, self.incremental.as_ref());
+						let body = BodyWriter::begin(f, header, options)?;
+						let mut compressor =
+							crate::dictionary::DictionaryWriter::new(body, dictionary.clone());
+						compressor.write_all(&tar_bytes)?; // Write data to file
+						compressor.finish()?.finish()?.finish()?
+					}
+					None => {
+						let header =
+							new_header(Codec::Lz4Block, options, self.incremental.as_ref());
+						let mut body = BodyWriter::begin(f, header, options)?;
+						body.write_all(&lz4_flex::block::compress_prepend_size(&tar_bytes))?; // Write data to file
+						body.finish()?.finish()?
+					}
+				};
+				(volumes, archive_size, stats)
+			}
+		};
+		if let Some(incremental) = self.incremental {
+			incremental.finish()?;
+		}
+		if let Some(journal) = self.journal {
+			journal.finish()?;
+		}
+		Ok(ArchiveStats {
+			archive_size,
+			volumes,
+			..stats
+		})
+	}
+
+	/// Append a regular file to the archive, recording the hash of its contents
+	///
+	/// # Arguments
+	///
+	/// * `name` - The path of the file within the archive
+	///
+	/// * `data` - A reader over the contents of the file
+	///
+	/// * `size` - The size of the file in bytes
+	///
+	/// * `hash` - The hash of the file's contents
+	///
+	/// * `metadata` - The metadata of the file
+	fn append_file(
+		&mut self,
+		name: &Path,
+		data: impl Read,
+		size: u64,
+		hash: blake3::Hash,
+		metadata: &EntryMetadata,
+	) -> Result<()> {
+		let format = self.options.format;
+		let mut header = entry_header(format, tar::EntryType::Regular, size, metadata, 0o644);
+		format::append(
+			&mut self.tar,
+			format,
+			&mut header,
+			name,
+			None,
+			None,
+			Some(hash),
+			data,
+		)?;
+		self.record(name, Some(hash))?;
+		self.report(name, size);
+		Ok(())
+	}
+
+	/// Whether an entry was written before the checkpoint being resumed from, and so should be left out
+	///
+	/// # Arguments
+	///
+	/// * `name` - The path of the entry within the archive
+	fn completed(&self, name: &Path) -> bool {
+		self.journal
+			.as_ref()
+			.is_some_and(|journal| journal.completed(name).is_some())
+	}
+
+	/// Record that an entry has been added in the checkpoint journal, if the archive can be resumed
+	///
+	/// # Arguments
+	///
+	/// * `name` - The path of the entry within the archive
+	///
+	/// * `hash` - The hash of the entry's contents, if it is a file
+	fn record(&mut self, name: &Path, hash: Option<blake3::Hash>) -> Result<()> {
+		match &mut self.journal {
+			Some(journal) => journal.record(&mut self.tar, name, hash),
+			None => Ok(()),
+		}
+	}
+
+	/// Report that an entry has been added, if progress is being reported
+	///
+	/// # Arguments
+	///
+	/// * `name` - The path of the entry within the archive
+	///
+	/// * `size` - The size of the entry in bytes
+	fn report(&self, name: &Path, size: u64) {
+		if let Some(progress) = &self.options.progress {
+			progress.report(name, size);
+		}
+	}
+}
+
+/// Create the directory an archive is written to, and begin a listed-incremental backup if a snapshot file was given
+///
+/// # Arguments
+///
+/// * `output_path` - Path to write the archive to
+///
+/// * `options` - Options controlling how the archive is written
+fn begin_incremental(
+	output_path: &Path,
+	options: &CompressOptions,
+) -> Result<Option<incremental::Incremental>> {
+	if let Some(parent) = output_path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	options
+		.snapshot
+		.as_deref()
+		.map(incremental::Incremental::begin)
+		.transpose()
+}
+
+/// Create the TAR header of an entry that is not read from disk
+///
+/// # Arguments
+///
+/// * `format` - The format the archive is written in
+///
+/// * `entry_type` - The type of the entry
+///
+/// * `size` - The size of the entry's contents in bytes
+///
+/// * `metadata` - The metadata of the entry
+///
+/// * `default_mode` - The permissions of the entry, if none are given in its metadata
+fn entry_header(
+	format: TarFormat,
+	entry_type: tar::EntryType,
+	size: u64,
+	metadata: &EntryMetadata,
+	default_mode: u32,
+) -> tar::Header {
+	let mut header = format::new_header(format);
+	header.set_entry_type(entry_type);
+	header.set_size(size);
+	header.set_mode(metadata.mode.unwrap_or(default_mode));
+	header.set_mtime(metadata.mtime.unwrap_or_else(|| {
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |elapsed| elapsed.as_secs())
+	}));
+	header.set_uid(metadata.uid);
+	header.set_gid(metadata.gid);
+	header
+}
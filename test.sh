@@ -1,3 +1,4 @@
 #!/bin/sh
 cargo test -p larz --all-targets --all-features --future-incompat-report
-cargo test --doc -p larz --all-features --future-incompat-report -- --show-output
\ No newline at end of file
+cargo test --doc -p larz --all-features --future-incompat-report -- --show-output
+cargo test -p larz --test wasm --target wasm32-unknown-unknown --features wasm --future-incompat-report
\ No newline at end of file
@@ -0,0 +1,160 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of the command-line interface.
+
+#![cfg(feature = "bin")]
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Run larz with the given arguments
+///
+/// # Arguments
+///
+/// * `arguments` - The arguments to run larz with
+fn larz<I: IntoIterator<Item = S>, S: AsRef<std::ffi::OsStr>>(arguments: I) -> Output {
+	Command::new(env!("CARGO_BIN_EXE_larz"))
+		.args(arguments)
+		.output()
+		.unwrap()
+}
+
+/// Write a directory holding a single file, returning its path
+///
+/// # Arguments
+///
+/// * `root` - Where to write the directory
+fn write_source(root: &Path) -> std::path::PathBuf {
+	let source = root.join("source");
+	std::fs::create_dir(&source).unwrap();
+	std::fs::write(source.join("file"), b"contents").unwrap();
+	source
+}
+
+#[test]
+fn force_and_no_clobber_conflict() {
+	let root = tempfile::tempdir().unwrap();
+	let source = write_source(root.path());
+	let archive = root.path().join("archive.larz");
+	let output = larz([
+		"compress".as_ref(),
+		source.as_os_str(),
+		archive.as_os_str(),
+		"--force".as_ref(),
+		"--no-clobber".as_ref(),
+	]);
+	assert!(!output.status.success());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be used with"));
+	assert!(!archive.exists());
+}
+
+#[test]
+fn no_clobber_keeps_an_existing_archive() {
+	let root = tempfile::tempdir().unwrap();
+	let source = write_source(root.path());
+	let archive = root.path().join("archive.larz");
+	std::fs::write(&archive, b"an existing archive").unwrap();
+	let output = larz([
+		"compress".as_ref(),
+		source.as_os_str(),
+		archive.as_os_str(),
+		"--no-clobber".as_ref(),
+	]);
+	assert_eq!(output.status.code(), Some(1));
+	assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+	assert_eq!(std::fs::read(&archive).unwrap(), b"an existing archive");
+
+	// Without it, the archive is replaced
+	let output = larz(["compress".as_ref(), source.as_os_str(), archive.as_os_str()]);
+	assert!(output.status.success());
+	assert_ne!(std::fs::read(&archive).unwrap(), b"an existing archive");
+}
+
+#[test]
+fn diff_exit_statuses_and_json() {
+	let root = tempfile::tempdir().unwrap();
+	let source = write_source(root.path());
+	let archive = root.path().join("archive.larz");
+	assert!(
+		larz(["compress".as_ref(), source.as_os_str(), archive.as_os_str()])
+			.status
+			.success()
+	);
+
+	let output = larz(["diff".as_ref(), archive.as_os_str(), source.as_os_str()]);
+	assert_eq!(output.status.code(), Some(0));
+	let output = larz([
+		"diff".as_ref(),
+		archive.as_os_str(),
+		source.as_os_str(),
+		"--json".as_ref(),
+	]);
+	assert_eq!(output.status.code(), Some(0));
+	assert_eq!(
+		String::from_utf8(output.stdout).unwrap().trim_end(),
+		r#"{"added":[],"removed":[],"modified":[]}"#
+	);
+
+	// Change the file's size but not its modification time, and add another
+	let modified = std::fs::metadata(source.join("file"))
+		.unwrap()
+		.modified()
+		.unwrap();
+	let file = std::fs::File::create(source.join("file")).unwrap();
+	std::io::Write::write_all(&mut &file, b"longer contents").unwrap();
+	file.set_modified(modified).unwrap();
+	std::fs::write(source.join("added"), b"added").unwrap();
+	let output = larz([
+		"diff".as_ref(),
+		archive.as_os_str(),
+		source.as_os_str(),
+		"--json".as_ref(),
+	]);
+	assert_eq!(output.status.code(), Some(1));
+	assert_eq!(
+		String::from_utf8(output.stdout).unwrap().trim_end(),
+		r#"{"added":["added"],"removed":[],"modified":[{"path":"file","differences":[{"field":"size","old":8,"new":15}]}]}"#
+	);
+
+	let output = larz([
+		"diff".as_ref(),
+		archive.as_os_str(),
+		root.path().join("missing").as_os_str(),
+	]);
+	assert_eq!(output.status.code(), Some(2));
+	assert!(String::from_utf8_lossy(&output.stderr).contains("Could not compare"));
+}
+
+#[cfg(unix)]
+#[test]
+fn failed_compression_leaves_nothing_behind() {
+	let root = tempfile::tempdir().unwrap();
+	let source = write_source(root.path());
+	// Following a link to nothing fails once the archive has been started
+	std::os::unix::fs::symlink("nowhere", source.join("broken")).unwrap();
+	let archive = root.path().join("archive.larz");
+	std::fs::write(&archive, b"an existing archive").unwrap();
+	let output = larz(["compress".as_ref(), source.as_os_str(), archive.as_os_str()]);
+	assert_eq!(output.status.code(), Some(1));
+	assert!(String::from_utf8_lossy(&output.stderr).contains("Could not write archive"));
+	assert_eq!(std::fs::read(&archive).unwrap(), b"an existing archive");
+
+	let mut names = std::fs::read_dir(root.path())
+		.unwrap()
+		.map(|entry| entry.unwrap().file_name())
+		.collect::<Vec<_>>();
+	names.sort();
+	assert_eq!(names, ["archive.larz", "source"]);
+}
@@ -0,0 +1,94 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of encrypting and decrypting the bodies of archives.
+
+#![cfg(feature = "encryption")]
+
+use larz::crypto::{DecryptReader, DecryptionError, EncryptWriter, Passphrase, CHUNK_SIZE};
+use larz::header::{ArchiveHeader, Codec};
+use std::io::{ErrorKind, Read, Write};
+
+/// Encrypt a body, returning the header it was encrypted with and the sealed chunks
+fn encrypt(passphrase: &[u8], body: &[u8]) -> (ArchiveHeader, Vec<u8>) {
+	let mut header = ArchiveHeader::new(Codec::Lz4Frame);
+	let mut writer =
+		EncryptWriter::new(Vec::new(), &Passphrase::new(passphrase), &mut header).unwrap();
+	writer.write_all(body).unwrap();
+	(header, writer.finish().unwrap())
+}
+
+/// Decrypt a body in full
+fn decrypt(passphrase: &[u8], header: &ArchiveHeader, sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+	let mut reader = DecryptReader::new(sealed, &Passphrase::new(passphrase), header)?;
+	let mut body = Vec::new();
+	reader.read_to_end(&mut body)?;
+	Ok(body)
+}
+
+#[test]
+fn round_trip() {
+	let body: Vec<u8> = (0..CHUNK_SIZE as u32 * 3 + 5)
+		.map(|i| (i % 251) as u8)
+		.collect();
+	let (header, sealed) = encrypt(b"correct horse", &body);
+	assert_ne!(&sealed[..body.len()], body.as_slice());
+	assert_eq!(decrypt(b"correct horse", &header, &sealed).unwrap(), body);
+
+	let (header, sealed) = encrypt(b"correct horse", b"");
+	assert!(decrypt(b"correct horse", &header, &sealed)
+		.unwrap()
+		.is_empty());
+}
+
+#[test]
+fn wrong_passphrase() {
+	let (header, sealed) = encrypt(b"correct horse", b"Hello, world!");
+	let error = decrypt(b"battery staple", &header, &sealed).unwrap_err();
+	assert_eq!(error.kind(), ErrorKind::InvalidData);
+	let error = error.get_ref().unwrap().downcast_ref::<DecryptionError>();
+	assert_eq!(error.map(|error| error.chunk), Some(0));
+}
+
+#[test]
+fn tampering() {
+	let body = vec![7u8; CHUNK_SIZE * 2];
+	let (header, sealed) = encrypt(b"correct horse", &body);
+
+	let mut flipped = sealed.clone();
+	flipped[CHUNK_SIZE + 100] ^= 1;
+	assert!(decrypt(b"correct horse", &header, &flipped).is_err());
+
+	// Dropping the final chunk makes the one before it look final, which its nonce does not allow
+	assert!(decrypt(b"correct horse", &header, &sealed[..CHUNK_SIZE + 16]).is_err());
+
+	// The header is authenticated along with every chunk
+	let mut altered = header.clone();
+	altered
+		.metadata
+		.insert("comment".to_owned(), b"added".to_vec());
+	assert!(decrypt(b"correct horse", &altered, &sealed).is_err());
+}
+
+#[test]
+fn costly_parameters_are_refused() {
+	let (mut header, sealed) = encrypt(b"correct horse", b"Hello, world!");
+	let mut kdf = Vec::new();
+	for cost in [u32::MAX, 1, 1] {
+		kdf.extend_from_slice(&cost.to_le_bytes());
+	}
+	header.metadata.insert("larz.kdf".to_owned(), kdf);
+	let error = decrypt(b"correct horse", &header, &sealed).unwrap_err();
+	assert_eq!(error.kind(), ErrorKind::InvalidData);
+}
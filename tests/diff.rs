@@ -0,0 +1,100 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of comparing archives with the directories they were written from.
+
+#![cfg(all(unix, feature = "streaming"))]
+
+use larz::diff::diff;
+use larz::header::Codec;
+use larz::{
+	compress_archive_streaming_with_options, CompressOptions, ExtractOptions, Symlinks, WalkOptions,
+};
+use std::io::Sink;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+/// Write a directory holding a file, a directory, links to both, and a link back to the directory itself
+///
+/// # Arguments
+///
+/// * `root` - Where to write the directory
+fn write_tree(root: &Path) -> PathBuf {
+	let source = root.join("source");
+	std::fs::create_dir_all(source.join("directory")).unwrap();
+	std::fs::write(source.join("file"), b"contents").unwrap();
+	std::fs::write(source.join("directory").join("inner"), b"inner").unwrap();
+	symlink("file", source.join("file link")).unwrap();
+	symlink("directory", source.join("directory link")).unwrap();
+	symlink("..", source.join("directory").join("loop")).unwrap();
+	source
+}
+
+/// Compress a directory, returning the path to the archive
+///
+/// # Arguments
+///
+/// * `source` - Path to the directory
+///
+/// * `symlinks` - How symbolic links are archived
+fn compress(source: &Path, symlinks: Symlinks) -> PathBuf {
+	let archive = source.with_extension("larz");
+	let options = CompressOptions {
+		walk: WalkOptions {
+			symlinks,
+			..Default::default()
+		},
+		..Default::default()
+	};
+	compress_archive_streaming_with_options::<Sink>(
+		vec![source.to_path_buf()],
+		archive.clone(),
+		&options,
+		None,
+	)
+	.unwrap();
+	archive
+}
+
+#[test]
+fn followed_links_match_their_archive() {
+	let root = tempfile::tempdir().unwrap();
+	let source = write_tree(root.path());
+	let archive = compress(&source, Symlinks::Follow);
+	let report = diff(
+		&source,
+		&archive,
+		Codec::Lz4Frame,
+		&ExtractOptions::default(),
+		Symlinks::Follow,
+	)
+	.unwrap();
+	assert!(report.is_empty(), "{report}");
+}
+
+#[test]
+fn stored_links_match_their_archive() {
+	let root = tempfile::tempdir().unwrap();
+	let source = write_tree(root.path());
+	let archive = compress(&source, Symlinks::Store);
+	let report = diff(
+		&archive,
+		&source,
+		Codec::Lz4Frame,
+		&ExtractOptions::default(),
+		Symlinks::Store,
+	)
+	.unwrap();
+	assert!(report.is_empty(), "{report}");
+}
@@ -0,0 +1,84 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of restoring chains of listed-incremental backups.
+
+#![cfg(all(unix, feature = "streaming"))]
+
+use larz::incremental::Snapshot;
+use larz::{
+	compress_archive_streaming_with_options, restore_archives, CompressOptions, ExtractOptions,
+	Symlinks, WalkOptions,
+};
+use std::io::Sink;
+use std::os::unix::fs::symlink;
+
+#[test]
+fn deletions_stay_inside_the_output() {
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	let outside = root.path().join("outside");
+	let snapshot = root.path().join("snapshot");
+	std::fs::create_dir(&source).unwrap();
+	std::fs::create_dir(&outside).unwrap();
+	std::fs::write(outside.join("victim"), b"keep me").unwrap();
+	std::fs::write(source.join("file"), b"contents").unwrap();
+	symlink(&outside, source.join("a")).unwrap();
+
+	let options = CompressOptions {
+		snapshot: Some(snapshot.clone()),
+		walk: WalkOptions {
+			symlinks: Symlinks::Store,
+			..Default::default()
+		},
+		..Default::default()
+	};
+	let full = root.path().join("full.larz");
+	compress_archive_streaming_with_options::<Sink>(
+		vec![source.clone()],
+		full.clone(),
+		&options,
+		None,
+	)
+	.unwrap();
+
+	// Claim the previous backup held a file beneath the link, so the next backup lists it as deleted
+	let mut previous = Snapshot::read(&snapshot).unwrap();
+	let (link, entry) = previous
+		.entries
+		.iter()
+		.find(|(member, _)| member.ends_with("a"))
+		.map(|(member, entry)| (member.clone(), entry.clone()))
+		.unwrap();
+	previous.entries.insert(link.join("victim"), entry);
+	previous.write(&snapshot).unwrap();
+	let incremental = root.path().join("incremental.larz");
+	compress_archive_streaming_with_options::<Sink>(
+		vec![source],
+		incremental.clone(),
+		&options,
+		None,
+	)
+	.unwrap();
+
+	let output = root.path().join("output");
+	restore_archives(
+		vec![full, incremental],
+		output.clone(),
+		&ExtractOptions::default(),
+	)
+	.unwrap();
+	assert_eq!(std::fs::read(outside.join("victim")).unwrap(), b"keep me");
+	assert_eq!(std::fs::read(output.join("file")).unwrap(), b"contents");
+}
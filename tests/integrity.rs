@@ -0,0 +1,165 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of naming the members of an archive whose contents do not match their recorded hashes.
+
+#![cfg(feature = "streaming")]
+
+use larz::header::{ArchiveHeader, Codec};
+use larz::integrity::IntegrityError;
+use larz::{
+	compress_archive_streaming_with_options, extract_archive_streaming_with_options, test_archive,
+	CompressOptions, ExtractOptions,
+};
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+use std::io::{ErrorKind, Read, Sink, Write};
+use std::path::{Path, PathBuf};
+
+/// Compress a directory holding two files, then alter the contents of the second within the archive, returning the path to the archive
+///
+/// The body is decompressed, altered, and compressed again, so the archive is still well-formed and only the member's hash can tell.
+///
+/// # Arguments
+///
+/// * `root` - Where to write the directory and the archive
+fn write_tampered_archive(root: &Path) -> PathBuf {
+	let source = root.join("source");
+	std::fs::create_dir(&source).unwrap();
+	std::fs::write(source.join("intact"), b"intact contents").unwrap();
+	std::fs::write(source.join("tampered"), b"original contents").unwrap();
+	let archive = root.join("archive.larz");
+	compress_archive_streaming_with_options::<Sink>(
+		vec![source],
+		archive.clone(),
+		&CompressOptions::default(),
+		None,
+	)
+	.unwrap();
+
+	let (header, body) = ArchiveHeader::read_from(std::fs::File::open(&archive).unwrap()).unwrap();
+	let header = header.unwrap();
+	let mut tar = Vec::new();
+	FrameDecoder::new(body).read_to_end(&mut tar).unwrap();
+	let offset = tar
+		.windows(b"original contents".len())
+		.position(|window| window == b"original contents")
+		.unwrap();
+	tar[offset..offset + 8].copy_from_slice(b"tampered");
+
+	let mut file = std::fs::File::create(&archive).unwrap();
+	header.write_to(&mut file).unwrap();
+	let mut encoder = FrameEncoder::new(file);
+	encoder.write_all(&tar).unwrap();
+	encoder.finish().unwrap();
+	archive
+}
+
+/// Get the integrity error an operation failed with
+///
+/// # Arguments
+///
+/// * `result` - The result of the operation
+fn integrity_error<T: std::fmt::Debug>(result: std::io::Result<T>) -> IntegrityError {
+	let error = result.unwrap_err();
+	assert_eq!(error.kind(), ErrorKind::InvalidData);
+	error
+		.get_ref()
+		.unwrap()
+		.downcast_ref::<IntegrityError>()
+		.unwrap()
+		.clone()
+}
+
+#[test]
+fn extracting_names_the_tampered_member() {
+	let root = tempfile::tempdir().unwrap();
+	let archive = write_tampered_archive(root.path());
+	let output = root.path().join("output");
+	let error = integrity_error(extract_archive_streaming_with_options(
+		vec![archive],
+		output.clone(),
+		&ExtractOptions::default(),
+	));
+	assert_eq!(error.members, vec![PathBuf::from("tampered")]);
+	assert_eq!(
+		std::fs::read(output.join("intact")).unwrap(),
+		b"intact contents"
+	);
+}
+
+#[test]
+fn testing_names_the_tampered_member() {
+	let root = tempfile::tempdir().unwrap();
+	let archive = write_tampered_archive(root.path());
+	let error = integrity_error(test_archive(
+		&archive,
+		Codec::Lz4Frame,
+		&ExtractOptions::default(),
+	));
+	assert_eq!(error.members, vec![PathBuf::from("tampered")]);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn extracting_asynchronously_names_the_tampered_member() {
+	let root = tempfile::tempdir().unwrap();
+	let archive = std::fs::read(write_tampered_archive(root.path())).unwrap();
+	let output = root.path().join("output");
+	let runtime = tokio::runtime::Builder::new_current_thread()
+		.enable_all()
+		.build()
+		.unwrap();
+	let error = integrity_error(runtime.block_on(larz::asynchronous::extract_archive(
+		archive.as_slice(),
+		&output,
+		&ExtractOptions::default(),
+	)));
+	assert_eq!(error.members, vec![PathBuf::from("tampered")]);
+}
+
+#[test]
+fn atomic_extraction_keeps_the_original_directory() {
+	let root = tempfile::tempdir().unwrap();
+	let archive = write_tampered_archive(root.path());
+	let output = root.path().join("output");
+	std::fs::create_dir(&output).unwrap();
+	std::fs::write(output.join("existing"), b"existing contents").unwrap();
+
+	let options = ExtractOptions {
+		atomic: true,
+		..Default::default()
+	};
+	let error = integrity_error(extract_archive_streaming_with_options(
+		vec![archive],
+		output.clone(),
+		&options,
+	));
+	assert_eq!(error.members, vec![PathBuf::from("tampered")]);
+
+	let names = |path: &Path| {
+		let mut names = std::fs::read_dir(path)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name())
+			.collect::<Vec<_>>();
+		names.sort();
+		names
+	};
+	assert_eq!(names(&output), ["existing"]);
+	assert_eq!(
+		std::fs::read(output.join("existing")).unwrap(),
+		b"existing contents"
+	);
+	// No staging directory is left beside it
+	assert_eq!(names(root.path()), ["archive.larz", "output", "source"]);
+}
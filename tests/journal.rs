@@ -0,0 +1,104 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of resuming interrupted extraction from a checkpoint journal.
+
+#![cfg(feature = "streaming")]
+
+use larz::journal::{journal_path, CHECKPOINT_INTERVAL};
+use larz::{
+	compress_archive_streaming_with_options, extract_archive_streaming_with_options,
+	CompressOptions, ExtractOptions, Progress,
+};
+use std::io::Sink;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn resume_after_a_checkpoint() {
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	std::fs::create_dir(&source).unwrap();
+	std::fs::write(
+		source.join("large"),
+		vec![1u8; CHECKPOINT_INTERVAL as usize],
+	)
+	.unwrap();
+	for name in ["a", "b", "c"] {
+		std::fs::write(source.join(name), name).unwrap();
+	}
+	let archive = root.path().join("archive.larz");
+	compress_archive_streaming_with_options::<Sink>(
+		vec![source.clone()],
+		archive.clone(),
+		&CompressOptions::default(),
+		None,
+	)
+	.unwrap();
+
+	// Interrupt extraction at the next entry after the journal has been synced
+	let output = root.path().join("output");
+	let reported: Arc<Mutex<Vec<(PathBuf, u64)>>> = Arc::default();
+	let progress = {
+		let reported = reported.clone();
+		Progress::new(move |member, size| {
+			let mut reported = reported.lock().unwrap();
+			if reported
+				.iter()
+				.any(|(_, size)| *size >= CHECKPOINT_INTERVAL)
+			{
+				// The lock is released first, so it can still be taken once extraction has been interrupted
+				drop(reported);
+				panic!("Interrupted");
+			}
+			reported.push((member.to_path_buf(), size));
+		})
+	};
+	let options = ExtractOptions {
+		resume: true,
+		progress: Some(progress),
+		..Default::default()
+	};
+	let interrupted = catch_unwind(AssertUnwindSafe(|| {
+		extract_archive_streaming_with_options(vec![archive.clone()], output.clone(), &options)
+	}));
+	assert!(interrupted.is_err());
+	assert!(journal_path(&output).unwrap().exists());
+
+	let extracted: Arc<Mutex<Vec<PathBuf>>> = Arc::default();
+	let progress = {
+		let extracted = extracted.clone();
+		Progress::new(move |member, _| extracted.lock().unwrap().push(member.to_path_buf()))
+	};
+	let options = ExtractOptions {
+		resume: true,
+		progress: Some(progress),
+		..Default::default()
+	};
+	extract_archive_streaming_with_options(vec![archive], output.clone(), &options).unwrap();
+
+	// Entries recorded before the interruption are not extracted again
+	let extracted = extracted.lock().unwrap();
+	for (member, _) in reported.lock().unwrap().iter() {
+		assert!(!extracted.contains(member));
+	}
+	assert!(!journal_path(&output).unwrap().exists());
+	for name in ["large", "a", "b", "c"] {
+		assert_eq!(
+			std::fs::read(output.join(name)).unwrap(),
+			std::fs::read(source.join(name)).unwrap()
+		);
+	}
+}
@@ -0,0 +1,108 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of storing files linked together on disk, or with identical contents, as hard links.
+
+#![cfg(all(unix, feature = "streaming"))]
+
+use larz::header::Codec;
+use larz::reader::ArchiveReader;
+use larz::{
+	compress_archive_streaming_with_options, extract_archive_streaming_with_options,
+	CompressOptions, ExtractOptions,
+};
+use std::io::Sink;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Compress a directory, then extract it, returning the path it was extracted to
+///
+/// # Arguments
+///
+/// * `root` - Where the directory is, and where to write the archive and extract it to
+///
+/// * `options` - Options controlling how the directory is compressed
+fn round_trip(root: &Path, options: &CompressOptions) -> PathBuf {
+	let archive = root.join("archive.larz");
+	compress_archive_streaming_with_options::<Sink>(
+		vec![root.join("source")],
+		archive.clone(),
+		options,
+		None,
+	)
+	.unwrap();
+
+	let mut reader =
+		ArchiveReader::open(&archive, Codec::Lz4Frame, &ExtractOptions::default()).unwrap();
+	let link = reader
+		.entries()
+		.unwrap()
+		.map(Result::unwrap)
+		.find(|entry| entry.path() == Path::new("second"))
+		.unwrap();
+	assert_eq!(link.entry_type(), tar::EntryType::Link);
+	assert_eq!(link.link_name().unwrap().unwrap(), Path::new("./first"));
+
+	let output = root.join("output");
+	extract_archive_streaming_with_options(
+		vec![archive],
+		output.clone(),
+		&ExtractOptions::default(),
+	)
+	.unwrap();
+	output
+}
+
+/// Whether two files are the same file on disk
+///
+/// # Arguments
+///
+/// * `first` - Path to the first file
+///
+/// * `second` - Path to the second file
+fn same_inode(first: &Path, second: &Path) -> bool {
+	let first = std::fs::metadata(first).unwrap();
+	let second = std::fs::metadata(second).unwrap();
+	(first.dev(), first.ino()) == (second.dev(), second.ino())
+}
+
+#[test]
+fn hard_links_share_an_inode() {
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	std::fs::create_dir(&source).unwrap();
+	std::fs::write(source.join("first"), b"contents").unwrap();
+	std::fs::hard_link(source.join("first"), source.join("second")).unwrap();
+
+	let output = round_trip(root.path(), &CompressOptions::default());
+	assert!(same_inode(&output.join("first"), &output.join("second")));
+	assert_eq!(std::fs::read(output.join("second")).unwrap(), b"contents");
+}
+
+#[test]
+fn duplicates_are_linked_when_asked() {
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	std::fs::create_dir(&source).unwrap();
+	std::fs::write(source.join("first"), b"contents").unwrap();
+	std::fs::write(source.join("second"), b"contents").unwrap();
+
+	let options = CompressOptions {
+		link_duplicates: true,
+		..Default::default()
+	};
+	let output = round_trip(root.path(), &options);
+	assert!(same_inode(&output.join("first"), &output.join("second")));
+	assert_eq!(std::fs::read(output.join("second")).unwrap(), b"contents");
+}
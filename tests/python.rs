@@ -0,0 +1,101 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of the Python bindings, run in an embedded interpreter.
+
+#![cfg(feature = "python")]
+
+// Linked for the module it exports, which is only named through `PyInit_larz`
+extern crate larz;
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::ffi::CString;
+use std::sync::Once;
+
+extern "C" {
+	/// Initialises the `larz` module; exported by the bindings for the interpreter to import
+	fn PyInit_larz() -> *mut pyo3::ffi::PyObject;
+}
+
+/// Ensures the interpreter is started only once, with the `larz` module built in
+static INITIALISE: Once = Once::new();
+
+/// Run Python code with the `larz` module importable, and a temporary directory in the `root` variable
+///
+/// # Arguments
+///
+/// * `code` - The Python code to run
+fn run(code: &str) {
+	INITIALISE.call_once(|| {
+		// Built-in modules must be added before the interpreter starts
+		assert_ne!(
+			unsafe { pyo3::ffi::PyImport_AppendInittab(c"larz".as_ptr(), Some(PyInit_larz)) },
+			-1
+		);
+		Python::initialize();
+	});
+	let root = tempfile::tempdir().unwrap();
+	Python::attach(|py| {
+		let globals = PyDict::new(py);
+		globals.set_item("root", root.path()).unwrap();
+		py.run(&CString::new(code).unwrap(), Some(&globals), None)
+			.map_err(|e| e.to_string())
+			.unwrap();
+	});
+}
+
+#[test]
+fn compress_and_list() {
+	run(r#"
+import larz, os
+
+source = os.path.join(root, "source")
+os.makedirs(os.path.join(source, "directory"))
+with open(os.path.join(source, "directory", "file"), "wb") as f:
+    f.write(b"contents")
+archive = os.path.join(root, "archive.larz")
+
+stats = larz.compress([source], archive)
+assert stats.compression_ratio() > 0
+assert [str(member) for member in larz.list(archive)] == [".", "directory", "directory/file"]
+larz.test(archive)
+with larz.open_entry(archive, "directory/file") as entry:
+    assert entry.read() == b"contents"
+"#);
+}
+
+#[test]
+fn errors_are_raised_as_larz_errors() {
+	run(r#"
+import larz, os
+
+try:
+    larz.list(os.path.join(root, "missing.larz"))
+except FileNotFoundError:
+    pass
+else:
+    raise AssertionError("listing a missing archive succeeded")
+
+path = os.path.join(root, "not an archive.larz")
+with open(path, "wb") as f:
+    f.write(b"not an archive")
+try:
+    larz.list(path)
+except (larz.LarzError, OSError):
+    pass
+else:
+    raise AssertionError("listing a file that is not an archive succeeded")
+"#);
+}
@@ -0,0 +1,195 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of serving the contents of an archive over HTTP.
+
+#![cfg(all(feature = "serve", feature = "streaming"))]
+
+use larz::header::Codec;
+use larz::serve::Server;
+use larz::{compress_archive_streaming_with_options, CompressOptions, ExtractOptions};
+use std::collections::HashMap;
+use std::io::{Read, Sink, Write};
+use std::net::{SocketAddr, TcpStream};
+
+/// The contents of the file served by each test
+const CONTENTS: &[u8] = b"Hello, world!";
+
+/// A response to a request, with its header names in lowercase
+struct Response {
+	/// The status code
+	status: u16,
+	/// The headers
+	headers: HashMap<String, String>,
+	/// The body
+	body: Vec<u8>,
+}
+
+/// Compress a directory holding a single file, and serve it on an unused local port, returning the server's address
+///
+/// The server runs until the tests end.
+fn serve() -> SocketAddr {
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	std::fs::create_dir(&source).unwrap();
+	std::fs::write(source.join("hello.txt"), CONTENTS).unwrap();
+	let archive = root.path().join("archive.larz");
+	compress_archive_streaming_with_options::<Sink>(
+		vec![source],
+		archive.clone(),
+		&CompressOptions::default(),
+		None,
+	)
+	.unwrap();
+
+	let server = Server::open(
+		&archive,
+		"127.0.0.1:0",
+		Codec::Lz4Frame,
+		&ExtractOptions::default(),
+	)
+	.unwrap();
+	let address = server.local_addr().unwrap();
+	std::thread::spawn(move || server.run());
+	address
+}
+
+/// Make a request of the server, returning its response
+///
+/// # Arguments
+///
+/// * `address` - The address of the server
+///
+/// * `method` - The method of the request
+///
+/// * `path` - The path requested
+///
+/// * `headers` - Other headers to send with the request
+fn request(address: SocketAddr, method: &str, path: &str, headers: &[(&str, &str)]) -> Response {
+	let mut stream = TcpStream::connect(address).unwrap();
+	let mut request =
+		format!("{method} {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n");
+	for (name, value) in headers {
+		request.push_str(&format!("{name}: {value}\r\n"));
+	}
+	request.push_str("\r\n");
+	stream.write_all(request.as_bytes()).unwrap();
+	let mut response = Vec::new();
+	stream.read_to_end(&mut response).unwrap();
+
+	let split = response
+		.windows(4)
+		.position(|window| window == b"\r\n\r\n")
+		.unwrap();
+	let head = String::from_utf8(response[..split].to_vec()).unwrap();
+	let mut lines = head.split("\r\n");
+	let status = lines
+		.next()
+		.unwrap()
+		.split(' ')
+		.nth(1)
+		.unwrap()
+		.parse()
+		.unwrap();
+	let headers = lines
+		.map(|line| {
+			let (name, value) = line.split_once(':').unwrap();
+			(name.trim().to_lowercase(), value.trim().to_owned())
+		})
+		.collect();
+	Response {
+		status,
+		headers,
+		body: response[split + 4..].to_vec(),
+	}
+}
+
+#[test]
+fn whole_files_carry_their_hash() {
+	let address = serve();
+	let response = request(address, "GET", "/hello.txt", &[]);
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, CONTENTS);
+	assert_eq!(
+		response.headers["etag"],
+		format!("\"{}\"", blake3::hash(CONTENTS).to_hex())
+	);
+	assert_eq!(response.headers["accept-ranges"], "bytes");
+	assert_eq!(
+		response.headers["content-type"],
+		"text/plain; charset=utf-8"
+	);
+
+	let etag = response.headers["etag"].clone();
+	let response = request(address, "GET", "/hello.txt", &[("If-None-Match", &etag)]);
+	assert_eq!(response.status, 304);
+	assert!(response.body.is_empty());
+}
+
+#[test]
+fn head_sends_no_body() {
+	let address = serve();
+	let response = request(address, "HEAD", "/hello.txt", &[]);
+	assert_eq!(response.status, 200);
+	assert_eq!(
+		response.headers["content-length"],
+		CONTENTS.len().to_string()
+	);
+	assert!(response.headers.contains_key("etag"));
+	assert!(response.body.is_empty());
+
+	let response = request(address, "POST", "/hello.txt", &[]);
+	assert_eq!(response.status, 405);
+	assert_eq!(response.headers["allow"], "GET, HEAD");
+}
+
+#[test]
+fn ranges() {
+	let address = serve();
+	let response = request(address, "GET", "/hello.txt", &[("Range", "bytes=7-11")]);
+	assert_eq!(response.status, 206);
+	assert_eq!(response.body, b"world");
+	assert_eq!(response.headers["content-range"], "bytes 7-11/13");
+
+	let response = request(address, "GET", "/hello.txt", &[("Range", "bytes=-6")]);
+	assert_eq!(response.status, 206);
+	assert_eq!(response.body, b"world!");
+
+	let response = request(address, "GET", "/hello.txt", &[("Range", "bytes=20-")]);
+	assert_eq!(response.status, 416);
+	assert_eq!(response.headers["content-range"], "bytes */13");
+}
+
+#[test]
+fn ranges_are_only_honoured_if_the_file_is_unchanged() {
+	let address = serve();
+	let etag = request(address, "HEAD", "/hello.txt", &[]).headers["etag"].clone();
+	let response = request(
+		address,
+		"GET",
+		"/hello.txt",
+		&[("Range", "bytes=0-4"), ("If-Range", &etag)],
+	);
+	assert_eq!(response.status, 206);
+	assert_eq!(response.body, b"Hello");
+
+	let response = request(
+		address,
+		"GET",
+		"/hello.txt",
+		&[("Range", "bytes=0-4"), ("If-Range", "\"another version\"")],
+	);
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, CONTENTS);
+}
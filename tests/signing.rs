@@ -0,0 +1,137 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of signing archives and refusing to extract those whose signatures cannot be verified.
+
+#![cfg(all(feature = "signing", feature = "streaming"))]
+
+use larz::signing::{
+	generate_signing_key, sign_archive, verify_archive, SignatureError, VerifyingKey,
+};
+use larz::{
+	compress_archive_streaming_with_options, extract_archive_streaming_with_options,
+	CompressOptions, ExtractOptions,
+};
+use std::io::{ErrorKind, Sink};
+use std::path::{Path, PathBuf};
+
+/// Compress a directory holding a single file, returning the path to the archive
+///
+/// # Arguments
+///
+/// * `root` - Where to write the directory and the archive
+fn write_archive(root: &Path) -> PathBuf {
+	let source = root.join("source");
+	std::fs::create_dir(&source).unwrap();
+	std::fs::write(source.join("file"), b"contents").unwrap();
+	let archive = root.join("archive.larz");
+	compress_archive_streaming_with_options::<Sink>(
+		vec![source],
+		archive.clone(),
+		&CompressOptions::default(),
+		None,
+	)
+	.unwrap();
+	archive
+}
+
+/// Extract an archive, trusting only the given key, returning the reason it was refused
+///
+/// # Arguments
+///
+/// * `archive` - Path to the archive
+///
+/// * `output` - Where to extract the archive to
+///
+/// * `verifying_key` - The key the archive must be signed with
+fn refused(archive: &Path, output: &Path, verifying_key: VerifyingKey) -> SignatureError {
+	let options = ExtractOptions {
+		verifying_key: Some(verifying_key),
+		..Default::default()
+	};
+	let error = extract_archive_streaming_with_options(
+		vec![archive.to_path_buf()],
+		output.to_path_buf(),
+		&options,
+	)
+	.unwrap_err();
+	assert_eq!(error.kind(), ErrorKind::InvalidData);
+	assert!(!output.exists());
+	error
+		.get_ref()
+		.unwrap()
+		.downcast_ref::<SignatureError>()
+		.unwrap()
+		.clone()
+}
+
+#[test]
+fn signed_archives_verify() {
+	let root = tempfile::tempdir().unwrap();
+	let archive = write_archive(root.path());
+	let signing_key = generate_signing_key().unwrap();
+	sign_archive(&archive, &signing_key).unwrap();
+	verify_archive(&archive, &signing_key.verifying_key()).unwrap();
+
+	let output = root.path().join("output");
+	let options = ExtractOptions {
+		verifying_key: Some(signing_key.verifying_key()),
+		..Default::default()
+	};
+	extract_archive_streaming_with_options(vec![archive], output.clone(), &options).unwrap();
+	assert_eq!(std::fs::read(output.join("file")).unwrap(), b"contents");
+}
+
+#[test]
+fn unsigned_archives_are_refused() {
+	let root = tempfile::tempdir().unwrap();
+	let archive = write_archive(root.path());
+	let verifying_key = generate_signing_key().unwrap().verifying_key();
+	assert_eq!(
+		refused(&archive, &root.path().join("output"), verifying_key),
+		SignatureError::Unsigned(archive)
+	);
+}
+
+#[test]
+fn other_keys_are_refused() {
+	let root = tempfile::tempdir().unwrap();
+	let archive = write_archive(root.path());
+	sign_archive(&archive, &generate_signing_key().unwrap()).unwrap();
+	let verifying_key = generate_signing_key().unwrap().verifying_key();
+	assert_eq!(
+		refused(&archive, &root.path().join("output"), verifying_key),
+		SignatureError::UntrustedKey(archive)
+	);
+}
+
+#[test]
+fn altered_archives_are_refused() {
+	let root = tempfile::tempdir().unwrap();
+	let archive = write_archive(root.path());
+	let signing_key = generate_signing_key().unwrap();
+	sign_archive(&archive, &signing_key).unwrap();
+	let mut contents = std::fs::read(&archive).unwrap();
+	let last = contents.len() - 1;
+	contents[last] ^= 1;
+	std::fs::write(&archive, contents).unwrap();
+	assert_eq!(
+		refused(
+			&archive,
+			&root.path().join("output"),
+			signing_key.verifying_key()
+		),
+		SignatureError::Invalid(archive)
+	);
+}
@@ -0,0 +1,127 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of archiving and extracting files with holes.
+
+#![cfg(all(target_os = "linux", feature = "streaming"))]
+
+use larz::header::Codec;
+use larz::reader::ArchiveReader;
+use larz::{
+	compress_archive_streaming_with_options, extract_archive_streaming_with_options,
+	CompressOptions, ExtractOptions,
+};
+use std::io::{Read, Seek, SeekFrom, Sink, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// The size of the file with holes written by each test
+const SIZE: u64 = 16 * 1024 * 1024;
+
+/// Write a file that is a hole but for a little data in the middle, returning its contents
+///
+/// # Arguments
+///
+/// * `path` - Path to the file
+fn write_holey(path: &Path) -> Vec<u8> {
+	let mut file = std::fs::File::create(path).unwrap();
+	file.set_len(SIZE).unwrap();
+	file.seek(SeekFrom::Start(SIZE / 2)).unwrap();
+	file.write_all(b"data in the middle").unwrap();
+	std::fs::read(path).unwrap()
+}
+
+/// Whether a file takes up less space on disk than its size
+///
+/// # Arguments
+///
+/// * `path` - Path to the file
+fn has_holes(path: &Path) -> bool {
+	let metadata = std::fs::metadata(path).unwrap();
+	metadata.blocks() * 512 < metadata.len()
+}
+
+#[test]
+fn default_format_keeps_holes() {
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	std::fs::create_dir(&source).unwrap();
+	let contents = write_holey(&source.join("holey"));
+	if !has_holes(&source.join("holey")) {
+		// The filesystem holding the temporary directory does not support holes
+		return;
+	}
+
+	let archive = root.path().join("archive.larz");
+	compress_archive_streaming_with_options::<Sink>(
+		vec![source],
+		archive.clone(),
+		&CompressOptions::default(),
+		None,
+	)
+	.unwrap();
+	assert!(std::fs::metadata(&archive).unwrap().len() < SIZE / 16);
+
+	let output = root.path().join("output");
+	extract_archive_streaming_with_options(
+		vec![archive.clone()],
+		output.clone(),
+		&ExtractOptions::default(),
+	)
+	.unwrap();
+	assert!(has_holes(&output.join("holey")));
+	assert_eq!(std::fs::read(output.join("holey")).unwrap(), contents);
+
+	let mut reader =
+		ArchiveReader::open(&archive, Codec::Lz4Frame, &ExtractOptions::default()).unwrap();
+	let mut entries = reader.entries().unwrap();
+	entries.next().unwrap().unwrap();
+	let mut entry = entries.next().unwrap().unwrap();
+	assert_eq!(entry.path(), Path::new("holey"));
+	assert_eq!(entry.size(), SIZE);
+	let mut read = Vec::new();
+	entry.read_to_end(&mut read).unwrap();
+	assert_eq!(read, contents);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn default_format_keeps_holes_asynchronously() {
+	use larz::asynchronous::{compress_archive, extract_archive};
+
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	std::fs::create_dir(&source).unwrap();
+	let contents = write_holey(&source.join("holey"));
+	if !has_holes(&source.join("holey")) {
+		return;
+	}
+
+	let output = root.path().join("output");
+	let runtime = tokio::runtime::Builder::new_current_thread()
+		.enable_all()
+		.build()
+		.unwrap();
+	runtime.block_on(async {
+		let archive = compress_archive(vec![source], Vec::new(), &CompressOptions::default())
+			.await
+			.unwrap();
+		assert!((archive.len() as u64) < SIZE / 16);
+		extract_archive(archive.as_slice(), &output, &ExtractOptions::default())
+			.await
+			.unwrap();
+	});
+	assert!(has_holes(&output.join("holey")));
+	assert_eq!(std::fs::read(output.join("holey")).unwrap(), contents);
+}
@@ -0,0 +1,141 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of splitting archives into volumes, and of refusing sets of volumes that are incomplete.
+
+#![cfg(feature = "streaming")]
+
+use larz::volume::{volume_path, VolumeError};
+use larz::{
+	compress_archive_streaming_with_options, extract_archive_streaming_with_options,
+	CompressOptions, ExtractOptions,
+};
+use std::io::{ErrorKind, Sink};
+use std::path::{Path, PathBuf};
+
+/// The maximum size of each volume written by the tests
+const VOLUME_SIZE: u64 = 1024;
+
+/// Compress a directory holding a file that does not compress well into volumes, returning the path of the archive and the file's contents
+///
+/// # Arguments
+///
+/// * `root` - Where to write the directory and the volumes
+fn write_volumes(root: &Path) -> (PathBuf, Vec<u8>) {
+	let source = root.join("source");
+	std::fs::create_dir(&source).unwrap();
+	let mut state = 0x2545_f491_4f6c_dd1du64;
+	let contents: Vec<u8> = (0..VOLUME_SIZE * 4)
+		.map(|_| {
+			state ^= state << 13;
+			state ^= state >> 7;
+			state ^= state << 17;
+			state as u8
+		})
+		.collect();
+	std::fs::write(source.join("file"), &contents).unwrap();
+
+	let archive = root.join("archive.larz");
+	let options = CompressOptions {
+		volume_size: Some(VOLUME_SIZE),
+		..Default::default()
+	};
+	compress_archive_streaming_with_options::<Sink>(vec![source], archive.clone(), &options, None)
+		.unwrap();
+	assert!(!archive.exists());
+	assert!(volume_path(&archive, 4).exists());
+	(archive, contents)
+}
+
+/// Get the reason the volumes of an archive could not be extracted
+///
+/// # Arguments
+///
+/// * `archive` - Path to the archive, as if it were not split into volumes
+fn volume_error(archive: &Path) -> VolumeError {
+	let output = archive.with_file_name("output");
+	let error = extract_archive_streaming_with_options(
+		vec![archive.to_path_buf()],
+		output.clone(),
+		&ExtractOptions::default(),
+	)
+	.unwrap_err();
+	assert_eq!(error.kind(), ErrorKind::InvalidData);
+	assert!(!output.exists());
+	error
+		.get_ref()
+		.unwrap()
+		.downcast_ref::<VolumeError>()
+		.unwrap()
+		.clone()
+}
+
+/// Get the path of the last volume of an archive
+///
+/// # Arguments
+///
+/// * `archive` - Path to the archive, as if it were not split into volumes
+fn last_volume(archive: &Path) -> PathBuf {
+	let count = (1..)
+		.take_while(|number| volume_path(archive, *number).exists())
+		.count();
+	volume_path(archive, count as u32)
+}
+
+#[test]
+fn volumes_round_trip() {
+	let root = tempfile::tempdir().unwrap();
+	let (archive, contents) = write_volumes(root.path());
+	let output = root.path().join("output");
+	extract_archive_streaming_with_options(
+		vec![archive],
+		output.clone(),
+		&ExtractOptions::default(),
+	)
+	.unwrap();
+	assert_eq!(std::fs::read(output.join("file")).unwrap(), contents);
+}
+
+#[test]
+fn missing_middle_volume() {
+	let root = tempfile::tempdir().unwrap();
+	let (archive, _) = write_volumes(root.path());
+	std::fs::remove_file(volume_path(&archive, 2)).unwrap();
+	assert_eq!(
+		volume_error(&archive),
+		VolumeError::Missing(volume_path(&archive, 2))
+	);
+}
+
+#[test]
+fn truncated_last_volume() {
+	let root = tempfile::tempdir().unwrap();
+	let (archive, _) = write_volumes(root.path());
+	let last_volume = last_volume(&archive);
+	let length = std::fs::metadata(&last_volume).unwrap().len();
+	std::fs::OpenOptions::new()
+		.write(true)
+		.open(&last_volume)
+		.unwrap()
+		.set_len(length - 1)
+		.unwrap();
+	assert_eq!(
+		volume_error(&archive),
+		VolumeError::Truncated {
+			volume: last_volume,
+			expected: None,
+			actual: length - 1,
+		}
+	);
+}
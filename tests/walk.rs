@@ -0,0 +1,139 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of how directories are walked when they are archived.
+
+#![cfg(all(unix, feature = "streaming"))]
+
+use larz::header::Codec;
+use larz::reader::ArchiveReader;
+use larz::{
+	compress_archive_streaming_with_options, CompressOptions, ExtractOptions, Symlinks, WalkOptions,
+};
+use std::io::Sink;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+/// Compress a directory, returning the paths of the members of the archive and their types
+///
+/// # Arguments
+///
+/// * `source` - Path to the directory
+///
+/// * `walk` - How the directory is walked
+fn members(source: &Path, walk: WalkOptions) -> Vec<(String, tar::EntryType)> {
+	let archive = source.with_extension("larz");
+	let options = CompressOptions {
+		walk,
+		..Default::default()
+	};
+	compress_archive_streaming_with_options::<Sink>(
+		vec![source.to_path_buf()],
+		archive.clone(),
+		&options,
+		None,
+	)
+	.unwrap();
+	let mut reader =
+		ArchiveReader::open(&archive, Codec::Lz4Frame, &ExtractOptions::default()).unwrap();
+	reader
+		.entries()
+		.unwrap()
+		.map(|entry| {
+			let entry = entry.unwrap();
+			(
+				entry.path().to_string_lossy().into_owned(),
+				entry.entry_type(),
+			)
+		})
+		.collect()
+}
+
+#[test]
+fn max_depth() {
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	std::fs::create_dir_all(source.join("a").join("b")).unwrap();
+	std::fs::write(source.join("a").join("b").join("file"), b"contents").unwrap();
+
+	let paths = |max_depth| {
+		members(
+			&source,
+			WalkOptions {
+				max_depth,
+				..Default::default()
+			},
+		)
+		.into_iter()
+		.map(|(path, _)| path)
+		.collect::<Vec<_>>()
+	};
+	assert_eq!(paths(Some(0)), ["."]);
+	assert_eq!(paths(Some(1)), [".", "a"]);
+	assert_eq!(paths(Some(2)), [".", "a", "a/b"]);
+	assert_eq!(paths(None), [".", "a", "a/b", "a/b/file"]);
+}
+
+#[test]
+fn symbolic_link_loops_are_not_followed() {
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	std::fs::create_dir_all(source.join("directory")).unwrap();
+	std::fs::write(source.join("directory").join("file"), b"contents").unwrap();
+	symlink("..", source.join("directory").join("loop")).unwrap();
+
+	// The directory the link leads back to is stored again, without its contents
+	assert_eq!(
+		members(&source, WalkOptions::default()),
+		[
+			(".".to_owned(), tar::EntryType::Directory),
+			("directory".to_owned(), tar::EntryType::Directory),
+			("directory/file".to_owned(), tar::EntryType::Regular),
+			("directory/loop".to_owned(), tar::EntryType::Directory),
+		]
+	);
+
+	let stored = WalkOptions {
+		symlinks: Symlinks::Store,
+		..Default::default()
+	};
+	assert_eq!(
+		members(&source, stored)[3],
+		("directory/loop".to_owned(), tar::EntryType::Symlink)
+	);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn one_file_system() {
+	let root = tempfile::tempdir().unwrap();
+	let source = root.path().join("source");
+	std::fs::create_dir(&source).unwrap();
+	std::fs::write(source.join("file"), b"contents").unwrap();
+	// procfs is never the filesystem the temporary directory is on
+	symlink("/proc", source.join("proc")).unwrap();
+
+	let walk = WalkOptions {
+		one_file_system: true,
+		..Default::default()
+	};
+	assert_eq!(
+		members(&source, walk),
+		[
+			(".".to_owned(), tar::EntryType::Directory),
+			("file".to_owned(), tar::EntryType::Regular),
+			("proc".to_owned(), tar::EntryType::Directory),
+		]
+	);
+}
@@ -0,0 +1,74 @@
+/*
+	This file is part of larz.
+	larz is free software: you can redistribute it and/or modify
+	it under the terms of the GNU Affero General Public License as published by
+	the Free Software Foundation, either version 3 of the License, or
+	(at your option) any later version.
+	larz is distributed in the hope that it will be useful,
+	but WITHOUT ANY WARRANTY; without even the implied warranty of
+	MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+	GNU Affero General Public License for more details.
+	You should have received a copy of the GNU Affero General Public License
+	along with larz.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tests of the WebAssembly bindings, run under Node with `wasm-pack test --node --features wasm`.
+
+#![cfg(all(feature = "wasm", target_arch = "wasm32"))]
+
+use js_sys::{Array, Map, Uint8Array};
+use larz::wasm::{compress, extract, list};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+/// A `[name, bytes]` pair, as passed to `compress`
+fn pair(name: &str, contents: &[u8]) -> JsValue {
+	Array::of2(&JsValue::from_str(name), &Uint8Array::from(contents)).into()
+}
+
+#[wasm_bindgen_test]
+fn round_trip() {
+	let large: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+	let files = Array::of3(
+		&pair("hello.txt", b"Hello, world!"),
+		&pair("data/large.bin", &large),
+		&pair("empty", b""),
+	);
+	let archive = compress(&files).unwrap();
+
+	assert_eq!(
+		list(&archive).unwrap(),
+		["hello.txt", "data/large.bin", "empty"]
+	);
+	let extracted = extract(&archive).unwrap();
+	assert_eq!(extracted.size(), 3);
+	let contents = |name: &str| Uint8Array::from(extracted.get(&JsValue::from_str(name))).to_vec();
+	assert_eq!(contents("hello.txt"), b"Hello, world!");
+	assert_eq!(contents("data/large.bin"), large);
+	assert!(contents("empty").is_empty());
+}
+
+#[wasm_bindgen_test]
+fn compress_map() {
+	let files = Map::new();
+	files.set(
+		&JsValue::from_str("a.txt"),
+		&Uint8Array::from(b"a".as_slice()),
+	);
+	let archive = compress(&files).unwrap();
+	assert_eq!(list(&archive).unwrap(), ["a.txt"]);
+}
+
+#[wasm_bindgen_test]
+fn rejects_invalid_input() {
+	assert!(compress(&JsValue::from_f64(1.0)).is_err());
+	assert!(compress(&Array::of1(&JsValue::from_str("not a pair"))).is_err());
+	assert!(compress(&Array::of1(&pair("../escape", b""))).is_err());
+}
+
+#[wasm_bindgen_test]
+fn rejects_damaged_archives() {
+	let archive = compress(&Array::of1(&pair("hello.txt", b"Hello, world!"))).unwrap();
+	assert!(list(&archive[..archive.len() - 4]).is_err());
+	assert!(extract(b"not an archive").is_err());
+}